@@ -119,8 +119,13 @@
       in {
         options.services.bluepython508.monfari = {
           enable = mkEnableOption "monfari server";
-          address = mkOption {
+          bind = mkOption {
             type = types.str;
+            default = "127.0.0.1";
+          };
+          port = mkOption {
+            type = types.port;
+            default = 8080;
           };
         };
         config.systemd = mkIf cfg.enable {
@@ -134,8 +139,8 @@
             };
             wantedBy = ["multi-user.target"];
             serviceConfig = {
-              ExecStart = "${self.packages.${pkgs.system}.monfari}/bin/monfari serve http ${cfg.address}";
-              ExecStop = "${lib.getExe pkgs.curl} -XPOST http://${cfg.address}/__stop__";
+              ExecStart = "${self.packages.${pkgs.system}.monfari}/bin/monfari serve http --bind ${cfg.bind} --port ${toString cfg.port}";
+              ExecStop = "${lib.getExe pkgs.curl} -XPOST http://${cfg.bind}:${toString cfg.port}/__stop__";
               DynamicUser = true;
               ProtectHome = true;
               PrivateUsers = true;
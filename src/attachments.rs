@@ -0,0 +1,78 @@
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use eyre::{ensure, eyre, Result};
+use ring::digest;
+use tracing::instrument;
+
+use crate::{s3, types::Attachment};
+
+/// Where attachments are kept: a bucket shared by everyone using the repository, and a local cache of what's been
+/// looked at
+#[derive(Debug)]
+pub struct Store {
+    bucket: s3::Bucket,
+    cache: PathBuf,
+}
+
+impl Store {
+    /// The store at `url`, like `s3://bucket/prefix`
+    pub fn open(url: &str) -> Result<Self> {
+        let cache = crate::cache_dir()?.join("attachments");
+        fs::create_dir_all(&cache)?;
+        Ok(Self {
+            bucket: s3::Bucket::from_url(url)?,
+            cache,
+        })
+    }
+
+    /// Upload the file at `path`, keyed by its contents so the same file is only ever stored once
+    #[instrument]
+    pub fn add(&self, path: &Path) -> Result<Attachment> {
+        let data = fs::read(path)?;
+        let attachment = Attachment {
+            name: path
+                .file_name()
+                .ok_or_else(|| eyre!("{} isn't a file", path.display()))?
+                .to_string_lossy()
+                .into_owned(),
+            sha256: sha256(&data),
+        };
+        self.bucket.put(&key(&attachment), &data)?;
+        fs::write(self.cache.join(&attachment.sha256), &data)?;
+        Ok(attachment)
+    }
+
+    /// A local copy of `attachment`, downloading it if it isn't cached
+    #[instrument]
+    pub fn get(&self, attachment: &Attachment) -> Result<PathBuf> {
+        let path = self.cache.join(&attachment.sha256);
+        if !path.exists() {
+            let data = self
+                .bucket
+                .get(&key(attachment))?
+                .ok_or_else(|| eyre!("Attachment {} is missing from storage", attachment.name))?;
+            ensure!(
+                sha256(&data) == attachment.sha256,
+                "Attachment {} is corrupt in storage",
+                attachment.name
+            );
+            fs::write(&path, data)?;
+        }
+        Ok(path)
+    }
+}
+
+fn key(attachment: &Attachment) -> String {
+    format!("sha256/{}", attachment.sha256)
+}
+
+fn sha256(data: &[u8]) -> String {
+    digest::digest(&digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
@@ -0,0 +1,107 @@
+use std::{env, ffi::OsString, fs, path::PathBuf, thread, time::Duration};
+
+use chrono::Utc;
+use eyre::{bail, eyre, Result};
+use tracing::{error, info, instrument};
+
+use crate::{repository::Repository, s3};
+
+/// When and where `serve` backs the repository up
+#[derive(Debug, clap::Args)]
+pub struct Schedule {
+    /// Where to keep backups: a directory, or an S3-compatible bucket as `s3://bucket/prefix`
+    #[arg(long = "backup-to")]
+    to: Option<String>,
+    /// How often to back up, like `30m`, `6h`, or `1d`
+    #[arg(long = "backup-every", value_parser = parse_interval, default_value = "1d")]
+    every: Duration,
+    /// How many backups to keep; older ones are deleted
+    #[arg(long = "backup-keep", default_value_t = 7)]
+    keep: usize,
+}
+
+/// Parse an interval like `90s`, `30m`, `6h`, or `1d`
+pub fn parse_interval(s: &str) -> Result<Duration> {
+    let unit = s
+        .chars()
+        .last()
+        .ok_or_else(|| eyre!("An interval is required"))?;
+    let n = s[..s.len() - unit.len_utf8()].parse::<u64>()?;
+    Ok(Duration::from_secs(match unit {
+        's' => n,
+        'm' => n * 60,
+        'h' => n * 60 * 60,
+        'd' => n * 60 * 60 * 24,
+        _ => bail!("Intervals are a number followed by s, m, h, or d"),
+    }))
+}
+
+#[derive(Debug)]
+enum Target {
+    Dir(PathBuf),
+    S3(s3::Bucket),
+}
+
+impl Schedule {
+    /// Back `repo` up now and on schedule in the background, if there's somewhere to back it up to
+    pub fn start(self, repo: OsString) -> Result<()> {
+        let Some(to) = &self.to else { return Ok(()) };
+        let target = if to.starts_with("s3://") {
+            Target::S3(s3::Bucket::from_url(to)?)
+        } else {
+            fs::create_dir_all(to)?;
+            Target::Dir(to.into())
+        };
+        thread::spawn(move || loop {
+            // A failed backup shouldn't take the server down with it; the next one may work
+            if let Err(e) = backup(&repo, &target, self.keep) {
+                error!("Backup failed: {e:?}");
+            }
+            thread::sleep(self.every);
+        });
+        Ok(())
+    }
+}
+
+#[instrument]
+fn backup(repo: &OsString, target: &Target, keep: usize) -> Result<()> {
+    let name = format!("monfari-{}", Utc::now().format("%Y%m%dT%H%M%SZ"));
+    match target {
+        Target::Dir(dir) => {
+            let path = Repository::snapshot(repo, dir, &name)?;
+            info!(?path, "Backed up");
+            let names = fs::read_dir(dir)?
+                .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+                .collect::<Result<Vec<_>>>()?;
+            for old in expired(names, keep) {
+                fs::remove_file(dir.join(old))?;
+            }
+        }
+        Target::S3(bucket) => {
+            let path = Repository::snapshot(repo, &env::temp_dir(), &name)?;
+            let uploaded = fs::read(&path)
+                .map_err(eyre::Report::from)
+                .and_then(|data| {
+                    let name = path.file_name().unwrap().to_string_lossy();
+                    bucket.put(&name, &data)
+                });
+            fs::remove_file(&path)?;
+            uploaded?;
+            info!(?bucket, "Backed up");
+            for old in expired(bucket.list()?, keep) {
+                bucket.delete(&old)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The backups among `names` beyond the newest `keep`
+fn expired(mut names: Vec<String>, keep: usize) -> Vec<String> {
+    names.retain(|x| x.starts_with("monfari-"));
+    // Names start with the time they were taken, so sort oldest first
+    names.sort();
+    let expired = names.len().saturating_sub(keep);
+    names.truncate(expired);
+    names
+}
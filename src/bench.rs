@@ -0,0 +1,108 @@
+//! Timing how quickly each kind of repository takes in and gives back transactions, to catch it getting slower
+
+use std::{
+    env, fs,
+    time::{Duration, Instant},
+};
+
+use chrono::{Datelike, Months, NaiveDate};
+use eyre::{eyre, Result};
+
+use crate::{
+    command::{Command, Origin},
+    demo,
+    repository::Repository,
+    types::{Config, Currency},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+enum Backend {
+    Local,
+    Sqlite,
+}
+
+/// What to time
+#[derive(Debug, clap::Args)]
+pub struct Bench {
+    /// How many transactions to add
+    #[arg(long, default_value_t = 10000)]
+    transactions: usize,
+    /// How many to add at once
+    #[arg(long, default_value_t = 1000)]
+    batch: usize,
+    /// The kinds of repository to time, each in a new temporary one
+    #[arg(long, value_enum, default_values_t = [Backend::Local, Backend::Sqlite])]
+    backend: Vec<Backend>,
+}
+
+impl Bench {
+    pub fn run(self) -> Result<()> {
+        // The demo history has a few dozen transactions a month
+        let months = (self.transactions / 30 + 1) as u32;
+        let until = NaiveDate::from_ymd_opt(2023, 12, 31).expect("Valid date");
+        let start = until
+            .with_day(1)
+            .and_then(|x| x.checked_sub_months(Months::new(months - 1)))
+            .ok_or_else(|| eyre!("Too many transactions"))?;
+        let (history, _) = demo::history(1, Currency::EUR, start, until);
+        let (accounts, transactions): (Vec<_>, Vec<_>) = history
+            .into_iter()
+            .partition(|x| matches!(x, Command::CreateAccount(_)));
+        let transactions = transactions
+            .into_iter()
+            .filter_map(|x| match x {
+                Command::AddTransaction(transaction) => Some(transaction),
+                _ => None,
+            })
+            .take(self.transactions)
+            .collect::<Vec<_>>();
+
+        for backend in self.backend {
+            let dir = env::temp_dir().join(format!("monfari-bench-{}", std::process::id()));
+            let (name, addr) = match backend {
+                Backend::Local => ("local", dir.join("local").into_os_string()),
+                Backend::Sqlite => (
+                    "sqlite",
+                    format!("sqlite:{}", dir.join("bench.sqlite3").display()).into(),
+                ),
+            };
+            fs::create_dir_all(&dir)?;
+            let timed = (|| {
+                let mut repo = match backend {
+                    Backend::Local => {
+                        Repository::init(dir.join("local"), vec![], Config::default())?
+                    }
+                    Backend::Sqlite => Repository::open(&addr)?,
+                };
+                for account in accounts.clone() {
+                    repo.run_command(account)?;
+                }
+                let (_, added) = time(|| {
+                    transactions
+                        .chunks(self.batch.max(1))
+                        .try_for_each(|batch| {
+                            repo.add_transactions_bulk(batch.to_vec(), Origin::local(), false)
+                        })
+                })?;
+                drop(repo);
+                let (repo, opened) = time(|| Repository::open(&addr))?;
+                let (_, read) = time(|| repo.all_transactions())?;
+                Ok::<_, eyre::Report>((added, opened, read))
+            })();
+            fs::remove_dir_all(&dir)?;
+            let (added, opened, read) = timed?;
+            println!(
+                "{name}: added {} transactions in {added:.2?} ({:.0}/s), opened in {opened:.2?}, read them all in {read:.2?}",
+                transactions.len(),
+                transactions.len() as f64 / added.as_secs_f64(),
+            );
+        }
+        Ok(())
+    }
+}
+
+fn time<T>(f: impl FnOnce() -> Result<T>) -> Result<(T, Duration)> {
+    let start = Instant::now();
+    let value = f()?;
+    Ok((value, start.elapsed()))
+}
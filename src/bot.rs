@@ -0,0 +1,314 @@
+//! Quick entry from a Telegram or Matrix chat, for adding transactions away from a terminal
+
+use std::{
+    ffi::{OsStr, OsString},
+    fmt::Write,
+    thread,
+    time::Duration,
+};
+
+use eyre::{bail, ensure, Result};
+use serde::Deserialize;
+use serde_json::json;
+use tracing::{error, info, instrument};
+
+use crate::{
+    command,
+    parser::{Command, Parser},
+    repl::Options,
+    repository::Repository,
+    types::{Id, Transaction},
+};
+
+/// How long to wait before trying again when the chat service can't be reached
+const RETRY_DELAY: Duration = Duration::from_secs(5);
+
+/// The chat service to take entries from
+#[derive(Debug, clap::Subcommand)]
+pub enum Chat {
+    Telegram {
+        /// The bot's token, from @BotFather
+        #[arg(long, env = "MONFARI_TELEGRAM_TOKEN", hide_env_values = true)]
+        token: String,
+        /// The ID of a chat allowed to add transactions; messages from any other are ignored
+        #[arg(long = "chat", required = true)]
+        chats: Vec<i64>,
+    },
+    Matrix {
+        /// The homeserver the bot's account is on, like `https://matrix.org`
+        #[arg(long)]
+        homeserver: String,
+        /// An access token for the bot's account
+        #[arg(long, env = "MONFARI_MATRIX_TOKEN", hide_env_values = true)]
+        token: String,
+        /// The ID of a room to take entries from, like `!abc:matrix.org`; messages in any other are ignored
+        #[arg(long = "room", required = true)]
+        rooms: Vec<String>,
+    },
+}
+
+impl Chat {
+    /// Answer entries in chat until stopped, opening `repo` for each one
+    pub fn run(self, repo: OsString, options: &Options) -> Result<()> {
+        match self {
+            Chat::Telegram { token, chats } => telegram(&repo, options, &token, &chats),
+            Chat::Matrix {
+                homeserver,
+                token,
+                rooms,
+            } => matrix(
+                &repo,
+                options,
+                homeserver.trim_end_matches('/'),
+                &token,
+                &rooms,
+            ),
+        }
+    }
+}
+
+/// What to say back to `text`: the transaction it added and the balances it changed, or why it couldn't be added
+#[instrument(skip(options))]
+fn reply(repo: &OsStr, options: &Options, text: &str) -> String {
+    entry(repo, options, text).unwrap_or_else(|e| e.to_string())
+}
+
+fn entry(repo: &OsStr, options: &Options, text: &str) -> Result<String> {
+    let mut repo = Repository::open(repo)?;
+    let cmd = Parser::parse(text, repo.accounts()?, repo.config()?, None).1?;
+    let Command::TransactionAdd {
+        amount,
+        inner,
+        notes,
+        attachments,
+        debts,
+        date,
+    } = cmd
+    else {
+        bail!("Only transactions can be entered here, like `12.50 @Tesco food`");
+    };
+    ensure!(
+        attachments.is_empty(),
+        "Attachments can't be added from chat"
+    );
+    let id = Id::generate();
+    let deltas = repo.run_command(command::Command::AddTransaction(Transaction {
+        id,
+        notes: notes.unwrap_or_default(),
+        amount,
+        inner,
+        attachments: vec![],
+        debts,
+        date,
+        voided: None,
+        tags: vec![],
+    }))?;
+    let mut reply = format!("Added {} ({id})", options.amount(amount));
+    for (id, _) in deltas {
+        let account = repo.account(id)?;
+        write!(
+            reply,
+            "\n{} now at {}",
+            account.name,
+            options.amount(account.current)
+        )?;
+    }
+    Ok(reply)
+}
+
+/// Run `f` until it succeeds, waiting between attempts, as the bot should outlast the chat service going away
+fn retrying<T>(mut f: impl FnMut() -> Result<T>) -> T {
+    loop {
+        match f() {
+            Ok(x) => return x,
+            Err(e) => {
+                error!("Chat service unavailable: {e:?}");
+                thread::sleep(RETRY_DELAY);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramResponse<T> {
+    ok: bool,
+    description: Option<String>,
+    result: Option<T>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Update {
+    update_id: i64,
+    message: Option<Message>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Message {
+    chat: TelegramChat,
+    text: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TelegramChat {
+    id: i64,
+}
+
+fn telegram(repo: &OsStr, options: &Options, token: &str, chats: &[i64]) -> Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_read(Duration::from_secs(90))
+        .build();
+    let api = |method: &str| format!("https://api.telegram.org/bot{token}/{method}");
+    let call = |request: ureq::Request, body: serde_json::Value| -> Result<_> {
+        let response = request
+            .send_json(body)?
+            .into_json::<TelegramResponse<Vec<Update>>>()?;
+        ensure!(
+            response.ok,
+            "Telegram: {}",
+            response.description.unwrap_or_default()
+        );
+        Ok(response.result.unwrap_or_default())
+    };
+    info!("Listening on Telegram");
+    let mut offset = 0;
+    loop {
+        let updates = retrying(|| {
+            call(
+                agent.post(&api("getUpdates")),
+                json!({ "offset": offset, "timeout": 60, "allowed_updates": ["message"] }),
+            )
+        });
+        for update in updates {
+            // Acknowledged by asking for the ones after it, so each is only handled once
+            offset = update.update_id + 1;
+            let Some(Message {
+                chat,
+                text: Some(text),
+            }) = update.message
+            else {
+                continue;
+            };
+            if !chats.contains(&chat.id) {
+                info!(chat = chat.id, "Ignoring message from a chat not allowed");
+                continue;
+            }
+            let reply = reply(repo, options, &text);
+            // The transaction's been added, so this mustn't be retried by handling the update again
+            if let Err(e) = agent
+                .post(&api("sendMessage"))
+                .send_json(json!({ "chat_id": chat.id, "text": reply }))
+            {
+                error!("Couldn't reply: {e}");
+            }
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct Sync {
+    next_batch: String,
+    #[serde(default)]
+    rooms: Rooms,
+}
+
+#[derive(Debug, Default, Deserialize)]
+struct Rooms {
+    #[serde(default)]
+    join: std::collections::BTreeMap<String, JoinedRoom>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JoinedRoom {
+    timeline: Timeline,
+}
+
+#[derive(Debug, Deserialize)]
+struct Timeline {
+    events: Vec<Event>,
+}
+
+#[derive(Debug, Deserialize)]
+struct Event {
+    #[serde(rename = "type")]
+    typ: String,
+    sender: String,
+    event_id: String,
+    #[serde(default)]
+    content: serde_json::Value,
+}
+
+#[derive(Debug, Deserialize)]
+struct WhoAmI {
+    user_id: String,
+}
+
+fn matrix(
+    repo: &OsStr,
+    options: &Options,
+    homeserver: &str,
+    token: &str,
+    rooms: &[String],
+) -> Result<()> {
+    let agent = ureq::AgentBuilder::new()
+        .timeout_read(Duration::from_secs(90))
+        .build();
+    let auth = format!("Bearer {token}");
+    let api = |path: &str| format!("{homeserver}/_matrix/client/v3/{path}");
+    let sync = |since: Option<&str>, timeout: u32| -> Result<Sync> {
+        let mut request = agent
+            .get(&api("sync"))
+            .set("Authorization", &auth)
+            .query("timeout", &timeout.to_string());
+        if let Some(since) = since {
+            request = request.query("since", since);
+        }
+        Ok(request.call()?.into_json()?)
+    };
+    let me = retrying(|| {
+        Ok(agent
+            .get(&api("account/whoami"))
+            .set("Authorization", &auth)
+            .call()?
+            .into_json::<WhoAmI>()?)
+    })
+    .user_id;
+    // Only messages sent from now on are entries; anything older has been seen already, or was never meant for us
+    let mut since = retrying(|| sync(None, 0)).next_batch;
+    info!(user = me, "Listening on Matrix");
+    loop {
+        let batch = retrying(|| sync(Some(&since), 30_000));
+        since = batch.next_batch;
+        for (room, joined) in batch.rooms.join {
+            if !rooms.contains(&room) {
+                continue;
+            }
+            for event in joined.timeline.events {
+                if event.typ != "m.room.message" || event.sender == me {
+                    continue;
+                }
+                let Some(text) = event.content["body"].as_str() else {
+                    continue;
+                };
+                let reply = reply(repo, options, text);
+                // Replies are keyed by the message they answer, so a resent one is only shown once
+                let path = format!(
+                    "rooms/{}/send/m.room.message/{}",
+                    encode(&room),
+                    encode(&event.event_id)
+                );
+                if let Err(e) = agent
+                    .put(&api(&path))
+                    .set("Authorization", &auth)
+                    .send_json(json!({ "msgtype": "m.notice", "body": reply }))
+                {
+                    error!("Couldn't reply: {e}");
+                }
+            }
+        }
+    }
+}
+
+/// Percent-encode a path segment
+fn encode(s: &str) -> String {
+    form_urlencoded::byte_serialize(s.as_bytes()).collect()
+}
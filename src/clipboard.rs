@@ -0,0 +1,28 @@
+//! Copying IDs to the clipboard, so they can be pasted into other tools
+
+use std::{
+    env,
+    io::{self, Write},
+};
+
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use eyre::Result;
+use tracing::debug;
+
+/// Put `text` on the clipboard
+///
+/// Over SSH, the clipboard wanted is the one by the terminal, so the terminal is asked to set it with an OSC 52 escape
+/// sequence instead; tmux passes that on if its `set-clipboard` option is on.
+pub fn copy(text: &str) -> Result<()> {
+    if env::var_os("SSH_TTY").is_none() {
+        match arboard::Clipboard::new().and_then(|mut x| x.set_text(text)) {
+            Ok(()) => return Ok(()),
+            // Without a display, the terminal may still have a clipboard
+            Err(e) => debug!(error = %e, "Couldn't reach the clipboard"),
+        }
+    }
+    let mut stdout = io::stdout().lock();
+    write!(stdout, "\x1b]52;c;{}\x07", BASE64.encode(text))?;
+    stdout.flush()?;
+    Ok(())
+}
@@ -1,5 +1,6 @@
-use std::fmt;
+use std::{collections::BTreeMap, fmt};
 
+use eyre::{bail, ensure, Result};
 use serde::{Deserialize, Serialize};
 
 use super::types::*;
@@ -9,6 +10,59 @@ pub enum Command {
     CreateAccount(Account),
     UpdateAccount(Id<Account>, Vec<AccountModification>),
     AddTransaction(Transaction),
+    /// Correct a transaction already added, changing balances to match
+    UpdateTransaction(Id<Transaction>, Vec<TransactionModification>),
+    /// Undo a transaction by adding a reversal of it as `reversal`, and mark it voided
+    VoidTransaction {
+        id: Id<Transaction>,
+        reversal: Id<Transaction>,
+    },
+    /// Make every payment to and receipt from any of `from` to or from `to` instead
+    RenamePayees {
+        from: Vec<String>,
+        to: String,
+    },
+    /// Add a schedule, or replace the one with its ID
+    SetSchedule(ScheduledTransaction),
+    RemoveSchedule(Id<ScheduledTransaction>),
+    /// Add `transaction` for the day it's dated as due by `schedule`, recording that day as the last it was added for
+    AddScheduled {
+        schedule: Id<ScheduledTransaction>,
+        transaction: Transaction,
+    },
+    /// Save a template for `transaction apply` as `name`, replacing any of that name
+    SetTemplate {
+        name: String,
+        template: TransactionTemplate,
+    },
+    RemoveTemplate(String),
+}
+
+/// Who ran a command, and from where
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Origin {
+    pub user: Option<String>,
+    pub client: Option<String>,
+}
+
+impl Origin {
+    pub fn local() -> Self {
+        Self {
+            user: std::env::var("USER").ok(),
+            client: Some(format!("monfari {}", env!("CARGO_PKG_VERSION"))),
+        }
+    }
+}
+
+/// A command as recorded in a repository's history
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogEntry {
+    /// The commit it was recorded as, in local repositories
+    pub commit: Option<String>,
+    pub timestamp: chrono::DateTime<chrono::Utc>,
+    pub origin: Origin,
+    /// What it did, as the command describes itself
+    pub description: String,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -16,6 +70,223 @@ pub enum AccountModification {
     Disable,
     UpdateName(String),
     UpdateNotes(String),
+    /// Set or clear the institution a physical account is held at
+    UpdateInstitution(Option<String>),
+    /// Only possible for accounts no transaction involves yet
+    ChangeType(AccountType),
+    /// Only possible for accounts no transaction involves yet, which so have nothing in them
+    Delete,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum TransactionModification {
+    UpdateNotes(String),
+    /// Only to another amount in the same currency
+    UpdateAmount(Amount),
+    /// Only for payments and receipts
+    UpdatePayee(String),
+    /// Use the second account wherever the transaction uses the first, which must be of the same type
+    ReplaceAccount(Id<Account>, Id<Account>),
+    /// Only for general transactions, on the account's leg
+    UpdateLegNotes(Id<Account>, String),
+    /// Add whichever of these tags it doesn't have already
+    Tag(Vec<String>),
+    /// Take off whichever of these tags it has
+    Untag(Vec<String>),
+}
+
+/// How much a command changed each account's balance by
+pub type Deltas = Vec<(Id<Account>, Amounts)>;
+
+impl Command {
+    /// The change running this makes to each account's balance
+    ///
+    /// Edits and voids need the transaction they change to tell, so are taken as changing nothing; see [`edit_deltas`].
+    pub fn deltas(&self) -> Result<Deltas> {
+        let mut deltas = BTreeMap::<_, Amounts>::new();
+        if let Some(transaction) = self.transaction() {
+            for (acc, amount) in transaction.results()? {
+                deltas.entry(acc).or_default().add_amount(amount)?;
+            }
+        }
+        Ok(deltas.into_iter().collect())
+    }
+
+    /// The accounts running this changes, whether their balances or anything else about them
+    ///
+    /// For edits, only those they name, and for voids and renaming payees none; the accounts the transactions were
+    /// already in aren't known without them.
+    pub fn accounts(&self) -> Vec<Id<Account>> {
+        match self {
+            Command::CreateAccount(account) => vec![account.id],
+            Command::UpdateAccount(id, _) => vec![*id],
+            Command::AddTransaction(transaction) | Command::AddScheduled { transaction, .. } => {
+                transaction.accounts()
+            }
+            Command::UpdateTransaction(_, changes) => changes
+                .iter()
+                .flat_map(|x| match *x {
+                    TransactionModification::ReplaceAccount(from, to) => vec![from, to],
+                    _ => vec![],
+                })
+                .collect(),
+            Command::VoidTransaction { .. }
+            | Command::RenamePayees { .. }
+            | Command::SetSchedule(_)
+            | Command::RemoveSchedule(_)
+            | Command::SetTemplate { .. }
+            | Command::RemoveTemplate(_) => vec![],
+        }
+    }
+
+    /// The transaction this adds as it's given, if it adds one
+    pub fn transaction(&self) -> Option<&Transaction> {
+        match self {
+            Command::AddTransaction(transaction) | Command::AddScheduled { transaction, .. } => {
+                Some(transaction)
+            }
+            _ => None,
+        }
+    }
+
+    /// Make the change to `templates` this makes, if it saves or removes one
+    pub fn apply_template(
+        &self,
+        templates: &mut BTreeMap<String, TransactionTemplate>,
+    ) -> Result<()> {
+        match self {
+            Command::SetTemplate { name, template } => {
+                templates.insert(name.clone(), template.clone());
+            }
+            Command::RemoveTemplate(name) => {
+                ensure!(templates.remove(name).is_some(), "No template {name}")
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+impl AccountModification {
+    pub fn apply(self, account: &mut Account) {
+        match self {
+            AccountModification::Disable => {
+                account.enabled = false;
+            }
+            AccountModification::UpdateName(name) => {
+                account.name = name;
+            }
+            AccountModification::UpdateNotes(notes) => {
+                account.notes = notes;
+            }
+            AccountModification::UpdateInstitution(institution) => {
+                account.institution = institution;
+            }
+            AccountModification::ChangeType(typ) => {
+                account.typ = typ;
+            }
+            AccountModification::Delete => {
+                account.deleted = true;
+            }
+        }
+    }
+}
+
+impl TransactionModification {
+    pub fn apply(self, transaction: &mut Transaction) -> Result<()> {
+        match self {
+            TransactionModification::UpdateNotes(notes) => {
+                transaction.notes = notes;
+            }
+            TransactionModification::UpdateAmount(amount) => {
+                ensure!(
+                    amount.1 == transaction.amount.1,
+                    "{} can only be corrected to another amount in {}",
+                    transaction.amount,
+                    transaction.amount.1
+                );
+                transaction.amount = amount;
+            }
+            TransactionModification::UpdatePayee(payee) => {
+                let Some(x) = transaction.payee_mut() else {
+                    bail!("Only payments and receipts have a payee")
+                };
+                *x = payee;
+            }
+            TransactionModification::ReplaceAccount(from, to) => {
+                ensure!(
+                    transaction.replace_account(from, to),
+                    "Transaction {} doesn't involve account {from}",
+                    transaction.id
+                );
+            }
+            TransactionModification::UpdateLegNotes(account, notes) => {
+                let Some(x) = transaction.leg_notes_mut(account) else {
+                    bail!("Only general transactions have notes on each account's part")
+                };
+                *x = notes;
+            }
+            TransactionModification::Tag(tags) => {
+                for tag in tags {
+                    if !transaction.tags.contains(&tag) {
+                        transaction.tags.push(tag);
+                    }
+                }
+            }
+            TransactionModification::Untag(tags) => {
+                transaction.tags.retain(|x| !tags.contains(x));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// `transaction` with `changes` made to it, checked as a new one would be besides the types of its accounts
+pub fn edit(transaction: &Transaction, changes: &[TransactionModification]) -> Result<Transaction> {
+    ensure!(
+        transaction.voided.is_none(),
+        "Transaction {} was voided, so can't be edited",
+        transaction.id
+    );
+    let mut edited = transaction.clone();
+    for change in changes.iter().cloned() {
+        change.apply(&mut edited)?;
+    }
+    edited.validate()?;
+    Ok(edited)
+}
+
+/// The change to each account's balance of `old` becoming `new`, including those it comes to nothing for
+pub fn edit_deltas(old: &Transaction, new: &Transaction) -> Result<Deltas> {
+    let mut deltas = BTreeMap::<_, Amounts>::new();
+    for (acc, amount) in old.results()? {
+        deltas.entry(acc).or_default().sub_amount(amount)?;
+    }
+    for (acc, amount) in new.results()? {
+        deltas.entry(acc).or_default().add_amount(amount)?;
+    }
+    Ok(deltas.into_iter().collect())
+}
+
+/// `transaction` marked voided, and the reversal of it added as `reversal`
+pub fn void(
+    transaction: &Transaction,
+    reversal: Id<Transaction>,
+) -> Result<(Transaction, Transaction)> {
+    ensure!(
+        transaction.voided.is_none(),
+        "Transaction {} was already voided",
+        transaction.id
+    );
+    let voided = Transaction {
+        voided: Some(reversal),
+        ..transaction.clone()
+    };
+    let reversal = Transaction {
+        id: reversal,
+        ..transaction.reversal()?
+    };
+    Ok((voided, reversal))
 }
 
 impl fmt::Display for Command {
@@ -26,7 +297,7 @@ impl fmt::Display for Command {
             }
             Command::AddTransaction(transaction) => write!(
                 f,
-                "Add transaction {} of {}: {}",
+                "Add transaction {} of {}: {}{}",
                 transaction.id,
                 transaction.amount,
                 match &transaction.inner {
@@ -36,7 +307,21 @@ impl fmt::Display for Command {
                     TransactionInner::MoveVirt { dst, .. } => format!("moved to {dst}"),
                     TransactionInner::Convert { new_amount, .. } =>
                         format!("converted to {new_amount}"),
-                }
+                    TransactionInner::Adjustment { reason, .. } => format!("adjusted for {reason}"),
+                    TransactionInner::General { legs } => format!("split into {} legs", legs.len()),
+                    TransactionInner::Remap { virt, dst, .. } =>
+                        format!("of {virt} remapped to {dst}"),
+                },
+                transaction
+                    .debts
+                    .iter()
+                    .map(|Debt { person, amount }| if amount.0 > 0 {
+                        format!(", {amount} owed by {person}")
+                    } else {
+                        // Only the very least amount can't be negated, so is shown as it is
+                        format!(", {} owed to {person}", (-*amount).unwrap_or(*amount))
+                    })
+                    .collect::<String>()
             ),
             Command::UpdateAccount(account, actions) => write!(
                 f,
@@ -50,9 +335,67 @@ impl fmt::Display for Command {
                             format!("  - set name to \"{}\"\n", name),
                         AccountModification::UpdateNotes(notes) =>
                             format!("  - set notes to \"{}\"\n", notes),
+                        AccountModification::UpdateInstitution(Some(institution)) =>
+                            format!("  - set institution to \"{institution}\"\n"),
+                        AccountModification::UpdateInstitution(None) =>
+                            "  - clear institution\n".to_owned(),
+                        AccountModification::ChangeType(typ) =>
+                            format!("  - change type to {typ}\n"),
+                        AccountModification::Delete => "  - delete account\n".to_owned(),
                     })
                     .collect::<String>()
             ),
+            Command::UpdateTransaction(transaction, changes) => write!(
+                f,
+                "Update transaction {}:\n{}",
+                transaction,
+                changes
+                    .iter()
+                    .map(|x| match x {
+                        TransactionModification::UpdateNotes(notes) =>
+                            format!("  - set notes to \"{}\"\n", notes),
+                        TransactionModification::UpdateAmount(amount) =>
+                            format!("  - set amount to {amount}\n"),
+                        TransactionModification::UpdatePayee(payee) =>
+                            format!("  - set payee to \"{payee}\"\n"),
+                        TransactionModification::ReplaceAccount(from, to) =>
+                            format!("  - use account {to} instead of {from}\n"),
+                        TransactionModification::UpdateLegNotes(account, notes) =>
+                            format!("  - set notes on account {account} to \"{notes}\"\n"),
+                        TransactionModification::Tag(tags) =>
+                            format!("  - tag with {}\n", tags.join(", ")),
+                        TransactionModification::Untag(tags) =>
+                            format!("  - untag {}\n", tags.join(", ")),
+                    })
+                    .collect::<String>()
+            ),
+            Command::VoidTransaction { id, reversal } => {
+                write!(f, "Void transaction {id}, reversing it with {reversal}")
+            }
+            Command::RenamePayees { from, to } => write!(
+                f,
+                "Rename payees {} to \"{to}\"",
+                from.iter()
+                    .map(|x| format!("\"{x}\""))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+            Command::SetSchedule(schedule) => write!(
+                f,
+                r#"Schedule {}: "{}" every {}"#,
+                schedule.id, schedule.name, schedule.every
+            ),
+            Command::RemoveSchedule(id) => write!(f, "Remove schedule {id}"),
+            Command::AddScheduled {
+                schedule,
+                transaction,
+            } => write!(
+                f,
+                "{} on schedule {schedule}",
+                Command::AddTransaction(transaction.clone())
+            ),
+            Command::SetTemplate { name, .. } => write!(f, r#"Save template "{name}""#),
+            Command::RemoveTemplate(name) => write!(f, r#"Remove template "{name}""#),
         }
     }
 }
@@ -0,0 +1,320 @@
+//! A throwaway repository of made-up accounts and transactions, to try monfari out on or test it against
+
+use std::{collections::BTreeMap, env, ffi::OsStr, path::PathBuf};
+
+use chrono::{DateTime, Datelike, Duration, Local, Months, NaiveDate, TimeZone, Utc};
+use eyre::{ensure, eyre, Result};
+use ulid::Ulid;
+
+use crate::{
+    command::{Command, Origin},
+    export::{Export, OnConflict},
+    repository::Repository,
+    types::{Account, AccountType, Amount, Config, Currency, Id, Transaction, TransactionInner},
+};
+
+/// How to make a demo repository
+#[derive(Debug, clap::Args)]
+pub struct Demo {
+    /// Where to make it; somewhere new in the system's temporary directory if not given
+    #[arg(long)]
+    path: Option<PathBuf>,
+    /// Make a SQLite repository rather than a git one, which is much quicker to fill
+    #[arg(long)]
+    sqlite: bool,
+    /// Print the commands that would fill it, as an export for `monfari import`, rather than making it
+    #[arg(long, conflicts_with_all = ["path", "sqlite"])]
+    export: bool,
+    /// The same seed makes the same accounts and transactions, given the same `--until`
+    #[arg(long, default_value_t = 1)]
+    seed: u64,
+    /// How many months of transactions to make
+    #[arg(long, default_value_t = 12)]
+    months: u32,
+    /// The last day to make transactions on; today if not given
+    #[arg(long)]
+    until: Option<NaiveDate>,
+    #[arg(long, default_value = "EUR")]
+    currency: Currency,
+}
+
+impl Demo {
+    pub fn run(self) -> Result<()> {
+        ensure!(
+            self.months > 0,
+            "At least a month of transactions must be made"
+        );
+        let until = self.until.unwrap_or_else(|| Local::now().date_naive());
+        let start = until
+            .with_day(1)
+            .and_then(|x| x.checked_sub_months(Months::new(self.months - 1)))
+            .ok_or_else(|| eyre!("Too many months"))?;
+        let (commands, config) = history(self.seed, self.currency, start, until);
+        if self.export {
+            println!("{}", serde_json::to_string(&Export::of(commands))?);
+            return Ok(());
+        }
+
+        let extension = if self.sqlite { ".sqlite3" } else { "" };
+        let path = self.path.unwrap_or_else(|| {
+            env::temp_dir().join(format!("monfari-demo-{}{extension}", std::process::id()))
+        });
+        let (mut repo, addr) = if self.sqlite {
+            ensure!(!path.try_exists()?, "{} already exists", path.display());
+            let addr = format!("sqlite:{}", path.display());
+            (Repository::open(OsStr::new(&addr))?, addr)
+        } else {
+            let repo = Repository::init(path.clone(), vec![], Config::default())?;
+            (repo, path.display().to_string())
+        };
+        Export::of(commands).import(&mut repo, OnConflict::Abort, None, false, &Origin::local())?;
+        repo.set_config(config)?;
+        println!("Made a demo repository; try it with\n\n    MONFARI_REPO={addr} monfari");
+        Ok(())
+    }
+}
+
+/// The commands making a demo repository's history from `start` to `until`, and the configuration that goes with them
+pub(crate) fn history(
+    seed: u64,
+    currency: Currency,
+    start: NaiveDate,
+    until: NaiveDate,
+) -> (Vec<Command>, Config) {
+    Generator::new(seed, currency).generate(start, until)
+}
+
+/// A splitmix64 generator, so that a seed always gives the same data
+pub(crate) struct Rng(pub(crate) u64);
+
+impl Rng {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        z ^ (z >> 31)
+    }
+
+    /// Somewhere in `low..high`
+    pub(crate) fn between(&mut self, low: i32, high: i32) -> i32 {
+        low + (self.next() % (high - low) as u64) as i32
+    }
+
+    pub(crate) fn chance(&mut self, percent: u64) -> bool {
+        self.next() % 100 < percent
+    }
+
+    fn pick<'a>(&mut self, choices: &[&'a str]) -> &'a str {
+        choices[(self.next() % choices.len() as u64) as usize]
+    }
+}
+
+struct Generator {
+    rng: Rng,
+    currency: Currency,
+    commands: Vec<Command>,
+    /// Every account's balance so far, so nothing is spent that isn't there
+    balances: BTreeMap<Id<Account>, i32>,
+    /// When the latest transaction was, so each comes after the last
+    clock: DateTime<Utc>,
+}
+
+impl Generator {
+    fn new(seed: u64, currency: Currency) -> Self {
+        Self {
+            rng: Rng(seed),
+            currency,
+            commands: vec![],
+            balances: BTreeMap::new(),
+            clock: DateTime::<Utc>::MIN_UTC,
+        }
+    }
+
+    /// The commands making a year or so of a household's money, from `start` to `until`, and the configuration
+    /// that goes with them
+    fn generate(mut self, start: NaiveDate, until: NaiveDate) -> (Vec<Command>, Config) {
+        let created = Utc.from_utc_datetime(&start.and_hms_opt(0, 0, 0).expect("Midnight exists"));
+        let mut account = |name: &str, typ, notes: &str| {
+            let id = Id::new(Ulid::from_parts(
+                created.timestamp_millis() as u64,
+                self.rng.next() as u128,
+            ));
+            self.commands.push(Command::CreateAccount(Account {
+                id,
+                name: name.to_owned(),
+                notes: notes.to_owned(),
+                typ,
+                current: Default::default(),
+                enabled: true,
+                institution: None,
+                deleted: false,
+            }));
+            id
+        };
+        let current = account("Current Account", AccountType::Physical, "").unerase();
+        let savings = account("Savings Account", AccountType::Physical, "").unerase();
+        let cash = account("Cash", AccountType::Physical, "In my wallet").unerase();
+        let unallocated = account(
+            "Unallocated",
+            AccountType::Virtual,
+            "Money not yet set aside for anything",
+        )
+        .unerase();
+        let budgets = [
+            ("Rent", 95000),
+            ("Bills", 18000),
+            ("Food", 42000),
+            ("Transport", 12000),
+            ("Fun", 25000),
+            ("Holidays", 10000),
+            ("Emergency Fund", 15000),
+        ]
+        .map(|(name, monthly)| (account(name, AccountType::Virtual, "").unerase(), monthly));
+        let [rent, bills, food, transport, fun, holidays, emergency] = budgets.map(|(id, _)| id);
+
+        let paid = |src_virt, dst: &str| TransactionInner::Paid {
+            src: current,
+            src_virt,
+            dst: dst.to_owned(),
+        };
+        self.transaction(
+            start,
+            150000,
+            "Brought over from before",
+            TransactionInner::Received {
+                src: "Opening balance".to_owned(),
+                dst: current,
+                dst_virt: unallocated,
+            },
+        );
+        for day in start.iter_days().take_while(|&x| x <= until) {
+            if day.day() == 1 {
+                let salary = self.rng.between(275000, 290000);
+                let received = TransactionInner::Received {
+                    src: "Acme Ltd".to_owned(),
+                    dst: current,
+                    dst_virt: unallocated,
+                };
+                self.transaction(day, salary, "Salary", received);
+                for (budget, monthly) in budgets {
+                    let allocated = TransactionInner::MoveVirt {
+                        src: unallocated,
+                        dst: budget,
+                        phys: None,
+                    };
+                    self.transaction(day, monthly, "Monthly budget", allocated);
+                }
+                self.transaction(day, 95000, "", paid(rent, "Landlord"));
+                let saved = TransactionInner::MovePhys {
+                    src: current,
+                    dst: savings,
+                    virt: None,
+                };
+                self.transaction(day, 30000, "Standing order", saved);
+                let withdrawn = TransactionInner::MovePhys {
+                    src: current,
+                    dst: cash,
+                    virt: None,
+                };
+                self.transaction(day, 10000, "Cash machine", withdrawn);
+            }
+            match day.day() {
+                5 => {
+                    let amount = self.rng.between(5500, 9500);
+                    self.transaction(day, amount, "Energy", paid(bills, "Northern Power"));
+                }
+                12 => self.transaction(day, 3500, "Broadband", paid(bills, "FibreNet")),
+                20 => self.transaction(day, 2800, "Water", paid(bills, "City Water")),
+                _ => {}
+            }
+            if self.rng.chance(35) {
+                let shop = self
+                    .rng
+                    .pick(&["Tesco", "Lidl", "Aldi", "Sainsbury's", "Co-op"]);
+                let amount = self.rng.between(800, 8500);
+                self.transaction(day, amount, "Groceries", paid(food, shop));
+            }
+            if self.rng.chance(20) {
+                let operator = self.rng.pick(&["Rail Ltd", "City Buses", "Fuel Station"]);
+                let amount = self.rng.between(300, 4500);
+                self.transaction(day, amount, "", paid(transport, operator));
+            }
+            if self.rng.chance(25) {
+                let place = self.rng.pick(&[
+                    "The Red Lion",
+                    "Cinema",
+                    "Pizza Place",
+                    "Bookshop",
+                    "Streamflix",
+                ]);
+                let amount = self.rng.between(500, 6000);
+                self.transaction(day, amount, "", paid(fun, place));
+            }
+            if self.rng.chance(40) {
+                let place = self.rng.pick(&["Café", "Market Stall"]);
+                let amount = self.rng.between(200, 900);
+                let spent = TransactionInner::Paid {
+                    src: cash,
+                    src_virt: fun,
+                    dst: place.to_owned(),
+                };
+                self.transaction(day, amount, "Paid in cash", spent);
+            }
+            if day.month() == 7 && day.day() == 15 {
+                let amount = self.rng.between(60000, 120000);
+                self.transaction(
+                    day,
+                    amount,
+                    "Summer holiday",
+                    paid(holidays, "Sunny Travel"),
+                );
+            }
+            if self.rng.chance(1) {
+                let amount = self.rng.between(15000, 45000);
+                self.transaction(day, amount, "Car repair", paid(emergency, "Garage"));
+            }
+        }
+
+        let config = Config {
+            default_currency: Some(self.currency),
+            default_account: Some(current),
+            default_virtual_account: Some(unallocated),
+            ..Config::default()
+        };
+        (self.commands, config)
+    }
+
+    /// Add a transaction of `amount` minor units on `day`, unless it would take an account below zero
+    fn transaction(&mut self, day: NaiveDate, amount: i32, notes: &str, inner: TransactionInner) {
+        let opening = Utc.from_utc_datetime(&day.and_hms_opt(8, 0, 0).expect("8am exists"));
+        let time = self.clock.max(opening) + Duration::minutes(self.rng.between(1, 60).into());
+        let transaction = Transaction {
+            id: Id::new(Ulid::from_parts(
+                time.timestamp_millis() as u64,
+                self.rng.next() as u128,
+            )),
+            notes: notes.to_owned(),
+            amount: Amount(amount, self.currency),
+            inner,
+            attachments: vec![],
+            debts: vec![],
+            date: None,
+            voided: None,
+            tags: vec![],
+        };
+        let Ok(results) = transaction.results() else {
+            return;
+        };
+        let mut balances = self.balances.clone();
+        for (id, Amount(change, _)) in results {
+            *balances.entry(id).or_default() += change;
+        }
+        if balances.values().any(|&x| x < 0) {
+            return;
+        }
+        self.balances = balances;
+        self.clock = time;
+        self.commands.push(Command::AddTransaction(transaction));
+    }
+}
@@ -0,0 +1,586 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fs,
+    io::{self, Read},
+    ops::AddAssign,
+    path::Path,
+};
+
+use chrono::NaiveDate;
+use eyre::{bail, ensure, eyre, Context, Result};
+use indicatif::{ProgressBar, ProgressStyle};
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, instrument};
+
+use crate::{
+    command::{Command, Origin},
+    repl,
+    repository::Repository,
+    types::{Account, Amount, Amounts, Currency, Id, Transaction, Virtual},
+};
+
+/// The version of the export format written by this version of monfari
+///
+/// Version 1 exports have no version marker, and are either a bare list of commands or a `commands` and `snapshot` object.
+const VERSION: u64 = 2;
+
+/// A repository's contents, as the commands that would recreate it
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Export {
+    #[serde(rename = "monfari_export_version")]
+    version: u64,
+    // Newline-delimited exports give their commands after the version, rather than with it
+    #[serde(default)]
+    commands: Vec<Command>,
+    /// The balance of every account once `commands` have been run
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    snapshot: Option<BTreeMap<Id<Account>, Amounts>>,
+}
+
+/// The first line of a newline-delimited export, before a line for each command
+#[derive(Serialize)]
+struct Header<'a> {
+    #[serde(rename = "monfari_export_version")]
+    version: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    snapshot: Option<&'a BTreeMap<Id<Account>, Amounts>>,
+}
+
+/// The lines of a newline-delimited export still to be read
+struct Lines {
+    pending: io::Cursor<Vec<u8>>,
+    commands: std::vec::IntoIter<Command>,
+}
+
+impl Read for Lines {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let read = self.pending.read(buf)?;
+            if read > 0 || buf.is_empty() {
+                return Ok(read);
+            }
+            let Some(command) = self.commands.next() else {
+                return Ok(0);
+            };
+            let mut line = serde_json::to_vec(&command)?;
+            line.push(b'\n');
+            self.pending = io::Cursor::new(line);
+        }
+    }
+}
+
+/// A version 1 export
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum Legacy {
+    Commands(Vec<Command>),
+    WithSnapshot {
+        commands: Vec<Command>,
+        snapshot: BTreeMap<Id<Account>, Amounts>,
+    },
+}
+
+/// Which part of a repository to export; by default, all of it
+#[derive(Debug, Default, clap::Args)]
+pub struct Selection {
+    /// Only export transactions involving this account, and the accounts they involve
+    #[arg(long)]
+    account: Option<Id<Account>>,
+    /// Only export transactions from this date onwards, like `2023-04-06`
+    #[arg(long)]
+    from: Option<NaiveDate>,
+    /// Only export transactions up to and including this date
+    #[arg(long)]
+    to: Option<NaiveDate>,
+}
+
+impl Selection {
+    pub fn is_everything(&self) -> bool {
+        self.account.is_none() && self.from.is_none() && self.to.is_none()
+    }
+
+    fn includes(&self, transaction: &Transaction) -> bool {
+        let date = transaction.date();
+        self.account
+            .is_none_or(|acc| transaction.accounts().contains(&acc))
+            && self.from.is_none_or(|from| date >= from)
+            && self.to.is_none_or(|to| date <= to)
+    }
+}
+
+impl Export {
+    /// An export of `commands`, made some other way than from a repository
+    pub fn of(commands: Vec<Command>) -> Self {
+        Self {
+            version: VERSION,
+            commands,
+            snapshot: None,
+        }
+    }
+
+    #[instrument(skip(repo))]
+    pub fn new(repo: &Repository, selection: &Selection, with_snapshot: bool) -> Result<Self> {
+        if with_snapshot && !selection.is_everything() {
+            bail!("Snapshots can only be taken of a whole repository");
+        }
+        let transactions = repo
+            .all_transactions()?
+            .into_iter()
+            .filter(|x| selection.includes(x))
+            .collect::<Vec<_>>();
+        let accounts = repo
+            .accounts()?
+            .into_iter()
+            .filter(|acc| {
+                selection.is_everything()
+                    || selection.account == Some(acc.id)
+                    || transactions.iter().any(|x| x.accounts().contains(&acc.id))
+            })
+            .collect::<Vec<_>>();
+        let commands = accounts
+            .iter()
+            .cloned()
+            .map(|mut acc| {
+                acc.current = Default::default();
+                Command::CreateAccount(acc)
+            })
+            .chain(transactions.into_iter().map(Command::AddTransaction))
+            // Schedules and templates are of the whole repository, so only come with all of it
+            .chain(
+                repo.schedules()?
+                    .into_iter()
+                    .filter(|_| selection.is_everything())
+                    .map(Command::SetSchedule),
+            )
+            .chain(
+                repo.config()?
+                    .transaction_templates
+                    .into_iter()
+                    .filter(|_| selection.is_everything())
+                    .map(|(name, template)| Command::SetTemplate { name, template }),
+            )
+            .collect::<Vec<_>>();
+        if !with_snapshot {
+            return Ok(Self {
+                version: VERSION,
+                commands,
+                snapshot: None,
+            });
+        }
+        let snapshot = accounts
+            .into_iter()
+            .map(|acc| (acc.id, acc.current))
+            .collect::<BTreeMap<_, _>>();
+        let replayed = balances(&commands)?;
+        for (id, current) in &snapshot {
+            if !(replayed.get(id).cloned().unwrap_or_default() - current.clone())?.is_zero() {
+                bail!("The balance of account {id} doesn't match its transactions");
+            }
+        }
+        Ok(Self {
+            version: VERSION,
+            commands,
+            snapshot: Some(snapshot),
+        })
+    }
+
+    /// Read an export written by this or any earlier version of monfari, either as one JSON document or as
+    /// newline-delimited JSON
+    pub fn read(reader: impl Read) -> Result<Self> {
+        let mut values =
+            serde_json::Deserializer::from_reader(reader).into_iter::<serde_json::Value>();
+        let value = values
+            .next()
+            .ok_or_else(|| eyre!("The export is empty"))??;
+        let lines = values
+            .map(|x| Ok(serde_json::from_value(x?)?))
+            .collect::<Result<Vec<Command>>>()?;
+        let Some(version) = value.get("monfari_export_version") else {
+            ensure!(
+                lines.is_empty(),
+                "A newline-delimited export must start with its version"
+            );
+            let (commands, snapshot) = match serde_json::from_value(value)? {
+                Legacy::Commands(commands) => (commands, None),
+                Legacy::WithSnapshot { commands, snapshot } => (commands, Some(snapshot)),
+            };
+            return Ok(Self {
+                version: 1,
+                commands,
+                snapshot,
+            });
+        };
+        let mut export = match version.as_u64() {
+            Some(version) if version > VERSION => bail!(
+                "This export is version {version}, but only versions up to {VERSION} are understood; upgrade monfari to import it"
+            ),
+            Some(_) => serde_json::from_value::<Self>(value)?,
+            None => bail!("Invalid export version {version}"),
+        };
+        export.commands.extend(lines);
+        Ok(export)
+    }
+
+    /// The export as newline-delimited JSON: the version and any snapshot, then each command on a line of its own
+    ///
+    /// Each line is only written as it's read, so large exports can be streamed without being held as text.
+    pub fn ndjson(self) -> Result<impl Read + Send> {
+        let mut header = serde_json::to_vec(&Header {
+            version: self.version,
+            snapshot: self.snapshot.as_ref(),
+        })?;
+        header.push(b'\n');
+        Ok(Lines {
+            pending: io::Cursor::new(header),
+            commands: self.commands.into_iter(),
+        })
+    }
+
+    /// Run the exported commands against `repo`, checking balances against the snapshot if there is one
+    ///
+    /// Commands whose accounts or transactions are already in `repo` are skipped, so importing from another copy of a
+    /// repository brings over only what's new. Commands are run in batches, each undone entirely if it fails; if
+    /// `cursor` is given, how far the import got is written there, to carry on from with `resume`.
+    #[instrument(skip(self, repo))]
+    pub fn import(
+        self,
+        repo: &mut Repository,
+        on_conflict: OnConflict,
+        cursor: Option<&Path>,
+        resume: bool,
+        origin: &Origin,
+    ) -> Result<Summary> {
+        debug!(version = self.version, "Importing");
+        let mut position = match cursor {
+            Some(cursor) if resume => serde_json::from_str::<Cursor>(
+                &fs::read_to_string(cursor).wrap_err("There's no failed import to resume")?,
+            )?,
+            None if resume => bail!("Only imports from a file can be resumed"),
+            _ => Cursor::default(),
+        };
+        let accounts = repo
+            .accounts()?
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<BTreeSet<_>>();
+        let transactions = repo
+            .all_transactions()?
+            .into_iter()
+            .map(|x| x.id)
+            .collect::<BTreeSet<_>>();
+        let uncategorized = repo.config()?.uncategorized;
+        let progress =
+            ProgressBar::new(self.commands.len() as u64).with_style(ProgressStyle::with_template(
+                "{wide_bar} {pos}/{len} commands ({per_sec}, {eta} left)",
+            )?);
+        progress.set_position(position.next as u64);
+        let mut summary = Summary::default();
+        let batches = self
+            .commands
+            .into_iter()
+            .skip(position.next)
+            .chunks(BATCH_SIZE);
+        for batch in &batches {
+            let batch = batch.collect_vec();
+            let len = batch.len();
+            let imported = repo.atomically(|repo| {
+                let mut summary = Summary::default();
+                for command in batch {
+                    summary.record(import_command(
+                        repo,
+                        command,
+                        &accounts,
+                        &transactions,
+                        uncategorized,
+                        on_conflict,
+                        origin,
+                        &progress,
+                    )?);
+                    progress.inc(1);
+                }
+                Ok(summary)
+            });
+            match imported {
+                Ok(imported) => {
+                    summary += imported;
+                    position.next += len;
+                }
+                Err(e) => {
+                    progress.abandon();
+                    eprintln!("{summary}");
+                    if let Some(cursor) = cursor {
+                        position.diverged |= summary.diverged();
+                        fs::write(cursor, serde_json::to_string(&position)?)?;
+                        eprintln!(
+                            "The first {} commands were imported; once the problem is fixed, carry on from there with `--resume`",
+                            position.next
+                        );
+                    }
+                    return Err(e);
+                }
+            }
+        }
+        progress.finish_and_clear();
+        eprintln!("{summary}");
+        if let Some(cursor) = cursor.filter(|cursor| cursor.exists()) {
+            fs::remove_file(cursor)?;
+        }
+        if position.diverged || summary.diverged() {
+            eprintln!(
+                "Some commands were skipped or edited, so balances may differ from those exported"
+            );
+            return Ok(summary);
+        }
+        for (id, expected) in self.snapshot.into_iter().flatten() {
+            let current = repo.account(id)?.current;
+            if !(current.clone() - expected.clone())?.is_zero() {
+                bail!("Account {id} has a balance of {current} after import, but {expected} was exported");
+            }
+        }
+        Ok(summary)
+    }
+}
+
+/// How many commands are run at a time; a failure undoes only the batch it's in
+const BATCH_SIZE: usize = 500;
+
+/// How far a failed import got
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Cursor {
+    /// The first command not yet imported
+    next: usize,
+    /// Whether any commands before `next` were skipped or edited
+    diverged: bool,
+}
+
+/// What happened to a command on import
+enum Outcome {
+    Created,
+    Edited,
+    /// Put in the uncategorized account, as its virtual account wasn't in the repository
+    Uncategorized,
+    /// Already in the repository
+    Present,
+    Skipped,
+}
+
+/// What an import did with each of the commands it was given
+#[derive(Debug, Default, Serialize)]
+pub struct Summary {
+    created: usize,
+    edited: usize,
+    uncategorized: usize,
+    present: usize,
+    skipped: usize,
+}
+
+impl Summary {
+    fn record(&mut self, outcome: Outcome) {
+        *match outcome {
+            Outcome::Created => &mut self.created,
+            Outcome::Edited => &mut self.edited,
+            Outcome::Uncategorized => &mut self.uncategorized,
+            Outcome::Present => &mut self.present,
+            Outcome::Skipped => &mut self.skipped,
+        } += 1;
+    }
+
+    fn diverged(&self) -> bool {
+        self.edited > 0 || self.uncategorized > 0 || self.skipped > 0
+    }
+}
+
+impl AddAssign for Summary {
+    fn add_assign(&mut self, other: Self) {
+        self.created += other.created;
+        self.edited += other.edited;
+        self.uncategorized += other.uncategorized;
+        self.present += other.present;
+        self.skipped += other.skipped;
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Imported {} commands ({} edited, {} uncategorized); {} were already present and {} were skipped",
+            self.created + self.edited + self.uncategorized,
+            self.edited,
+            self.uncategorized,
+            self.present,
+            self.skipped
+        )
+    }
+}
+
+/// Run `command` unless it's already been, resolving any conflict as `on_conflict` says
+#[allow(clippy::too_many_arguments)]
+fn import_command(
+    repo: &mut Repository,
+    mut command: Command,
+    accounts: &BTreeSet<Id<Account>>,
+    transactions: &BTreeSet<Id<Transaction>>,
+    uncategorized: Option<Id<Account<Virtual>>>,
+    on_conflict: OnConflict,
+    origin: &Origin,
+    progress: &ProgressBar,
+) -> Result<Outcome> {
+    match &command {
+        Command::CreateAccount(account) if accounts.contains(&account.id) => {
+            return Ok(Outcome::Present)
+        }
+        Command::AddTransaction(transaction) if transactions.contains(&transaction.id) => {
+            return Ok(Outcome::Present)
+        }
+        _ => {}
+    }
+    let mut outcome = Outcome::Created;
+    // A payment or receipt whose virtual account was left behind is kept, to be triaged, rather than failing
+    if let (Command::AddTransaction(transaction), Some(uncategorized)) =
+        (&mut command, uncategorized)
+    {
+        if let Some(virt) = transaction.virtual_account_mut() {
+            if repo.account(virt.erase()).is_err() {
+                *virt = uncategorized;
+                outcome = Outcome::Uncategorized;
+            }
+        }
+    }
+    while let Err(e) = repo.simulate(&command) {
+        let resolution = match on_conflict {
+            OnConflict::Abort => Resolution::Abort,
+            OnConflict::Skip => Resolution::Skip,
+            OnConflict::Ask => progress.suspend(|| ask(&command, &e))?,
+        };
+        match resolution {
+            Resolution::Abort => return Err(e.wrap_err(format!("Couldn't import {command}"))),
+            Resolution::Skip => {
+                progress.suspend(|| eprintln!("Skipping {command}: {e}"));
+                return Ok(Outcome::Skipped);
+            }
+            Resolution::Edit(edited) => {
+                command = *edited;
+                outcome = Outcome::Edited;
+            }
+        }
+    }
+    // Whatever currency was used where it was exported from was meant
+    repo.run_command_with(command, origin.clone(), true)?;
+    Ok(outcome)
+}
+
+/// What to do with a command that can't be imported, such as a transaction that would overdraw an account
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+pub enum OnConflict {
+    /// Stop the import
+    Abort,
+    /// Leave the command out and carry on
+    Skip,
+    /// Ask whether to skip, edit, or abort each time
+    Ask,
+}
+
+enum Resolution {
+    Abort,
+    Skip,
+    Edit(Box<Command>),
+}
+
+fn ask(command: &Command, error: &eyre::Report) -> Result<Resolution> {
+    eprintln!("Can't import {command}: {error}");
+    loop {
+        let Some(choice) = repl::ask("[s]kip, [e]dit, or [a]bort")? else {
+            return Ok(Resolution::Abort);
+        };
+        match choice.trim() {
+            "s" | "skip" => return Ok(Resolution::Skip),
+            "a" | "abort" => return Ok(Resolution::Abort),
+            "e" | "edit" => {
+                let edited = edit::edit(serde_json::to_string_pretty(command)?)?;
+                match serde_json::from_str(&edited) {
+                    Ok(command) => return Ok(Resolution::Edit(Box::new(command))),
+                    Err(e) => eprintln!("Invalid command: {e}"),
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The balance of each account after running `commands` from empty
+fn balances(commands: &[Command]) -> Result<BTreeMap<Id<Account>, Amounts>> {
+    let mut balances = BTreeMap::<_, Amounts>::new();
+    for command in commands {
+        if let Command::AddTransaction(transaction) = command {
+            for (acc, amount) in transaction.results()? {
+                balances.entry(acc).or_default().add_amount(amount)?;
+            }
+        }
+    }
+    Ok(balances)
+}
+
+/// `transactions` of `account` as CSV for spreadsheets, a row for each currency each changed its balance in
+///
+/// Amounts are plain numbers, negative for money going out, with their currency in a column of its own.
+pub fn csv(account: Id<Account>, transactions: &[Transaction]) -> Result<String> {
+    let mut csv = "id,date,type,payee,amount,currency,notes,tags,voided\n".to_owned();
+    for transaction in transactions {
+        let mut changes = BTreeMap::<Currency, i32>::new();
+        for (_, Amount(amount, currency)) in transaction
+            .results()?
+            .into_iter()
+            .filter(|x| x.0 == account)
+        {
+            *changes.entry(currency).or_default() += amount;
+        }
+        // Moves of money held in the account don't change its balance, but are still its transactions
+        if changes.is_empty() {
+            changes.insert(transaction.amount.1, 0);
+        }
+        // As it's tagged in JSON
+        let typ = serde_json::to_value(&transaction.inner)?["type"]
+            .as_str()
+            .unwrap_or_default()
+            .to_owned();
+        let date = transaction.date();
+        for (currency, amount) in changes {
+            let sign = if amount < 0 { "-" } else { "" };
+            let amount = amount.unsigned_abs();
+            let row = [
+                transaction.id.full(),
+                date.to_string(),
+                typ.clone(),
+                csv_text(transaction.payee().unwrap_or_default()),
+                format!("{sign}{}.{:02}", amount / 100, amount % 100),
+                currency.to_string(),
+                csv_text(&transaction.notes),
+                csv_text(&transaction.tags.join(" ")),
+                transaction.voided.map(|x| x.full()).unwrap_or_default(),
+            ];
+            csv.push_str(&row.iter().map(|x| csv_field(x)).join(","));
+            csv.push('\n');
+        }
+    }
+    Ok(csv)
+}
+
+/// `s` as text, even to spreadsheets, which take a cell starting with any of `=+-@` as a formula
+fn csv_text(s: &str) -> String {
+    if s.starts_with(['=', '+', '-', '@']) {
+        format!("'{s}")
+    } else {
+        s.to_owned()
+    }
+}
+
+/// `s` quoted if it has to be, with its quotes doubled
+fn csv_field(s: &str) -> String {
+    if s.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    } else {
+        s.to_owned()
+    }
+}
@@ -0,0 +1,225 @@
+//! Which physical accounts hold each virtual account's money, worked out from the history where it isn't recorded
+
+use std::collections::BTreeMap;
+
+use eyre::Result;
+
+use crate::types::{
+    Account, AccountType, Amount, Amounts, Currency, Id, Transaction, TransactionInner,
+};
+
+/// A virtual account and a physical one, or `None` for money that couldn't be placed on that side
+pub type Cell = (Option<Id<Account>>, Option<Id<Account>>);
+
+/// How much of each virtual account is held in each physical account
+///
+/// Payments, receipts, conversions and adjustments name both sides, so are placed exactly, as are moves that say whose
+/// money they move, and remaps. A move between physical accounts that doesn't takes from the virtual accounts held in
+/// its source in proportion to how much of each it holds, and a move between virtual accounts takes from the physical
+/// accounts holding its source likewise; what they can't cover is left unplaced. Each virtual account's cells add up to
+/// its balance, as do each physical account's.
+pub fn holdings(
+    accounts: &[Account],
+    transactions: &[Transaction],
+) -> Result<BTreeMap<Cell, Amounts>> {
+    let types = accounts
+        .iter()
+        .map(|x| (x.id, x.typ))
+        .collect::<BTreeMap<_, _>>();
+    let mut held = BTreeMap::<Currency, BTreeMap<Cell, i32>>::new();
+    for transaction in transactions {
+        let Amount(amount, currency) = transaction.amount;
+        let cells = held.entry(currency).or_default();
+        match transaction.inner {
+            TransactionInner::MovePhys {
+                src,
+                dst,
+                virt: Some(virt),
+            } => {
+                let virt = Some(virt.erase());
+                transfer(
+                    cells,
+                    (virt, Some(src.erase())),
+                    (virt, Some(dst.erase())),
+                    amount,
+                );
+                continue;
+            }
+            TransactionInner::MoveVirt {
+                src,
+                dst,
+                phys: Some(phys),
+            } => {
+                let phys = Some(phys.erase());
+                transfer(
+                    cells,
+                    (Some(src.erase()), phys),
+                    (Some(dst.erase()), phys),
+                    amount,
+                );
+                continue;
+            }
+            TransactionInner::Remap { virt, src, dst } => {
+                let (virt, src, dst) = (virt.erase(), src.erase(), dst.erase());
+                // What was there in its place goes back to where it came from
+                shift(cells, AccountType::Physical, dst, src, amount, Some(virt));
+                transfer(
+                    cells,
+                    (Some(virt), Some(src)),
+                    (Some(virt), Some(dst)),
+                    amount,
+                );
+                continue;
+            }
+            _ => {}
+        }
+        let mut changes = BTreeMap::<Currency, [BTreeMap<_, i32>; 2]>::new();
+        for (id, Amount(amount, currency)) in transaction.results()? {
+            let [phys, virt] = changes.entry(currency).or_default();
+            match types.get(&id) {
+                Some(AccountType::Physical) => *phys.entry(id).or_default() += amount,
+                Some(AccountType::Virtual) => *virt.entry(id).or_default() += amount,
+                None => {}
+            }
+        }
+        for (currency, [phys, virt]) in changes {
+            let changed = |x: BTreeMap<_, _>| x.into_iter().filter(|x| x.1 != 0).collect();
+            place(
+                held.entry(currency).or_default(),
+                changed(phys),
+                changed(virt),
+            );
+        }
+    }
+
+    let mut holdings = BTreeMap::<Cell, Amounts>::new();
+    for (currency, cells) in held {
+        for (cell, amount) in cells.into_iter().filter(|x| x.1 != 0) {
+            (holdings.entry(cell).or_default()).add_amount(Amount(amount, currency))?;
+        }
+    }
+    Ok(holdings)
+}
+
+/// Place one transaction's changes in one currency: each physical change with virtual ones going the same way, then
+/// what's left as moves between physical accounts or between virtual ones
+fn place(
+    held: &mut BTreeMap<Cell, i32>,
+    mut phys: Vec<(Id<Account>, i32)>,
+    mut virt: Vec<(Id<Account>, i32)>,
+) {
+    for (p, p_amount) in &mut phys {
+        for (v, v_amount) in &mut virt {
+            if p_amount.signum() * v_amount.signum() <= 0 {
+                continue;
+            }
+            let amount = if p_amount.abs() < v_amount.abs() {
+                *p_amount
+            } else {
+                *v_amount
+            };
+            *held.entry((Some(*v), Some(*p))).or_default() += amount;
+            *p_amount -= amount;
+            *v_amount -= amount;
+        }
+    }
+    moves(held, AccountType::Physical, phys);
+    moves(held, AccountType::Virtual, virt);
+}
+
+/// Pair what went out of accounts of one type with what came into others, shifting the money held between them
+fn moves(held: &mut BTreeMap<Cell, i32>, typ: AccountType, changes: Vec<(Id<Account>, i32)>) {
+    let (mut from, mut to): (Vec<_>, Vec<_>) = changes
+        .into_iter()
+        .filter(|x| x.1 != 0)
+        .partition(|x| x.1 < 0);
+    while let (Some((src, out)), Some((dst, into))) = (from.last_mut(), to.last_mut()) {
+        let amount = (-*out).min(*into);
+        shift(held, typ, *src, *dst, amount, None);
+        *out += amount;
+        *into -= amount;
+        from.retain(|x| x.1 != 0);
+        to.retain(|x| x.1 != 0);
+    }
+    // Only when the transaction's accounts have changed type since, so its sides don't balance
+    for (id, amount) in from.into_iter().chain(to) {
+        *held.entry(unplaced(typ, id)).or_default() += amount;
+    }
+}
+
+/// Move `amount` of what's held in `src` to `dst`, both of type `typ`, from each cell of `src` in proportion to how
+/// much is in it, besides that with `except` on the other side
+fn shift(
+    held: &mut BTreeMap<Cell, i32>,
+    typ: AccountType,
+    src: Id<Account>,
+    dst: Id<Account>,
+    amount: i32,
+    except: Option<Id<Account>>,
+) {
+    let sources = held
+        .iter()
+        .filter(|&(&cell, &x)| side(cell, typ) == Some(src) && x > 0)
+        .filter(|&(&cell, _)| except.is_none() || side(cell, other(typ)) != except)
+        .map(|(&cell, &x)| (cell, x))
+        .collect::<Vec<_>>();
+    let total = sources.iter().map(|x| i64::from(x.1)).sum::<i64>();
+    let mut shares = sources
+        .iter()
+        .map(|&(cell, x)| {
+            let share = if total <= i64::from(amount) {
+                x
+            } else {
+                (i64::from(amount) * i64::from(x) / total) as i32
+            };
+            (cell, share)
+        })
+        .collect::<Vec<_>>();
+    let left = amount - shares.iter().map(|x| x.1).sum::<i32>();
+    if total > i64::from(amount) {
+        // Rounding down leaves less than one from each, which each has left to give
+        for share in shares.iter_mut().take(left as usize) {
+            share.1 += 1;
+        }
+    } else {
+        shares.push((unplaced(typ, src), left));
+    }
+    for (cell, share) in shares {
+        let mut moved = cell;
+        *side_mut(&mut moved, typ) = Some(dst);
+        transfer(held, cell, moved, share);
+    }
+}
+
+fn transfer(held: &mut BTreeMap<Cell, i32>, from: Cell, to: Cell, amount: i32) {
+    *held.entry(from).or_default() -= amount;
+    *held.entry(to).or_default() += amount;
+}
+
+/// The cell for what's in `id`, of type `typ`, that isn't placed on the other side
+fn unplaced(typ: AccountType, id: Id<Account>) -> Cell {
+    let mut cell = (None, None);
+    *side_mut(&mut cell, typ) = Some(id);
+    cell
+}
+
+fn other(typ: AccountType) -> AccountType {
+    match typ {
+        AccountType::Virtual => AccountType::Physical,
+        AccountType::Physical => AccountType::Virtual,
+    }
+}
+
+fn side(cell: Cell, typ: AccountType) -> Option<Id<Account>> {
+    match typ {
+        AccountType::Virtual => cell.0,
+        AccountType::Physical => cell.1,
+    }
+}
+
+fn side_mut(cell: &mut Cell, typ: AccountType) -> &mut Option<Id<Account>> {
+    match typ {
+        AccountType::Virtual => &mut cell.0,
+        AccountType::Physical => &mut cell.1,
+    }
+}
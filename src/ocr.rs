@@ -0,0 +1,100 @@
+//! Reading receipts with the `tesseract` OCR engine, to fill in transactions from photos of them
+
+use std::{path::Path, process::Command};
+
+use chrono::NaiveDate;
+use eyre::{ensure, eyre, Result};
+use itertools::Itertools;
+use tracing::instrument;
+
+use crate::types::{Amount, Currency};
+
+/// What could be made out on a receipt; any of it may be missing or misread, so it's only ever a suggestion
+#[derive(Debug, Default)]
+pub struct Receipt {
+    pub merchant: Option<String>,
+    /// The total, and its currency if the receipt shows a symbol for it
+    pub amount: Option<(i32, Option<Currency>)>,
+    pub date: Option<NaiveDate>,
+}
+
+/// Read the receipt in the image at `path`
+#[instrument]
+pub fn read(path: &Path) -> Result<Receipt> {
+    let output = Command::new("tesseract")
+        .arg(path)
+        .arg("stdout")
+        .output()
+        .map_err(|e| eyre!("Couldn't run tesseract, which reading receipts needs: {e}"))?;
+    ensure!(
+        output.status.success(),
+        "tesseract failed: {}",
+        String::from_utf8_lossy(&output.stderr).trim()
+    );
+    Ok(parse(&String::from_utf8_lossy(&output.stdout)))
+}
+
+/// Pick the merchant, total, and date out of a receipt's text
+fn parse(text: &str) -> Receipt {
+    let lines = text
+        .lines()
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+        .collect_vec();
+    // Receipts are headed by whoever issued them
+    let merchant = lines
+        .iter()
+        .find(|x| x.chars().filter(|c| c.is_alphabetic()).count() >= 3)
+        .map(|x| x.to_string());
+    let amounts = |line: &str| line.split_whitespace().filter_map(amount).collect_vec();
+    // Subtotals are labelled too, and come before the total
+    let amount = lines
+        .iter()
+        .rev()
+        .filter(|x| {
+            let x = x.to_lowercase();
+            x.contains("total") && !x.contains("subtotal") && !x.contains("sub total")
+        })
+        .find_map(|x| amounts(x).pop())
+        .or_else(|| {
+            lines
+                .iter()
+                .flat_map(|x| amounts(x))
+                .max_by_key(|(num, _)| *num)
+        });
+    let date = lines
+        .iter()
+        .flat_map(|x| x.split_whitespace())
+        .find_map(date);
+    Receipt {
+        merchant,
+        amount,
+        date,
+    }
+}
+
+/// An amount like `12.50`, `€12.50`, `12,50`, or `1,234.50`
+fn amount(word: &str) -> Option<(i32, Option<Currency>)> {
+    let currency = word.chars().find_map(Currency::from_symbol);
+    let num = word.trim_matches(|c: char| !c.is_ascii_digit());
+    let num = if num.contains('.') {
+        num.replace(',', "")
+    } else {
+        num.replace(',', ".")
+    };
+    // Prices always show cents, which tells them apart from quantities and product codes
+    if !num.contains('.') {
+        return None;
+    }
+    Some((Amount::parse_num(&num).filter(|&x| x > 0)?, currency))
+}
+
+fn date(word: &str) -> Option<NaiveDate> {
+    let word = word.trim_matches(|c: char| !c.is_ascii_digit());
+    // Two-digit years first, as `%Y` would take `24` to be the year 24
+    [
+        "%d/%m/%y", "%d.%m.%y", "%d/%m/%Y", "%d.%m.%Y", "%d-%m-%Y", "%Y-%m-%d",
+    ]
+    .into_iter()
+    .find_map(|format| NaiveDate::parse_from_str(word, format).ok())
+}
@@ -0,0 +1,412 @@
+//! Checking that every kind of repository makes the same of the same commands, by running random histories
+//! against each and comparing everything they can be asked
+
+use std::{env, ffi::OsStr, fs, path::Path};
+
+use chrono::NaiveDate;
+use eyre::{bail, ensure, Result};
+use serde::Serialize;
+use ulid::Ulid;
+
+use crate::{
+    command::{AccountModification, Command, TransactionModification},
+    demo::{self, Rng},
+    repository::{Filter, Page, Repository},
+    types::{
+        Account, AccountType, Amount, Config, Currency, Id, Leg, Party, ScheduledTransaction,
+        Stats, Transaction, TransactionInner, TransactionTemplate,
+    },
+};
+
+/// One of the hundred days transactions are dated, when they're dated, and schedules start
+fn day(rng: &mut Rng) -> NaiveDate {
+    NaiveDate::from_num_days_from_ce_opt(738_000 + (rng.next() % 100) as i32).expect("In range")
+}
+
+/// A demo history, with mistakes, edits and voids made at random throughout it
+fn commands(seed: u64, start: NaiveDate, until: NaiveDate) -> Vec<Command> {
+    let (history, _) = demo::history(seed, Currency::EUR, start, until);
+    let mut rng = Rng(!seed);
+    let mut accounts = vec![];
+    // Scheduled moves are between these, so most of them can be added
+    let mut physical = vec![];
+    let mut transactions = vec![];
+    let mut schedules = vec![];
+    let mut commands = vec![];
+    // When the last transaction was, so mistakes are dated among the rest
+    let mut time = 0;
+    for command in history {
+        match &command {
+            Command::CreateAccount(account) => {
+                accounts.push(account.id);
+                if account.typ == AccountType::Physical {
+                    physical.push(account.id);
+                }
+            }
+            Command::AddTransaction(transaction) => {
+                time = transaction.id.0.timestamp_ms();
+                transactions.push(transaction.id);
+            }
+            Command::UpdateAccount(..)
+            | Command::UpdateTransaction(..)
+            | Command::VoidTransaction { .. }
+            | Command::RenamePayees { .. }
+            | Command::SetSchedule(_)
+            | Command::RemoveSchedule(_)
+            | Command::AddScheduled { .. }
+            | Command::SetTemplate { .. }
+            | Command::RemoveTemplate(_) => {}
+        }
+        commands.push(command);
+        if accounts.is_empty() {
+            continue;
+        }
+        let account = |rng: &mut Rng| accounts[(rng.next() % accounts.len() as u64) as usize];
+        if physical.len() > 1 && rng.chance(10) {
+            let mut account = || physical[(rng.next() % physical.len() as u64) as usize];
+            let (src, dst) = (account(), account());
+            let command = schedule(&mut rng, src, dst, &mut schedules, time);
+            if let Command::AddScheduled { transaction, .. } = &command {
+                transactions.push(transaction.id);
+            }
+            commands.push(command);
+        }
+        if !physical.is_empty() && rng.chance(5) {
+            let src = physical[(rng.next() % physical.len() as u64) as usize];
+            // Sometimes any account, to be refused if it's of the wrong type
+            let dst = if rng.chance(20) {
+                account(&mut rng)
+            } else {
+                physical[(rng.next() % physical.len() as u64) as usize]
+            };
+            commands.push(template(&mut rng, src, dst));
+        }
+        if !rng.chance(10) {
+            continue;
+        }
+        let (src, dst, other) = (account(&mut rng), account(&mut rng), account(&mut rng));
+        let modification = match rng.next() % 8 {
+            0 => AccountModification::UpdateName(format!("Account {}", rng.next() % 100)),
+            1 => AccountModification::UpdateNotes(format!("Edited {}", rng.next() % 100)),
+            2 if rng.chance(10) => AccountModification::Disable,
+            3 => AccountModification::Delete,
+            4 => AccountModification::ChangeType(AccountType::Virtual),
+            5 => AccountModification::UpdateInstitution(Some(format!("Bank {}", rng.next() % 3))),
+            6 if !transactions.is_empty() => {
+                let id = transactions[(rng.next() % transactions.len() as u64) as usize];
+                let change = match rng.next() % 7 {
+                    0 => TransactionModification::UpdateNotes(format!(
+                        "Corrected {}",
+                        rng.next() % 100
+                    )),
+                    1 => TransactionModification::UpdateAmount(Amount(
+                        rng.between(1, 50000),
+                        Currency::EUR,
+                    )),
+                    2 => TransactionModification::ReplaceAccount(src, dst),
+                    3 => TransactionModification::UpdateLegNotes(
+                        src,
+                        format!("#corrected{}", rng.next() % 10),
+                    ),
+                    4 => TransactionModification::Tag(vec![format!("tag{}", rng.next() % 3)]),
+                    5 => TransactionModification::Untag(vec![format!("tag{}", rng.next() % 3)]),
+                    _ => {
+                        let reversal = Id::new(Ulid::from_parts(time, rng.next() as u128));
+                        transactions.push(reversal);
+                        commands.push(Command::VoidTransaction { id, reversal });
+                        continue;
+                    }
+                };
+                commands.push(Command::UpdateTransaction(id, vec![change]));
+                continue;
+            }
+            _ => {
+                let amount = Amount(rng.between(1, 50000), Currency::EUR);
+                // Whose money is moved is only sometimes said, and not always as an account of the right type
+                let holder = rng.chance(50).then_some(other);
+                let inner = match rng.next() % 4 {
+                    0 => TransactionInner::MovePhys {
+                        src: src.unerase(),
+                        dst: dst.unerase(),
+                        virt: holder.map(|x| x.unerase()),
+                    },
+                    1 => TransactionInner::MoveVirt {
+                        src: src.unerase(),
+                        dst: dst.unerase(),
+                        phys: holder.map(|x| x.unerase()),
+                    },
+                    2 => TransactionInner::Remap {
+                        virt: other.unerase(),
+                        src: src.unerase(),
+                        dst: dst.unerase(),
+                    },
+                    _ => TransactionInner::General {
+                        legs: vec![
+                            Leg {
+                                party: Party::Account(src),
+                                amount: (-amount).expect("Small amount"),
+                                notes: String::new(),
+                            },
+                            Leg {
+                                party: Party::Account(dst),
+                                amount,
+                                notes: format!("#leg{}", rng.next() % 10),
+                            },
+                        ],
+                    },
+                };
+                let id = Id::new(Ulid::from_parts(time, rng.next() as u128));
+                // Some are dated days before they were added
+                let date = rng.next().is_multiple_of(2).then(|| day(&mut rng));
+                commands.push(Command::AddTransaction(Transaction {
+                    id,
+                    notes: String::new(),
+                    amount,
+                    inner,
+                    attachments: vec![],
+                    debts: vec![],
+                    date,
+                    voided: None,
+                    tags: vec![],
+                }));
+                continue;
+            }
+        };
+        commands.push(Command::UpdateAccount(src, vec![modification]));
+    }
+    commands
+}
+
+/// A command setting, replacing, or removing one of `schedules`, or adding a transaction on one, on a day it may or
+/// may not be due on and may have been added for already
+fn schedule(
+    rng: &mut Rng,
+    src: Id<Account>,
+    dst: Id<Account>,
+    schedules: &mut Vec<Id<ScheduledTransaction>>,
+    time: u64,
+) -> Command {
+    let amount = Amount(rng.between(1, 50000), Currency::EUR);
+    let inner = TransactionInner::MovePhys {
+        src: src.unerase(),
+        dst: dst.unerase(),
+        virt: None,
+    };
+    let existing =
+        (!schedules.is_empty()).then(|| schedules[(rng.next() % schedules.len() as u64) as usize]);
+    match existing {
+        Some(schedule) if rng.chance(60) => {
+            let id = Id::new(Ulid::from_parts(time, rng.next() as u128));
+            Command::AddScheduled {
+                schedule,
+                transaction: Transaction {
+                    id,
+                    notes: String::new(),
+                    amount,
+                    inner,
+                    attachments: vec![],
+                    debts: vec![],
+                    date: Some(day(rng)),
+                    voided: None,
+                    tags: vec![],
+                },
+            }
+        }
+        Some(schedule) if rng.chance(30) => Command::RemoveSchedule(schedule),
+        _ => {
+            let id = match existing {
+                Some(schedule) if rng.chance(30) => schedule,
+                _ => Id::new(Ulid::from_parts(time, rng.next() as u128)),
+            };
+            schedules.push(id);
+            let every = if rng.chance(50) {
+                "* * *"
+            } else {
+                "1,15 * mon"
+            };
+            Command::SetSchedule(ScheduledTransaction {
+                id,
+                name: format!("Schedule {}", rng.next() % 20),
+                every: every.parse().expect("Valid recurrence"),
+                start: day(rng),
+                last: None,
+                notes: format!("Scheduled {}", rng.next() % 100),
+                amount,
+                inner,
+                debts: vec![],
+            })
+        }
+    }
+}
+
+/// A command saving or removing one of a few templates, which may not have been saved
+fn template(rng: &mut Rng, src: Id<Account>, dst: Id<Account>) -> Command {
+    let name = format!("Template {}", rng.next() % 5);
+    if rng.chance(30) {
+        return Command::RemoveTemplate(name);
+    }
+    Command::SetTemplate {
+        name,
+        template: TransactionTemplate {
+            notes: format!("Templated {}", rng.next() % 100),
+            inner: TransactionInner::MovePhys {
+                src: src.unerase(),
+                dst: dst.unerase(),
+                virt: None,
+            },
+        },
+    }
+}
+
+#[test]
+fn backends_agree() -> Result<()> {
+    // Local repositories commit, which git won't do without someone to commit as
+    for var in ["GIT_AUTHOR_NAME", "GIT_COMMITTER_NAME"] {
+        env::set_var(var, "monfari");
+    }
+    for var in ["GIT_AUTHOR_EMAIL", "GIT_COMMITTER_EMAIL"] {
+        env::set_var(var, "monfari@localhost");
+    }
+    let start = NaiveDate::from_ymd_opt(2023, 11, 1).expect("Valid date");
+    let until = NaiveDate::from_ymd_opt(2023, 12, 31).expect("Valid date");
+    for seed in 1..=5 {
+        check(seed, commands(seed, start, until))
+            .map_err(|e| e.wrap_err(format!("With seed {seed}")))?;
+    }
+    Ok(())
+}
+
+/// Run `commands` against new repositories of every kind, checking they accept and refuse the same ones and
+/// end up the same
+fn check(seed: u64, commands: Vec<Command>) -> Result<()> {
+    let dir = env::temp_dir().join(format!("monfari-parity-{}-{seed}", std::process::id()));
+    fs::create_dir_all(&dir)?;
+    let result = check_in(&dir, commands);
+    fs::remove_dir_all(&dir)?;
+    result
+}
+
+fn check_in(dir: &Path, commands: Vec<Command>) -> Result<()> {
+    // Each is compared with the local one
+    let mut local = Repository::init(dir.join("local"), vec![], Config::default())?;
+    let mut others = [
+        (
+            "SQLite",
+            Repository::open(OsStr::new(&format!(
+                "sqlite:{}",
+                dir.join("sql.sqlite3").display()
+            )))?,
+        ),
+        ("memory", Repository::open(OsStr::new("memory:"))?),
+    ];
+    for (i, command) in commands.into_iter().enumerate() {
+        let expected = local.run_command(command.clone());
+        for (kind, other) in &mut others {
+            match (&expected, other.run_command(command.clone())) {
+                (Ok(l), Ok(o)) => same(kind, &format!("the deltas of command {i}"), Ok(l), Ok(&o))?,
+                (Err(_), Err(_)) => {}
+                (l, o) => bail!(
+                    "Local and {kind} repositories disagree on command {i}, {command:?}:\nLocally {}\nIn {kind} {}",
+                    outcome(l.as_ref()),
+                    outcome(o.as_ref())
+                ),
+            }
+        }
+    }
+
+    let accounts = local.accounts()?;
+    for (kind, other) in &others {
+        same(
+            kind,
+            "the accounts",
+            Ok(sorted(accounts.clone())),
+            other.accounts().map(sorted),
+        )?;
+        for Account { id, name, .. } in &accounts {
+            let what = |x| format!("{x} of {name} ({id})");
+            same(
+                kind,
+                &what("the transactions"),
+                local.transactions(*id),
+                other.transactions(*id),
+            )?;
+            let tagged = Filter {
+                tag: Some("tag0"),
+                ..Filter::default()
+            };
+            same(
+                kind,
+                &what("the transactions tagged tag0"),
+                local.transactions_filtered(*id, tagged, Page::default()),
+                other.transactions_filtered(*id, tagged, Page::default()),
+            )?;
+            let dated = Filter {
+                from: NaiveDate::from_num_days_from_ce_opt(738_020),
+                to: NaiveDate::from_num_days_from_ce_opt(738_060),
+                ..Filter::default()
+            };
+            same(
+                kind,
+                &what("the transactions dated in a range"),
+                local.transactions_filtered(*id, dated, Page::default()),
+                other.transactions_filtered(*id, dated, Page::default()),
+            )?;
+            same(
+                kind,
+                &what("the balance history"),
+                local.balance_history(*id),
+                other.balance_history(*id),
+            )?;
+        }
+        same(kind, "the activity", local.activity(), other.activity())?;
+        same(kind, "the schedules", local.schedules(), other.schedules())?;
+        let templates = |x: Config| x.transaction_templates;
+        same(
+            kind,
+            "the templates",
+            local.config().map(templates),
+            other.config().map(templates),
+        )?;
+        // Which backend each is is the one thing that should differ
+        let stats = |x: Stats| Stats {
+            backend: String::new(),
+            ..x
+        };
+        same(
+            kind,
+            "the stats",
+            local.stats().map(stats),
+            other.stats().map(stats),
+        )?;
+        for query in ["edited", "#leg3", "corrected 4", "\"account OR"] {
+            same(
+                kind,
+                &format!("a search for {query}"),
+                local.search(query),
+                other.search(query),
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn outcome<T>(result: Result<T, &eyre::Report>) -> String {
+    match result {
+        Ok(_) => "accepted".to_owned(),
+        Err(e) => format!("refused: {e}"),
+    }
+}
+
+fn sorted(mut accounts: Vec<Account>) -> Vec<Account> {
+    accounts.sort_by_key(|x| x.id);
+    accounts
+}
+
+fn same<T: Serialize>(kind: &str, what: &str, local: Result<T>, other: Result<T>) -> Result<()> {
+    let (local, other) = (serde_json::to_value(local?)?, serde_json::to_value(other?)?);
+    ensure!(
+        local == other,
+        "Local and {kind} repositories disagree on {what}:\n{local:#}\n{other:#}"
+    );
+    Ok(())
+}
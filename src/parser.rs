@@ -0,0 +1,1557 @@
+//! The grammar of commands as typed into the REPL or given on the command line, independent of any line editor
+
+use std::{fmt, iter::Peekable, path::PathBuf};
+
+use chrono::{Datelike, Days, NaiveDate};
+use itertools::Itertools;
+use serde::Serialize;
+
+use crate::{
+    command::{AccountModification, TransactionModification},
+    types::{
+        Account, AccountCondition, AccountSelector, AccountType, Amount, Config, Currency, Debt,
+        Id, Leg, Party, Period, Periods, Physical, Share, Transaction, TransactionInner,
+        TransactionTemplate, Virtual,
+    },
+};
+
+/// Something that could come next, or replace a token
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Suggestion {
+    pub value: String,
+    pub description: Option<String>,
+    /// The characters of the input it would replace
+    pub span: Span,
+}
+
+/// A range of characters in the input, excluding `end`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn new(start: usize, end: usize) -> Self {
+        Self { start, end }
+    }
+}
+
+#[derive(Default, Debug, Clone)]
+pub struct Completions(pub Vec<Suggestion>);
+
+impl Completions {
+    fn set_span(&mut self, span: Span) {
+        for suggestion in self.0.iter_mut() {
+            suggestion.span = span;
+        }
+    }
+}
+
+impl FromIterator<String> for Completions {
+    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
+        iter.into_iter().map(|x| (x, None)).collect()
+    }
+}
+
+impl FromIterator<(String, Option<String>)> for Completions {
+    fn from_iter<T: IntoIterator<Item = (String, Option<String>)>>(iter: T) -> Self {
+        Self(
+            iter.into_iter()
+                .map(|(value, description)| Suggestion {
+                    value,
+                    description,
+                    span: Span::default(),
+                })
+                .collect(),
+        )
+    }
+}
+
+/// Why input couldn't be parsed as a command
+#[derive(Debug, Clone)]
+pub struct Error {
+    /// The first token that couldn't be understood, or `None` if the input ended too soon
+    pub found: Option<String>,
+    /// Where in the input that token is, or where it ends
+    pub at: usize,
+    /// What would have been accepted instead
+    pub expected: Completions,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.found {
+            Some(found) => write!(f, "Unexpected \"{found}\" at {}", self.at)?,
+            None => write!(f, "Incomplete command")?,
+        }
+        let expected = &self.expected.0;
+        if !expected.is_empty() {
+            write!(
+                f,
+                "; expected {}",
+                expected.iter().take(5).map(|x| &x.value).join(", ")
+            )?;
+            if expected.len() > 5 {
+                write!(f, ", ...")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for Error {}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum TokenType {
+    Command,
+    String,
+    Id,
+    Amount,
+    /// An amount without a currency, which is taken to be the default one
+    Implied,
+    Invalid,
+    Whitespace,
+}
+
+#[derive(Clone, Debug)]
+pub struct Token {
+    /// The first and last characters of the token in the input
+    pub bounds: (usize, usize),
+    pub str: String,
+    pub typ: TokenType,
+    /// What could have been given in its place
+    pub completions: Completions,
+}
+
+#[derive(Debug, Default)]
+pub struct AccountFilter {
+    pub typ: Option<AccountType>,
+    pub include_disabled: bool,
+    pub show_empty: bool,
+    pub sort: AccountSort,
+    /// Group physical accounts by the institution they're held at, with the total at each
+    pub by_institution: bool,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub enum AccountSort {
+    #[default]
+    Id,
+    Name,
+    Balance,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShowColumn {
+    Date,
+    Amount,
+    Description,
+    Notes,
+}
+
+impl ShowColumn {
+    pub const ALL: &'static [Self] = &[Self::Date, Self::Amount, Self::Description, Self::Notes];
+
+    pub fn header(&self) -> &'static str {
+        match self {
+            ShowColumn::Date => "Date",
+            ShowColumn::Amount => "Amount",
+            ShowColumn::Description => "Description",
+            ShowColumn::Notes => "Notes",
+        }
+    }
+}
+
+/// What to group transactions by in the summary of `account show`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SummaryPeriod {
+    Week,
+    /// A month, or whatever period the year is divided into
+    Month,
+    Year,
+}
+
+impl SummaryPeriod {
+    /// The period `transaction` is dated in, named such that periods sort chronologically
+    pub fn of(&self, transaction: &Transaction, periods: &Periods) -> String {
+        self.name(self.period(transaction.date(), periods))
+    }
+
+    /// The period `date` is in
+    pub fn period(&self, date: NaiveDate, periods: &Periods) -> Period {
+        match self {
+            SummaryPeriod::Week => {
+                let start = date - Days::new(date.weekday().num_days_from_monday().into());
+                Period {
+                    start,
+                    end: start + Days::new(7),
+                }
+            }
+            SummaryPeriod::Month => periods.of(date),
+            SummaryPeriod::Year => periods.year_of(date),
+        }
+    }
+
+    /// A name for `period` that sorts chronologically
+    pub fn name(&self, period: Period) -> String {
+        match self {
+            SummaryPeriod::Week => period.start.format("%G-W%V").to_string(),
+            _ => period.key(),
+        }
+    }
+}
+
+enum AccountShowArg {
+    Full,
+    Column(ShowColumn),
+    Summary(SummaryPeriod),
+    From(NaiveDate),
+    To(NaiveDate),
+    Tag(String),
+}
+
+enum TransactionAddArg {
+    Attach,
+    /// Owed by others if positive, or to them if negative
+    Owed(i32),
+    On(NaiveDate),
+}
+
+enum BulkModifyArg {
+    Filter(AccountCondition),
+    Change(AccountModification),
+}
+
+enum AccountListArg {
+    Type(AccountType),
+    All,
+    ShowEmpty,
+    Sort(AccountSort),
+    ByInstitution,
+}
+
+/// A transaction as named in a command, by its ID or by the start of its short ID
+///
+/// Short IDs are only found once the command is run, as there are too many transactions to search as a line is typed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TransactionRef {
+    Id(Id<Transaction>),
+    Short(String),
+}
+
+/// A command as given to the REPL or on the command line
+#[derive(Debug)]
+pub enum Command {
+    AccountsList(AccountFilter),
+    AccountCreate {
+        typ: AccountType,
+        name: String,
+    },
+    AccountShow {
+        id: Id<Account>,
+        columns: Vec<ShowColumn>,
+        full: bool,
+        summary: Option<SummaryPeriod>,
+        /// Only transactions dated from this day onwards
+        from: Option<NaiveDate>,
+        /// Only transactions dated up to and including this day
+        to: Option<NaiveDate>,
+        /// Only transactions with this tag
+        tag: Option<String>,
+    },
+    AccountModify(Id<Account>, Vec<AccountModification>),
+    /// Make the same changes to every account selected
+    AccountBulkModify {
+        selector: AccountSelector,
+        changes: Vec<AccountModification>,
+    },
+    /// Count the cash in a physical account, and correct its balance to match
+    AccountCount(Id<Account>),
+    /// Every command that touched an account, renames and notes included
+    AccountLog(Id<Account>),
+    /// How much of each virtual account's money is held in each physical account
+    AccountHoldings,
+    TransactionAdd {
+        amount: Amount,
+        inner: TransactionInner,
+        /// Asked for in an editor if not given
+        notes: Option<String>,
+        /// Files to upload to the attachment store and attach
+        attachments: Vec<PathBuf>,
+        /// Parts owed by or to others
+        debts: Vec<Debt>,
+        /// The day it happened, if not today; kept as the time in its ID
+        date: Option<NaiveDate>,
+    },
+    /// Save a transaction to add again with `transaction apply`, replacing any of the same name
+    TransactionTemplate {
+        name: String,
+        template: TransactionTemplate,
+    },
+    TransactionSearch {
+        query: String,
+    },
+    /// Undo a transaction by adding its opposite
+    TransactionReverse {
+        id: TransactionRef,
+    },
+    /// Undo a transaction by adding its opposite, marking it voided
+    TransactionVoid {
+        id: TransactionRef,
+    },
+    /// Correct a transaction; with no changes given, its notes are edited
+    TransactionEdit {
+        id: TransactionRef,
+        changes: Vec<TransactionModification>,
+    },
+    /// Fetch a transaction's attachments, and show where they can be opened
+    TransactionAttachments {
+        id: TransactionRef,
+    },
+    /// Read a receipt, and add the transaction it's for with it attached
+    #[cfg(feature = "ocr")]
+    TransactionFromImage {
+        path: PathBuf,
+    },
+    TransactionLast {
+        count: usize,
+        /// Only transactions involving this account
+        account: Option<Id<Account>>,
+    },
+    /// What was spent from each virtual account in each period, to pick rows of and see what was spent
+    Spending(SummaryPeriod),
+    /// Go through the payments and receipts in a virtual account, the default one if not given, moving each into the
+    /// virtual account it belongs in
+    Triage(Option<Id<Account<Virtual>>>),
+    /// Share `amount` out of `src` between virtual accounts, as the plan of that name in the config says
+    Allocate {
+        amount: Amount,
+        src: Id<Account<Virtual>>,
+        plan: String,
+    },
+    /// Open an event: a new physical account named `name`, with a cash float of `float` moved into it from `src`
+    EventOpen {
+        name: String,
+        float: Amount,
+        src: Id<Account<Physical>>,
+    },
+    /// Count an event's cash, move what's left back to where its float came from, and close it, reporting what it
+    /// made or lost
+    EventClose(Id<Account<Physical>>),
+    /// Record `amount` paid back between me and `person`, into or out of `acc` and `acc_virt`, and refund the virtual
+    /// accounts their shares came out of
+    Settle {
+        person: String,
+        amount: Amount,
+        acc: Id<Account<Physical>>,
+        acc_virt: Id<Account<Virtual>>,
+    },
+    /// Pay `amount` off the loan `name`, its instalment if not given, from `src`
+    LoanPay {
+        name: String,
+        amount: Option<Amount>,
+        src: Id<Account<Physical>>,
+    },
+    /// Go through groups of payees that look alike, merging each into one name
+    PayeeNormalize,
+    /// Set or clear the account commands in the REPL default to
+    Use(Option<Id<Account>>),
+    /// Copy an ID to the clipboard, or that of what was last added if not given
+    #[cfg(feature = "clipboard")]
+    Copy(Option<ulid::Ulid>),
+    /// Check a command and show what it would do, without doing it
+    DryRun(Box<Command>),
+    /// Run a command in a currency that isn't used yet, allowing it from then on
+    NewCurrency(Box<Command>),
+}
+
+/// Currencies offered as completions
+const CURRENCIES: &[Currency] = &[Currency::EUR, Currency::GBP, Currency::USD];
+
+pub struct Parser<'a> {
+    iter: Peekable<<&'a mut Vec<Token> as IntoIterator>::IntoIter>,
+    accounts: Vec<Account>,
+    config: Config,
+    /// The account set with `use account`, which commands default to
+    context: Option<Id<Account>>,
+}
+
+impl<'a> Parser<'a> {
+    /// Split `input` into tokens and parse them, giving the tokens annotated with what they were taken as and
+    /// what could replace them
+    pub fn parse(
+        input: &str,
+        accounts: Vec<Account>,
+        config: Config,
+        context: Option<Id<Account>>,
+    ) -> (Vec<Token>, Result<Command, Error>) {
+        let tokens = input
+            .chars()
+            .enumerate()
+            .group_by({
+                let mut in_string = false;
+                move |&(_, c)| {
+                    if c == '"' {
+                        in_string = !in_string;
+                        2
+                    } else if in_string {
+                        2
+                    } else if c.is_whitespace() {
+                        1
+                    } else {
+                        0
+                    }
+                }
+            })
+            .into_iter()
+            .map(|(_, chars)| {
+                let mut bounds = (usize::MAX, 0);
+                let s = chars
+                    .map(|(idx, c)| {
+                        bounds.0 = usize::min(bounds.0, idx);
+                        bounds.1 = usize::max(bounds.1, idx);
+                        c
+                    })
+                    .collect::<String>();
+                (bounds, s)
+            })
+            .map(|(bounds, str)| Token {
+                typ: if str.chars().all(char::is_whitespace) {
+                    TokenType::Whitespace
+                } else {
+                    TokenType::Invalid
+                },
+                completions: Completions::default(),
+                bounds,
+                str: str.trim_matches('"').to_owned(),
+            })
+            .collect::<Vec<_>>();
+        Self::parse_tokens(tokens, accounts, config, context)
+    }
+
+    /// Parse arguments that have already been split, such as by a shell, taking each as exactly one token
+    pub fn parse_args(
+        args: Vec<String>,
+        accounts: Vec<Account>,
+        config: Config,
+    ) -> (Vec<Token>, Result<Command, Error>) {
+        let mut start = 0;
+        let tokens = args
+            .into_iter()
+            .filter(|x| !x.is_empty())
+            .map(|str| {
+                let bounds = (start, start + str.len() - 1);
+                start += str.len() + 1;
+                Token {
+                    typ: TokenType::Invalid,
+                    completions: Completions::default(),
+                    bounds,
+                    str,
+                }
+            })
+            .collect();
+        Self::parse_tokens(tokens, accounts, config, None)
+    }
+
+    fn parse_tokens(
+        mut tokens: Vec<Token>,
+        accounts: Vec<Account>,
+        config: Config,
+        context: Option<Id<Account>>,
+    ) -> (Vec<Token>, Result<Command, Error>) {
+        let mut this = Parser {
+            accounts,
+            config,
+            context,
+            iter: tokens.iter_mut().peekable(),
+        };
+        let res = this.run();
+        for tok in &mut tokens {
+            tok.completions
+                .set_span(Span::new(tok.bounds.0, tok.bounds.1 + 1))
+        }
+        let res = res.map_err(|mut expected| {
+            let end = tokens.last().map(|x| x.bounds.1 + 1).unwrap_or_default();
+            expected.set_span(Span::new(end, end));
+            let invalid = tokens.iter().find(|x| x.typ == TokenType::Invalid);
+            Error {
+                found: invalid.map(|x| x.str.clone()),
+                at: invalid.map_or(end, |x| x.bounds.0),
+                expected,
+            }
+        });
+        (tokens, res)
+    }
+
+    fn run(&mut self) -> Result<Command, Completions> {
+        let value = self.dispatch_or(
+            &[
+                ("account", &Self::account),
+                ("transaction", &Self::transaction),
+                ("triage", &Self::triage),
+                ("allocate", &Self::allocate),
+                ("event", &Self::event),
+                ("settle", &Self::settle),
+                ("loan", &Self::loan),
+                ("spending", &Self::spending),
+                ("payee", &|this| {
+                    this.dispatch(&[("normalize", &|_| Ok(Command::PayeeNormalize))])
+                }),
+                ("--dry-run", &|this| {
+                    Ok(Command::DryRun(Box::new(this.command()?)))
+                }),
+                ("--new-currency", &|this| {
+                    Ok(Command::NewCurrency(Box::new(this.command()?)))
+                }),
+                ("use", &Self::use_context),
+                #[cfg(feature = "clipboard")]
+                ("copy", &Self::copy),
+            ],
+            Self::quick_entry,
+        )?;
+        Ok(value)
+    }
+
+    fn command(&mut self) -> Result<Command, Completions> {
+        self.dispatch_or(
+            &[
+                ("account", &Self::account),
+                ("transaction", &Self::transaction),
+                ("triage", &Self::triage),
+                ("allocate", &Self::allocate),
+                ("event", &Self::event),
+                ("settle", &Self::settle),
+                ("loan", &Self::loan),
+                ("payee", &|this| {
+                    this.dispatch(&[("normalize", &|_| Ok(Command::PayeeNormalize))])
+                }),
+            ],
+            Self::quick_entry,
+        )
+    }
+
+    fn triage(&mut self) -> Result<Command, Completions> {
+        let bucket = if self.at_end() {
+            None
+        } else {
+            Some(self.account_id(Some(AccountType::Virtual))?.unerase())
+        };
+        Ok(Command::Triage(bucket))
+    }
+
+    /// `allocate AMOUNT [CURRENCY] from VIRTUAL using PLAN`
+    fn allocate(&mut self) -> Result<Command, Completions> {
+        let amount = self.amount()?;
+        let src = self.account_virt("from")?;
+        self.expect("using")?;
+        let plans = self
+            .config
+            .plans
+            .iter()
+            .map(|(name, allocations)| {
+                (
+                    name.clone(),
+                    Some(format!("{} accounts", allocations.len())),
+                )
+            })
+            .collect();
+        let plan = self.token(Some(plans), |this, tok| {
+            this.config
+                .plans
+                .contains_key(tok)
+                .then(|| (TokenType::String, tok.to_owned()))
+        })?;
+        Ok(Command::Allocate { amount, src, plan })
+    }
+
+    fn event(&mut self) -> Result<Command, Completions> {
+        self.dispatch(&[("open", &Self::event_open), ("close", &Self::event_close)])
+    }
+
+    /// `event open NAME float AMOUNT [CURRENCY] from PHYSICAL`
+    fn event_open(&mut self) -> Result<Command, Completions> {
+        let name = self.string()?;
+        self.expect("float")?;
+        let float = self.amount()?;
+        let src = self.account_phys("from")?;
+        Ok(Command::EventOpen { name, float, src })
+    }
+
+    fn event_close(&mut self) -> Result<Command, Completions> {
+        let context = self.context_of(AccountType::Physical);
+        let id = match context {
+            Some(id) if self.at_end() => id,
+            _ => self.account_id(Some(AccountType::Physical))?,
+        };
+        Ok(Command::EventClose(id.unerase()))
+    }
+
+    /// `settle PERSON AMOUNT [CURRENCY] account PHYSICAL virtual VIRTUAL`
+    fn settle(&mut self) -> Result<Command, Completions> {
+        let person = self.string()?;
+        let amount = self.amount()?;
+        let acc = self.account_phys("account")?;
+        let acc_virt = self.account_virt("virtual")?;
+        Ok(Command::Settle {
+            person,
+            amount,
+            acc,
+            acc_virt,
+        })
+    }
+
+    fn loan(&mut self) -> Result<Command, Completions> {
+        self.dispatch(&[("pay", &Self::loan_pay)])
+    }
+
+    /// `loan pay NAME [AMOUNT [CURRENCY]] from PHYSICAL`
+    fn loan_pay(&mut self) -> Result<Command, Completions> {
+        let loans = self
+            .config
+            .loans
+            .iter()
+            .map(|(name, loan)| (name.clone(), Some(format!("{} a month", loan.instalment))))
+            .collect();
+        let name = self.token(Some(loans), |this, tok| {
+            this.config
+                .loans
+                .contains_key(tok)
+                .then(|| (TokenType::String, tok.to_owned()))
+        })?;
+        let amount = if self.peek().as_deref() == Some("from") {
+            None
+        } else {
+            Some(self.amount()?)
+        };
+        let src = self.account_phys("from")?;
+        Ok(Command::LoanPay { name, amount, src })
+    }
+
+    fn use_context(&mut self) -> Result<Command, Completions> {
+        self.dispatch(&[
+            ("account", &|this| {
+                Ok(Command::Use(Some(this.account_id(None)?)))
+            }),
+            ("none", &|_| Ok(Command::Use(None))),
+        ])
+    }
+
+    /// `copy ID`, or `copy $last`
+    #[cfg(feature = "clipboard")]
+    fn copy(&mut self) -> Result<Command, Completions> {
+        let last = ("$last".to_owned(), Some("What was last added".to_owned()));
+        let completions = std::iter::once(last)
+            .chain(
+                self.accounts
+                    .iter()
+                    .filter(|x| x.enabled)
+                    .map(|x| (x.id.to_string(), Some(x.name.clone()))),
+            )
+            .collect();
+        self.token(Some(completions), |this, tok| {
+            if tok == "$last" {
+                return Some((TokenType::Id, None));
+            }
+            // Any ID can be copied, but only accounts can be named by their short IDs
+            let id = tok.parse::<Id<Account>>().ok().or_else(|| {
+                this.accounts
+                    .iter()
+                    .map(|x| x.id)
+                    .find(|id| id.short() == tok)
+            })?;
+            Some((TokenType::Id, Some(id.0)))
+        })
+        .map(Command::Copy)
+    }
+
+    /// `AMOUNT [CURRENCY] @PAYEE [VIRTUAL-ACCOUNT] [NOTES...]`, paid from the default account
+    fn quick_entry(&mut self, tok: &str) -> Option<(TokenType, Result<Command, Completions>)> {
+        let (typ, amount) = self.amount_of(positive(tok)?);
+        let command = amount.and_then(|amount| {
+            let src = self
+                .context_of(AccountType::Physical)
+                .map(|x| x.unerase())
+                .or(self.config.default_account)
+                .ok_or_else(Completions::default)?;
+            let dst = self.payee()?;
+            let default_virt = self
+                .context_of(AccountType::Virtual)
+                .map(|x| x.unerase())
+                .or(self.config.default_virtual_account);
+            let src_virt = match default_virt {
+                Some(default) if self.at_end() => default,
+                _ => self.account_name(AccountType::Virtual)?.unerase(),
+            };
+            let mut notes = vec![];
+            while !self.at_end() {
+                notes.push(self.string()?);
+            }
+            Ok(Command::TransactionAdd {
+                amount,
+                inner: TransactionInner::Paid { src, src_virt, dst },
+                notes: Some(notes.join(" ")),
+                attachments: vec![],
+                debts: vec![],
+                date: None,
+            })
+        });
+        Some((typ, command))
+    }
+
+    /// `@PAYEE`, or `@"PAYEE WITH SPACES"`
+    fn payee(&mut self) -> Result<String, Completions> {
+        let payee = self.token(Some(["@".to_owned()].into_iter().collect()), |_, tok| {
+            Some((TokenType::String, tok.strip_prefix('@')?.to_owned()))
+        })?;
+        if payee.is_empty() {
+            self.string()
+        } else {
+            Ok(payee)
+        }
+    }
+
+    /// An account by its name, ignoring case, or the start of its name if that's unambiguous
+    fn account_name(&mut self, typ: AccountType) -> Result<Id<Account>, Completions> {
+        let candidates = self
+            .accounts
+            .iter()
+            .filter(|x| x.enabled && x.typ == typ)
+            .map(|x| (x.name.to_lowercase(), x.id))
+            .collect_vec();
+        self.token(
+            Some(
+                candidates
+                    .iter()
+                    .map(|(name, id)| (name.clone(), Some(id.to_string())))
+                    .collect(),
+            ),
+            |_, tok| {
+                let tok = tok.to_lowercase();
+                let exact = candidates.iter().find(|(name, _)| *name == tok);
+                let prefixed = candidates
+                    .iter()
+                    .filter(|(name, _)| name.starts_with(&tok))
+                    .exactly_one()
+                    .ok();
+                Some((TokenType::Id, exact.or(prefixed)?.1))
+            },
+        )
+    }
+
+    fn account(&mut self) -> Result<Command, Completions> {
+        self.dispatch(&[
+            ("list", &Self::account_list),
+            ("create", &Self::account_create),
+            ("disable", &Self::account_disable),
+            ("delete", &Self::account_delete),
+            ("bulk-modify", &Self::account_bulk_modify),
+            ("rename", &Self::account_rename),
+            ("convert-type", &Self::account_convert_type),
+            ("institution", &Self::account_institution),
+            ("show", &Self::account_show),
+            ("count", &Self::account_count),
+            ("log", &Self::account_log),
+            ("holdings", &|_| Ok(Command::AccountHoldings)),
+        ])
+    }
+
+    fn account_list(&mut self) -> Result<Command, Completions> {
+        let mut filter = AccountFilter::default();
+        while !self.at_end() {
+            match self.dispatch(&[
+                ("physical", &|_| {
+                    Ok(AccountListArg::Type(AccountType::Physical))
+                }),
+                ("virtual", &|_| {
+                    Ok(AccountListArg::Type(AccountType::Virtual))
+                }),
+                ("--all", &|_| Ok(AccountListArg::All)),
+                ("--show-empty", &|_| Ok(AccountListArg::ShowEmpty)),
+                ("--by-institution", &|_| Ok(AccountListArg::ByInstitution)),
+                ("--sort", &|this| {
+                    Ok(AccountListArg::Sort(this.dispatch(&[
+                        ("id", &|_| Ok(AccountSort::Id)),
+                        ("name", &|_| Ok(AccountSort::Name)),
+                        ("balance", &|_| Ok(AccountSort::Balance)),
+                    ])?))
+                }),
+            ])? {
+                AccountListArg::Type(typ) => filter.typ = Some(typ),
+                AccountListArg::All => filter.include_disabled = true,
+                AccountListArg::ShowEmpty => filter.show_empty = true,
+                AccountListArg::Sort(sort) => filter.sort = sort,
+                AccountListArg::ByInstitution => filter.by_institution = true,
+            }
+        }
+        Ok(Command::AccountsList(filter))
+    }
+
+    fn account_create(&mut self) -> Result<Command, Completions> {
+        let typ = self.dispatch(&[
+            ("physical", &|_| Ok(AccountType::Physical)),
+            ("virtual", &|_| Ok(AccountType::Virtual)),
+        ])?;
+        let name = self.string()?;
+        Ok(Command::AccountCreate { typ, name })
+    }
+
+    fn account_disable(&mut self) -> Result<Command, Completions> {
+        let id = self.account_id(None)?;
+        Ok(Command::AccountModify(
+            id,
+            vec![AccountModification::Disable],
+        ))
+    }
+
+    fn account_delete(&mut self) -> Result<Command, Completions> {
+        let id = self.account_id(None)?;
+        Ok(Command::AccountModify(
+            id,
+            vec![AccountModification::Delete],
+        ))
+    }
+
+    fn account_bulk_modify(&mut self) -> Result<Command, Completions> {
+        let mut selector = AccountSelector::default();
+        let mut changes = vec![];
+        // At least one change must be given
+        while changes.is_empty() || !self.at_end() {
+            match self.dispatch(&[
+                ("--filter", &|this| {
+                    let conditions = [
+                        "type=physical",
+                        "type=virtual",
+                        "enabled=true",
+                        "enabled=false",
+                        "empty=true",
+                        "empty=false",
+                        "name=",
+                    ];
+                    Ok(BulkModifyArg::Filter(this.token(
+                        Some(conditions.into_iter().map(str::to_owned).collect()),
+                        |_, tok| Some((TokenType::String, tok.parse().ok()?)),
+                    )?))
+                }),
+                ("--disable", &|_| {
+                    Ok(BulkModifyArg::Change(AccountModification::Disable))
+                }),
+                ("--delete", &|_| {
+                    Ok(BulkModifyArg::Change(AccountModification::Delete))
+                }),
+                ("--notes", &|this| {
+                    Ok(BulkModifyArg::Change(AccountModification::UpdateNotes(
+                        this.string()?,
+                    )))
+                }),
+            ])? {
+                BulkModifyArg::Filter(condition) => selector.add(condition),
+                BulkModifyArg::Change(change) => changes.push(change),
+            }
+        }
+        Ok(Command::AccountBulkModify { selector, changes })
+    }
+
+    fn account_rename(&mut self) -> Result<Command, Completions> {
+        let id = self.account_id(None)?;
+        let name = self.string()?;
+        Ok(Command::AccountModify(
+            id,
+            vec![AccountModification::UpdateName(name)],
+        ))
+    }
+
+    fn account_convert_type(&mut self) -> Result<Command, Completions> {
+        let id = self.account_id(None)?;
+        let typ = self.dispatch(&[
+            ("physical", &|_| Ok(AccountType::Physical)),
+            ("virtual", &|_| Ok(AccountType::Virtual)),
+        ])?;
+        Ok(Command::AccountModify(
+            id,
+            vec![AccountModification::ChangeType(typ)],
+        ))
+    }
+
+    /// `account institution ID NAME`, or with no name to clear it
+    fn account_institution(&mut self) -> Result<Command, Completions> {
+        let id = self.account_id(Some(AccountType::Physical))?;
+        let institution = if self.at_end() {
+            None
+        } else {
+            Some(self.string()?)
+        };
+        Ok(Command::AccountModify(
+            id,
+            vec![AccountModification::UpdateInstitution(institution)],
+        ))
+    }
+
+    fn account_count(&mut self) -> Result<Command, Completions> {
+        let context = self.context_of(AccountType::Physical);
+        let id = match context {
+            Some(id) if self.at_end() => id,
+            _ => self.account_id(Some(AccountType::Physical))?,
+        };
+        Ok(Command::AccountCount(id))
+    }
+
+    fn account_log(&mut self) -> Result<Command, Completions> {
+        let context = self.context;
+        let id = match context {
+            Some(id) if self.at_end() => id,
+            _ => self.account_id(None)?,
+        };
+        Ok(Command::AccountLog(id))
+    }
+
+    fn account_show(&mut self) -> Result<Command, Completions> {
+        let context = self.context;
+        let id = match context {
+            Some(id) if self.peek().is_none_or(|x| x.starts_with("--")) => id,
+            _ => self.account_id(None)?,
+        };
+        let mut columns = vec![];
+        let mut full = false;
+        let mut summary = None;
+        let mut from = None;
+        let mut to = None;
+        let mut tag = None;
+        while !self.at_end() {
+            match self.dispatch(&[
+                ("--full", &|_| Ok(AccountShowArg::Full)),
+                ("--column", &|this| {
+                    Ok(AccountShowArg::Column(this.dispatch(&[
+                        ("date", &|_| Ok(ShowColumn::Date)),
+                        ("amount", &|_| Ok(ShowColumn::Amount)),
+                        ("description", &|_| Ok(ShowColumn::Description)),
+                        ("notes", &|_| Ok(ShowColumn::Notes)),
+                    ])?))
+                }),
+                ("--by", &|this| {
+                    Ok(AccountShowArg::Summary(this.summary_period()?))
+                }),
+                ("--from", &|this| Ok(AccountShowArg::From(this.date()?))),
+                ("--to", &|this| Ok(AccountShowArg::To(this.date()?))),
+                ("--tag", &|this| {
+                    let tag = this.string()?;
+                    Ok(AccountShowArg::Tag(tag.trim_start_matches('#').to_owned()))
+                }),
+            ])? {
+                AccountShowArg::Full => full = true,
+                AccountShowArg::Column(column) => columns.push(column),
+                AccountShowArg::Summary(period) => summary = Some(period),
+                AccountShowArg::From(date) => from = Some(date),
+                AccountShowArg::To(date) => to = Some(date),
+                AccountShowArg::Tag(name) => tag = Some(name),
+            }
+        }
+        if columns.is_empty() {
+            columns = ShowColumn::ALL.to_vec();
+        }
+        Ok(Command::AccountShow {
+            id,
+            columns,
+            full,
+            summary,
+            from,
+            to,
+            tag,
+        })
+    }
+
+    fn summary_period(&mut self) -> Result<SummaryPeriod, Completions> {
+        self.dispatch(&[
+            ("week", &|_| Ok(SummaryPeriod::Week)),
+            ("month", &|_| Ok(SummaryPeriod::Month)),
+            ("year", &|_| Ok(SummaryPeriod::Year)),
+        ])
+    }
+
+    /// `spending [--by week|month|year]`
+    fn spending(&mut self) -> Result<Command, Completions> {
+        if self.at_end() {
+            return Ok(Command::Spending(SummaryPeriod::Month));
+        }
+        self.expect("--by")?;
+        Ok(Command::Spending(self.summary_period()?))
+    }
+
+    /// A day, like `2024-03-01`
+    fn date(&mut self) -> Result<NaiveDate, Completions> {
+        self.token(None, |_, tok| Some((TokenType::Amount, tok.parse().ok()?)))
+    }
+
+    fn transaction(&mut self) -> Result<Command, Completions> {
+        self.dispatch_or(
+            &[
+                ("search", &Self::transaction_search),
+                ("last", &Self::transaction_last),
+                ("reverse", &Self::transaction_reverse),
+                ("void", &Self::transaction_void),
+                ("edit", &Self::transaction_edit),
+                ("tag", &Self::transaction_tag),
+                ("untag", &Self::transaction_untag),
+                ("split", &Self::transaction_split),
+                ("template", &Self::transaction_template),
+                ("apply", &Self::transaction_apply),
+                ("attachments", &Self::transaction_attachments),
+                #[cfg(feature = "ocr")]
+                ("from-image", &Self::transaction_from_image),
+            ],
+            |this, tok| {
+                let (typ, amount) = this.amount_of(positive(tok)?);
+                Some((typ, amount.and_then(|amount| this.transaction_add(amount))))
+            },
+        )
+    }
+
+    fn transaction_search(&mut self) -> Result<Command, Completions> {
+        let query = self.string()?;
+        Ok(Command::TransactionSearch { query })
+    }
+
+    fn transaction_last(&mut self) -> Result<Command, Completions> {
+        let count = if self.at_end() {
+            10
+        } else {
+            self.token(None, |_, tok| Some((TokenType::Amount, tok.parse().ok()?)))?
+        };
+        Ok(Command::TransactionLast {
+            count,
+            account: self.context,
+        })
+    }
+
+    /// A transaction's ID, or the start of its short ID
+    fn transaction_id(&mut self) -> Result<TransactionRef, Completions> {
+        self.token(None, |_, tok| {
+            let id = match tok.parse() {
+                Ok(id) => TransactionRef::Id(id),
+                Err(_)
+                    if (1..=8).contains(&tok.len())
+                        && tok.bytes().all(|x| x.is_ascii_hexdigit()) =>
+                {
+                    TransactionRef::Short(tok.to_ascii_lowercase())
+                }
+                Err(_) => return None,
+            };
+            Some((TokenType::Id, id))
+        })
+    }
+
+    fn transaction_reverse(&mut self) -> Result<Command, Completions> {
+        let id = self.transaction_id()?;
+        Ok(Command::TransactionReverse { id })
+    }
+
+    fn transaction_void(&mut self) -> Result<Command, Completions> {
+        let id = self.transaction_id()?;
+        Ok(Command::TransactionVoid { id })
+    }
+
+    fn transaction_edit(&mut self) -> Result<Command, Completions> {
+        let id = self.transaction_id()?;
+        let mut changes = vec![];
+        while !self.at_end() {
+            changes.push(self.dispatch(&[
+                ("notes", &|this| {
+                    Ok(TransactionModification::UpdateNotes(this.string()?))
+                }),
+                ("amount", &|this| {
+                    Ok(TransactionModification::UpdateAmount(this.amount()?))
+                }),
+                ("payee", &|this| {
+                    Ok(TransactionModification::UpdatePayee(this.string()?))
+                }),
+                ("account", &|this| {
+                    let from = this.account_id(None)?;
+                    Ok(TransactionModification::ReplaceAccount(
+                        from,
+                        this.account_id(None)?,
+                    ))
+                }),
+                ("leg-notes", &|this| {
+                    let account = this.account_id(None)?;
+                    Ok(TransactionModification::UpdateLegNotes(
+                        account,
+                        this.string()?,
+                    ))
+                }),
+            ])?);
+        }
+        Ok(Command::TransactionEdit { id, changes })
+    }
+
+    fn transaction_tag(&mut self) -> Result<Command, Completions> {
+        self.tags(TransactionModification::Tag)
+    }
+
+    fn transaction_untag(&mut self) -> Result<Command, Completions> {
+        self.tags(TransactionModification::Untag)
+    }
+
+    /// `ID TAG...`, with or without a `#` before each tag
+    fn tags(
+        &mut self,
+        change: fn(Vec<String>) -> TransactionModification,
+    ) -> Result<Command, Completions> {
+        let id = self.transaction_id()?;
+        let mut tags = vec![];
+        while tags.is_empty() || !self.at_end() {
+            tags.push(self.string()?.trim_start_matches('#').to_owned());
+        }
+        Ok(Command::TransactionEdit {
+            id,
+            changes: vec![change(tags)],
+        })
+    }
+
+    fn transaction_attachments(&mut self) -> Result<Command, Completions> {
+        let id = self.transaction_id()?;
+        Ok(Command::TransactionAttachments { id })
+    }
+
+    #[cfg(feature = "ocr")]
+    fn transaction_from_image(&mut self) -> Result<Command, Completions> {
+        let path = self.string()?.into();
+        Ok(Command::TransactionFromImage { path })
+    }
+
+    fn transaction_add(&mut self, amount: Amount) -> Result<Command, Completions> {
+        let with_amount = |f: fn(&mut Self) -> Result<TransactionInner, Completions>| {
+            move |this: &mut Self| Ok((amount, f(this)?))
+        };
+        let (amount, inner) = self.dispatch(&[
+            ("received", &with_amount(Self::transaction_received)),
+            ("paid", &with_amount(Self::transaction_paid)),
+            ("move-phys", &with_amount(Self::transaction_move_phys)),
+            ("move-virt", &with_amount(Self::transaction_move_virt)),
+            ("remap", &with_amount(Self::transaction_remap)),
+            ("convert", &with_amount(Self::transaction_convert)),
+            ("adjust", &|this| this.transaction_adjust(amount)),
+        ])?;
+        self.transaction_details(amount, inner)
+    }
+
+    /// `NAME received|paid|move-phys|move-virt|remap ... [notes NOTES]`, a transaction as it's added without its amount
+    fn transaction_template(&mut self) -> Result<Command, Completions> {
+        let name = self.string()?;
+        // Conversions and adjustments depend on their amounts too much to leave them out
+        let inner = self.dispatch(&[
+            ("received", &Self::transaction_received),
+            ("paid", &Self::transaction_paid),
+            ("move-phys", &Self::transaction_move_phys),
+            ("move-virt", &Self::transaction_move_virt),
+            ("remap", &Self::transaction_remap),
+        ])?;
+        let notes = if self.peek().as_deref() == Some("notes") {
+            self.expect("notes")?;
+            self.string()?
+        } else {
+            String::new()
+        };
+        Ok(Command::TransactionTemplate {
+            name,
+            template: TransactionTemplate { notes, inner },
+        })
+    }
+
+    /// `NAME AMOUNT [CURRENCY]`, then what can follow any transaction being added
+    fn transaction_apply(&mut self) -> Result<Command, Completions> {
+        let templates = self
+            .config
+            .transaction_templates
+            .iter()
+            .map(|(name, template)| {
+                (
+                    name.clone(),
+                    (!template.notes.is_empty()).then(|| template.notes.clone()),
+                )
+            })
+            .collect();
+        let name = self.token(Some(templates), |this, tok| {
+            this.config
+                .transaction_templates
+                .contains_key(tok)
+                .then(|| (TokenType::String, tok.to_owned()))
+        })?;
+        let template = self.config.transaction_templates[&name].clone();
+        let amount = self.amount()?;
+        let mut command = self.transaction_details(amount, template.inner)?;
+        if let Command::TransactionAdd { notes, .. } = &mut command {
+            if !template.notes.is_empty() {
+                *notes = Some(template.notes);
+            }
+        }
+        Ok(command)
+    }
+
+    /// `[in ACCOUNT AMOUNT | out ACCOUNT AMOUNT | from PARTY AMOUNT | to PARTY AMOUNT] [notes NOTES]...`, at least two
+    /// of them
+    fn transaction_split(&mut self) -> Result<Command, Completions> {
+        let mut legs = vec![];
+        // The legs come before anything else, so the amount any shares owed are of is known
+        while legs.len() < 2
+            || self
+                .peek()
+                .is_some_and(|x| ["in", "out", "from", "to"].contains(&x.as_str()))
+        {
+            let (party, sign) = self.dispatch(&[
+                ("in", &|this| {
+                    Ok((Party::Account(this.account_id(None)?), 1))
+                }),
+                ("out", &|this| {
+                    Ok((Party::Account(this.account_id(None)?), -1))
+                }),
+                ("from", &|this| Ok((Party::External(this.string()?), -1))),
+                ("to", &|this| Ok((Party::External(this.string()?), 1))),
+            ])?;
+            let Amount(amount, currency) = self.amount()?;
+            let notes = if self.peek().as_deref() == Some("notes") {
+                self.expect("notes")?;
+                self.string()?
+            } else {
+                String::new()
+            };
+            legs.push(Leg {
+                party,
+                amount: Amount(sign * amount, currency),
+                notes,
+            });
+        }
+        let largest = legs
+            .iter()
+            .map(|x| x.amount)
+            .max_by_key(|x| x.0.abs())
+            .expect("There are at least two legs");
+        let amount = Amount(largest.0.abs(), largest.1);
+        self.transaction_details(amount, TransactionInner::General { legs })
+    }
+
+    /// What can follow any transaction being added: attachments, debts, and the day it happened
+    fn transaction_details(
+        &mut self,
+        amount: Amount,
+        inner: TransactionInner,
+    ) -> Result<Command, Completions> {
+        let mut attachments = vec![];
+        let mut debts = vec![];
+        let mut date = None;
+        while !self.at_end() {
+            let sign = match self.dispatch(&[
+                ("attach", &|_| Ok(TransactionAddArg::Attach)),
+                ("owed-by", &|_| Ok(TransactionAddArg::Owed(1))),
+                ("owed-to", &|_| Ok(TransactionAddArg::Owed(-1))),
+                ("on", &|this| Ok(TransactionAddArg::On(this.date()?))),
+            ])? {
+                TransactionAddArg::Attach => {
+                    attachments.push(self.string()?.into());
+                    continue;
+                }
+                TransactionAddArg::On(day) => {
+                    date = Some(day);
+                    continue;
+                }
+                TransactionAddArg::Owed(sign) => sign,
+            };
+            let person = self.string()?;
+            // A fixed share is kept as given, so one too big or in another currency is refused rather than cut down
+            let owed = match self.share()? {
+                Share::Fixed(owed) => owed,
+                share => share.of(amount).ok_or_else(Completions::default)?,
+            };
+            debts.push(Debt {
+                person,
+                amount: Amount(sign * owed.0, owed.1),
+            });
+        }
+        Ok(Command::TransactionAdd {
+            amount,
+            inner,
+            notes: None,
+            attachments,
+            debts,
+            date,
+        })
+    }
+
+    /// `PERCENT%`, or `AMOUNT [CURRENCY]`
+    fn share(&mut self) -> Result<Share, Completions> {
+        self.token(None, |this, tok| {
+            if tok.ends_with('%') {
+                return Some((TokenType::Amount, Ok(tok.parse().ok()?)));
+            }
+            let (typ, amount) = this.amount_of(positive(tok)?);
+            Some((typ, amount.map(Share::Fixed)))
+        })?
+    }
+
+    fn transaction_received(&mut self) -> Result<TransactionInner, Completions> {
+        self.expect("src")?;
+        let src = self.string()?;
+        let dst = self.account_phys("dst")?;
+        let dst_virt = self.account_virt("dst-virt")?;
+        Ok(TransactionInner::Received { src, dst, dst_virt })
+    }
+
+    fn transaction_paid(&mut self) -> Result<TransactionInner, Completions> {
+        self.expect("dst")?;
+        let dst = self.string()?;
+        let src = self.account_phys("src")?;
+        let src_virt = self.account_virt("src-virt")?;
+        Ok(TransactionInner::Paid { src, dst, src_virt })
+    }
+
+    /// `dst ACCOUNT src ACCOUNT [virt ACCOUNT]`, with the virtual account whose money it moves if holdings are tracked
+    fn transaction_move_phys(&mut self) -> Result<TransactionInner, Completions> {
+        let dst = self.account_phys("dst")?;
+        let src = self.account_phys("src")?;
+        let virt = if self.peek().as_deref() == Some("virt") {
+            Some(self.account_virt("virt")?)
+        } else {
+            None
+        };
+        Ok(TransactionInner::MovePhys { src, dst, virt })
+    }
+
+    /// `dst ACCOUNT src ACCOUNT [phys ACCOUNT]`, with the physical account the money is held in if holdings are tracked
+    fn transaction_move_virt(&mut self) -> Result<TransactionInner, Completions> {
+        let dst = self.account_virt("dst")?;
+        let src = self.account_virt("src")?;
+        let phys = if self.peek().as_deref() == Some("phys") {
+            Some(self.account_phys("phys")?)
+        } else {
+            None
+        };
+        Ok(TransactionInner::MoveVirt { src, dst, phys })
+    }
+
+    /// `virt ACCOUNT src ACCOUNT dst ACCOUNT`, moving where some of a virtual account's money is held
+    fn transaction_remap(&mut self) -> Result<TransactionInner, Completions> {
+        let virt = self.account_virt("virt")?;
+        let src = self.account_phys("src")?;
+        let dst = self.account_phys("dst")?;
+        Ok(TransactionInner::Remap { virt, src, dst })
+    }
+
+    fn transaction_convert(&mut self) -> Result<TransactionInner, Completions> {
+        self.expect("into")?;
+        let new_amount = self.amount()?;
+        let acc = self.account_phys("account")?;
+        let acc_virt = self.account_virt("virtual")?;
+        let fee = if self.peek().as_deref() == Some("fee") {
+            self.expect("fee")?;
+            Some(self.amount()?)
+        } else {
+            None
+        };
+        Ok(TransactionInner::Convert {
+            acc,
+            acc_virt,
+            new_amount,
+            fee,
+        })
+    }
+
+    /// `up` or `down` by `amount`, as amounts are never given negative
+    fn transaction_adjust(
+        &mut self,
+        amount: Amount,
+    ) -> Result<(Amount, TransactionInner), Completions> {
+        let sign = self.dispatch(&[("up", &|_| Ok(1)), ("down", &|_| Ok(-1))])?;
+        let acc = self.account_phys("account")?;
+        let acc_virt = self.account_virt("virtual")?;
+        self.expect("reason")?;
+        let reason = self.string()?;
+        Ok((
+            Amount(sign * amount.0, amount.1),
+            TransactionInner::Adjustment {
+                acc,
+                acc_virt,
+                reason,
+            },
+        ))
+    }
+
+    fn amount(&mut self) -> Result<Amount, Completions> {
+        self.token(None, |this, tok| Some(this.amount_of(positive(tok)?)))?
+    }
+
+    /// Finish an amount of `num` with the currency following it, or the default currency if none does
+    fn amount_of(&mut self, num: i32) -> (TokenType, Result<Amount, Completions>) {
+        // Anything that is, or could become, a currency is taken as one
+        let explicit = self.peek().is_some_and(|tok| {
+            tok.parse::<Currency>().is_ok()
+                || CURRENCIES.iter().any(|x| x.to_string().starts_with(&tok))
+        });
+        match self.config.default_currency {
+            Some(currency) if !explicit => (TokenType::Implied, Ok(Amount(num, currency))),
+            _ => (
+                TokenType::Amount,
+                self.currency().map(|currency| Amount(num, currency)),
+            ),
+        }
+    }
+
+    fn currency(&mut self) -> Result<Currency, Completions> {
+        self.token(
+            Some(CURRENCIES.iter().map(|x| x.to_string()).collect()),
+            |_, tok| Some((TokenType::Amount, tok.parse().ok()?)),
+        )
+    }
+
+    fn string(&mut self) -> Result<String, Completions> {
+        self.token(None, |_, s| Some((TokenType::String, s.to_owned())))
+    }
+
+    fn account_id(
+        &mut self,
+        account_type: Option<AccountType>,
+    ) -> Result<Id<Account>, Completions> {
+        self.token(
+            Some(
+                self.accounts
+                    .iter()
+                    .filter(|x| x.enabled)
+                    .filter(|x| account_type.is_none_or(|typ| x.typ == typ))
+                    .map(|x| {
+                        (
+                            x.id.to_string(),
+                            Some(format!("{} ({})", x.name, x.current)),
+                        )
+                    })
+                    .collect(),
+            ),
+            |this, tok| {
+                // A short ID is only an account's if it's the short ID of one of those there are
+                let id = tok.parse().ok().or_else(|| {
+                    this.accounts
+                        .iter()
+                        .map(|x| x.id)
+                        .find(|id| id.short() == tok)
+                })?;
+                Some((
+                    TokenType::Id,
+                    Some(id).filter(|&s| {
+                        this.accounts
+                            .iter()
+                            .find(|x| x.id == s)
+                            .is_some_and(|acc| account_type.is_none_or(|typ| acc.typ == typ))
+                    })?,
+                ))
+            },
+        )
+    }
+
+    /// The context account, if it's of type `typ`
+    fn context_of(&self, typ: AccountType) -> Option<Id<Account>> {
+        self.context
+            .filter(|&id| self.accounts.iter().any(|x| x.id == id && x.typ == typ))
+    }
+
+    /// `KEYWORD ACCOUNT`, or the context account if it's of the right type and `keyword` isn't given
+    fn account_arg(
+        &mut self,
+        keyword: &'static str,
+        typ: AccountType,
+    ) -> Result<Id<Account>, Completions> {
+        if let Some(id) = self.context_of(typ) {
+            if self.peek().as_deref() != Some(keyword) {
+                return Ok(id);
+            }
+        }
+        self.expect(keyword)?;
+        self.account_id(Some(typ))
+    }
+
+    fn account_phys(
+        &mut self,
+        keyword: &'static str,
+    ) -> Result<Id<Account<Physical>>, Completions> {
+        self.account_arg(keyword, AccountType::Physical)
+            .map(|x| x.unerase())
+    }
+    fn account_virt(&mut self, keyword: &'static str) -> Result<Id<Account<Virtual>>, Completions> {
+        self.account_arg(keyword, AccountType::Virtual)
+            .map(|x| x.unerase())
+    }
+
+    fn expect(&mut self, x: &'static str) -> Result<(), Completions> {
+        self.token(Some([x.to_string()].into_iter().collect()), |_, tok| {
+            (tok == x).then_some((TokenType::Command, ()))
+        })
+    }
+
+    #[allow(clippy::type_complexity)]
+    fn dispatch<T>(
+        &mut self,
+        args: &[(&'static str, &dyn Fn(&mut Self) -> Result<T, Completions>)],
+    ) -> Result<T, Completions> {
+        self.token(
+            Some(args.iter().map(|(key, _)| (*key).to_owned()).collect()),
+            |this, tok| {
+                args.iter()
+                    .find(|(key, _)| key == &tok)
+                    .map(|(_, f)| (TokenType::Command, f(this)))
+            },
+        )?
+    }
+
+    /// Like `dispatch`, but a token matching none of the keys is given to `default` instead
+    #[allow(clippy::type_complexity)]
+    fn dispatch_or<T>(
+        &mut self,
+        args: &[(&'static str, &dyn Fn(&mut Self) -> Result<T, Completions>)],
+        default: impl FnOnce(&mut Self, &str) -> Option<(TokenType, Result<T, Completions>)>,
+    ) -> Result<T, Completions> {
+        self.token(
+            Some(args.iter().map(|(key, _)| (*key).to_owned()).collect()),
+            |this, tok| match args.iter().find(|(key, _)| key == &tok) {
+                Some((_, f)) => Some((TokenType::Command, f(this))),
+                None => default(this, tok),
+            },
+        )?
+    }
+
+    /// The next token, without consuming it
+    fn peek(&mut self) -> Option<String> {
+        if self.at_end() {
+            return None;
+        }
+        self.iter.peek().map(|x| x.str.clone())
+    }
+
+    fn at_end(&mut self) -> bool {
+        while self
+            .iter
+            .next_if(|x| x.typ == TokenType::Whitespace)
+            .is_some()
+        {}
+        self.iter.peek().is_none()
+    }
+
+    fn token<T>(
+        &mut self,
+        completions: Option<Completions>,
+        f: impl FnOnce(&mut Self, &str) -> Option<(TokenType, T)>,
+    ) -> Result<T, Completions> {
+        let completions = completions.unwrap_or_default();
+        let tok = self
+            .iter
+            .find(|x| x.typ != TokenType::Whitespace)
+            .ok_or_else(|| completions.clone())?;
+        tok.completions = completions;
+        if let Some((typ, val)) = f(self, &tok.str) {
+            tok.typ = typ;
+            Ok(val)
+        } else {
+            Err(tok.completions.clone())
+        }
+    }
+}
+
+/// Amounts are never negative or zero; mistakes are undone with `transaction reverse`
+fn positive(tok: &str) -> Option<i32> {
+    Amount::parse_num(tok).filter(|&x| x > 0)
+}
@@ -0,0 +1,73 @@
+//! Finding payees that are likely the same, written differently, like `AMZN*123` and `Amazon EU`, to merge them
+
+use std::collections::BTreeMap;
+
+/// Words statements add to payees that say nothing about who they are
+const NOISE: &[&str] = &[
+    "THE", "LTD", "LIMITED", "PLC", "INC", "LLC", "GMBH", "SARL", "SA", "BV", "CO", "COM", "WWW",
+    "EU", "UK", "US", "PAYPAL", "SQ", "SUMUP", "IZ",
+];
+
+/// The word of a payee that tells it apart: the first besides reference numbers, punctuation, company suffixes and
+/// payment processors' prefixes, in capitals
+fn key(payee: &str) -> Option<String> {
+    payee
+        .to_uppercase()
+        .split(|c: char| !c.is_alphabetic())
+        .find(|x| x.len() > 1 && !NOISE.contains(x))
+        .map(str::to_owned)
+}
+
+/// Whether two keys likely name the same payee: the same, or one an abbreviation of the other, like `AMZN` of `AMAZON`
+fn alike(a: &str, b: &str) -> bool {
+    let (short, long) = if a.len() <= b.len() { (a, b) } else { (b, a) };
+    if short == long {
+        return true;
+    }
+    if short.chars().count() < 4 || short.chars().next() != long.chars().next() {
+        return false;
+    }
+    let mut long = long.chars();
+    short.chars().all(|c| long.any(|x| x == c))
+}
+
+/// The groups of payees that look alike, of more than one each, with how many transactions each payee has, the most
+/// used first
+pub fn clusters(counts: &BTreeMap<String, usize>) -> Vec<Vec<(String, usize)>> {
+    let payees = counts
+        .iter()
+        .filter_map(|(payee, &count)| Some((key(payee)?, payee.clone(), count)))
+        .collect::<Vec<_>>();
+    // Each payee's group is the first alike with it, or alike with one alike with it, and so on
+    let mut group = (0..payees.len()).collect::<Vec<_>>();
+    fn root(group: &mut [usize], mut i: usize) -> usize {
+        while group[i] != i {
+            group[i] = group[group[i]];
+            i = group[i];
+        }
+        i
+    }
+    for i in 0..payees.len() {
+        for j in i + 1..payees.len() {
+            if alike(&payees[i].0, &payees[j].0) {
+                let (a, b) = (root(&mut group, i), root(&mut group, j));
+                group[a.max(b)] = a.min(b);
+            }
+        }
+    }
+    let mut clusters = BTreeMap::<usize, Vec<(String, usize)>>::new();
+    for (i, (_, payee, count)) in payees.into_iter().enumerate() {
+        clusters
+            .entry(root(&mut group, i))
+            .or_default()
+            .push((payee, count));
+    }
+    clusters
+        .into_values()
+        .filter(|x| x.len() > 1)
+        .map(|mut x| {
+            x.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+            x
+        })
+        .collect()
+}
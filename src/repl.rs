@@ -1,379 +1,323 @@
-use std::sync::{Arc, RwLock};
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Display,
+    fs,
+    io::{self, IsTerminal},
+    path::PathBuf,
+    str::FromStr,
+    sync::{Arc, RwLock},
+};
 
-use eyre::{eyre, Result};
+use eyre::{bail, ensure, eyre, Context, Result};
 use itertools::Itertools;
 use tracing::instrument;
 
 use crate::{
-    command::{self, AccountModification},
-    repository::Repository,
+    attachments,
+    command::{self, AccountModification, Deltas, TransactionModification},
+    holdings,
+    parser::{
+        self, AccountFilter, AccountSort, Command, Completions, Parser, ShowColumn, Suggestion,
+        SummaryPeriod, Token, TokenType, TransactionRef,
+    },
+    payees, report,
+    repository::{Filter, Page, Point, Repository},
     types::{
-        Account, AccountType, Amount, Currency, Id, Physical, Transaction, TransactionInner,
-        Virtual,
+        Account, AccountSelector, AccountType, Activity, Allocation, Amount, AmountError, Amounts,
+        Config, Debt, Id, Party, Physical, SearchResult, Transaction, TransactionInner,
+        TransactionTemplate, Virtual,
     },
 };
 use reedline::{
     default_emacs_keybindings, ColumnarMenu, Completer, DefaultPrompt, DefaultPromptSegment, Emacs,
-    Highlighter, KeyCode, KeyModifiers, Reedline, ReedlineEvent, ReedlineMenu, Signal, Span,
-    StyledText, Suggestion, ValidationResult, Validator,
+    Highlighter, KeyCode, KeyModifiers, Reedline, ReedlineEvent, ReedlineMenu, Signal, StyledText,
+    ValidationResult, Validator,
 };
 
-use nu_ansi_term::Color;
+use nu_ansi_term::{Color, Style};
+use serde::Deserialize;
 
-#[derive(Default, Debug, Clone)]
-struct Completions(Vec<Suggestion>);
+/// How the REPL presents its output
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct Options {
+    /// Show amounts with currency symbols where known, e.g. €12.50
+    #[arg(long, global = true, env = "MONFARI_SYMBOLS")]
+    symbols: bool,
+    /// Show disabled accounts with no balance in `account list --all`, rather than just counting them
+    #[arg(long, global = true, env = "MONFARI_SHOW_EMPTY")]
+    show_empty: bool,
+    /// Show notes and payees in full, rather than truncating them to fit the terminal
+    #[arg(long, global = true, env = "MONFARI_FULL")]
+    full: bool,
+    /// Highlighting theme: `default`, `monochrome`, or the path to a TOML file of colours by token type
+    #[arg(long, global = true, env = "MONFARI_THEME", default_value = "default")]
+    theme: String,
+    /// Take notes from this file, or standard input if `-`, rather than asking for them in an editor
+    #[arg(long, global = true)]
+    notes_file: Option<PathBuf>,
+}
 
-impl Completions {
-    fn set_span(&mut self, span: Span) {
-        for suggestion in self.0.iter_mut() {
-            suggestion.span = span;
+impl Options {
+    /// The width free text should be cut to, if at all, given the share of the terminal it may take up
+    fn text_width(&self, full: bool, share: u16) -> Option<usize> {
+        if full || self.full {
+            return None;
         }
+        // Leave room for the cell's padding and border
+        Some(usize::from(comfy_table::Table::new().width()? / share).saturating_sub(3))
     }
-}
 
-impl FromIterator<String> for Completions {
-    fn from_iter<T: IntoIterator<Item = String>>(iter: T) -> Self {
-        iter.into_iter().map(|x| (x, None)).collect()
+    /// Format an `Amount` or `Amounts`
+    pub fn amount(&self, amount: impl Display) -> String {
+        if self.symbols {
+            format!("{amount:#}")
+        } else {
+            amount.to_string()
+        }
     }
 }
 
-impl FromIterator<(String, Option<String>)> for Completions {
-    fn from_iter<T: IntoIterator<Item = (String, Option<String>)>>(iter: T) -> Self {
-        Self(
-            iter.into_iter()
-                .map(|(value, description)| Suggestion {
-                    span: Span::new(0, 0),
-                    value,
-                    description,
-                    extra: None,
-                    append_whitespace: true,
-                })
-                .collect(),
-        )
-    }
-}
+#[derive(Debug, Clone, Copy)]
+struct ThemeStyle(Style);
 
-#[derive(Clone, Copy, Debug, Eq, PartialEq)]
-enum TokenType {
-    Command,
-    String,
-    Id,
-    Amount,
-    Invalid,
-    Whitespace,
-}
+impl FromStr for ThemeStyle {
+    type Err = eyre::Report;
 
-#[derive(Clone, Debug)]
-struct Token {
-    bounds: (usize, usize),
-    str: String,
-    typ: TokenType,
-    completions: Completions,
+    /// Parse styles like `red`, `light-blue`, or `bold dimmed green`
+    fn from_str(s: &str) -> Result<Self> {
+        let mut style = Style::new();
+        for word in s.split_whitespace() {
+            style = match word {
+                "bold" => style.bold(),
+                "dimmed" => style.dimmed(),
+                "italic" => style.italic(),
+                "underline" => style.underline(),
+                color => style.fg(match color {
+                    "black" => Color::Black,
+                    "red" => Color::Red,
+                    "green" => Color::Green,
+                    "yellow" => Color::Yellow,
+                    "blue" => Color::Blue,
+                    "purple" => Color::Purple,
+                    "cyan" => Color::Cyan,
+                    "white" => Color::White,
+                    "light-gray" => Color::LightGray,
+                    "dark-gray" => Color::DarkGray,
+                    "light-red" => Color::LightRed,
+                    "light-green" => Color::LightGreen,
+                    "light-yellow" => Color::LightYellow,
+                    "light-blue" => Color::LightBlue,
+                    "light-purple" => Color::LightPurple,
+                    "light-cyan" => Color::LightCyan,
+                    color => bail!("Unknown colour {color}"),
+                }),
+            }
+        }
+        Ok(Self(style))
+    }
 }
 
-#[derive(Debug)]
-enum Command {
-    AccountsList,
-    AccountCreate {
-        typ: AccountType,
-        name: String,
-    },
-    AccountShow {
-        id: Id<Account>,
-    },
-    AccountModify(Id<Account>, Vec<AccountModification>),
-    TransactionAdd {
-        amount: Amount,
-        inner: TransactionInner,
-    },
+impl<'de> Deserialize<'de> for ThemeStyle {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(serde::de::Error::custom)
+    }
 }
 
-struct Parser<'a> {
-    iter: <&'a mut Vec<Token> as IntoIterator>::IntoIter,
-    accounts: Vec<Account>,
+/// Styles for each `TokenType` when highlighting the REPL input
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(default)]
+struct Theme {
+    command: ThemeStyle,
+    string: ThemeStyle,
+    id: ThemeStyle,
+    amount: ThemeStyle,
+    implied: ThemeStyle,
+    invalid: ThemeStyle,
 }
 
-impl<'a> Parser<'a> {
-    fn parse(input: &str, accounts: Vec<Account>) -> (Vec<Token>, Result<Command, Completions>) {
-        let mut tokens = input
-            .chars()
-            .enumerate()
-            .group_by({
-                let mut in_string = false;
-                move |&(_, c)| {
-                    if c == '"' {
-                        in_string = !in_string;
-                        2
-                    } else if in_string {
-                        2
-                    } else if c.is_whitespace() {
-                        1
-                    } else {
-                        0
-                    }
-                }
-            })
-            .into_iter()
-            .map(|(_, chars)| {
-                let mut bounds = (usize::MAX, 0);
-                let s = chars
-                    .map(|(idx, c)| {
-                        bounds.0 = usize::min(bounds.0, idx);
-                        bounds.1 = usize::max(bounds.1, idx);
-                        c
-                    })
-                    .collect::<String>();
-                (bounds, s)
-            })
-            .map(|(bounds, str)| Token {
-                typ: if str.chars().all(char::is_whitespace) {
-                    TokenType::Whitespace
-                } else {
-                    TokenType::Invalid
-                },
-                completions: Completions::default(),
-                bounds,
-                str,
-            })
-            .collect::<Vec<_>>();
-        let mut this = Parser {
-            accounts,
-            iter: tokens.iter_mut(),
-        };
-        let mut res = this.run();
-        for tok in &mut tokens {
-            tok.completions
-                .set_span(Span::new(tok.bounds.0, tok.bounds.1 + 1))
+impl Default for Theme {
+    fn default() -> Self {
+        Self {
+            command: ThemeStyle(Color::Blue.dimmed()),
+            string: ThemeStyle(Color::LightGreen.normal()),
+            id: ThemeStyle(Color::Green.dimmed()),
+            amount: ThemeStyle(Color::LightBlue.normal()),
+            implied: ThemeStyle(Color::LightBlue.italic()),
+            invalid: ThemeStyle(Color::Red.normal()),
         }
-        if let Err(completions) = &mut res {
-            let end = tokens.last().map(|x| x.bounds.1 + 1).unwrap_or_default();
-            completions.set_span(Span::new(end, end));
-        }
-        (tokens, res)
-    }
-
-    fn run(&mut self) -> Result<Command, Completions> {
-        let value = self.dispatch(&[
-            ("account", &Self::account),
-            ("transaction", &Self::transaction),
-        ])?;
-        Ok(value)
-    }
-
-    fn account(&mut self) -> Result<Command, Completions> {
-        self.dispatch(&[
-            ("list", &|_| Ok(Command::AccountsList)),
-            ("create", &Self::account_create),
-            ("disable", &Self::account_disable),
-            ("rename", &Self::account_rename),
-            ("show", &Self::account_show),
-        ])
-    }
-
-    fn account_create(&mut self) -> Result<Command, Completions> {
-        let typ = self.dispatch(&[
-            ("physical", &|_| Ok(AccountType::Physical)),
-            ("virtual", &|_| Ok(AccountType::Virtual)),
-        ])?;
-        let name = self.string()?;
-        Ok(Command::AccountCreate { typ, name })
-    }
-
-    fn account_disable(&mut self) -> Result<Command, Completions> {
-        let id = self.account_id(None)?;
-        Ok(Command::AccountModify(
-            id,
-            vec![AccountModification::Disable],
-        ))
     }
+}
 
-    fn account_rename(&mut self) -> Result<Command, Completions> {
-        let id = self.account_id(None)?;
-        let name = self.string()?;
-        Ok(Command::AccountModify(
-            id,
-            vec![AccountModification::UpdateName(name)],
-        ))
+impl Theme {
+    fn monochrome() -> Self {
+        Self {
+            command: ThemeStyle(Style::new().bold()),
+            string: ThemeStyle(Style::new()),
+            id: ThemeStyle(Style::new().italic()),
+            amount: ThemeStyle(Style::new()),
+            implied: ThemeStyle(Style::new().dimmed()),
+            invalid: ThemeStyle(Style::new().underline()),
+        }
     }
 
-    fn account_show(&mut self) -> Result<Command, Completions> {
-        let id = self.account_id(None)?;
-        Ok(Command::AccountShow { id })
-    }
-
-    fn transaction(&mut self) -> Result<Command, Completions> {
-        let amount = self.amount()?;
-        let inner = self.dispatch(&[
-            ("received", &Self::transaction_received),
-            ("paid", &Self::transaction_paid),
-            ("move-phys", &Self::transaction_move_phys),
-            ("move-virt", &Self::transaction_move_virt),
-            ("convert", &Self::transaction_convert),
-        ])?;
-        Ok(Command::TransactionAdd { amount, inner })
-    }
-
-    fn transaction_received(&mut self) -> Result<TransactionInner, Completions> {
-        self.expect("src")?;
-        let src = self.string()?;
-        self.expect("dst")?;
-        let dst = self.account_phys()?;
-        self.expect("dst-virt")?;
-        let dst_virt = self.account_virt()?;
-        Ok(TransactionInner::Received { src, dst, dst_virt })
-    }
-
-    fn transaction_paid(&mut self) -> Result<TransactionInner, Completions> {
-        self.expect("dst")?;
-        let dst = self.string()?;
-        self.expect("src")?;
-        let src = self.account_phys()?;
-        self.expect("src-virt")?;
-        let src_virt = self.account_virt()?;
-        Ok(TransactionInner::Paid { src, dst, src_virt })
-    }
-
-    fn transaction_move_phys(&mut self) -> Result<TransactionInner, Completions> {
-        self.expect("dst")?;
-        let dst = self.account_phys()?;
-        self.expect("src")?;
-        let src = self.account_phys()?;
-        Ok(TransactionInner::MovePhys { src, dst })
-    }
-
-    fn transaction_move_virt(&mut self) -> Result<TransactionInner, Completions> {
-        self.expect("dst")?;
-        let dst = self.account_virt()?;
-        self.expect("src")?;
-        let src = self.account_virt()?;
-        Ok(TransactionInner::MoveVirt { src, dst })
-    }
-
-    fn transaction_convert(&mut self) -> Result<TransactionInner, Completions> {
-        self.expect("into")?;
-        let new_amount = self.amount()?;
-        self.expect("account")?;
-        let acc = self.account_phys()?;
-        self.expect("virtual")?;
-        let acc_virt = self.account_virt()?;
-        Ok(TransactionInner::Convert {
-            acc,
-            acc_virt,
-            new_amount,
+    fn load(options: &Options) -> Result<Self> {
+        // https://no-color.org
+        if env::var_os("NO_COLOR").is_some_and(|x| !x.is_empty()) || !io::stdout().is_terminal() {
+            return Ok(Self::monochrome());
+        }
+        Ok(match options.theme.as_str() {
+            "default" => Self::default(),
+            "monochrome" => Self::monochrome(),
+            path => toml::from_str(&fs::read_to_string(path)?)?,
         })
     }
 
-    fn amount(&mut self) -> Result<Amount, Completions> {
-        let amount = self.token(None, |_, tok| {
-            Some((TokenType::Amount, Amount::parse_num(tok)?))
-        })?;
-        let currency = self.token(
-            Some(
-                [Currency::EUR, Currency::GBP, Currency::USD]
-                    .into_iter()
-                    .map(|x| x.to_string())
-                    .collect(),
-            ),
-            |_, tok| Some((TokenType::Amount, tok.parse().ok()?)),
-        )?;
-        Ok(Amount(amount, currency))
+    fn style(&self, typ: TokenType) -> Style {
+        match typ {
+            TokenType::Command => self.command.0,
+            TokenType::String => self.string.0,
+            TokenType::Id => self.id.0,
+            TokenType::Amount => self.amount.0,
+            TokenType::Implied => self.implied.0,
+            TokenType::Invalid => self.invalid.0,
+            TokenType::Whitespace => Default::default(),
+        }
     }
+}
 
-    fn string(&mut self) -> Result<String, Completions> {
-        self.token(None, |_, s| {
-            Some((TokenType::String, s.trim_matches('"').to_owned()))
-        })
+#[derive(Clone)]
+struct ReedlineCmd(
+    Arc<RwLock<Vec<Account>>>,
+    Theme,
+    Arc<RwLock<Config>>,
+    Arc<RwLock<Option<Id<Account>>>>,
+);
+/// What's needed to finish an incomplete line
+enum Missing {
+    /// The only thing that can come next
+    Keyword(String),
+    /// An account, picked from these; the line is to be cut to `prefix` before adding it
+    Account {
+        prefix: String,
+        candidates: Vec<Suggestion>,
+    },
+}
+
+impl ReedlineCmd {
+    fn parse(&self, line: &str) -> (Vec<Token>, Result<Command, parser::Error>) {
+        Parser::parse(
+            line,
+            self.0.read().unwrap().clone(),
+            self.2.read().unwrap().clone(),
+            *self.3.read().unwrap(),
+        )
     }
 
-    fn account_id(
-        &mut self,
-        account_type: Option<AccountType>,
-    ) -> Result<Id<Account>, Completions> {
-        self.token(
-            Some(
-                self.accounts
+    fn missing(&self, line: &str) -> Option<Missing> {
+        let (tokens, res) = self.parse(line);
+        let completions = res.err()?.expected.0;
+        let invalid = tokens.iter().find(|x| x.typ == TokenType::Invalid);
+        let accounts = self.0.read().unwrap();
+        // Accounts are suggested by their IDs as shown, which can't always be parsed
+        let is_account = |x: &Suggestion| accounts.iter().any(|acc| acc.id.to_string() == x.value);
+        match invalid {
+            None if completions.len() == 1 && !is_account(&completions[0]) => {
+                Some(Missing::Keyword(completions[0].value.clone()))
+            }
+            None if !completions.is_empty() && completions.iter().all(is_account) => {
+                Some(Missing::Account {
+                    prefix: line.to_owned(),
+                    candidates: completions,
+                })
+            }
+            // An account was given, but not unambiguously, so offer the ones it could mean
+            Some(token)
+                if tokens.last().is_some_and(|x| x.bounds == token.bounds)
+                    && !token.completions.0.is_empty()
+                    && token.completions.0.iter().all(is_account) =>
+            {
+                let partial = token.str.to_lowercase();
+                let candidates = token
+                    .completions
+                    .0
                     .iter()
-                    .filter(|x| x.enabled)
-                    .filter(|x| account_type.map_or(true, |typ| x.typ == typ))
-                    .map(|x| {
-                        (
-                            x.id.to_string(),
-                            Some(format!("{} ({})", x.name, x.current)),
-                        )
+                    .filter(|x| {
+                        x.value.starts_with(&partial)
+                            || x.description
+                                .as_ref()
+                                .is_some_and(|x| x.to_lowercase().contains(&partial))
                     })
-                    .collect(),
-            ),
-            |this, tok| {
-                Some((
-                    TokenType::Id,
-                    tok.parse().ok().filter(|&s| {
-                        this.accounts
-                            .iter()
-                            .find(|x| x.id == s)
-                            .is_some_and(|acc| account_type.map_or(true, |typ| acc.typ == typ))
-                    })?,
-                ))
-            },
-        )
+                    .cloned()
+                    .collect_vec();
+                (!candidates.is_empty()).then(|| Missing::Account {
+                    prefix: line[..token.bounds.0].to_owned(),
+                    candidates,
+                })
+            }
+            _ => None,
+        }
     }
 
-    fn account_phys(&mut self) -> Result<Id<Account<Physical>>, Completions> {
-        self.account_id(Some(AccountType::Physical))
-            .map(|x| x.unerase())
-    }
-    fn account_virt(&mut self) -> Result<Id<Account<Virtual>>, Completions> {
-        self.account_id(Some(AccountType::Virtual))
-            .map(|x| x.unerase())
+    /// Fill in whatever's missing from `line` by asking for accounts, until it's complete or we can't help
+    fn complete_interactively(&self, mut line: String) -> Result<String> {
+        while let Some(missing) = self.missing(&line) {
+            match missing {
+                Missing::Keyword(keyword) => line = format!("{} {keyword}", line.trim_end()),
+                Missing::Account { prefix, candidates } => {
+                    let Some(id) = pick_account(candidates)? else { break };
+                    line = format!("{} {id}", prefix.trim_end());
+                }
+            }
+        }
+        Ok(line)
     }
+}
 
-    fn expect(&mut self, x: &'static str) -> Result<(), Completions> {
-        self.token(Some([x.to_string()].into_iter().collect()), |_, tok| {
-            (tok == x).then_some((TokenType::Command, ()))
-        })
+fn pick_account(candidates: Vec<Suggestion>) -> Result<Option<String>> {
+    if let [only] = &candidates[..] {
+        return Ok(Some(only.value.clone()));
     }
-
-    #[allow(clippy::type_complexity)]
-    fn dispatch<T>(
-        &mut self,
-        args: &[(&'static str, &dyn Fn(&mut Self) -> Result<T, Completions>)],
-    ) -> Result<T, Completions> {
-        self.token(
-            Some(args.iter().map(|(key, _)| (*key).to_owned()).collect()),
-            |this, tok| {
-                args.iter()
-                    .find(|(key, _)| key == &tok)
-                    .map(|(_, f)| (TokenType::Command, f(this)))
-            },
-        )?
-    }
-
-    fn token<T>(
-        &mut self,
-        completions: Option<Completions>,
-        f: impl FnOnce(&mut Self, &str) -> Option<(TokenType, T)>,
-    ) -> Result<T, Completions> {
-        let completions = completions.unwrap_or_default();
-        let tok = self
-            .iter
-            .find(|x| x.typ != TokenType::Whitespace)
-            .ok_or_else(|| completions.clone())?;
-        tok.completions = completions;
-        if let Some((typ, val)) = f(self, &tok.str) {
-            tok.typ = typ;
-            Ok(val)
-        } else {
-            Err(tok.completions.clone())
+    for (i, candidate) in candidates.iter().enumerate() {
+        println!(
+            "{:>3}: {} ({})",
+            i + 1,
+            candidate.description.as_deref().unwrap_or_default(),
+            candidate.value
+        );
+    }
+    while let Some(choice) = ask("account")? {
+        match choice
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| candidates.get(i.wrapping_sub(1)))
+        {
+            Some(candidate) => return Ok(Some(candidate.value.clone())),
+            None => eprintln!("Pick an account by its number"),
         }
     }
+    Ok(None)
 }
 
-#[derive(Clone)]
-struct ReedlineCmd(Arc<RwLock<Vec<Account>>>);
-impl ReedlineCmd {
-    fn parse(&self, line: &str) -> (Vec<Token>, Result<Command, Completions>) {
-        Parser::parse(line, self.0.read().unwrap().clone())
-    }
+/// Read a line from the terminal, or `None` if the user gives up with Ctrl-C or Ctrl-D
+pub fn ask(prompt: &str) -> Result<Option<String>> {
+    let prompt = DefaultPrompt::new(
+        DefaultPromptSegment::Basic(prompt.to_owned()),
+        DefaultPromptSegment::Empty,
+    );
+    Ok(match Reedline::create().read_line(&prompt)? {
+        Signal::Success(line) => Some(line),
+        Signal::CtrlC | Signal::CtrlD => None,
+    })
 }
+
 impl Completer for ReedlineCmd {
     fn complete(&mut self, line: &str, pos: usize) -> Vec<reedline::Suggestion> {
         let (tokens, res) = self.parse(line);
@@ -384,13 +328,20 @@ impl Completer for ReedlineCmd {
         let prefix = token.as_ref().map(|x| x.str.clone());
         token
             .map(|x| x.completions)
-            .unwrap_or_else(|| res.err().unwrap_or_default())
+            .unwrap_or_else(|| res.err().map(|e| e.expected).unwrap_or_default())
             .0
             .into_iter()
             .filter(|x| {
                 prefix
                     .as_ref()
-                    .map_or(true, |prefix| x.value.starts_with(prefix))
+                    .is_none_or(|prefix| x.value.starts_with(prefix))
+            })
+            .map(|x| reedline::Suggestion {
+                span: reedline::Span::new(x.span.start, x.span.end),
+                value: x.value,
+                description: x.description,
+                extra: None,
+                append_whitespace: true,
             })
             .collect()
     }
@@ -402,19 +353,7 @@ impl Highlighter for ReedlineCmd {
         StyledText {
             buffer: tokens
                 .into_iter()
-                .map(|Token { str, typ, .. }| {
-                    (
-                        match typ {
-                            TokenType::Command => Color::Blue.dimmed(),
-                            TokenType::String => Color::LightGreen.normal(),
-                            TokenType::Id => Color::Green.dimmed(),
-                            TokenType::Amount => Color::LightBlue.normal(),
-                            TokenType::Invalid => Color::Red.normal(),
-                            TokenType::Whitespace => Default::default(),
-                        },
-                        str,
-                    )
-                })
+                .map(|Token { str, typ, .. }| (self.1.style(typ), str))
                 .collect(),
         }
     }
@@ -422,7 +361,9 @@ impl Highlighter for ReedlineCmd {
 
 impl Validator for ReedlineCmd {
     fn validate(&self, line: &str) -> ValidationResult {
-        if self.parse(line).1.is_ok() {
+        // Lines only missing accounts are completed by `complete_interactively`
+        if self.parse(line).1.is_ok() || matches!(self.missing(line), Some(Missing::Account { .. }))
+        {
             ValidationResult::Complete
         } else {
             ValidationResult::Incomplete
@@ -430,8 +371,8 @@ impl Validator for ReedlineCmd {
     }
 }
 
-pub fn repl(mut repo: Repository) -> Result<Repository> {
-    let custom = ReedlineCmd(Arc::new(RwLock::new(repo.accounts()?)));
+/// A line editor offering what `completer` gives in a menu on Tab
+fn completing_editor(completer: Box<dyn Completer>) -> Reedline {
     let completion_menu = Box::new(ColumnarMenu::default().with_name("completion_menu"));
     let mut keybindings = default_emacs_keybindings();
     keybindings.add_binding(
@@ -445,19 +386,41 @@ pub fn repl(mut repo: Repository) -> Result<Repository> {
 
     let edit_mode = Box::new(Emacs::new(keybindings));
 
-    let mut line_editor = Reedline::create()
-        .with_completer(Box::new(custom.clone()))
+    Reedline::create()
+        .with_completer(completer)
         .with_menu(ReedlineMenu::EngineCompleter(completion_menu))
         .with_quick_completions(true)
         .with_partial_completions(true)
         .with_edit_mode(edit_mode)
+}
+
+/// A line editor completing, highlighting, and checking commands with `custom`
+fn line_editor(custom: &ReedlineCmd) -> Reedline {
+    completing_editor(Box::new(custom.clone()))
         .with_highlighter(Box::new(custom.clone()))
-        .with_validator(Box::new(custom.clone()));
-    let prompt = DefaultPrompt::new(DefaultPromptSegment::Empty, DefaultPromptSegment::Empty);
+        .with_validator(Box::new(custom.clone()))
+}
+
+pub fn repl(mut repo: Repository, options: Options) -> Result<Repository> {
+    let custom = ReedlineCmd(
+        Arc::new(RwLock::new(repo.accounts()?)),
+        Theme::load(&options)?,
+        Arc::new(RwLock::new(repo.config()?)),
+        Default::default(),
+    );
+    let mut line_editor = line_editor(&custom);
     loop {
+        let context = match *custom.3.read().unwrap() {
+            Some(id) => DefaultPromptSegment::Basic(repo.account(id)?.name),
+            None => DefaultPromptSegment::Empty,
+        };
+        let prompt = DefaultPrompt::new(context, DefaultPromptSegment::Empty);
         match line_editor.read_line(&prompt)? {
             Signal::Success(line) => {
-                if let Err(e) = run_command(&mut repo, &custom, line) {
+                if let Err(e) = custom
+                    .complete_interactively(line)
+                    .and_then(|line| run_command(&mut repo, &custom, &options, line))
+                {
                     eprintln!("{e}");
                 }
             }
@@ -468,149 +431,2075 @@ pub fn repl(mut repo: Repository) -> Result<Repository> {
     Ok(repo)
 }
 
-pub fn command(mut repo: Repository, cmd: String) -> Result<Repository> {
-    let custom = ReedlineCmd(Arc::new(RwLock::new(repo.accounts()?)));
-    run_command(&mut repo, &custom, cmd)?;
+/// Run a single command, given as already-split arguments
+pub fn command(mut repo: Repository, options: Options, args: Vec<String>) -> Result<Repository> {
+    let cmd = Parser::parse_args(args.clone(), repo.accounts()?, repo.config()?)
+        .1
+        .map_err(|e| eyre!("Invalid Command: {}: {e}", args.join(" ")))?;
+    execute(&mut repo, &options, cmd, false)?;
     Ok(repo)
 }
 
+/// Run a single command against the repository as it was at `at`, which only listing accounts can be
+pub fn command_at(repo: Repository, options: Options, at: Point, args: Vec<String>) -> Result<()> {
+    let accounts = repo.accounts_at(&at)?;
+    let cmd = Parser::parse_args(args.clone(), accounts.clone(), repo.config()?)
+        .1
+        .map_err(|e| eyre!("Invalid Command: {}: {e}", args.join(" ")))?;
+    match cmd {
+        // Activity isn't kept for the past
+        Command::AccountsList(filter) => {
+            print_accounts(&options, filter, accounts, BTreeMap::new())
+        }
+        _ => bail!("Only `account list` can be run as of {at}"),
+    }
+}
+
 #[allow(clippy::await_holding_lock)]
-fn run_command(repo: &mut Repository, custom: &ReedlineCmd, cmd: String) -> Result<()> {
+fn run_command(
+    repo: &mut Repository,
+    custom: &ReedlineCmd,
+    options: &Options,
+    cmd: String,
+) -> Result<()> {
     let cmd = custom
         .parse(&cmd)
         .1
-        .map_err(|_| eyre!("Invalid Command: {}", cmd))?;
+        .map_err(|e| eyre!("Invalid Command: {cmd}: {e}"))?;
+    if let Command::Use(context) = cmd {
+        *custom.3.write().unwrap() = context;
+        return Ok(());
+    }
+    execute(repo, options, cmd, false)?;
+    *custom.0.write().unwrap() = repo.accounts()?;
+    // Such as a template just saved
+    *custom.2.write().unwrap() = repo.config()?;
+    Ok(())
+}
+
+fn execute(repo: &mut Repository, options: &Options, cmd: Command, dry_run: bool) -> Result<()> {
     match cmd {
-        Command::AccountsList => accounts_list(repo)?,
-        Command::AccountCreate { typ, name } => account_create(repo, typ, name)?,
-        Command::AccountShow { id } => account_show(repo, id)?,
-        Command::AccountModify(id, mods) => account_modify(repo, id, mods)?,
-        Command::TransactionAdd { amount, inner } => transaction(repo, amount, inner)?,
+        Command::AccountsList(filter) => accounts_list(repo, options, filter)?,
+        Command::AccountCreate { typ, name } => account_create(repo, options, typ, name, dry_run)?,
+        Command::AccountShow {
+            id,
+            columns,
+            full,
+            summary,
+            from,
+            to,
+            tag,
+        } => account_show(
+            repo,
+            options,
+            id,
+            &columns,
+            full,
+            summary,
+            Filter {
+                tag: tag.as_deref(),
+                from,
+                to,
+            },
+        )?,
+        Command::AccountModify(id, mods) => account_modify(repo, options, id, mods, dry_run)?,
+        Command::AccountBulkModify { selector, changes } => {
+            account_bulk_modify(repo, &selector, changes, dry_run)?
+        }
+        Command::AccountCount(id) => account_count(repo, options, id, dry_run)?,
+        Command::AccountLog(id) => account_log(repo, id)?,
+        Command::AccountHoldings => account_holdings(repo, options)?,
+        Command::TransactionAdd {
+            amount,
+            inner,
+            notes,
+            attachments,
+            debts,
+            date,
+        } => transaction(
+            repo,
+            options,
+            amount,
+            inner,
+            notes,
+            &attachments,
+            debts,
+            date,
+            false,
+            dry_run,
+        )?,
+        Command::TransactionTemplate { name, template } => {
+            transaction_template(repo, name, template, dry_run)?
+        }
+        Command::TransactionSearch { query } => transaction_search(repo, options, &query)?,
+        Command::TransactionReverse { id } => {
+            let id = transaction_id(repo, id)?;
+            transaction_reverse(repo, options, id, dry_run)?
+        }
+        Command::TransactionVoid { id } => {
+            let id = transaction_id(repo, id)?;
+            transaction_void(repo, options, id, dry_run)?
+        }
+        Command::TransactionEdit { id, changes } => {
+            let id = transaction_id(repo, id)?;
+            transaction_edit(repo, options, id, changes, dry_run)?
+        }
+        Command::TransactionAttachments { id } => {
+            let id = transaction_id(repo, id)?;
+            transaction_attachments(repo, id)?
+        }
+        #[cfg(feature = "ocr")]
+        Command::TransactionFromImage { path } => {
+            transaction_from_image(repo, options, &path, dry_run)?
+        }
+        Command::TransactionLast { count, account } => {
+            transaction_last(repo, options, count, account)?
+        }
+        Command::Spending(by) => spending(repo, options, by)?,
+        Command::Triage(bucket) => triage(repo, options, bucket, dry_run)?,
+        Command::Allocate { amount, src, plan } => {
+            allocate(repo, options, amount, src, &plan, dry_run)?
+        }
+        Command::EventOpen { name, float, src } => {
+            event_open(repo, options, name, float, src, dry_run)?
+        }
+        Command::EventClose(id) => event_close(repo, options, id, dry_run)?,
+        Command::Settle {
+            person,
+            amount,
+            acc,
+            acc_virt,
+        } => settle(repo, options, person, amount, acc, acc_virt, dry_run)?,
+        Command::LoanPay { name, amount, src } => {
+            loan_pay(repo, options, &name, amount, src, dry_run)?
+        }
+        Command::PayeeNormalize => payee_normalize(repo, dry_run)?,
+        Command::Use(_) => bail!("`use` only applies within the REPL"),
+        #[cfg(feature = "clipboard")]
+        Command::Copy(id) => copy(id)?,
+        Command::DryRun(cmd) => execute(repo, options, *cmd, true)?,
+        Command::NewCurrency(cmd) => match *cmd {
+            Command::TransactionAdd {
+                amount,
+                inner,
+                notes,
+                attachments,
+                debts,
+                date,
+            } => transaction(
+                repo,
+                options,
+                amount,
+                inner,
+                notes,
+                &attachments,
+                debts,
+                date,
+                true,
+                dry_run,
+            )?,
+            cmd => execute(repo, options, cmd, dry_run)?,
+        },
     };
-    *custom.0.write().unwrap() = repo.accounts()?;
     Ok(())
 }
 
+thread_local! {
+    /// The ID of the account or transaction last added, which `copy` copies by default
+    static LAST_ADDED: std::cell::Cell<Option<ulid::Ulid>> = const { std::cell::Cell::new(None) };
+}
+
+/// Copy `id`, or the ID of what was last added, to the clipboard
+#[cfg(feature = "clipboard")]
+fn copy(id: Option<ulid::Ulid>) -> Result<()> {
+    let id = id
+        .or_else(|| LAST_ADDED.get())
+        .ok_or_else(|| eyre!("Nothing has been added yet"))?;
+    let id = Id::<()>::new(id).full();
+    crate::clipboard::copy(&id)?;
+    println!("Copied {id}");
+    Ok(())
+}
+
+/// Run `cmd`, or if this is a dry run, show what it would change instead; gives the change it made to each account's
+/// balance if it was run
+fn submit(
+    repo: &mut Repository,
+    options: &Options,
+    cmd: command::Command,
+    dry_run: bool,
+) -> Result<Option<Deltas>> {
+    submit_with(repo, options, cmd, dry_run, false)
+}
+
+/// [`submit`] `cmd`, taking any currencies in it that aren't used yet as meant if `new_currency`
+fn submit_with(
+    repo: &mut Repository,
+    options: &Options,
+    cmd: command::Command,
+    dry_run: bool,
+    new_currency: bool,
+) -> Result<Option<Deltas>> {
+    if !dry_run {
+        if let command::Command::AddTransaction(transaction) = &cmd {
+            if let Some(limit) = repo.config()?.large(transaction) {
+                let answer = ask(&format!(
+                    "{} is more than {}; add it anyway? [y/N]",
+                    options.amount(transaction.amount),
+                    options.amount(limit)
+                ))?;
+                if !answer.is_some_and(|x| x.trim().eq_ignore_ascii_case("y")) {
+                    println!("Nothing was changed");
+                    return Ok(None);
+                }
+            }
+        }
+        let added = match &cmd {
+            command::Command::CreateAccount(account) => Some(account.id.0),
+            command::Command::AddTransaction(transaction) => Some(transaction.id.0),
+            _ => None,
+        };
+        let deltas = repo.run_command_with(cmd, command::Origin::local(), new_currency)?;
+        if added.is_some() {
+            LAST_ADDED.set(added);
+        }
+        return Ok(Some(deltas));
+    }
+    let deltas = repo.simulate(&cmd)?;
+    println!("Dry run: {cmd}");
+    if !deltas.is_empty() {
+        use comfy_table::*;
+        let mut table = Table::new();
+        table
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec!["Account", "Change", "Balance after"]);
+        for (id, delta) in deltas {
+            let Account { name, current, .. } = repo.account(id)?;
+            table.add_row(vec![
+                name,
+                options.amount(&delta),
+                options.amount((current + delta)?),
+            ]);
+        }
+        println!("{table}");
+    }
+    println!("Nothing was changed");
+    Ok(None)
+}
+
+/// Show the balances of the accounts a command changed
+fn balances(repo: &Repository, options: &Options, deltas: &Deltas) -> Result<()> {
+    for (id, _) in deltas {
+        let Account { name, current, .. } = repo.account(*id)?;
+        println!("{name} now at {}", options.amount(current));
+    }
+    Ok(())
+}
+
+/// Ask for notes in an editor, starting from `initial`, unless this is a dry run or they're given in a file
+fn edit_notes(options: &Options, dry_run: bool, initial: &str) -> Result<String> {
+    if dry_run {
+        return Ok(String::new());
+    }
+    let notes = match &options.notes_file {
+        Some(path) if path.as_os_str() == "-" => io::read_to_string(io::stdin())?,
+        Some(path) => fs::read_to_string(path)
+            .wrap_err_with(|| format!("Couldn't read notes from {}", path.display()))?,
+        None => edit::edit(format!("# Notes\n{initial}"))?
+            .lines()
+            .filter(|x| !x.starts_with('#'))
+            .join("\n"),
+    };
+    Ok(notes.trim_end().to_owned())
+}
+
+/// The configured notes template for a transaction of `amount`, with `{date}`, `{amount}` and `{payee}` filled in
+fn notes_template(
+    repo: &Repository,
+    options: &Options,
+    amount: Amount,
+    inner: &TransactionInner,
+) -> Result<String> {
+    let Some(template) = repo.config()?.notes_template else {
+        return Ok(String::new());
+    };
+    let payee = match inner {
+        TransactionInner::Received { src: payee, .. }
+        | TransactionInner::Paid { dst: payee, .. } => payee.as_str(),
+        _ => "",
+    };
+    Ok(template
+        .replace("{date}", &chrono::Local::now().date_naive().to_string())
+        .replace("{amount}", &options.amount(amount))
+        .replace("{payee}", payee))
+}
+
 #[instrument]
-fn transaction(repo: &mut Repository, amount: Amount, inner: TransactionInner) -> Result<()> {
-    let notes = edit::edit("# Notes")?
-        .lines()
-        .filter(|x| !x.starts_with('#'))
-        .collect();
+#[allow(clippy::too_many_arguments)]
+fn transaction(
+    repo: &mut Repository,
+    options: &Options,
+    amount: Amount,
+    inner: TransactionInner,
+    notes: Option<String>,
+    attachments: &[PathBuf],
+    debts: Vec<Debt>,
+    date: Option<chrono::NaiveDate>,
+    new_currency: bool,
+    dry_run: bool,
+) -> Result<()> {
+    let notes = match notes {
+        Some(notes) => notes,
+        None => edit_notes(
+            options,
+            dry_run,
+            &notes_template(repo, options, amount, &inner)?,
+        )?,
+    };
+    // Nothing is uploaded on a dry run, so there's nothing left behind if it isn't run
+    let attachments = if attachments.is_empty() || dry_run {
+        vec![]
+    } else {
+        let store = attachment_store(repo)?;
+        attachments
+            .iter()
+            .map(|path| store.add(path))
+            .collect::<Result<_>>()?
+    };
     let id = Id::generate();
-    repo.run_command(command::Command::AddTransaction(Transaction {
+    let cmd = command::Command::AddTransaction(Transaction {
         id,
         notes,
         amount,
         inner,
-    }))?;
-    println!("Added transaction {}", id);
+        attachments,
+        debts,
+        date,
+        voided: None,
+        tags: vec![],
+    });
+    if let Some(deltas) = submit_with(repo, options, cmd, dry_run, new_currency)? {
+        println!("Added transaction {}", id);
+        balances(repo, options, &deltas)?;
+    }
     Ok(())
 }
 
-#[instrument]
-fn account_modify(
+/// Save `template` as `name`, for `transaction apply`
+fn transaction_template(
     repo: &mut Repository,
-    id: Id<Account>,
-    mods: Vec<AccountModification>,
+    name: String,
+    template: TransactionTemplate,
+    dry_run: bool,
 ) -> Result<()> {
-    repo.run_command(command::Command::UpdateAccount(id, mods))?;
+    let replaced = repo.config()?.transaction_templates.contains_key(&name);
+    let cmd = command::Command::SetTemplate {
+        name: name.clone(),
+        template,
+    };
+    if dry_run {
+        repo.simulate(&cmd)?;
+        println!("Dry run: {cmd}");
+        return Ok(());
+    }
+    repo.run_command(cmd)?;
+    if replaced {
+        println!("Replaced template {name}");
+    } else {
+        println!("Saved template {name}");
+    }
     Ok(())
 }
 
+/// Add the transaction a receipt is for, from what OCR can make out of it, given as a command to correct and finish
+#[cfg(feature = "ocr")]
 #[instrument]
-fn account_create(repo: &mut Repository, typ: AccountType, name: String) -> Result<()> {
-    let notes = edit::edit("# Notes")?
-        .lines()
-        .filter(|x| !x.starts_with('#'))
-        .collect();
-    let id = Id::generate();
-    repo.run_command(command::Command::CreateAccount(Account {
-        id,
-        name: name.clone(),
+fn transaction_from_image(
+    repo: &mut Repository,
+    options: &Options,
+    path: &std::path::Path,
+    dry_run: bool,
+) -> Result<()> {
+    use std::fmt::Write;
+
+    let receipt = crate::ocr::read(path)?;
+    let config = repo.config()?;
+    let mut line = "transaction".to_owned();
+    if let Some((num, currency)) = receipt.amount {
+        write!(line, " {}.{:02}", num / 100, num % 100)?;
+        if let Some(currency) = currency {
+            write!(line, " {currency}")?;
+        }
+    }
+    line += " paid dst";
+    if let Some(merchant) = &receipt.merchant {
+        write!(line, " \"{}\"", merchant.replace('"', ""))?;
+    }
+    // Ending with a keyword needing an account means the line can be finished by picking accounts
+    line += " src";
+    if let Some(src) = config.default_account {
+        write!(line, " {src} src-virt")?;
+        if let Some(src_virt) = config.default_virtual_account {
+            write!(line, " {src_virt}")?;
+        }
+    }
+    let custom = ReedlineCmd(
+        Arc::new(RwLock::new(repo.accounts()?)),
+        Theme::load(options)?,
+        Arc::new(RwLock::new(config)),
+        Default::default(),
+    );
+    let mut editor = line_editor(&custom);
+    editor.run_edit_commands(&[reedline::EditCommand::InsertString(line)]);
+    let prompt = DefaultPrompt::new(
+        DefaultPromptSegment::Basic("receipt".to_owned()),
+        DefaultPromptSegment::Empty,
+    );
+    let Signal::Success(line) = editor.read_line(&prompt)? else {
+        return Ok(());
+    };
+    let line = custom.complete_interactively(line)?;
+    let cmd = custom
+        .parse(&line)
+        .1
+        .map_err(|e| eyre!("Invalid Command: {line}: {e}"))?;
+    let Command::TransactionAdd {
+        amount,
+        inner,
         notes,
-        typ,
-        current: Default::default(),
-        enabled: true,
-    }))?;
-    println!("Created account \"{}\" ({})", name, id);
-    Ok(())
+        mut attachments,
+        debts,
+        date,
+    } = cmd
+    else {
+        bail!("A receipt can only be recorded as a transaction");
+    };
+    attachments.push(path.to_owned());
+    let notes = match notes {
+        Some(notes) => notes,
+        None => {
+            let mut initial = notes_template(repo, options, amount, &inner)?;
+            if let Some(date) = receipt.date {
+                initial = format!("{initial}\nReceipt dated {date}").trim().to_owned();
+            }
+            edit_notes(options, dry_run, &initial)?
+        }
+    };
+    transaction(
+        repo,
+        options,
+        amount,
+        inner,
+        Some(notes),
+        &attachments,
+        debts,
+        date,
+        false,
+        dry_run,
+    )
 }
 
-#[instrument]
-fn accounts_list(repo: &Repository) -> Result<()> {
-    use comfy_table::*;
-    let mut table = Table::new();
-    table
-        .set_content_arrangement(ContentArrangement::Dynamic)
-        .set_header(vec!["ID", "Name", "Type", "Enabled", "Contents"]);
-    table
-        .column_mut(0)
-        .expect("Column 0 exists")
-        .set_delimiter('-');
-    for account in repo.accounts()? {
-        let Account {
-            id,
-            name,
-            typ,
-            current,
-            enabled,
-            ..
-        } = account;
-        table.add_row(vec![
-            id.to_string(),
-            name,
-            typ.to_string(),
-            enabled.to_string(),
-            current.to_string(),
-        ]);
+fn attachment_store(repo: &mut Repository) -> Result<attachments::Store> {
+    let url = repo.config()?.attachments.ok_or_else(|| {
+        eyre!("No attachment store is configured; set one with `monfari config --attachments`")
+    })?;
+    attachments::Store::open(&url)
+}
+
+/// The ID of the transaction `id` names, which if it's the start of a short ID must be that of only one
+fn transaction_id(repo: &Repository, id: TransactionRef) -> Result<Id<Transaction>> {
+    let short = match id {
+        TransactionRef::Id(id) => return Ok(id),
+        TransactionRef::Short(short) => short,
+    };
+    let matching = repo
+        .all_transactions()?
+        .into_iter()
+        .map(|x| x.id)
+        .filter(|x| x.short().starts_with(&short))
+        .collect_vec();
+    match &matching[..] {
+        [] => bail!("No transaction {short}"),
+        [id] => Ok(*id),
+        _ => bail!(
+            "{} transactions' short IDs start with {short}; give more of it",
+            matching.len()
+        ),
     }
-    println!("{table}");
-    Ok(())
 }
 
-fn account_show(repo: &Repository, account: Id<Account>) -> Result<()> {
-    let Account {
-        id,
-        name,
-        typ,
-        current,
-        enabled: _,
-        notes: _,
-    } = repo.account(account)?;
-    let transactions = repo.transactions(id)?;
-    println!("{name} ({typ}: {id})");
-    println!("{current}");
-    use comfy_table::*;
-    let mut table = Table::new();
-    table
-        .set_content_arrangement(ContentArrangement::Dynamic)
-        .set_header(vec!["Amount", "Description", "Notes"]);
-    for transaction in transactions {
-        let moved = |src, dst| {
-            let (direction, other) = if src == account {
-                ("into", dst)
-            } else {
-                ("from", src)
-            };
-            let name = repo.account(other)?.name;
-            Ok::<_, eyre::Report>(format!("Moved {direction} \"{name}\""))
-        };
-        let Transaction {
-            id: _,
-            notes,
-            amount,
-            inner,
-        } = transaction;
-        let desc = match inner {
-            TransactionInner::Received { src, .. } => format!("Received from {src}"),
-            TransactionInner::Paid { dst, .. } => format!("Paid to {dst}"),
-            TransactionInner::MovePhys { src, dst } => moved(src.erase(), dst.erase())?,
-            TransactionInner::MoveVirt { src, dst } => moved(src.erase(), dst.erase())?,
-            TransactionInner::Convert { new_amount, .. } => format!("Converted into {new_amount}"),
-        };
-        table.add_row(vec![amount.to_string(), desc, notes]);
+fn transaction_attachments(repo: &mut Repository, id: Id<Transaction>) -> Result<()> {
+    let Some(transaction) = repo.all_transactions()?.into_iter().find(|x| x.id == id) else {
+        bail!("No transaction {id}");
+    };
+    if transaction.attachments.is_empty() {
+        println!("Transaction {id} has no attachments");
+        return Ok(());
+    }
+    let store = attachment_store(repo)?;
+    for attachment in &transaction.attachments {
+        println!("{}: {}", attachment.name, store.get(attachment)?.display());
     }
-    println!("{table}");
     Ok(())
 }
+
+fn transaction_reverse(
+    repo: &mut Repository,
+    options: &Options,
+    id: Id<Transaction>,
+    dry_run: bool,
+) -> Result<()> {
+    let Some(transaction) = repo.all_transactions()?.into_iter().find(|x| x.id == id) else {
+        bail!("No transaction {id}");
+    };
+    let reversal = transaction.reversal()?;
+    let reversal_id = reversal.id;
+    if let Some(deltas) = submit(
+        repo,
+        options,
+        command::Command::AddTransaction(reversal),
+        dry_run,
+    )? {
+        println!("Added transaction {reversal_id}, reversing {id}");
+        balances(repo, options, &deltas)?;
+    }
+    Ok(())
+}
+
+fn transaction_void(
+    repo: &mut Repository,
+    options: &Options,
+    id: Id<Transaction>,
+    dry_run: bool,
+) -> Result<()> {
+    let reversal = Id::generate();
+    if let Some(deltas) = submit(
+        repo,
+        options,
+        command::Command::VoidTransaction { id, reversal },
+        dry_run,
+    )? {
+        println!("Voided transaction {id}, reversing it with {reversal}");
+        balances(repo, options, &deltas)?;
+    }
+    Ok(())
+}
+
+fn transaction_edit(
+    repo: &mut Repository,
+    options: &Options,
+    id: Id<Transaction>,
+    mut changes: Vec<TransactionModification>,
+    dry_run: bool,
+) -> Result<()> {
+    if changes.is_empty() {
+        let transaction = repo.transaction(id)?;
+        let notes = if dry_run {
+            transaction.notes
+        } else {
+            edit_notes(options, dry_run, &transaction.notes)?
+        };
+        changes.push(TransactionModification::UpdateNotes(notes));
+    }
+    let cmd = command::Command::UpdateTransaction(id, changes);
+    if let Some(deltas) = submit(repo, options, cmd, dry_run)? {
+        println!("Updated transaction {id}");
+        balances(repo, options, &deltas)?;
+    }
+    Ok(())
+}
+
+/// Completes an answer in `triage`: a virtual account's name, then tags
+struct TriageCompleter {
+    accounts: Vec<Account>,
+    tags: Vec<String>,
+}
+
+impl Completer for TriageCompleter {
+    fn complete(&mut self, line: &str, pos: usize) -> Vec<reedline::Suggestion> {
+        let suggestion = |start, value, description| reedline::Suggestion {
+            value,
+            description,
+            extra: None,
+            span: reedline::Span::new(start, pos),
+            append_whitespace: true,
+        };
+        // Names can have spaces in, so everything before the first tag is the name
+        if line[..pos].contains('#') {
+            let start = line[..pos].rfind(char::is_whitespace).map_or(0, |x| x + 1);
+            let word = &line[start..pos];
+            self.tags
+                .iter()
+                .filter(|x| x.starts_with(word))
+                .map(|x| suggestion(start, x.clone(), None))
+                .collect()
+        } else {
+            let name = line[..pos].trim_start().to_lowercase();
+            self.accounts
+                .iter()
+                .filter(|x| x.name.to_lowercase().starts_with(&name))
+                .map(|x| suggestion(0, x.name.clone(), Some(x.current.to_string())))
+                .collect()
+        }
+    }
+}
+
+/// Go through the payments and receipts in `bucket` not yet moved out of it, asking which virtual account and tags
+/// each belongs with, then move them all at once
+#[instrument]
+fn triage(
+    repo: &mut Repository,
+    options: &Options,
+    bucket: Option<Id<Account<Virtual>>>,
+    dry_run: bool,
+) -> Result<()> {
+    let config = repo.config()?;
+    let Some(bucket) = bucket
+        .or(config.uncategorized)
+        .or(config.default_virtual_account)
+    else {
+        bail!("Give a virtual account to triage, or set a default one with `monfari config --default-virtual-account`");
+    };
+    let transactions = repo.all_transactions()?;
+    let queue = report::backlog(&transactions, bucket);
+    let name = repo.account(bucket.erase())?.name;
+    if queue.is_empty() {
+        println!("Nothing to triage in {name}");
+        return Ok(());
+    }
+    let accounts = repo
+        .accounts()?
+        .into_iter()
+        .filter(|x| x.enabled && x.typ == AccountType::Virtual && x.id != bucket.erase())
+        .collect_vec();
+    let tags = transactions
+        .iter()
+        .map(Transaction::all_notes)
+        .flat_map(|x| {
+            x.split_whitespace()
+                .filter(|x| x.len() > 1 && x.starts_with('#'))
+                .map(str::to_owned)
+                .collect_vec()
+        })
+        .sorted()
+        .dedup()
+        .collect();
+    let mut editor = completing_editor(Box::new(TriageCompleter {
+        accounts: accounts.clone(),
+        tags,
+    }));
+    let prompt = DefaultPrompt::new(
+        DefaultPromptSegment::Basic("triage".to_owned()),
+        DefaultPromptSegment::Empty,
+    );
+    println!(
+        "{} transactions in {name} to triage: give the virtual account each belongs in and any #tags, nothing to leave it, or `stop`",
+        queue.len()
+    );
+    let mut batch = vec![];
+    let mut deltas = BTreeMap::<Id<Account>, Amounts>::new();
+    'queue: for transaction in queue {
+        println!(
+            "{}",
+            transaction_row(repo, options, transaction.clone(), None)?.join("  ")
+        );
+        loop {
+            let Signal::Success(line) = editor.read_line(&prompt)? else {
+                break 'queue;
+            };
+            let (tags, words): (Vec<_>, Vec<_>) =
+                line.split_whitespace().partition(|x| x.starts_with('#'));
+            let name = words.join(" ").to_lowercase();
+            match &*name {
+                "stop" => break 'queue,
+                "" if tags.is_empty() => continue 'queue,
+                _ => {}
+            }
+            // As in commands, by its name or the start of its name if that's unambiguous
+            let exact = accounts.iter().find(|x| x.name.to_lowercase() == name);
+            let prefixed = accounts
+                .iter()
+                .filter(|x| x.name.to_lowercase().starts_with(&name))
+                .exactly_one()
+                .ok();
+            let Some(account) = exact.or(prefixed) else {
+                eprintln!("Give the name of one virtual account");
+                continue;
+            };
+            let Some(categorization) = transaction.categorization(account.id.unerase(), &tags)
+            else {
+                continue 'queue;
+            };
+            let changes = command::Command::AddTransaction(categorization.clone()).deltas()?;
+            // Checked against what's already been categorized too, as it'll all be run together
+            let mut short = None;
+            for (id, delta) in &changes {
+                let Account { name, current, .. } = repo.account(*id)?;
+                let pending = deltas.get(id).cloned().unwrap_or_default();
+                if ((current + pending)? + delta.clone())?.is_negative() {
+                    short = Some(name);
+                }
+            }
+            if let Some(name) = short {
+                eprintln!("{name} doesn't have enough in it for this");
+                continue;
+            }
+            for (id, delta) in changes {
+                let pending = deltas.remove(&id).unwrap_or_default();
+                deltas.insert(id, (pending + delta)?);
+            }
+            batch.push(categorization);
+            continue 'queue;
+        }
+    }
+    if batch.is_empty() || dry_run {
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let count = batch.len();
+    repo.atomically(|repo| {
+        batch.into_iter().try_for_each(|x| {
+            repo.run_command(command::Command::AddTransaction(x))
+                .map(drop)
+        })
+    })?;
+    println!("Categorized {count} transactions");
+    balances(repo, options, &deltas.into_iter().collect())
+}
+
+/// Share `amount` out of `src` as the plan `name` says, showing the moves it makes and asking before making them
+#[instrument]
+fn allocate(
+    repo: &mut Repository,
+    options: &Options,
+    amount: Amount,
+    src: Id<Account<Virtual>>,
+    name: &str,
+    dry_run: bool,
+) -> Result<()> {
+    let config = repo.config()?;
+    let plan = config
+        .plans
+        .get(name)
+        .ok_or_else(|| eyre!("There's no plan called {name}"))?;
+    let mut moves = vec![];
+    let mut left = amount;
+    for &Allocation { to, share } in plan {
+        // A fixed amount in another currency has no part in this
+        let Some(part) = share.of(amount) else {
+            continue;
+        };
+        left.0 -= part.0;
+        moves.push(Transaction {
+            id: Id::generate(),
+            notes: format!("Allocated by plan {name}"),
+            amount: part,
+            inner: TransactionInner::MoveVirt {
+                src,
+                dst: to,
+                phys: None,
+            },
+            attachments: vec![],
+            debts: vec![],
+            date: None,
+            voided: None,
+            tags: vec![],
+        });
+    }
+    ensure!(
+        left.0 >= 0,
+        "The plan {name} shares out {} more than {}",
+        options.amount(Amount(-left.0, amount.1)),
+        options.amount(amount)
+    );
+    ensure!(
+        !moves.is_empty(),
+        "The plan {name} shares out nothing of {}",
+        options.amount(amount)
+    );
+
+    let mut deltas = BTreeMap::<Id<Account>, Amounts>::new();
+    for transaction in &moves {
+        for (id, delta) in transaction.results()? {
+            deltas.entry(id).or_default().add_amount(delta)?;
+        }
+    }
+    use comfy_table::*;
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["Account", "Change", "Balance after"]);
+    for (id, delta) in &deltas {
+        let Account { name, current, .. } = repo.account(*id)?;
+        let after = (current + delta.clone())?;
+        ensure!(
+            !after.is_negative(),
+            "{name} doesn't have enough in it for this"
+        );
+        table.add_row(vec![name, options.amount(delta), options.amount(after)]);
+    }
+    println!("{table}");
+    if left.0 > 0 {
+        println!("{} is left where it is", options.amount(left));
+    }
+    if dry_run {
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let answer = ask(&format!("Make these {} moves? [y/N]", moves.len()))?;
+    if !answer.is_some_and(|x| x.trim().eq_ignore_ascii_case("y")) {
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    repo.add_transactions_bulk(moves, command::Origin::local(), false)?;
+    balances(repo, options, &deltas.into_iter().collect())
+}
+
+/// Record `amount` paid back between me and `person`, whichever way it's owed; money paid back to me is moved on to
+/// the virtual accounts that paid their shares, oldest first
+#[instrument]
+fn settle(
+    repo: &mut Repository,
+    options: &Options,
+    person: String,
+    amount: Amount,
+    acc: Id<Account<Physical>>,
+    acc_virt: Id<Account<Virtual>>,
+    dry_run: bool,
+) -> Result<()> {
+    let transactions = repo.all_transactions()?;
+    let owed = |transactions: &[Transaction]| -> Result<Amount> {
+        Ok(report::owed(transactions)?
+            .into_iter()
+            .find(|(name, _)| name.to_lowercase() == person.to_lowercase())
+            .and_then(|(_, amounts)| amounts.0.get(&amount.1).copied())
+            .unwrap_or(Amount(0, amount.1)))
+    };
+    let before = owed(&transactions)?;
+    ensure!(
+        before.0 != 0,
+        "{person} owes nothing in {}, and is owed nothing",
+        amount.1
+    );
+    ensure!(
+        amount.0 <= before.0.abs(),
+        "{person} {} only {}",
+        if before.0 > 0 { "owes" } else { "is owed" },
+        options.amount(Amount(before.0.abs(), amount.1))
+    );
+
+    let first = Id::<Transaction>::generate();
+    let mut batch = vec![];
+    if before.0 > 0 {
+        batch.push(Transaction {
+            id: first,
+            notes: format!("Paid back by {person}"),
+            amount,
+            inner: TransactionInner::Received {
+                src: person.clone(),
+                dst: acc,
+                dst_virt: acc_virt,
+            },
+            attachments: vec![],
+            debts: vec![Debt {
+                person: person.clone(),
+                amount: (-amount)?,
+            }],
+            date: None,
+            voided: None,
+            tags: vec![],
+        });
+        let mut left = amount.0;
+        for (virt, part) in report::unsettled(&transactions, &person, amount.1) {
+            let part = part.0.min(left);
+            left -= part;
+            if let Some(virt) = virt.filter(|&x| x != acc_virt && part > 0) {
+                batch.push(Transaction {
+                    // After the receipt, so the money's there to move
+                    id: Id::new(ulid::Ulid(first.0 .0.wrapping_add(batch.len() as u128))),
+                    notes: format!("Refund of {person}'s share"),
+                    amount: Amount(part, amount.1),
+                    inner: TransactionInner::MoveVirt {
+                        src: acc_virt,
+                        dst: virt,
+                        phys: Some(acc),
+                    },
+                    attachments: vec![],
+                    debts: vec![],
+                    date: None,
+                    voided: None,
+                    tags: vec![],
+                });
+            }
+        }
+    } else {
+        batch.push(Transaction {
+            id: first,
+            notes: format!("Paid back to {person}"),
+            amount,
+            inner: TransactionInner::Paid {
+                src: acc,
+                src_virt: acc_virt,
+                dst: person.clone(),
+            },
+            attachments: vec![],
+            debts: vec![Debt {
+                person: person.clone(),
+                amount,
+            }],
+            date: None,
+            voided: None,
+            tags: vec![],
+        });
+    }
+
+    if dry_run {
+        for transaction in batch {
+            println!("Dry run: {}", command::Command::AddTransaction(transaction));
+        }
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let mut deltas = BTreeMap::<Id<Account>, Amounts>::new();
+    for transaction in &batch {
+        for (id, delta) in transaction.results()? {
+            deltas.entry(id).or_default().add_amount(delta)?;
+        }
+    }
+    let count = batch.len();
+    repo.add_transactions_bulk(batch, command::Origin::local(), false)?;
+    println!("Added {count} transactions");
+    balances(repo, options, &deltas.into_iter().collect())?;
+    let after = owed(&repo.all_transactions()?)?;
+    match after.0 {
+        0 => println!("{person} and I are settled up in {}", amount.1),
+        x if x > 0 => println!("{person} still owes {}", options.amount(after)),
+        _ => println!("{person} is still owed {}", options.amount((-after)?)),
+    }
+    Ok(())
+}
+
+/// Pay `amount` off the loan `name`, as a payment of the month's interest from its interest account and one of the
+/// rest from its principal account
+#[instrument]
+fn loan_pay(
+    repo: &mut Repository,
+    options: &Options,
+    name: &str,
+    amount: Option<Amount>,
+    src: Id<Account<Physical>>,
+    dry_run: bool,
+) -> Result<()> {
+    let config = repo.config()?;
+    let loan = config
+        .loans
+        .get(name)
+        .ok_or_else(|| eyre!("There's no loan called {name}"))?;
+    let amount = amount.unwrap_or(loan.instalment);
+    ensure!(
+        amount.1 == loan.principal.1,
+        "{name} is paid in {}, not {}",
+        loan.principal.1,
+        amount.1
+    );
+    let (repaid, _, _) = report::repaid(&repo.all_transactions()?, name, loan);
+    let left = Amount(loan.principal.0 - repaid.0, amount.1);
+    ensure!(left.0 > 0, "{name} is already paid off");
+    let interest = loan.interest(left).min(amount);
+    let principal = Amount(amount.0 - interest.0, amount.1);
+    ensure!(
+        principal <= left,
+        "Only {} is left to pay on {name}, with {} of interest",
+        options.amount(left),
+        options.amount(interest)
+    );
+
+    let first = Id::<Transaction>::generate();
+    let batch = [
+        (interest, loan.interest_account, "Interest"),
+        (principal, loan.principal_account, "Principal"),
+    ]
+    .into_iter()
+    .filter(|(part, ..)| part.0 > 0)
+    .enumerate()
+    .map(|(i, (part, src_virt, what))| Transaction {
+        id: Id::new(ulid::Ulid(first.0 .0.wrapping_add(i as u128))),
+        notes: format!("{what} of loan {name}"),
+        amount: part,
+        inner: TransactionInner::Paid {
+            src,
+            src_virt,
+            dst: name.to_owned(),
+        },
+        attachments: vec![],
+        debts: vec![],
+        date: None,
+        voided: None,
+        tags: vec![],
+    })
+    .collect_vec();
+
+    if dry_run {
+        for transaction in batch {
+            println!("Dry run: {}", command::Command::AddTransaction(transaction));
+        }
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let mut deltas = BTreeMap::<Id<Account>, Amounts>::new();
+    for transaction in &batch {
+        for (id, delta) in transaction.results()? {
+            deltas.entry(id).or_default().add_amount(delta)?;
+        }
+    }
+    repo.add_transactions_bulk(batch, command::Origin::local(), false)?;
+    println!(
+        "Paid {} of interest and {} of principal",
+        options.amount(interest),
+        options.amount(principal)
+    );
+    balances(repo, options, &deltas.into_iter().collect())?;
+    println!(
+        "{} left to pay on {name}",
+        options.amount(Amount(left.0 - principal.0, left.1))
+    );
+    Ok(())
+}
+
+/// Show each group of payees that look alike, asking which name to give them all
+#[instrument]
+fn payee_normalize(repo: &mut Repository, dry_run: bool) -> Result<()> {
+    let counts = repo
+        .all_transactions()?
+        .iter()
+        .filter_map(|x| x.payee())
+        .map(str::to_owned)
+        .counts()
+        .into_iter()
+        .collect();
+    let clusters = payees::clusters(&counts);
+    if clusters.is_empty() {
+        println!("No payees look alike");
+        return Ok(());
+    }
+    let mut renamed = 0;
+    for cluster in clusters {
+        println!();
+        for (i, (payee, count)) in cluster.iter().enumerate() {
+            println!("{}. {payee} ({count} transactions)", i + 1);
+        }
+        let Some(answer) = ask("Merge into [number, or a new name; nothing to skip]")? else {
+            break;
+        };
+        let to = match answer.trim() {
+            "" => continue,
+            answer => match answer.parse::<usize>() {
+                Ok(i) if (1..=cluster.len()).contains(&i) => cluster[i - 1].0.clone(),
+                _ => answer.to_owned(),
+            },
+        };
+        let from = cluster
+            .into_iter()
+            .map(|(payee, _)| payee)
+            .filter(|x| *x != to)
+            .collect_vec();
+        let cmd = command::Command::RenamePayees { from, to };
+        if dry_run {
+            repo.simulate(&cmd)?;
+            println!("Dry run: {cmd}");
+            continue;
+        }
+        println!("{cmd}");
+        repo.run_command(cmd)?;
+        renamed += 1;
+    }
+    if dry_run {
+        println!("Nothing was changed");
+    } else {
+        println!("Merged {renamed} groups of payees");
+    }
+    Ok(())
+}
+
+/// Create a physical account for an event, and move its cash float into it from `src`
+#[instrument]
+fn event_open(
+    repo: &mut Repository,
+    options: &Options,
+    name: String,
+    float: Amount,
+    src: Id<Account<Physical>>,
+    dry_run: bool,
+) -> Result<()> {
+    let from = repo.account(src.erase())?;
+    let mut after = from.current.clone();
+    after.sub_amount(float)?;
+    ensure!(
+        !after.is_negative(),
+        "{} doesn't have {} in it for the float",
+        from.name,
+        options.amount(float)
+    );
+    if dry_run {
+        println!(
+            "Dry run: would open {name}, with a float of {} from {}",
+            options.amount(float),
+            from.name
+        );
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let id = Id::generate();
+    repo.run_command(command::Command::CreateAccount(Account {
+        id,
+        name: name.clone(),
+        notes: format!("Event, with its float from {}", from.name),
+        typ: AccountType::Physical,
+        current: Default::default(),
+        enabled: true,
+        institution: None,
+        deleted: false,
+    }))?;
+    let deltas = repo.run_command(command::Command::AddTransaction(Transaction {
+        id: Id::generate(),
+        notes: format!("Float for {name}"),
+        amount: float,
+        inner: TransactionInner::MovePhys {
+            src,
+            dst: id.unerase(),
+            virt: None,
+        },
+        attachments: vec![],
+        debts: vec![],
+        date: None,
+        voided: None,
+        tags: vec![],
+    }))?;
+    LAST_ADDED.set(Some(id.0));
+    println!("Opened {name} ({id})");
+    balances(repo, options, &deltas)
+}
+
+/// Count the cash in an event's account, show what the event made or lost, and move what's left back to where its
+/// float came from, disabling the account
+#[instrument]
+fn event_close(
+    repo: &mut Repository,
+    options: &Options,
+    id: Id<Account<Physical>>,
+    dry_run: bool,
+) -> Result<()> {
+    let mut transactions = repo.transactions(id.erase())?;
+    transactions.sort_by_key(|x| x.id);
+    // The float is the first cash moved in
+    let home = transactions
+        .iter()
+        .find_map(|x| match x.inner {
+            TransactionInner::MovePhys { src, dst, .. } if dst == id => Some(src),
+            _ => None,
+        })
+        .ok_or_else(|| {
+            eyre!("No cash was ever moved into this account, so it isn't an event with a float to return")
+        })?;
+
+    account_count(repo, options, id.erase(), dry_run)?;
+    let transactions = repo.transactions(id.erase())?;
+    let (mut float, mut takings, mut spent, mut adjusted) = Default::default();
+    for transaction in &transactions {
+        let total: &mut Amounts = match transaction.inner {
+            TransactionInner::MovePhys { .. } => &mut float,
+            TransactionInner::Received { .. } => &mut takings,
+            TransactionInner::Paid { .. } => &mut spent,
+            _ => &mut adjusted,
+        };
+        for (acc, amount) in transaction.results()? {
+            if acc == id.erase() {
+                total.add_amount(amount)?;
+            }
+        }
+    }
+    let account = repo.account(id.erase())?;
+    let profit = ((takings.clone() + spent.clone())? + adjusted.clone())?;
+
+    use comfy_table::*;
+    let mut table = Table::new();
+    table.set_content_arrangement(ContentArrangement::Dynamic);
+    for (what, amounts) in [
+        ("Float", &float),
+        ("Takings", &takings),
+        ("Spent", &spent),
+        ("Adjustments", &adjusted),
+        ("Profit", &profit),
+        ("Left", &account.current),
+    ] {
+        table.add_row(vec![what.to_owned(), options.amount(amounts)]);
+    }
+    println!("{}\n{table}", account.name);
+
+    let home_name = repo.account(home.erase())?.name;
+    let moves = account
+        .current
+        .0
+        .values()
+        .filter(|x| x.0 > 0)
+        .map(|&amount| Transaction {
+            id: Id::generate(),
+            notes: format!("Closing {}", account.name),
+            amount,
+            inner: TransactionInner::MovePhys {
+                src: id,
+                dst: home,
+                virt: None,
+            },
+            attachments: vec![],
+            debts: vec![],
+            date: None,
+            voided: None,
+            tags: vec![],
+        })
+        .collect_vec();
+    let left = options.amount(&account.current);
+    if dry_run {
+        println!(
+            "Dry run: would move {left} back to {home_name}, and close {}",
+            account.name
+        );
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let answer = ask(&format!(
+        "Move {left} back to {home_name}, and close {}? [y/N]",
+        account.name
+    ))?;
+    if !answer.is_some_and(|x| x.trim().eq_ignore_ascii_case("y")) {
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    repo.add_transactions_bulk(moves, command::Origin::local(), false)?;
+    repo.run_command(command::Command::UpdateAccount(
+        id.erase(),
+        vec![AccountModification::Disable],
+    ))?;
+    println!("Closed {}", account.name);
+    balances(repo, options, &vec![(home.erase(), Amounts::default())])
+}
+
+#[instrument]
+fn account_modify(
+    repo: &mut Repository,
+    options: &Options,
+    id: Id<Account>,
+    mods: Vec<AccountModification>,
+    dry_run: bool,
+) -> Result<()> {
+    if let Some(AccountModification::ChangeType(typ)) = mods
+        .iter()
+        .find(|x| matches!(x, AccountModification::ChangeType(_)))
+    {
+        let used = repo.transactions(id)?.len();
+        if used > 0 {
+            bail!(
+                "{used} transactions involve this account, so it can't become {typ}; \
+                create a new {typ} account, move its balance there, and disable this one instead"
+            );
+        }
+    }
+    if mods
+        .iter()
+        .any(|x| matches!(x, AccountModification::Delete))
+    {
+        let used = repo.transactions(id)?.len();
+        if used > 0 {
+            bail!("{used} transactions involve this account, so it can't be deleted; disable it instead");
+        }
+    }
+    submit(
+        repo,
+        options,
+        command::Command::UpdateAccount(id, mods),
+        dry_run,
+    )?;
+    Ok(())
+}
+
+#[instrument]
+fn account_bulk_modify(
+    repo: &mut Repository,
+    selector: &AccountSelector,
+    changes: Vec<AccountModification>,
+    dry_run: bool,
+) -> Result<()> {
+    if dry_run {
+        let accounts = repo
+            .accounts()?
+            .into_iter()
+            .filter(|x| selector.matches(x))
+            .collect_vec();
+        for account in &accounts {
+            repo.simulate(&command::Command::UpdateAccount(
+                account.id,
+                changes.clone(),
+            ))?;
+        }
+        println!(
+            "Dry run: {} accounts would be changed: {}",
+            accounts.len(),
+            accounts.iter().map(|x| &x.name).join(", ")
+        );
+        println!("Nothing was changed");
+        return Ok(());
+    }
+    let changed = repo.bulk_modify(selector, changes, command::Origin::local())?;
+    println!("Changed {} accounts", changed.len());
+    Ok(())
+}
+
+fn account_create(
+    repo: &mut Repository,
+    options: &Options,
+    typ: AccountType,
+    name: String,
+    dry_run: bool,
+) -> Result<()> {
+    let notes = edit_notes(options, dry_run, "")?;
+    let id = Id::generate();
+    let cmd = command::Command::CreateAccount(Account {
+        id,
+        name: name.clone(),
+        notes,
+        typ,
+        current: Default::default(),
+        enabled: true,
+        institution: None,
+        deleted: false,
+    });
+    if submit(repo, options, cmd, dry_run)?.is_some() {
+        println!("Created account \"{}\" ({})", name, id);
+    }
+    Ok(())
+}
+
+#[instrument]
+fn accounts_list(repo: &Repository, options: &Options, filter: AccountFilter) -> Result<()> {
+    print_accounts(options, filter, repo.accounts()?, repo.activity()?)
+}
+
+fn print_accounts(
+    options: &Options,
+    filter: AccountFilter,
+    accounts: Vec<Account>,
+    mut activity: BTreeMap<Id<Account>, Activity>,
+) -> Result<()> {
+    use comfy_table::*;
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec![
+            "ID",
+            "Name",
+            "Type",
+            "Enabled",
+            "Contents",
+            "Transactions",
+            "Last activity",
+        ]);
+    table
+        .column_mut(0)
+        .expect("Column 0 exists")
+        .set_delimiter('-');
+    let mut accounts = accounts
+        .into_iter()
+        .filter(|x| filter.typ.is_none_or(|typ| x.typ == typ))
+        .filter(|x| filter.include_disabled || x.enabled)
+        .collect_vec();
+    let mut collapsed = 0;
+    if !(filter.show_empty || options.show_empty) {
+        let before = accounts.len();
+        accounts.retain(|x| !x.is_collapsible());
+        collapsed = before - accounts.len();
+    }
+    match filter.sort {
+        AccountSort::Id => accounts.sort_by_key(|x| x.id),
+        AccountSort::Name => accounts.sort_by_key(|x| x.name.to_lowercase()),
+        AccountSort::Balance => accounts.sort_by_key(|x| Reverse(x.current.0.clone())),
+    }
+    // Only physical accounts are held anywhere, and those held nowhere in particular come last
+    let groups = if filter.by_institution {
+        let mut groups = BTreeMap::<_, Vec<_>>::new();
+        for account in accounts
+            .into_iter()
+            .filter(|x| x.typ == AccountType::Physical)
+        {
+            let institution = account.institution.clone();
+            groups
+                .entry((institution.is_none(), institution))
+                .or_default()
+                .push(account);
+        }
+        groups
+            .into_iter()
+            .map(|((_, institution), accounts)| {
+                let total = match institution {
+                    Some(institution) => format!("Total at {institution}"),
+                    None => "Total at no institution".to_owned(),
+                };
+                (Some(total), accounts)
+            })
+            .collect_vec()
+    } else {
+        vec![(None, accounts)]
+    };
+    for (total, accounts) in groups {
+        let sum = accounts
+            .iter()
+            .map(|x| x.current.clone())
+            .try_fold(Amounts::default(), |a, b| a + b)?;
+        for account in accounts {
+            let Account {
+                id,
+                name,
+                typ,
+                current,
+                enabled,
+                ..
+            } = account;
+            // Unknown for remote repositories while offline
+            let (transactions, last_activity) = match activity.remove(&id) {
+                Some(activity) => (
+                    activity.transactions.to_string(),
+                    activity
+                        .last_activity
+                        .map(|x| {
+                            x.with_timezone(&chrono::Local)
+                                .format("%Y-%m-%d")
+                                .to_string()
+                        })
+                        .unwrap_or_default(),
+                ),
+                None => Default::default(),
+            };
+            table.add_row(vec![
+                id.to_string(),
+                name,
+                typ.to_string(),
+                enabled.to_string(),
+                options.amount(current),
+                transactions,
+                last_activity,
+            ]);
+        }
+        if let Some(total) = total {
+            table.add_row(vec![
+                String::new(),
+                total,
+                String::new(),
+                String::new(),
+                options.amount(sum),
+            ]);
+        }
+    }
+    println!("{table}");
+    if collapsed > 0 {
+        println!(
+            "{collapsed} disabled accounts with no balance hidden (use --show-empty to list them)"
+        );
+    }
+    Ok(())
+}
+
+#[instrument]
+fn account_log(repo: &Repository, id: Id<Account>) -> Result<()> {
+    use comfy_table::*;
+    let log = repo.account_log(id)?;
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["Time", "Commit", "By", "Command"]);
+    for entry in log {
+        table.add_row(vec![
+            entry
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string(),
+            // Abbreviated as git does
+            entry.commit.map(|x| x[..7].to_owned()).unwrap_or_default(),
+            [entry.origin.user, entry.origin.client]
+                .into_iter()
+                .flatten()
+                .join(", "),
+            entry.description,
+        ]);
+    }
+    println!("{table}");
+    Ok(())
+}
+
+fn account_holdings(repo: &Repository, options: &Options) -> Result<()> {
+    use comfy_table::*;
+    let accounts = repo.accounts()?;
+    let holdings = holdings::holdings(&accounts, &repo.all_transactions()?)?;
+    if holdings.is_empty() {
+        println!("Nothing is held in any account");
+        return Ok(());
+    }
+    // Accounts in the order they're listed, then what couldn't be placed
+    let sides = |side: fn(&holdings::Cell) -> Option<Id<Account>>| {
+        let held = holdings.keys().map(side).collect::<BTreeSet<_>>();
+        accounts
+            .iter()
+            .map(|x| Some(x.id))
+            .chain([None])
+            .filter(|x| held.contains(x))
+            .collect_vec()
+    };
+    let (virts, physes) = (sides(|x| x.0), sides(|x| x.1));
+    let name = |id: Option<Id<Account>>| {
+        let account = accounts.iter().find(|x| Some(x.id) == id);
+        account.map_or_else(|| "Unplaced".to_owned(), |x| x.name.clone())
+    };
+    let total = |held: &dyn Fn(&holdings::Cell) -> bool| -> Result<String> {
+        let mut cells = holdings.iter().filter(|x| held(x.0)).map(|x| x.1.clone());
+        Ok(options.amount(cells.try_fold(Amounts::default(), |a, b| a + b)?))
+    };
+
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(
+            std::iter::once(String::new())
+                .chain(physes.iter().map(|&x| name(x)))
+                .chain(["Total".to_owned()]),
+        );
+    for &virt in &virts {
+        let row = physes.iter().map(|&phys| {
+            holdings
+                .get(&(virt, phys))
+                .map(|x| options.amount(x))
+                .unwrap_or_default()
+        });
+        let held = total(&|x| x.0 == virt)?;
+        table.add_row(std::iter::once(name(virt)).chain(row).chain([held]));
+    }
+    let totals = physes
+        .iter()
+        .map(|&phys| total(&|x| x.1 == phys))
+        .collect::<Result<Vec<_>>>()?;
+    table.add_row(
+        std::iter::once("Total".to_owned())
+            .chain(totals)
+            .chain([total(&|_| true)?]),
+    );
+    println!("{table}");
+    Ok(())
+}
+
+fn account_count(
+    repo: &mut Repository,
+    options: &Options,
+    id: Id<Account>,
+    dry_run: bool,
+) -> Result<()> {
+    let account = repo.account(id)?;
+    let config = repo.config()?;
+    let mut currencies = account.current.0.keys().copied().collect::<Vec<_>>();
+    if currencies.is_empty() {
+        currencies.extend(config.default_currency);
+    }
+    let mut counted = Amounts::default();
+    for currency in currencies {
+        let denominations = config.denominations(currency);
+        if denominations.is_empty() {
+            // Nothing to count by, so just ask for the total
+            let Some(total) = ask(&format!("{currency} in total"))? else {
+                return Ok(());
+            };
+            let total =
+                Amount::parse_num(total.trim()).ok_or_else(|| eyre!("Invalid amount {total}"))?;
+            counted.add_amount(Amount(total, currency))?;
+            continue;
+        }
+        for denomination in denominations {
+            let count = loop {
+                let Some(count) = ask(&format!("{} ×", options.amount(denomination)))? else {
+                    return Ok(());
+                };
+                match count.trim() {
+                    "" => break 0,
+                    count => match count.parse::<i32>() {
+                        Ok(count) if count >= 0 => break count,
+                        _ => eprintln!("Give a number of notes or coins"),
+                    },
+                }
+            };
+            let value = (denomination.0)
+                .checked_mul(count)
+                .ok_or(AmountError::Overflow(currency))?;
+            counted.add_amount(Amount(value, currency))?;
+        }
+    }
+
+    use comfy_table::*;
+    let mut table = Table::new();
+    table.set_header(vec!["Currency", "Counted", "Recorded", "Difference"]);
+    let mut differences = vec![];
+    for (&currency, &count) in &counted.0 {
+        let recorded = account
+            .current
+            .0
+            .get(&currency)
+            .copied()
+            .unwrap_or(Amount(0, currency));
+        let difference = Amount(count.0 - recorded.0, currency);
+        table.add_row(vec![
+            currency.to_string(),
+            options.amount(count),
+            options.amount(recorded),
+            options.amount(difference),
+        ]);
+        if difference.0 != 0 {
+            differences.push(difference);
+        }
+    }
+    println!("{table}");
+    if differences.is_empty() {
+        println!("{} matches what was counted", account.name);
+        return Ok(());
+    }
+
+    for difference in differences {
+        let answer = ask(&format!(
+            "Record an adjustment of {}? [y/N]",
+            options.amount(difference)
+        ))?;
+        if !answer.is_some_and(|x| x.trim().eq_ignore_ascii_case("y")) {
+            continue;
+        }
+        let virt = match config.default_virtual_account {
+            Some(virt) => virt,
+            None => {
+                let accounts = repo
+                    .accounts()?
+                    .into_iter()
+                    .filter(|x| x.enabled && x.typ == AccountType::Virtual)
+                    .collect_vec();
+                let candidates = accounts
+                    .iter()
+                    .map(|x| (x.id.to_string(), Some(x.name.clone())))
+                    .collect::<Completions>();
+                let Some(virt) = pick_account(candidates.0)? else {
+                    continue;
+                };
+                // Short IDs can't be parsed, so the account picked is found by how it was shown
+                let picked = accounts.iter().find(|x| x.id.to_string() == virt);
+                picked
+                    .expect("The account picked is a candidate")
+                    .id
+                    .unerase()
+            }
+        };
+        transaction(
+            repo,
+            options,
+            difference,
+            TransactionInner::Adjustment {
+                acc: id.unerase(),
+                acc_virt: virt,
+                reason: "cash count".to_owned(),
+            },
+            Some(String::new()),
+            &[],
+            vec![],
+            None,
+            false,
+            dry_run,
+        )?;
+    }
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn account_show(
+    repo: &Repository,
+    options: &Options,
+    account: Id<Account>,
+    columns: &[ShowColumn],
+    full: bool,
+    summary: Option<SummaryPeriod>,
+    filter: Filter,
+) -> Result<()> {
+    let Account {
+        id,
+        name,
+        typ,
+        current,
+        enabled: _,
+        notes: _,
+        institution,
+        deleted: _,
+    } = repo.account(account)?;
+    let transactions = repo.transactions_filtered(id, filter, Page::default())?;
+    match institution {
+        Some(institution) => println!("{name} ({typ} at {institution}: {id})"),
+        None => println!("{name} ({typ}: {id})"),
+    }
+    println!("{}", options.amount(current));
+    use comfy_table::*;
+    if let Some(period) = summary {
+        let config = repo.config()?;
+        let mut periods = BTreeMap::<_, (Amounts, Amounts)>::new();
+        for transaction in &transactions {
+            let (incoming, outgoing) = periods
+                .entry(period.of(transaction, &config.periods))
+                .or_default();
+            for (_, amount) in transaction
+                .results()?
+                .into_iter()
+                .filter(|(acc, _)| *acc == id)
+            {
+                if amount.is_negative() {
+                    outgoing.sub_amount(amount)?;
+                } else {
+                    incoming.add_amount(amount)?;
+                }
+            }
+        }
+        let mut table = Table::new();
+        table.set_header(vec!["Period", "In", "Out", "Net"]);
+        for (period, (incoming, outgoing)) in periods {
+            let net = (incoming.clone() - outgoing.clone())?;
+            table.add_row(vec![
+                period,
+                options.amount(incoming),
+                options.amount(outgoing),
+                options.amount(net),
+            ]);
+        }
+        println!("{table}");
+    }
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(columns.iter().map(ShowColumn::header).collect_vec());
+    let description_width = options.text_width(full, 3);
+    let notes_width = options.text_width(full, 3);
+    for transaction in transactions {
+        let moved = |src, dst| {
+            let name = |id| Ok::<_, eyre::Report>(repo.account(id)?.name);
+            // The account whose money was moved is neither end of it
+            Ok(if src == account {
+                format!("Moved into \"{}\"", name(dst)?)
+            } else if dst == account {
+                format!("Moved from \"{}\"", name(src)?)
+            } else {
+                format!("Moved from \"{}\" into \"{}\"", name(src)?, name(dst)?)
+            })
+        };
+        let date = transaction.date();
+        // What this account's part of a split was for comes before what the whole of it was, then its tags
+        let tags = transaction.tags.iter().map(|x| format!("#{x}")).join(" ");
+        let notes = transaction.leg_notes(account).into_iter();
+        let notes = notes
+            .chain([&*transaction.notes, &tags])
+            .filter(|x| !x.is_empty())
+            .join("; ");
+        let Transaction {
+            id: _,
+            notes: _,
+            amount,
+            inner,
+            attachments: _,
+            debts,
+            date: _,
+            voided: reversal,
+            tags: _,
+        } = transaction;
+        let name = |id| Ok(repo.account(id)?.name);
+        let desc =
+            describe(&inner, options, name, moved)? + &owed(options, &debts) + &voided(reversal);
+        let desc = ellipsize(desc, description_width);
+        let notes = ellipsize(notes, notes_width);
+        table.add_row(
+            columns
+                .iter()
+                .map(|column| match column {
+                    ShowColumn::Date => date.to_string(),
+                    ShowColumn::Amount => options.amount(amount),
+                    ShowColumn::Description => desc.clone(),
+                    ShowColumn::Notes => notes.clone(),
+                })
+                .collect_vec(),
+        );
+    }
+    println!("{table}");
+    Ok(())
+}
+
+/// Cut `s` down to `width` characters, marking that it has been cut
+fn ellipsize(s: String, width: Option<usize>) -> String {
+    match width {
+        Some(width) if s.chars().count() > width => s
+            .chars()
+            .take(width.saturating_sub(1))
+            .chain(['…'])
+            .collect(),
+        _ => s,
+    }
+}
+
+fn describe(
+    inner: &TransactionInner,
+    options: &Options,
+    name: impl Fn(Id<Account>) -> Result<String>,
+    moved: impl Fn(Id<Account>, Id<Account>) -> Result<String>,
+) -> Result<String> {
+    // Whose money a move was, if that's tracked
+    let of = |what, id: Option<Id<Account>>| {
+        id.map_or(Ok(String::new()), |id| {
+            Ok::<_, eyre::Report>(format!(", {what} \"{}\"", name(id)?))
+        })
+    };
+    Ok(match inner {
+        TransactionInner::Received { src, .. } => format!("Received from {src}"),
+        TransactionInner::Paid { dst, .. } => format!("Paid to {dst}"),
+        TransactionInner::MovePhys { src, dst, virt } => {
+            moved(src.erase(), dst.erase())? + &of("of", virt.map(|x| x.erase()))?
+        }
+        TransactionInner::MoveVirt { src, dst, phys } => {
+            moved(src.erase(), dst.erase())? + &of("held in", phys.map(|x| x.erase()))?
+        }
+        TransactionInner::Remap { virt, src, dst } => format!(
+            "Remapped \"{}\" from \"{}\" into \"{}\"",
+            name(virt.erase())?,
+            name(src.erase())?,
+            name(dst.erase())?
+        ),
+        TransactionInner::Convert {
+            new_amount,
+            fee: None,
+            ..
+        } => format!("Converted into {}", options.amount(new_amount)),
+        TransactionInner::Convert {
+            new_amount,
+            fee: Some(fee),
+            ..
+        } => format!(
+            "Converted into {} (fee {})",
+            options.amount(new_amount),
+            options.amount(fee)
+        ),
+        TransactionInner::Adjustment { reason, .. } => format!("Adjusted for {reason}"),
+        TransactionInner::General { legs } => {
+            let accounts = legs
+                .iter()
+                .filter(|x| matches!(x.party, Party::Account(_)))
+                .count();
+            let mut description = format!("Split across {accounts} accounts");
+            for leg in legs {
+                if let Party::External(party) = &leg.party {
+                    description += &if leg.amount.0 < 0 {
+                        format!(
+                            ", {} from {party}",
+                            options.amount((-leg.amount).unwrap_or(leg.amount))
+                        )
+                    } else {
+                        format!(", {} to {party}", options.amount(leg.amount))
+                    };
+                }
+            }
+            description
+        }
+    })
+}
+
+/// What of a transaction is owed, to follow its description
+fn owed(options: &Options, debts: &[Debt]) -> String {
+    debts
+        .iter()
+        .map(|Debt { person, amount }| {
+            if amount.0 > 0 {
+                format!(", {} owed by {person}", options.amount(*amount))
+            } else {
+                // Only the very least amount can't be negated, so is shown as it is
+                format!(
+                    ", {} owed to {person}",
+                    options.amount((-*amount).unwrap_or(*amount))
+                )
+            }
+        })
+        .collect()
+}
+
+/// Whether a transaction was voided, to follow its description
+fn voided(reversal: Option<Id<Transaction>>) -> String {
+    reversal
+        .map(|x| format!(", voided by {x}"))
+        .unwrap_or_default()
+}
+
+#[instrument]
+fn transaction_search(repo: &Repository, options: &Options, query: &str) -> Result<()> {
+    use comfy_table::*;
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["ID", "Amount", "Description", "Notes"]);
+    let notes_width = options.text_width(false, 4);
+    for result in repo.search(query)? {
+        match result {
+            SearchResult::Account(Account {
+                id,
+                name,
+                notes,
+                current,
+                ..
+            }) => table.add_row(vec![
+                id.to_string(),
+                options.amount(current),
+                format!("Account \"{name}\""),
+                ellipsize(notes, notes_width),
+            ]),
+            SearchResult::Transaction(transaction) => {
+                table.add_row(transaction_row(repo, options, transaction, notes_width)?)
+            }
+        };
+    }
+    println!("{table}");
+    Ok(())
+}
+
+/// The most recently added transactions, across all accounts
+#[instrument]
+fn transaction_last(
+    repo: &Repository,
+    options: &Options,
+    count: usize,
+    account: Option<Id<Account>>,
+) -> Result<()> {
+    use comfy_table::*;
+    let mut table = Table::new();
+    table
+        .set_content_arrangement(ContentArrangement::Dynamic)
+        .set_header(vec!["ID", "Amount", "Description", "Notes"]);
+    let notes_width = options.text_width(false, 4);
+    for transaction in repo
+        .all_transactions()?
+        .into_iter()
+        .rev()
+        .filter(|x| account.is_none_or(|acc| x.accounts().contains(&acc)))
+        .take(count)
+    {
+        table.add_row(transaction_row(repo, options, transaction, notes_width)?);
+    }
+    println!("{table}");
+    Ok(())
+}
+
+/// What was spent from each virtual account in each period, then, at a terminal, the transactions behind each row
+/// picked by its number
+fn spending(repo: &Repository, options: &Options, by: SummaryPeriod) -> Result<()> {
+    use comfy_table::*;
+    let periods = repo.config()?.periods;
+    let names = repo
+        .accounts()?
+        .into_iter()
+        .map(|x| (x.id, x.name))
+        .collect::<BTreeMap<_, _>>();
+    let mut spent = BTreeMap::<_, Amounts>::new();
+    for transaction in repo.all_transactions()? {
+        if let Some((virt, amount)) = transaction.spent_from() {
+            let period = by.period(transaction.date(), &periods);
+            let name = names.get(&virt.erase()).cloned().unwrap_or_default();
+            spent
+                .entry((period, name, virt))
+                .or_default()
+                .add_amount(amount)?;
+        }
+    }
+    if spent.is_empty() {
+        println!("Nothing has been spent");
+        return Ok(());
+    }
+    let rows = spent.into_iter().collect_vec();
+    let mut table = Table::new();
+    table.set_header(vec!["", "Period", "Virtual account", "Spent"]);
+    for (i, ((period, name, _), amount)) in rows.iter().enumerate() {
+        table.add_row(vec![
+            (i + 1).to_string(),
+            by.name(*period),
+            name.clone(),
+            options.amount(amount),
+        ]);
+    }
+    println!("{table}");
+    // There's no one to pick rows when it's run from a script
+    if !io::stdin().is_terminal() {
+        return Ok(());
+    }
+    println!("Give a row's number to see what was spent, or nothing to stop");
+    while let Some(choice) = ask("row")? {
+        if choice.trim().is_empty() {
+            break;
+        }
+        let Some(((period, name, virt), _)) = choice
+            .trim()
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| rows.get(i.wrapping_sub(1)))
+        else {
+            eprintln!("Pick a row by its number");
+            continue;
+        };
+        // Only the row's account and days are looked at, rather than everything again
+        let days = Filter {
+            tag: None,
+            from: Some(period.start),
+            to: Some(period.end - chrono::Days::new(1)),
+        };
+        let mut table = Table::new();
+        table
+            .set_content_arrangement(ContentArrangement::Dynamic)
+            .set_header(vec!["ID", "Amount", "Description", "Notes"]);
+        let notes_width = options.text_width(false, 4);
+        for transaction in repo.transactions_filtered(virt.erase(), days, Page::default())? {
+            if transaction.spent_from().is_some_and(|(x, _)| x == *virt) {
+                table.add_row(transaction_row(repo, options, transaction, notes_width)?);
+            }
+        }
+        println!("Spent from {name} in {}", by.name(*period));
+        println!("{table}");
+    }
+    Ok(())
+}
+
+pub fn transaction_row(
+    repo: &Repository,
+    options: &Options,
+    Transaction {
+        id,
+        notes,
+        amount,
+        inner,
+        attachments: _,
+        debts,
+        date: _,
+        voided: reversal,
+        tags: _,
+    }: Transaction,
+    notes_width: Option<usize>,
+) -> Result<Vec<String>> {
+    let name = |id| Ok(repo.account(id)?.name);
+    let moved = |src, dst| {
+        Ok(format!(
+            "Moved from \"{}\" into \"{}\"",
+            name(src)?,
+            name(dst)?
+        ))
+    };
+    Ok(vec![
+        id.to_string(),
+        options.amount(amount),
+        describe(&inner, options, name, moved)? + &owed(options, &debts) + &voided(reversal),
+        ellipsize(notes, notes_width),
+    ])
+}
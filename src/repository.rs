@@ -1,12 +1,13 @@
 use std::{
+    collections::{BTreeMap, BTreeSet},
     ffi::OsStr,
     fmt::Debug,
-    net::{TcpStream, ToSocketAddrs},
     path::{Path, PathBuf},
     sync::Mutex,
 };
 
-use eyre::{bail, Result};
+use eyre::{bail, ensure, Result};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use tracing::instrument;
 
 use crate::{command::*, types::*};
@@ -14,40 +15,232 @@ use crate::{command::*, types::*};
 mod local;
 use local::LocalRepository;
 
+mod memory;
+use memory::MemoryRepository;
+
 mod remote;
 use remote::RemoteRepository;
 
 mod sql;
 use sql::SqlRepository;
 
-pub use remote::serve;
+pub use remote::{serve, HttpOptions};
 
 #[derive(Debug)]
 enum RepositoryInner {
     Local(LocalRepository),
     Sql(SqlRepository),
-    Remote(Mutex<RemoteRepository>),
+    // Boxed, as it holds everything in it
+    Memory(Box<MemoryRepository>),
+    // Boxed, as it carries the offline state with it
+    Remote(Box<Mutex<RemoteRepository>>),
 }
 
 #[derive(Debug)]
 pub struct Repository(RepositoryInner);
 
+/// Part of a listing of entities, which are listed in ID order, and so in the order they were created
+#[derive(Debug)]
+pub struct Page<T> {
+    /// Only those after this one
+    pub after: Option<Id<T>>,
+    /// Only those before this one
+    pub before: Option<Id<T>>,
+    /// At most this many
+    pub limit: Option<usize>,
+}
+
+impl<T> Clone for Page<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for Page<T> {}
+
+impl<T> Default for Page<T> {
+    fn default() -> Self {
+        Self {
+            after: None,
+            before: None,
+            limit: None,
+        }
+    }
+}
+
+impl<T> Page<T> {
+    /// Cut `entities`, in ID order, down to the page
+    fn apply(self, entities: Vec<T>, id: impl Fn(&T) -> Id<T>) -> Vec<T> {
+        entities
+            .into_iter()
+            .filter(|x| self.after.is_none_or(|after| id(x) > after))
+            .filter(|x| self.before.is_none_or(|before| id(x) < before))
+            .take(self.limit.unwrap_or(usize::MAX))
+            .collect()
+    }
+}
+
+/// A kind of entity, and where a local repository keeps each of them, as TOML
+pub trait Entity: DeserializeOwned + Serialize + Debug {
+    const PATH: &'static str;
+    fn id(&self) -> Id<Self>;
+    /// Subdirectory of `PATH` to store the entity in, for kinds with too many entities to keep in one directory
+    fn shard(_id: Id<Self>) -> Option<String> {
+        None
+    }
+}
+
+/// A kind of entity every backend keeps whole from its [`Entity`] definition alone, under its `PATH`: as TOML files in
+/// a local repository, as JSON in SQL and in memory, and from a server at `GET /{PATH}`
+///
+/// A new kind needs only this, and adding to [`stored`].
+pub trait Stored: Entity + Clone {
+    /// What one is called in errors
+    const NAME: &'static str;
+}
+
+impl Entity for ScheduledTransaction {
+    const PATH: &'static str = "schedules";
+    fn id(&self) -> Id<Self> {
+        self.id
+    }
+}
+impl Stored for ScheduledTransaction {
+    const NAME: &'static str = "schedule";
+}
+
+/// Lists every entity of one [`Stored`] kind, as JSON
+type ListStored = fn(&Repository) -> Result<Vec<serde_json::Value>>;
+
+/// How to list the entities of the [`Stored`] kind kept under `path`, for a server to send on
+fn stored(path: &str) -> Option<ListStored> {
+    fn list<T: Stored>(repo: &Repository) -> Result<Vec<serde_json::Value>> {
+        Ok(repo
+            .entities::<T>()?
+            .iter()
+            .map(serde_json::to_value)
+            .collect::<Result<_, _>>()?)
+    }
+    match path {
+        ScheduledTransaction::PATH => Some(list::<ScheduledTransaction>),
+        _ => None,
+    }
+}
+
+/// Which of an account's transactions to list, besides those outside the page
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Filter<'a> {
+    /// Only those tagged this
+    pub tag: Option<&'a str>,
+    /// Only those dated this day or later
+    pub from: Option<chrono::NaiveDate>,
+    /// Only those dated this day or earlier
+    pub to: Option<chrono::NaiveDate>,
+}
+
+impl Filter<'_> {
+    pub fn includes(&self, transaction: &Transaction) -> bool {
+        let date = transaction.date();
+        self.tag
+            .is_none_or(|tag| transaction.tags.iter().any(|x| x == tag))
+            && self.from.is_none_or(|from| date >= from)
+            && self.to.is_none_or(|to| date <= to)
+    }
+}
+
+/// A point in the repository's history to look at it as of
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Point {
+    /// The end of a day, in UTC
+    Date(chrono::NaiveDate),
+    /// A commit, for local repositories
+    Commit(String),
+}
+
+impl Point {
+    /// When the day given ends, for points given as dates
+    fn end(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        match self {
+            Point::Date(date) => Some(chrono::TimeZone::from_utc_datetime(
+                &chrono::Utc,
+                &date.succ_opt()?.and_hms_opt(0, 0, 0)?,
+            )),
+            Point::Commit(_) => None,
+        }
+    }
+}
+
+impl std::str::FromStr for Point {
+    type Err = std::convert::Infallible;
+
+    /// A date like `2024-03-01`, or otherwise a commit
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match chrono::NaiveDate::parse_from_str(s, "%Y-%m-%d") {
+            Ok(date) => Point::Date(date),
+            Err(_) => Point::Commit(s.to_owned()),
+        })
+    }
+}
+
+impl std::fmt::Display for Point {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Point::Date(date) => write!(f, "{date}"),
+            Point::Commit(commit) => write!(f, "{commit}"),
+        }
+    }
+}
+
 impl Repository {
+    /// Make a new local repository at `path`, starting with `accounts` and `config`
     #[instrument]
-    pub fn init(path: PathBuf) -> Result<Self> {
-        Ok(Self(RepositoryInner::Local(LocalRepository::init(path)?)))
+    pub fn init(path: PathBuf, accounts: Vec<Account>, config: Config) -> Result<Self> {
+        Ok(Self(RepositoryInner::Local(LocalRepository::init(
+            path, accounts, config,
+        )?)))
     }
 
     #[instrument]
     pub fn open(addr: &OsStr) -> Result<Repository> {
-        let Some(addr) = addr.to_str() else { return Self::open_local(addr.as_ref()) };
+        let mut repo = match addr.to_str() {
+            None => Self::open_local(addr.as_ref())?,
+            Some(addr) => match addr.split_once(':') {
+                None => Self::open_local(addr.as_ref())?,
+                Some(("path", path)) => Self::open_local(path.as_ref())?,
+                Some(("tcp", addr)) => Self::open_tcp(addr)?,
+                Some(("http" | "https", _)) => Self::open_http(addr.to_owned())?,
+                Some(("sqlite", path)) => Self(RepositoryInner::Sql(SqlRepository::open(path)?)),
+                Some(("memory", "")) => Self(RepositoryInner::Memory(Box::default())),
+                Some((proto, _)) => bail!("Unknown proto {proto}"),
+            },
+        };
+        // IDs are shown as the repository says, from the moment it's opened
+        show_ids(repo.config()?.id_style);
+        repo.move_schedules()?;
+        Ok(repo)
+    }
+
+    /// Write a copy of the repository at `addr` to `dir`, named `name` with an extension suiting its kind
+    ///
+    /// This works alongside a server that has the repository open.
+    #[instrument]
+    pub fn snapshot(addr: &OsStr, dir: &Path, name: &str) -> Result<PathBuf> {
+        let local = |path: &Path| {
+            let dest = dir.join(format!("{name}.bundle"));
+            LocalRepository::snapshot(path, &dest)?;
+            Ok(dest)
+        };
+        let Some(addr) = addr.to_str() else { return local(addr.as_ref()) };
         match addr.split_once(':') {
-            None => Self::open_local(addr.as_ref()),
-            Some(("path", path)) => Self::open_local(path.as_ref()),
-            Some(("tcp", addr)) => Self::open_tcp(addr),
-            Some(("http" | "https", _)) => Self::open_http(addr.to_owned()),
-            Some(("sqlite", path)) => Ok(Self(RepositoryInner::Sql(SqlRepository::open(path)?))),
-            Some((proto, _)) => bail!("Unknown proto {proto}"),
+            None => local(addr.as_ref()),
+            Some(("path", path)) => local(path.as_ref()),
+            Some(("sqlite", path)) => {
+                let dest = dir.join(format!("{name}.sqlite3"));
+                SqlRepository::snapshot(path, &dest)?;
+                Ok(dest)
+            }
+            Some(("memory", _)) => bail!("Repositories in memory can't be backed up"),
+            Some((proto, _)) => bail!("Repositories over {proto} must be backed up by their server"),
         }
     }
 
@@ -57,24 +250,361 @@ impl Repository {
         )?)))
     }
 
-    fn open_tcp(s: impl ToSocketAddrs) -> Result<Self> {
-        let stream = TcpStream::connect(s)?;
-        Ok(Self(RepositoryInner::Remote(Mutex::new(
-            RemoteRepository::open_tcp(stream)?,
-        ))))
+    fn open_tcp(addr: &str) -> Result<Self> {
+        Ok(Self(RepositoryInner::Remote(Box::new(Mutex::new(
+            RemoteRepository::open(remote::Address::Tcp(addr.to_owned()))?,
+        )))))
     }
 
     fn open_http(s: String) -> Result<Self> {
-        Ok(Self(RepositoryInner::Remote(Mutex::new(
-            RemoteRepository::open_http(s)?,
-        ))))
+        Ok(Self(RepositoryInner::Remote(Box::new(Mutex::new(
+            RemoteRepository::open(remote::Address::Http(s))?,
+        )))))
+    }
+
+    pub fn run_command(&mut self, cmd: Command) -> Result<Deltas> {
+        self.run_command_from(cmd, Origin::local())
+    }
+
+    /// Check `cmd` as running it would, giving the change it would make to each account's balance
+    pub fn simulate(&self, cmd: &Command) -> Result<Deltas> {
+        check(
+            cmd,
+            |id| self.account(id),
+            |id| self.transactions(id),
+            |id| self.transaction(id),
+        )
     }
 
-    pub fn run_command(&mut self, cmd: Command) -> Result<()> {
+    /// Run `cmd`, giving the change it made to each account's balance
+    ///
+    /// A transaction the funding rules apply to is added along with the moves they make, all of them or none.
+    pub fn run_command_from(&mut self, cmd: Command, origin: Origin) -> Result<Deltas> {
+        self.run_command_with(cmd, origin, false)
+    }
+
+    /// Run `cmd` as [`run_command_from`](Self::run_command_from) does, taking any currencies in it that aren't used
+    /// here as meant if `new_currency`, and allowing them from then on along with running it
+    pub fn run_command_with(
+        &mut self,
+        cmd: Command,
+        origin: Origin,
+        new_currency: bool,
+    ) -> Result<Deltas> {
+        if !new_currency {
+            return self.run(cmd, origin, false);
+        }
+        let currencies = cmd
+            .transaction()
+            .map_or_else(Vec::new, |transaction| transaction.currencies());
+        // So they're only allowed if it's run, and it's only run if they can be
+        self.atomically(|repo| {
+            let deltas = repo.run(cmd, origin, true)?;
+            repo.allow_currencies(currencies)?;
+            Ok(deltas)
+        })
+    }
+
+    fn run(&mut self, cmd: Command, origin: Origin, new_currency: bool) -> Result<Deltas> {
+        if let Some(transaction) = cmd.transaction() {
+            transaction.validate()?;
+            if !new_currency {
+                self.check_currencies(transaction.currencies())?;
+            }
+            let mut transactions = self.with_funding(vec![transaction.clone()])?;
+            if transactions.len() > 1 {
+                let mut deltas = BTreeMap::<_, Amounts>::new();
+                for transaction in &transactions {
+                    for (acc, amount) in transaction.results()? {
+                        deltas.entry(acc).or_default().add_amount(amount)?;
+                    }
+                }
+                if let Command::AddScheduled { .. } = cmd {
+                    // Only the command itself records how far its schedule has been added
+                    let funding = transactions.split_off(1);
+                    self.atomically(|repo| {
+                        repo.store(cmd, origin.clone(), new_currency, vec![])?;
+                        repo.add_transactions(funding, origin, new_currency)
+                    })?;
+                } else {
+                    self.add_transactions(transactions, origin, new_currency)?;
+                }
+                return Ok(deltas.into_iter().collect());
+            }
+        }
+        let deltas = match &cmd {
+            Command::RenamePayees { .. } => {
+                self.simulate(&cmd)?;
+                cmd.deltas()?
+            }
+            Command::SetSchedule(schedule) => {
+                self.simulate(&cmd)?;
+                // Schedules are picked out by name
+                let taken = self
+                    .schedules()?
+                    .into_iter()
+                    .any(|x| x.name == schedule.name && x.id != schedule.id);
+                ensure!(!taken, "There's already a schedule named {}", schedule.name);
+                cmd.deltas()?
+            }
+            Command::UpdateTransaction(..) | Command::VoidTransaction { .. } => {
+                self.simulate(&cmd)?
+            }
+            Command::SetTemplate { .. } => {
+                self.simulate(&cmd)?;
+                cmd.deltas()?
+            }
+            _ => cmd.deltas()?,
+        };
+        self.store(cmd, origin, new_currency, deltas)
+    }
+
+    /// Have the backend run `cmd`, giving `deltas`, or for a remote repository the changes its server reports
+    fn store(
+        &mut self,
+        cmd: Command,
+        origin: Origin,
+        new_currency: bool,
+        deltas: Deltas,
+    ) -> Result<Deltas> {
         match &mut self.0 {
-            RepositoryInner::Local(repo) => repo.run_command(cmd),
-            RepositoryInner::Sql(repo) => repo.run_command(cmd),
-            RepositoryInner::Remote(repo) => repo.get_mut().unwrap().run_command(cmd),
+            RepositoryInner::Local(repo) => repo.run_command(cmd, origin)?,
+            RepositoryInner::Sql(repo) => repo.run_command(cmd, origin)?,
+            RepositoryInner::Memory(repo) => repo.run_command(cmd, origin)?,
+            // As the server reports them; it allows new currencies itself
+            RepositoryInner::Remote(repo) => {
+                return repo
+                    .get_mut()
+                    .unwrap()
+                    .run_command(cmd, origin, new_currency)
+            }
+        }
+        Ok(deltas)
+    }
+
+    /// Add all of `transactions` or none of them, much faster than a command for each
+    ///
+    /// A remote repository has them sent one at a time, so there a failure leaves earlier ones in place. Currencies
+    /// not used here are taken as meant if `new_currency`, and allowed along with them.
+    pub fn add_transactions_bulk(
+        &mut self,
+        transactions: Vec<Transaction>,
+        origin: Origin,
+        new_currency: bool,
+    ) -> Result<()> {
+        for transaction in &transactions {
+            transaction.validate()?;
+        }
+        let currencies = transactions
+            .iter()
+            .flat_map(|x| x.currencies())
+            .collect::<BTreeSet<_>>();
+        if !new_currency {
+            self.check_currencies(currencies)?;
+            let transactions = self.with_funding(transactions)?;
+            return self.add_transactions(transactions, origin, false);
+        }
+        let transactions = self.with_funding(transactions)?;
+        self.atomically(|repo| {
+            repo.add_transactions(transactions, origin, true)?;
+            repo.allow_currencies(currencies)
+        })
+    }
+
+    fn add_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+        origin: Origin,
+        new_currency: bool,
+    ) -> Result<()> {
+        match &mut self.0 {
+            RepositoryInner::Local(repo) => repo.add_transactions(transactions, origin),
+            RepositoryInner::Sql(repo) => repo.add_transactions(transactions, origin),
+            RepositoryInner::Memory(repo) => repo.add_transactions(transactions, origin),
+            RepositoryInner::Remote(repo) => {
+                let repo = repo.get_mut().unwrap();
+                for transaction in transactions {
+                    let command = Command::AddTransaction(transaction);
+                    repo.run_command(command, origin.clone(), new_currency)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// The currencies transactions may be in: those configured, the default, and those already held
+    fn allowed_currencies(&self) -> Result<BTreeSet<Currency>> {
+        let config = self.config()?;
+        let mut allowed = config
+            .currencies
+            .into_iter()
+            .chain(config.default_currency)
+            .collect::<BTreeSet<_>>();
+        allowed.extend(match &self.0 {
+            RepositoryInner::Local(repo) => repo.currencies(),
+            RepositoryInner::Sql(repo) => repo.currencies()?,
+            RepositoryInner::Memory(repo) => repo.currencies(),
+            RepositoryInner::Remote(repo) => (repo.lock().unwrap().accounts()?.into_iter())
+                .flat_map(|x| x.current.0.into_keys())
+                .collect(),
+        });
+        Ok(allowed)
+    }
+
+    /// Refuse `currencies` that aren't allowed, as they may be typos
+    fn check_currencies(&self, currencies: impl IntoIterator<Item = Currency>) -> Result<()> {
+        // A server checks what it's sent against its own
+        if let RepositoryInner::Remote(_) = self.0 {
+            return Ok(());
+        }
+        let allowed = self.allowed_currencies()?;
+        // Before anything's been added, there's nothing to be mistaken for
+        if allowed.is_empty() {
+            return Ok(());
+        }
+        if let Some(currency) = currencies.into_iter().find(|x| !allowed.contains(x)) {
+            bail!(
+                "{currency} isn't a currency used here, so may be a typo; to use it anyway, give --new-currency, or allow it with `monfari config --currency {currency}`"
+            );
+        }
+        Ok(())
+    }
+
+    /// Allow transactions in `currencies` from now on, even once nothing's held in them
+    fn allow_currencies(&mut self, currencies: impl IntoIterator<Item = Currency>) -> Result<()> {
+        // A server allows those it's told are new itself
+        if let RepositoryInner::Remote(_) = self.0 {
+            return Ok(());
+        }
+        let mut config = self.config()?;
+        let new = currencies
+            .into_iter()
+            .filter(|x| !config.currencies.contains(x) && config.default_currency != Some(*x))
+            .collect::<BTreeSet<_>>();
+        if new.is_empty() {
+            return Ok(());
+        }
+        config.currencies.extend(new);
+        self.set_config(config)
+    }
+
+    /// `transactions`, each followed by the moves the funding rules make after it
+    fn with_funding(&self, transactions: Vec<Transaction>) -> Result<Vec<Transaction>> {
+        // A server applies its own rules, to what it's sent
+        if let RepositoryInner::Remote(_) = self.0 {
+            return Ok(transactions);
+        }
+        let rules = self.config()?.funding;
+        if rules.is_empty() {
+            return Ok(transactions);
+        }
+        Ok(transactions
+            .into_iter()
+            .flat_map(|transaction| {
+                let mut left = transaction.amount;
+                let funding = rules
+                    .iter()
+                    .zip(1..)
+                    .filter_map(|(rule, offset)| {
+                        let funded = rule.fund(&transaction, left, offset)?;
+                        left.0 -= funded.amount.0;
+                        Some(funded)
+                    })
+                    .collect::<Vec<_>>();
+                std::iter::once(transaction).chain(funding)
+            })
+            .collect())
+    }
+
+    /// Run `f`, undoing every command it ran if it fails
+    ///
+    /// A remote repository runs each command on its own, so there a failure leaves earlier commands in place. Within
+    /// another such run, `f` is just part of that, undone only along with the rest.
+    pub fn atomically<T>(&mut self, f: impl FnOnce(&mut Self) -> Result<T>) -> Result<T> {
+        let nested = match &self.0 {
+            RepositoryInner::Local(repo) => repo.in_batch(),
+            RepositoryInner::Sql(repo) => repo.in_batch(),
+            RepositoryInner::Memory(repo) => repo.in_batch(),
+            RepositoryInner::Remote(_) => false,
+        };
+        if nested {
+            return f(self);
+        }
+        match &mut self.0 {
+            RepositoryInner::Local(repo) => repo.begin()?,
+            RepositoryInner::Sql(repo) => repo.begin()?,
+            RepositoryInner::Memory(repo) => repo.begin()?,
+            RepositoryInner::Remote(_) => {}
+        }
+        let result = f(self);
+        match (&mut self.0, result.is_ok()) {
+            (RepositoryInner::Local(repo), true) => repo.commit()?,
+            (RepositoryInner::Local(repo), false) => repo.rollback()?,
+            (RepositoryInner::Sql(repo), true) => repo.commit()?,
+            (RepositoryInner::Sql(repo), false) => repo.rollback()?,
+            (RepositoryInner::Memory(repo), true) => repo.commit()?,
+            (RepositoryInner::Memory(repo), false) => repo.rollback()?,
+            (RepositoryInner::Remote(_), _) => {}
+        }
+        result
+    }
+
+    /// Make `changes` to every account `selector` matches, all of them or none, giving those changed
+    ///
+    /// A remote repository has its server make them, so they're still made all together.
+    pub fn bulk_modify(
+        &mut self,
+        selector: &AccountSelector,
+        changes: Vec<AccountModification>,
+        origin: Origin,
+    ) -> Result<Vec<Id<Account>>> {
+        if let RepositoryInner::Remote(repo) = &mut self.0 {
+            return repo
+                .get_mut()
+                .unwrap()
+                .bulk_modify(selector, changes, origin);
+        }
+        let ids = self
+            .accounts()?
+            .into_iter()
+            .filter(|x| selector.matches(x))
+            .map(|x| x.id)
+            .collect::<Vec<_>>();
+        self.atomically(|repo| {
+            for &id in &ids {
+                repo.run_command_from(Command::UpdateAccount(id, changes.clone()), origin.clone())?;
+            }
+            Ok(())
+        })?;
+        Ok(ids)
+    }
+
+    /// Compact the underlying storage
+    pub fn maintenance(&mut self) -> Result<()> {
+        match &mut self.0 {
+            RepositoryInner::Local(repo) => repo.maintenance(),
+            RepositoryInner::Sql(repo) => repo.maintenance(),
+            // There is nothing stored to compact
+            RepositoryInner::Memory(_) => Ok(()),
+            RepositoryInner::Remote(_) => bail!("Maintenance must be run on the server"),
+        }
+    }
+
+    pub fn config(&self) -> Result<Config> {
+        match &self.0 {
+            RepositoryInner::Local(repo) => repo.config(),
+            RepositoryInner::Sql(repo) => repo.config(),
+            RepositoryInner::Memory(repo) => repo.config(),
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().config(),
+        }
+    }
+
+    pub fn set_config(&mut self, config: Config) -> Result<()> {
+        match &mut self.0 {
+            RepositoryInner::Local(repo) => repo.set_config(config),
+            RepositoryInner::Sql(repo) => repo.set_config(config),
+            RepositoryInner::Memory(repo) => repo.set_config(config),
+            RepositoryInner::Remote(_) => bail!("Configuration must be changed on the server"),
         }
     }
 
@@ -82,7 +612,8 @@ impl Repository {
         Ok(match &self.0 {
             RepositoryInner::Local(repo) => repo.accounts(),
             RepositoryInner::Sql(repo) => repo.accounts()?,
-            RepositoryInner::Remote(repo) => repo.lock().unwrap().accounts(),
+            RepositoryInner::Memory(repo) => repo.accounts(),
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().accounts()?,
         })
     }
 
@@ -92,19 +623,432 @@ impl Repository {
                 .account(id)
                 .ok_or_else(|| eyre::eyre!("No such account"))?,
             RepositoryInner::Sql(repo) => repo.account(id)?,
+            RepositoryInner::Memory(repo) => repo
+                .account(id)
+                .ok_or_else(|| eyre::eyre!("No such account"))?,
             RepositoryInner::Remote(repo) => repo
                 .lock()
                 .unwrap()
-                .account(id)
+                .account(id)?
                 .ok_or_else(|| eyre::eyre!("No such account"))?,
         })
     }
 
+    /// Every account as it was at `at`, balances and all
+    pub fn accounts_at(&self, at: &Point) -> Result<Vec<Account>> {
+        let accounts = match &self.0 {
+            RepositoryInner::Local(repo) => repo.accounts_at(at)?,
+            RepositoryInner::Sql(repo) => repo.accounts_at(at)?,
+            RepositoryInner::Memory(repo) => repo.accounts_at(at)?,
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().accounts_at(at)?,
+        };
+        // Tombstones are kept in the past as they are now
+        Ok(accounts.into_iter().filter(|x| !x.deleted).collect())
+    }
+
+    /// Every command that touched `id`, oldest first, whether it changed its balance or anything else about it
+    pub fn account_log(&self, id: Id<Account>) -> Result<Vec<LogEntry>> {
+        match &self.0 {
+            RepositoryInner::Local(repo) => repo.account_log(id),
+            RepositoryInner::Sql(repo) => repo.account_log(id),
+            RepositoryInner::Memory(repo) => repo.account_log(id),
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().account_log(id),
+        }
+    }
+
     pub fn transactions(&self, id: Id<Account>) -> Result<Vec<Transaction>> {
+        self.transactions_page(id, Page::default())
+    }
+
+    /// The transactions involving `id`, oldest first, cut down to `page`
+    pub fn transactions_page(
+        &self,
+        id: Id<Account>,
+        page: Page<Transaction>,
+    ) -> Result<Vec<Transaction>> {
+        self.transactions_filtered(id, Filter::default(), page)
+    }
+
+    /// The transactions involving `id` that `filter` includes, oldest first, cut down to `page`
+    pub fn transactions_filtered(
+        &self,
+        id: Id<Account>,
+        filter: Filter,
+        page: Page<Transaction>,
+    ) -> Result<Vec<Transaction>> {
+        match &self.0 {
+            RepositoryInner::Local(repo) => repo.transactions(id, page, filter),
+            RepositoryInner::Sql(repo) => repo.transactions(id, page, filter),
+            RepositoryInner::Memory(repo) => repo.transactions(id, page, filter),
+            RepositoryInner::Remote(repo) => {
+                let mut transactions = repo.lock().unwrap().transactions(id)?;
+                transactions.retain(|x| filter.includes(x));
+                transactions.sort_unstable_by_key(|x| x.id);
+                Ok(page.apply(transactions, |x| x.id))
+            }
+        }
+    }
+
+    /// How many transactions each account has, and when it was last used
+    pub fn activity(&self) -> Result<BTreeMap<Id<Account>, Activity>> {
         match &self.0 {
-            RepositoryInner::Local(repo) => repo.transactions(id),
-            RepositoryInner::Sql(repo) => repo.transactions(id),
-            RepositoryInner::Remote(repo) => repo.lock().unwrap().transactions(id),
+            RepositoryInner::Local(_) | RepositoryInner::Memory(_) => self
+                .accounts()?
+                .into_iter()
+                .map(|acc| Ok((acc.id, Activity::of(&self.transactions(acc.id)?))))
+                .collect(),
+            RepositoryInner::Sql(repo) => repo.activity(),
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().activity(),
+        }
+    }
+
+    /// The balance of `id` at the end of each day it changed, oldest first
+    pub fn balance_history(&self, id: Id<Account>) -> Result<Vec<(chrono::NaiveDate, Amounts)>> {
+        match &self.0 {
+            // Kept up to date as transactions are added, so it needn't go through them all
+            RepositoryInner::Sql(repo) => repo.balance_history(id),
+            _ => {
+                let mut transactions = self.transactions(id)?;
+                transactions.sort_by_key(|x| (x.date(), x.id));
+                let mut balance = Amounts::default();
+                let mut history = Vec::<(chrono::NaiveDate, Amounts)>::new();
+                for transaction in transactions {
+                    let results = transaction.results()?;
+                    // Those it's only involved in as whose money a move was, or in a remap, don't change it
+                    if !results.iter().any(|(acc, _)| *acc == id) {
+                        continue;
+                    }
+                    for (acc, amount) in results {
+                        if acc == id {
+                            balance.add_amount(amount)?;
+                        }
+                    }
+                    match history.last_mut() {
+                        Some((day, balances)) if *day == transaction.date() => {
+                            *balances = balance.clone()
+                        }
+                        _ => history.push((transaction.date(), balance.clone())),
+                    }
+                }
+                Ok(history)
+            }
+        }
+    }
+
+    /// Counts, dates, and totals across the whole repository
+    pub fn stats(&self) -> Result<Stats> {
+        let (backend, (transactions, range)) = match &self.0 {
+            RepositoryInner::Local(_) | RepositoryInner::Memory(_) => {
+                let all = self.all_transactions()?;
+                let range = all.first().zip(all.last()).map(|(x, y)| x.id..=y.id);
+                let backend = match self.0 {
+                    RepositoryInner::Memory(_) => "memory",
+                    _ => "local",
+                };
+                (backend, (all.len(), range))
+            }
+            RepositoryInner::Sql(repo) => ("sqlite", repo.transaction_range()?),
+            // As the server has it, including how it's stored there
+            RepositoryInner::Remote(repo) => return repo.lock().unwrap().stats(),
+        };
+        let accounts = self.accounts()?;
+        let count = |typ| accounts.iter().filter(|x| x.typ == typ).count();
+        Ok(Stats {
+            physical_accounts: count(AccountType::Physical),
+            virtual_accounts: count(AccountType::Virtual),
+            disabled_accounts: accounts.iter().filter(|x| !x.enabled).count(),
+            transactions,
+            first_transaction: range.as_ref().map(|x| x.start().0.datetime().into()),
+            last_transaction: range.as_ref().map(|x| x.end().0.datetime().into()),
+            totals: accounts
+                .iter()
+                .filter(|x| x.typ == AccountType::Physical)
+                .try_fold(Amounts::default(), |total, x| total + x.current.clone())?,
+            backend: backend.to_owned(),
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+        })
+    }
+
+    /// Every entity of a [`Stored`] kind
+    pub fn entities<T: Stored>(&self) -> Result<Vec<T>> {
+        match &self.0 {
+            RepositoryInner::Local(repo) => repo.entities(),
+            RepositoryInner::Sql(repo) => repo.entities(),
+            RepositoryInner::Memory(repo) => repo.entities(),
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().entities(),
+        }
+    }
+
+    /// Every schedule, by name
+    pub fn schedules(&self) -> Result<Vec<ScheduledTransaction>> {
+        let mut schedules = self.entities::<ScheduledTransaction>()?;
+        schedules.sort_by(|x, y| (&x.name, x.id).cmp(&(&y.name, y.id)));
+        Ok(schedules)
+    }
+
+    /// Move schedules kept in the configuration, as they once were, out to be kept on their own, all together
+    ///
+    /// Each carries on from the last day it was added for, told by the IDs it gave transactions then: the start of the
+    /// day, and a hash of its name.
+    fn move_schedules(&mut self) -> Result<()> {
+        // A server moves its own
+        if let RepositoryInner::Remote(_) = self.0 {
+            return Ok(());
+        }
+        let mut config = self.config()?;
+        if config.legacy_schedules.is_empty() {
+            return Ok(());
+        }
+        let schedules = std::mem::take(&mut config.legacy_schedules);
+        let transactions = self.all_transactions()?;
+        self.atomically(|repo| {
+            for (name, schedule) in schedules {
+                let key = u128::from(fnv(&name));
+                let last = transactions
+                    .iter()
+                    .filter(|x| x.id.0.random() == key)
+                    .filter_map(|x| x.date)
+                    .max();
+                let LegacySchedule {
+                    every,
+                    start,
+                    notes,
+                    amount,
+                    inner,
+                    debts,
+                } = schedule;
+                let schedule = ScheduledTransaction {
+                    id: Id::generate(),
+                    name,
+                    every,
+                    start,
+                    last,
+                    notes,
+                    amount,
+                    inner,
+                    debts,
+                };
+                repo.run_command(Command::SetSchedule(schedule))?;
+            }
+            repo.set_config(config)
+        })
+    }
+
+    /// Every transaction, across all accounts, oldest first
+    pub fn all_transactions(&self) -> Result<Vec<Transaction>> {
+        let mut transactions = BTreeMap::new();
+        for account in self.accounts()? {
+            for transaction in self.transactions(account.id)? {
+                transactions.insert(transaction.id, transaction);
+            }
+        }
+        Ok(transactions.into_values().collect())
+    }
+
+    /// The transaction with ID `id`, wherever it is
+    pub fn transaction(&self, id: Id<Transaction>) -> Result<Transaction> {
+        self.all_transactions()?
+            .into_iter()
+            .find(|x| x.id == id)
+            .ok_or_else(|| eyre::eyre!("No transaction {id}"))
+    }
+
+    /// Find accounts and transactions whose notes match `query`, best matches first
+    pub fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        match &self.0 {
+            RepositoryInner::Local(repo) => repo.search(query),
+            RepositoryInner::Sql(repo) => repo.search(query),
+            RepositoryInner::Memory(repo) => repo.search(query),
+            RepositoryInner::Remote(repo) => repo.lock().unwrap().search(query),
+        }
+    }
+}
+
+/// Record that this process is serving the repository at `addr` from `address`, until the guard given is dropped
+///
+/// Only SQLite repositories can be opened by other processes while served, so only they are marked.
+fn serving(addr: &OsStr, address: &str) -> Result<Option<sql::Serving>> {
+    match addr.to_str().and_then(|x| x.strip_prefix("sqlite:")) {
+        Some(path) => SqlRepository::serve(path, address),
+        None => Ok(None),
+    }
+}
+
+/// A hash of `s` that's the same wherever and whenever it's taken, unlike the standard library's
+fn fnv(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// The terms of `query`, leaving out those with no words in them, which can't match anything
+fn search_terms(query: &str) -> Vec<&str> {
+    query
+        .split_whitespace()
+        .filter(|x| !words(x).is_empty())
+        .collect()
+}
+
+/// The words in `s`, ignoring case, as SQLite's full-text search splits it
+fn words(s: &str) -> Vec<String> {
+    s.split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Those of `accounts` and `transactions` whose notes have every term of `query` in them as whole words, accounts
+/// first and then those with the most matches
+///
+/// A term of several words, like `don't`, must have them in a row. Each backend searches this way, however it narrows
+/// down what it checks.
+fn search_notes(
+    query: &str,
+    accounts: impl IntoIterator<Item = Account>,
+    transactions: impl IntoIterator<Item = Transaction>,
+) -> Vec<SearchResult> {
+    let terms = search_terms(query)
+        .into_iter()
+        .map(words)
+        .collect::<Vec<_>>();
+    let score = |notes: &str| {
+        if terms.is_empty() {
+            return None;
+        }
+        let notes = words(notes);
+        let counts = terms
+            .iter()
+            .map(|term| notes.windows(term.len()).filter(|x| x == term).count())
+            .collect::<Vec<_>>();
+        (!counts.contains(&0)).then(|| counts.into_iter().sum::<usize>())
+    };
+    let accounts = accounts
+        .into_iter()
+        .filter_map(|acc| Some((score(&acc.notes)?, SearchResult::Account(acc))));
+    let transactions = transactions
+        .into_iter()
+        .filter_map(|t| Some((score(&t.all_notes())?, SearchResult::Transaction(t))));
+    let mut results = accounts.chain(transactions).collect::<Vec<_>>();
+    // Stable, so accounts stay ahead of transactions that score the same
+    results.sort_by_key(|(score, _)| std::cmp::Reverse(*score));
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+/// Check `cmd` against the accounts and transactions given, giving the change it would make to each account's balance
+fn check(
+    cmd: &Command,
+    mut account: impl FnMut(Id<Account>) -> Result<Account>,
+    mut transactions: impl FnMut(Id<Account>) -> Result<Vec<Transaction>>,
+    transaction: impl FnOnce(Id<Transaction>) -> Result<Transaction>,
+) -> Result<Deltas> {
+    match cmd {
+        Command::CreateAccount(acc) => {
+            let id = acc.id;
+            ensure!(
+                account(id).is_err(),
+                "Cannot overwrite account with duplicate id {id}"
+            );
+            acc.check_institution()?;
+            Ok(vec![])
+        }
+        Command::UpdateAccount(id, changes) => {
+            let account = account(*id)?;
+            if changes
+                .iter()
+                .any(|x| matches!(x, AccountModification::ChangeType(_)))
+            {
+                ensure!(
+                    transactions(*id)?.is_empty(),
+                    "Cannot change the type of an account with transactions"
+                );
+            }
+            if changes
+                .iter()
+                .any(|x| matches!(x, AccountModification::Delete))
+            {
+                ensure!(
+                    account.current.is_zero() && transactions(*id)?.is_empty(),
+                    "Only accounts with nothing in them and no transactions can be deleted"
+                );
+            }
+            let mut changed = account;
+            for change in changes.iter().cloned() {
+                change.apply(&mut changed);
+            }
+            changed.check_institution()?;
+            Ok(vec![])
+        }
+        Command::AddTransaction(transaction) | Command::AddScheduled { transaction, .. } => {
+            transaction.validate()?;
+            transaction.check_account_types(|id| {
+                let account = account(id)?;
+                Ok((account.name, account.typ))
+            })?;
+            let deltas = cmd.deltas()?;
+            for (id, delta) in &deltas {
+                ensure!(
+                    !(account(*id)?.current + delta.clone())?.is_negative(),
+                    "Account balance must never be below 0 in any currency"
+                );
+            }
+            Ok(deltas)
+        }
+        Command::UpdateTransaction(id, changes) => {
+            let old = transaction(*id)?;
+            let new = edit(&old, changes)?;
+            new.check_account_types(|id| {
+                let account = account(id)?;
+                Ok((account.name, account.typ))
+            })?;
+            let deltas = edit_deltas(&old, &new)?;
+            for (id, delta) in &deltas {
+                ensure!(
+                    !(account(*id)?.current + delta.clone())?.is_negative(),
+                    "Account balance must never be below 0 in any currency"
+                );
+            }
+            Ok(deltas)
+        }
+        Command::VoidTransaction { id, reversal } => {
+            let (_, reversal) = void(&transaction(*id)?, *reversal)?;
+            let deltas = Command::AddTransaction(reversal).deltas()?;
+            for (id, delta) in &deltas {
+                ensure!(
+                    !(account(*id)?.current + delta.clone())?.is_negative(),
+                    "Account balance must never be below 0 in any currency"
+                );
+            }
+            Ok(deltas)
+        }
+        Command::RenamePayees { from, to } => {
+            ensure!(!to.trim().is_empty(), "Payees can't be renamed to nothing");
+            ensure!(!from.is_empty(), "There are no payees to rename");
+            Ok(vec![])
+        }
+        Command::SetSchedule(schedule) => {
+            ensure!(
+                !schedule.name.trim().is_empty(),
+                "Schedules must have a name"
+            );
+            let transaction = schedule.transaction(schedule.start);
+            transaction.validate()?;
+            transaction.check_account_types(|id| {
+                let account = account(id)?;
+                Ok((account.name, account.typ))
+            })?;
+            Ok(vec![])
+        }
+        Command::SetTemplate { name, template } => {
+            ensure!(!name.trim().is_empty(), "Templates must have a name");
+            // Its accounts are all that can be checked without an amount
+            template
+                .transaction(Amount(0, Currency::EUR))
+                .check_account_types(|id| {
+                    let account = account(id)?;
+                    Ok((account.name, account.typ))
+                })?;
+            Ok(vec![])
         }
+        Command::RemoveSchedule(_) | Command::RemoveTemplate(_) => Ok(vec![]),
     }
 }
@@ -1,16 +1,19 @@
-use std::{collections::BTreeMap, fmt::Debug, fs, io::Write, path::PathBuf, process};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt::Debug,
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+    process,
+};
 
 use eyre::{ensure, eyre, Context, Result};
 use itertools::Itertools;
-use serde::{de::DeserializeOwned, Serialize};
 use tracing::{debug, instrument};
 
+use super::{Entity, Filter, Page, Point, Stored};
 use crate::{command::*, types::*};
 
-pub trait Entity: DeserializeOwned + Serialize + Debug {
-    const PATH: &'static str;
-    fn id(&self) -> Id<Self>;
-}
 impl Entity for Account {
     const PATH: &'static str = "accounts";
     fn id(&self) -> Id<Self> {
@@ -22,6 +25,13 @@ impl Entity for Transaction {
     fn id(&self) -> Id<Self> {
         self.id
     }
+    fn shard(id: Id<Self>) -> Option<String> {
+        Some(
+            chrono::DateTime::<chrono::Utc>::from(id.0.datetime())
+                .format("%Y/%m")
+                .to_string(),
+        )
+    }
 }
 
 #[instrument]
@@ -56,7 +66,7 @@ macro_rules! git {
 }
 
 #[derive(Debug)]
-struct LockFile(fs::File, PathBuf);
+struct LockFile(#[allow(dead_code)] fs::File, PathBuf);
 
 impl LockFile {
     fn acquire(path: PathBuf) -> Result<Self> {
@@ -80,16 +90,28 @@ impl Drop for LockFile {
     }
 }
 
+/// The version of the layout on disk this build writes, kept in `format-version`; older repositories are upgraded to it
+/// when opened, and newer ones refused
+///
+/// 0. Before versioning
+/// 1. Transactions sharded by month
+const FORMAT_VERSION: u32 = 1;
+
 #[derive(Debug)]
 pub(super) struct LocalRepository {
     path: PathBuf,
     _lock: LockFile,
     accounts: BTreeMap<Id<Account>, Account>,
+    /// The commit to return to if the current batch of commands is rolled back
+    checkpoint: Option<String>,
+    /// Files written since the last commit, to add to the next
+    staged: BTreeSet<PathBuf>,
 }
 
 impl LocalRepository {
+    /// Make a new repository at `path`, starting with `accounts` and `config`
     #[instrument]
-    pub(super) fn init(path: PathBuf) -> Result<Self> {
+    pub(super) fn init(path: PathBuf, accounts: Vec<Account>, config: Config) -> Result<Self> {
         if path.try_exists()? {
             ensure!(
                 path.read_dir()?.next().is_none(),
@@ -99,6 +121,7 @@ impl LocalRepository {
             fs::create_dir_all(&path)?;
         }
         fs::write(path.join(".gitignore"), "monfari-repo-lock\n")?;
+        fs::write(path.join("format-version"), format!("{FORMAT_VERSION}\n"))?;
 
         for dir in ["transactions", "accounts"] {
             let p = path.join(dir);
@@ -107,22 +130,25 @@ impl LocalRepository {
         }
 
         git!(in &path, "init")?;
-        git!(in &path, "add", "transactions", "accounts", ".gitignore")?;
+        git!(in &path, "add", "transactions", "accounts", ".gitignore", "format-version")?;
 
         let lock = LockFile::acquire(path.join("monfari-repo-lock"))?;
         let mut this = Self {
             path,
             _lock: lock,
             accounts: Default::default(),
+            checkpoint: None,
+            staged: Default::default(),
         };
-        this.create_account(Account {
-            id: Id::generate(),
-            name: "Default Virtual Account".to_owned(),
-            notes: "A virtual account is required to do much, but many transactions don't really need one, so this is a default to use".to_owned(),
-            typ: AccountType::Virtual,
-            current: Default::default(),
-            enabled: true,
-        })?;
+        for account in accounts {
+            this.create_account(account)?;
+        }
+        if config != Config::default() {
+            let path = this.path.join("config.toml");
+            fs::write(&path, toml::to_string_pretty(&config)?)?;
+            git!(in &this.path, "add", &path)?;
+        }
+        this.stage()?;
 
         git!(in &this.path, "commit", "-m", "Initial Commit")?;
         Ok(this)
@@ -135,31 +161,123 @@ impl LocalRepository {
             .wrap_err("repo is dirty - monfari has crashed previously")?;
         ensure!(path.join("accounts").is_dir(), "Not initialized");
         ensure!(path.join("transactions").is_dir(), "Not initialized");
+        let version = Self::format_version(&path)?;
+        ensure!(
+            version <= FORMAT_VERSION,
+            "This repository is in format version {version}, but this build of monfari only understands up to \
+             {FORMAT_VERSION}; upgrade monfari to open it"
+        );
         let lock = LockFile::acquire(path.join("monfari-repo-lock"))?;
         let mut this = Self {
             path,
             _lock: lock,
             accounts: Default::default(),
+            checkpoint: None,
+            staged: Default::default(),
         };
-        this.accounts = this
+        this.upgrade(version)?;
+        // Every command is a commit, so loose objects pile up quickly; this is a no-op unless there are enough of them
+        git!(in &this.path, "gc", "--auto", "--quiet")?;
+        this.load_accounts()?;
+        Ok(this)
+    }
+
+    /// The version of the layout of the repository at `path`
+    fn format_version(path: &Path) -> Result<u32> {
+        match fs::read_to_string(path.join("format-version")) {
+            Ok(version) => version
+                .trim()
+                .parse()
+                .wrap_err("format-version must be a whole number"),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(0),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Bring the repository from the layout of version `from` up to [`FORMAT_VERSION`], in one commit
+    #[instrument]
+    fn upgrade(&mut self, from: u32) -> Result<()> {
+        if from == FORMAT_VERSION {
+            return Ok(());
+        }
+        for version in from..FORMAT_VERSION {
+            match version {
+                0 => self.migrate_shards::<Transaction>()?,
+                _ => unreachable!("Every version before the current one has an upgrade"),
+            }
+        }
+        let path = self.path.join("format-version");
+        fs::write(&path, format!("{FORMAT_VERSION}\n"))?;
+        git!(in &self.path, "add", &path)?;
+        git!(
+            in &self.path,
+            "commit",
+            "-m",
+            format!("Upgrade repository to format version {FORMAT_VERSION}")
+        )?;
+        Ok(())
+    }
+
+    fn load_accounts(&mut self) -> Result<()> {
+        self.accounts = self
             .list::<Account>()?
             .into_iter()
-            .map(|acc| Ok((acc, this.get(acc)?)))
+            .map(|acc| Ok((acc, self.get::<Account>(acc)?)))
+            // Tombstones stay on disk, but nothing else should see them
+            .filter_ok(|(_, acc)| !acc.deleted)
             .collect::<Result<_>>()?;
-        Ok(this)
+        Ok(())
+    }
+
+    /// Whether a batch has been started and not yet committed or rolled back
+    pub(super) fn in_batch(&self) -> bool {
+        self.checkpoint.is_some()
+    }
+
+    /// Start a batch of commands, which are only kept if it's committed
+    ///
+    /// Each command is still its own commit; rolling back resets to the commit the batch started from.
+    #[instrument]
+    pub(super) fn begin(&mut self) -> Result<()> {
+        self.checkpoint = Some(git!(in &self.path, "rev-parse", "HEAD")?.trim().to_owned());
+        Ok(())
+    }
+
+    #[instrument]
+    pub(super) fn commit(&mut self) -> Result<()> {
+        self.checkpoint = None;
+        Ok(())
+    }
+
+    #[instrument]
+    pub(super) fn rollback(&mut self) -> Result<()> {
+        let checkpoint = self
+            .checkpoint
+            .take()
+            .ok_or_else(|| eyre!("No batch to roll back"))?;
+        self.discard()?;
+        // Only the files the batch's commits changed are put back, and none that have been changed since
+        git!(in &self.path, "reset", "--keep", "--quiet", checkpoint)?;
+        self.load_accounts()
     }
 }
 
 impl LocalRepository {
     fn path_for<T: Entity>(&self, id: Id<T>) -> PathBuf {
-        self.path.join(format!("{}/{id}.toml", T::PATH))
+        let dir = self.path.join(T::PATH);
+        match T::shard(id) {
+            Some(shard) => dir.join(shard),
+            None => dir,
+        }
+        .join(format!("{}.toml", id.proquint()))
     }
 
     #[instrument]
     fn create<T: Entity>(&mut self, value: &T) -> Result<()> {
         let path = self.path_for(value.id());
+        fs::create_dir_all(path.parent().expect("Entities are always in a directory"))?;
         fs::write(&path, toml::to_string_pretty(&value)?)?;
-        git!(in &self.path, "add", &path)?;
+        self.staged.insert(path);
         Ok(())
     }
 
@@ -177,22 +295,119 @@ impl LocalRepository {
         f(value)?;
         assert!(value.id == id);
         fs::write(&path, toml::to_string_pretty(&value)?)?;
-        git!(in &self.path, "add", &path)?;
+        self.staged.insert(path);
         Ok(())
     }
 }
 
 impl LocalRepository {
+    /// Add every file written since the last commit, in as few runs of git as the command line allows
+    fn stage(&mut self) -> Result<()> {
+        let staged = std::mem::take(&mut self.staged).into_iter().collect_vec();
+        for paths in staged.chunks(1000) {
+            cmd(process::Command::new("git")
+                .arg("-C")
+                .arg(&self.path)
+                .args(["add", "--"])
+                .args(paths))?;
+        }
+        Ok(())
+    }
+
+    /// Put the files written since the last commit back as they were then, leaving everything else alone
+    fn discard(&mut self) -> Result<()> {
+        let written = std::mem::take(&mut self.staged).into_iter().collect_vec();
+        for paths in written.chunks(1000) {
+            let git = || {
+                let mut git = process::Command::new("git");
+                git.arg("-C").arg(&self.path);
+                git
+            };
+            let tracked = cmd(git().args(["ls-files", "-z", "--"]).args(paths))?
+                .split('\0')
+                .filter(|x| !x.is_empty())
+                .map(|x| self.path.join(x))
+                .collect::<BTreeSet<_>>();
+            if !tracked.is_empty() {
+                cmd(git().args(["checkout", "HEAD", "--"]).args(&tracked))?;
+            }
+            // Those that weren't there before are new, so go altogether
+            for path in paths.iter().filter(|x| !tracked.contains(*x)) {
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != std::io::ErrorKind::NotFound => return Err(e.into()),
+                    _ => {}
+                }
+            }
+        }
+        self.load_accounts()
+    }
+
+    /// Commit everything written for the command described by `description`, marked with `origin`
+    fn commit_command(&mut self, description: String, origin: Origin) -> Result<()> {
+        let mut message = format!("{description}\n");
+        // git records the time itself, so only the origin needs to go in the message
+        if let Some(user) = origin.user {
+            message += &format!("\nUser: {user}");
+        }
+        if let Some(client) = origin.client {
+            message += &format!("\nClient: {client}");
+        }
+        self.stage()?;
+        // Even a command that changes nothing, like renaming an account to its own name, is kept in the history
+        git!(in &self.path, "commit", "--allow-empty", "-m", message)?;
+        Ok(())
+    }
+
     #[instrument]
     fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        transaction.check_account_types(|id| {
+            let account = self
+                .accounts
+                .get(&id)
+                .ok_or_else(|| eyre!("No such account"))?;
+            Ok((account.name.clone(), account.typ))
+        })?;
         self.create(&transaction)?;
-        for (acc, amounts) in &transaction.results().into_iter().group_by(|x| x.0) {
+        for (acc, amounts) in &transaction.results()?.into_iter().group_by(|x| x.0) {
             self.modify(acc, |acc| {
                 for amount in amounts {
-                    acc.current += amount.1;
+                    acc.current.add_amount(amount.1)?;
+                }
+                ensure!(
+                    !acc.current.is_negative(),
+                    "Account balance must never be below 0 in any currency"
+                );
+                Ok(())
+            })?;
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    fn update_transaction(
+        &mut self,
+        id: Id<Transaction>,
+        changes: &[TransactionModification],
+    ) -> Result<()> {
+        let old = self
+            .get::<Transaction>(id)
+            .wrap_err_with(|| format!("No transaction {id}"))?;
+        let new = edit(&old, changes)?;
+        new.check_account_types(|id| {
+            let account = self
+                .accounts
+                .get(&id)
+                .ok_or_else(|| eyre!("No such account"))?;
+            Ok((account.name.clone(), account.typ))
+        })?;
+        self.create(&new)?;
+        for (acc, delta) in edit_deltas(&old, &new)? {
+            self.modify(acc, |acc| {
+                for amount in delta.0.into_values() {
+                    acc.current.add_amount(amount)?;
                 }
                 ensure!(
-                    acc.current.0.values().all(|x| x.0 >= 0),
+                    !acc.current.is_negative(),
                     "Account balance must never be below 0 in any currency"
                 );
                 Ok(())
@@ -201,8 +416,57 @@ impl LocalRepository {
         Ok(())
     }
 
+    #[instrument]
+    fn void_transaction(&mut self, id: Id<Transaction>, reversal: Id<Transaction>) -> Result<()> {
+        let transaction = self
+            .get::<Transaction>(id)
+            .wrap_err_with(|| format!("No transaction {id}"))?;
+        let (voided, reversal) = void(&transaction, reversal)?;
+        self.create(&voided)?;
+        self.add_transaction(reversal)
+    }
+
+    #[instrument]
+    fn rename_payees(&mut self, from: &[String], to: &str) -> Result<()> {
+        let mut transactions = self.select(Page::default(), |x: &Transaction| {
+            x.payee()
+                .is_some_and(|payee| from.iter().any(|x| x == payee))
+        })?;
+        for transaction in &mut transactions {
+            if let Some(payee) = transaction.payee_mut() {
+                *payee = to.to_owned();
+            }
+            self.create(transaction)?;
+        }
+        Ok(())
+    }
+
+    #[instrument]
+    fn add_scheduled(
+        &mut self,
+        id: Id<ScheduledTransaction>,
+        transaction: Transaction,
+    ) -> Result<()> {
+        let mut schedule = self.entity(id)?;
+        schedule.advance(transaction.date)?;
+        self.create(&schedule)?;
+        self.add_transaction(transaction)
+    }
+
+    /// Save or remove a template as `cmd` does, in the configuration, so it's committed with the command
+    #[instrument]
+    fn update_templates(&mut self, cmd: &Command) -> Result<()> {
+        let mut config = self.config()?;
+        cmd.apply_template(&mut config.transaction_templates)?;
+        let path = self.path.join("config.toml");
+        fs::write(&path, toml::to_string_pretty(&config)?)?;
+        self.staged.insert(path);
+        Ok(())
+    }
+
     #[instrument]
     fn create_account(&mut self, account: Account) -> Result<()> {
+        account.check_institution()?;
         self.create(&account)?;
         let id = account.id;
         ensure!(
@@ -214,53 +478,203 @@ impl LocalRepository {
 
     #[instrument]
     fn modify_account(&mut self, id: Id<Account>, changes: Vec<AccountModification>) -> Result<()> {
+        if changes
+            .iter()
+            .any(|x| matches!(x, AccountModification::ChangeType(_)))
+        {
+            ensure!(
+                self.transactions(
+                    id,
+                    Page {
+                        limit: Some(1),
+                        ..Page::default()
+                    },
+                    Filter::default()
+                )?
+                .is_empty(),
+                "Cannot change the type of an account with transactions"
+            );
+        }
+        if changes
+            .iter()
+            .any(|x| matches!(x, AccountModification::Delete))
+        {
+            ensure!(
+                self.account(id).is_some_and(|x| x.current.is_zero())
+                    && self
+                        .transactions(
+                            id,
+                            Page {
+                                limit: Some(1),
+                                ..Page::default()
+                            },
+                            Filter::default()
+                        )?
+                        .is_empty(),
+                "Only accounts with nothing in them and no transactions can be deleted"
+            );
+        }
         self.modify(id, |account| {
+            let mut changed = account.clone();
             for change in changes {
-                match change {
-                    AccountModification::Disable => {
-                        account.enabled = false;
-                    }
-                    AccountModification::UpdateName(name) => {
-                        account.name = name;
-                    }
-                    AccountModification::UpdateNotes(notes) => {
-                        account.notes = notes;
-                    }
-                }
+                change.apply(&mut changed);
             }
+            changed.check_institution()?;
+            *account = changed;
             Ok(())
         })?;
+        if self.accounts[&id].deleted {
+            self.accounts.remove(&id);
+        }
         Ok(())
     }
 
     #[instrument]
     fn list<T: Entity>(&self) -> Result<Vec<Id<T>>> {
-        self.path
-            .join(T::PATH)
-            .read_dir()?
-            .filter_map_ok(|entry| entry.file_name().into_string().ok())
-            .filter_map_ok(|filename| Some(filename.strip_suffix(".toml")?.to_owned()))
-            .map(|x| x?.parse::<Id<T>>().map_err(|e| eyre!("{e}")))
+        fn walk(dir: &Path, files: &mut Vec<String>) -> Result<()> {
+            for entry in dir.read_dir()? {
+                let entry = entry?;
+                if entry.file_type()?.is_dir() {
+                    walk(&entry.path(), files)?;
+                } else if let Ok(filename) = entry.file_name().into_string() {
+                    files.push(filename);
+                }
+            }
+            Ok(())
+        }
+        let dir = self.path.join(T::PATH);
+        // Kinds kept since after the repository was made have no directory until one is stored
+        if !dir.try_exists()? {
+            return Ok(vec![]);
+        }
+        let mut files = vec![];
+        walk(&dir, &mut files)?;
+        files
+            .into_iter()
+            .filter_map(|filename| Some(filename.strip_suffix(".toml")?.to_owned()))
+            .map(|x| x.parse::<Id<T>>().map_err(|e| eyre!("{e}")))
+            .collect()
+    }
+
+    /// The entities of type `T` that pass `filter`, in ID order, cut down to `page`
+    fn select<T: Entity>(&self, page: Page<T>, filter: impl Fn(&T) -> bool) -> Result<Vec<T>> {
+        let mut ids = self.list::<T>()?;
+        ids.sort_unstable();
+        ids.into_iter()
+            .filter(|&id| page.after.is_none_or(|after| id > after))
+            .filter(|&id| page.before.is_none_or(|before| id < before))
+            .map(|id| self.get(id))
+            .filter_ok(|x| filter(x))
+            .take(page.limit.unwrap_or(usize::MAX))
             .collect()
     }
 
+    /// Move entities stored before sharding was introduced into their shard directories
+    #[instrument]
+    fn migrate_shards<T: Entity>(&mut self) -> Result<()> {
+        for entry in self.path.join(T::PATH).read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_dir() {
+                continue;
+            }
+            let Some(id) = entry
+                .file_name()
+                .to_str()
+                .and_then(|x| x.strip_suffix(".toml"))
+                .and_then(|x| x.parse::<Id<T>>().ok())
+            else {
+                continue;
+            };
+            let path = self.path_for(id);
+            if path == entry.path() {
+                continue;
+            }
+            fs::create_dir_all(path.parent().expect("Entities are always in a directory"))?;
+            git!(in &self.path, "mv", entry.path(), &path)?;
+        }
+        Ok(())
+    }
+
     #[instrument(ret)]
     fn get<T: Entity>(&self, id: Id<T>) -> Result<T> {
         Ok(toml::from_str(&fs::read_to_string(self.path_for(id))?)?)
     }
+
+    #[instrument]
+    fn entity<T: Stored>(&self, id: Id<T>) -> Result<T> {
+        self.get(id)
+            .wrap_err_with(|| format!("No {} {id}", T::NAME))
+    }
+
+    #[instrument]
+    fn remove<T: Stored>(&mut self, id: Id<T>) -> Result<()> {
+        let path = self.path_for(id);
+        ensure!(path.try_exists()?, "No {} {id}", T::NAME);
+        fs::remove_file(&path)?;
+        // Staging a file that's gone stages its removal
+        self.staged.insert(path);
+        Ok(())
+    }
 }
 
 impl LocalRepository {
     #[instrument]
-    pub(super) fn run_command(&mut self, cmd: Command) -> Result<()> {
-        let message = format!("{cmd}");
-        match cmd {
-            Command::CreateAccount(account) => self.create_account(account)?,
-            Command::UpdateAccount(id, f) => self.modify_account(id, f)?,
-            Command::AddTransaction(transaction) => self.add_transaction(transaction)?,
+    pub(super) fn run_command(&mut self, cmd: Command, origin: Origin) -> Result<()> {
+        let description = cmd.to_string();
+        let result = match cmd {
+            Command::CreateAccount(account) => self.create_account(account),
+            Command::UpdateAccount(id, f) => self.modify_account(id, f),
+            Command::AddTransaction(transaction) => self.add_transaction(transaction),
+            Command::UpdateTransaction(id, changes) => self.update_transaction(id, &changes),
+            Command::VoidTransaction { id, reversal } => self.void_transaction(id, reversal),
+            Command::RenamePayees { from, to } => self.rename_payees(&from, &to),
+            Command::SetSchedule(schedule) => self.create(&schedule),
+            Command::RemoveSchedule(id) => self.remove(id),
+            Command::AddScheduled {
+                schedule,
+                transaction,
+            } => self.add_scheduled(schedule, transaction),
+            cmd @ (Command::SetTemplate { .. } | Command::RemoveTemplate(_)) => {
+                self.update_templates(&cmd)
+            }
+        };
+        if result.is_err() {
+            // Undo whatever it got as far as changing; every command before it is committed
+            self.discard()?;
         }
+        result?;
+        self.commit_command(description, origin)
+    }
 
-        git!(in &self.path, "commit", "-m", message)?;
+    /// Add all of `transactions` in one commit, or none of them
+    #[instrument(skip(transactions))]
+    pub(super) fn add_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+        origin: Origin,
+    ) -> Result<()> {
+        let count = transactions.len();
+        let result = transactions
+            .into_iter()
+            .try_for_each(|transaction| self.add_transaction(transaction));
+        if result.is_err() {
+            self.discard()?;
+        }
+        result?;
+        self.commit_command(format!("Add {count} transactions"), origin)
+    }
+
+    /// Bundle the history of the repository at `path` into `dest`, without needing the lock
+    #[instrument]
+    pub(super) fn snapshot(path: &Path, dest: &Path) -> Result<()> {
+        git!(in path, "bundle", "create", dest, "--all")?;
+        Ok(())
+    }
+
+    #[instrument]
+    pub(super) fn maintenance(&mut self) -> Result<()> {
+        git!(in &self.path, "gc", "--quiet")?;
+        git!(in &self.path, "pack-refs", "--all")?;
         Ok(())
     }
 
@@ -269,22 +683,119 @@ impl LocalRepository {
         self.accounts.values().cloned().collect()
     }
 
+    /// Every currency an account has held
+    pub(super) fn currencies(&self) -> BTreeSet<Currency> {
+        (self.accounts.values())
+            .flat_map(|x| x.current.0.keys().copied())
+            .collect()
+    }
+
     #[instrument]
     pub(super) fn account(&self, id: Id<Account>) -> Option<Account> {
         self.accounts.get(&id).cloned()
     }
 
+    /// The commits that changed the file of `id`, which every command that touches it does
     #[instrument]
-    pub(super) fn transactions(&self, id: Id<Account>) -> Result<Vec<Transaction>> {
+    pub(super) fn account_log(&self, id: Id<Account>) -> Result<Vec<LogEntry>> {
         ensure!(self.account(id).is_some(), "No such account {id}");
-        self.list::<Transaction>()?
-            .into_iter()
-            .map(|x| self.get(x))
-            .filter_ok(|x| x.accounts().contains(&id))
-            .collect::<Result<Vec<_>>>()
-            .map(|mut x| {
-                x.sort_unstable_by_key(|t| t.id);
-                x
+        git!(in &self.path, "log", "--reverse", "--format=%H%x00%cI%x00%B%x1e", "--", self.path_for(id))?
+            .split('\x1e')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(|entry| {
+                let [commit, timestamp, message] = entry.splitn(3, '\0').collect_vec()[..] else {
+                    return Err(eyre!("Malformed git log entry {entry:?}"));
+                };
+                // As written by `run_command`
+                let mut origin = Origin::default();
+                let mut lines = message.trim_end().lines().collect_vec();
+                while let Some(line) = lines.last() {
+                    if let Some(user) = line.strip_prefix("User: ") {
+                        origin.user = Some(user.to_owned());
+                    } else if let Some(client) = line.strip_prefix("Client: ") {
+                        origin.client = Some(client.to_owned());
+                    } else {
+                        break;
+                    }
+                    lines.pop();
+                }
+                Ok(LogEntry {
+                    commit: Some(commit.to_owned()),
+                    timestamp: chrono::DateTime::parse_from_rfc3339(timestamp)?.into(),
+                    origin,
+                    description: lines.join("\n").trim_end().to_owned(),
+                })
+            })
+            .collect()
+    }
+
+    /// Every account as it was at `at`, read from the commit that was the latest then
+    #[instrument]
+    pub(super) fn accounts_at(&self, at: &Point) -> Result<Vec<Account>> {
+        let commit = match at.end() {
+            Some(end) => {
+                git!(in &self.path, "rev-list", "-1", format!("--before={}", end.to_rfc3339()), "HEAD")?
+            }
+            None => git!(in &self.path, "rev-parse", "--verify", format!("{at}^{{commit}}"))
+                .wrap_err_with(|| format!("No such commit {at}"))?,
+        };
+        let commit = commit.trim();
+        // From before the repository was made
+        if commit.is_empty() {
+            return Ok(vec![]);
+        }
+        git!(in &self.path, "ls-tree", "-r", "--name-only", commit, Account::PATH)?
+            .lines()
+            .filter(|path| path.ends_with(".toml"))
+            .map(|path| {
+                Ok(toml::from_str(
+                    &git!(in &self.path, "show", format!("{commit}:{path}"))?,
+                )?)
             })
+            .collect()
+    }
+
+    #[instrument]
+    pub(super) fn config(&self) -> Result<Config> {
+        let path = self.path.join("config.toml");
+        if !path.try_exists()? {
+            return Ok(Config::default());
+        }
+        Ok(toml::from_str(&fs::read_to_string(path)?)?)
+    }
+
+    #[instrument]
+    pub(super) fn set_config(&mut self, config: Config) -> Result<()> {
+        let path = self.path.join("config.toml");
+        fs::write(&path, toml::to_string_pretty(&config)?)?;
+        git!(in &self.path, "add", &path)?;
+        git!(in &self.path, "commit", "--allow-empty", "-m", "Update config")?;
+        Ok(())
+    }
+
+    #[instrument]
+    pub(super) fn entities<T: Stored>(&self) -> Result<Vec<T>> {
+        self.select(Page::default(), |_| true)
+    }
+
+    #[instrument]
+    pub(super) fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        Ok(super::search_notes(
+            query,
+            self.accounts.values().cloned(),
+            self.select::<Transaction>(Page::default(), |_| true)?,
+        ))
+    }
+
+    #[instrument]
+    pub(super) fn transactions(
+        &self,
+        id: Id<Account>,
+        page: Page<Transaction>,
+        filter: Filter,
+    ) -> Result<Vec<Transaction>> {
+        ensure!(self.account(id).is_some(), "No such account {id}");
+        self.select(page, |x| x.accounts().contains(&id) && filter.includes(x))
     }
 }
@@ -0,0 +1,442 @@
+//! Repositories kept only in memory, gone once closed, for trying things out and checking the others against
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use eyre::{bail, ensure, eyre, Result};
+use tracing::instrument;
+use ulid::Ulid;
+
+use super::{Filter, Page, Point, Stored};
+use crate::{command::*, types::*};
+
+type Accounts = BTreeMap<Id<Account>, Account>;
+
+/// What a change replaced, to put it back if the command or batch it was part of doesn't go through
+#[derive(Debug)]
+enum Undo {
+    Account(Id<Account>, Option<Account>),
+    Transaction(Id<Transaction>, Option<Transaction>),
+    Entity((&'static str, Ulid), Option<serde_json::Value>),
+    Config(Config),
+    /// A command was logged
+    Logged,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Deleted ones included, as tombstones
+    accounts: Accounts,
+    transactions: BTreeMap<Id<Transaction>, Transaction>,
+    /// Every [`Stored`] entity, as JSON, by kind and ID
+    entities: BTreeMap<(&'static str, Ulid), serde_json::Value>,
+    config: Config,
+    /// Every command run, with the accounts it named, oldest first
+    log: Vec<(Vec<Id<Account>>, LogEntry)>,
+    /// The accounts each command changed, as it left them, to look back at them as of a point
+    history: Vec<(chrono::DateTime<chrono::Utc>, Vec<Account>)>,
+    /// Every change since the last that can no longer be undone, oldest first
+    undo: Vec<Undo>,
+}
+
+#[derive(Debug, Default)]
+pub(super) struct MemoryRepository {
+    state: State,
+    /// Whether a batch has been started and not yet committed or rolled back
+    batch: bool,
+}
+
+impl MemoryRepository {
+    pub(super) fn in_batch(&self) -> bool {
+        self.batch
+    }
+
+    /// Start a batch of commands, which are only kept if it's committed
+    pub(super) fn begin(&mut self) -> Result<()> {
+        self.batch = true;
+        Ok(())
+    }
+
+    pub(super) fn commit(&mut self) -> Result<()> {
+        self.batch = false;
+        self.state.undo.clear();
+        Ok(())
+    }
+
+    pub(super) fn rollback(&mut self) -> Result<()> {
+        ensure!(self.batch, "No batch to roll back");
+        self.batch = false;
+        self.state.undo_to(0);
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn run_command(&mut self, cmd: Command, origin: Origin) -> Result<()> {
+        self.all_or_nothing(|state| state.run_command(cmd, origin))
+    }
+
+    /// Add all of `transactions`, each as its own command, or none of them
+    #[instrument(skip(self, transactions))]
+    pub(super) fn add_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+        origin: Origin,
+    ) -> Result<()> {
+        self.all_or_nothing(|state| {
+            for transaction in transactions {
+                state.run_command(Command::AddTransaction(transaction), origin.clone())?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Make the changes `f` does, or, if it fails part way, none of them
+    fn all_or_nothing(&mut self, f: impl FnOnce(&mut State) -> Result<()>) -> Result<()> {
+        let mark = self.state.undo.len();
+        let result = f(&mut self.state);
+        if result.is_err() {
+            self.state.undo_to(mark);
+        } else if !self.batch {
+            self.state.undo.clear();
+        }
+        result
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn accounts(&self) -> Vec<Account> {
+        self.state
+            .accounts
+            .values()
+            .filter(|x| !x.deleted)
+            .cloned()
+            .collect()
+    }
+
+    /// Every currency an account has held
+    pub(super) fn currencies(&self) -> BTreeSet<Currency> {
+        (self.state.accounts.values())
+            .filter(|x| !x.deleted)
+            .flat_map(|x| x.current.0.keys().copied())
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn account(&self, id: Id<Account>) -> Option<Account> {
+        self.state.account(id).cloned()
+    }
+
+    /// Every command that named `id`
+    #[instrument(skip(self))]
+    pub(super) fn account_log(&self, id: Id<Account>) -> Result<Vec<LogEntry>> {
+        ensure!(self.account(id).is_some(), "No such account {id}");
+        Ok(self
+            .state
+            .log
+            .iter()
+            .filter(|(accounts, _)| accounts.contains(&id))
+            .map(|(_, entry)| entry.clone())
+            .collect())
+    }
+
+    /// Every account as the last command run by the end of `at` left it
+    #[instrument(skip(self))]
+    pub(super) fn accounts_at(&self, at: &Point) -> Result<Vec<Account>> {
+        let Some(end) = at.end() else {
+            bail!("Repositories in memory have no commits to look back at")
+        };
+        let mut accounts = Accounts::new();
+        for (_, changed) in self.state.history.iter().filter(|(x, _)| *x < end) {
+            accounts.extend(changed.iter().map(|x| (x.id, x.clone())));
+        }
+        Ok(accounts.into_values().collect())
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn config(&self) -> Result<Config> {
+        Ok(self.state.config.clone())
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn set_config(&mut self, config: Config) -> Result<()> {
+        self.state.config = config;
+        Ok(())
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn entities<T: Stored>(&self) -> Result<Vec<T>> {
+        self.state
+            .entities
+            .iter()
+            .filter(|((kind, _), _)| *kind == T::PATH)
+            .map(|(_, entity)| Ok(T::deserialize(entity)?))
+            .collect()
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        Ok(super::search_notes(
+            query,
+            self.accounts(),
+            self.state.transactions.values().cloned(),
+        ))
+    }
+
+    #[instrument(skip(self))]
+    pub(super) fn transactions(
+        &self,
+        id: Id<Account>,
+        page: Page<Transaction>,
+        filter: Filter,
+    ) -> Result<Vec<Transaction>> {
+        ensure!(self.account(id).is_some(), "No such account {id}");
+        let transactions = self
+            .state
+            .transactions
+            .values()
+            .filter(|x| x.accounts().contains(&id) && filter.includes(x))
+            .cloned()
+            .collect();
+        Ok(page.apply(transactions, |x| x.id))
+    }
+}
+
+impl State {
+    /// Put back every change since the first `mark`, newest first
+    fn undo_to(&mut self, mark: usize) {
+        fn restore<K: Ord, V>(map: &mut BTreeMap<K, V>, key: K, old: Option<V>) {
+            match old {
+                Some(old) => map.insert(key, old),
+                None => map.remove(&key),
+            };
+        }
+        for undo in self.undo.split_off(mark).into_iter().rev() {
+            match undo {
+                Undo::Account(id, old) => restore(&mut self.accounts, id, old),
+                Undo::Transaction(id, old) => restore(&mut self.transactions, id, old),
+                Undo::Entity(key, old) => restore(&mut self.entities, key, old),
+                Undo::Config(old) => self.config = old,
+                Undo::Logged => {
+                    self.log.pop();
+                    self.history.pop();
+                }
+            }
+        }
+    }
+
+    fn set_account(&mut self, account: Account) {
+        let id = account.id;
+        let old = self.accounts.insert(id, account);
+        self.undo.push(Undo::Account(id, old));
+    }
+
+    fn set_transaction(&mut self, transaction: Transaction) {
+        let id = transaction.id;
+        let old = self.transactions.insert(id, transaction);
+        self.undo.push(Undo::Transaction(id, old));
+    }
+
+    fn put<T: Stored>(&mut self, entity: &T) -> Result<()> {
+        let key = (T::PATH, entity.id().0);
+        let old = self.entities.insert(key, serde_json::to_value(entity)?);
+        self.undo.push(Undo::Entity(key, old));
+        Ok(())
+    }
+
+    fn entity<T: Stored>(&self, id: Id<T>) -> Result<T> {
+        let entity = self
+            .entities
+            .get(&(T::PATH, id.0))
+            .ok_or_else(|| eyre!("No {} {id}", T::NAME))?;
+        Ok(T::deserialize(entity)?)
+    }
+
+    fn remove<T: Stored>(&mut self, id: Id<T>) -> Result<()> {
+        let key = (T::PATH, id.0);
+        let old = (self.entities.remove(&key)).ok_or_else(|| eyre!("No {} {id}", T::NAME))?;
+        self.undo.push(Undo::Entity(key, Some(old)));
+        Ok(())
+    }
+
+    fn account(&self, id: Id<Account>) -> Option<&Account> {
+        self.accounts.get(&id).filter(|x| !x.deleted)
+    }
+
+    fn transaction(&self, id: Id<Transaction>) -> Result<Transaction> {
+        self.transactions
+            .get(&id)
+            .cloned()
+            .ok_or_else(|| eyre!("No transaction {id}"))
+    }
+
+    fn involved(&self, id: Id<Account>) -> bool {
+        self.transactions
+            .values()
+            .any(|x| x.accounts().contains(&id))
+    }
+
+    /// Run `cmd`, logging it and keeping the accounts as it left them
+    fn run_command(&mut self, cmd: Command, origin: Origin) -> Result<()> {
+        let mark = self.undo.len();
+        let accounts = cmd.accounts();
+        let description = cmd.to_string().trim_end().to_owned();
+        match cmd {
+            Command::CreateAccount(account) => self.create_account(account)?,
+            Command::UpdateAccount(id, changes) => self.modify_account(id, changes)?,
+            Command::AddTransaction(transaction) => self.add_transaction(transaction)?,
+            Command::UpdateTransaction(id, changes) => self.update_transaction(id, &changes)?,
+            Command::VoidTransaction { id, reversal } => {
+                let (voided, reversal) = void(&self.transaction(id)?, reversal)?;
+                self.set_transaction(voided);
+                self.add_transaction(reversal)?;
+            }
+            Command::RenamePayees { from, to } => self.rename_payees(&from, &to),
+            Command::SetSchedule(schedule) => self.put(&schedule)?,
+            Command::RemoveSchedule(id) => self.remove(id)?,
+            Command::AddScheduled {
+                schedule,
+                transaction,
+            } => {
+                let mut schedule = self.entity(schedule)?;
+                schedule.advance(transaction.date)?;
+                self.put(&schedule)?;
+                self.add_transaction(transaction)?;
+            }
+            cmd @ (Command::SetTemplate { .. } | Command::RemoveTemplate(_)) => {
+                self.undo.push(Undo::Config(self.config.clone()));
+                cmd.apply_template(&mut self.config.transaction_templates)?
+            }
+        }
+        let timestamp = chrono::Utc::now();
+        let entry = LogEntry {
+            commit: None,
+            timestamp,
+            origin,
+            description,
+        };
+        let changed = (self.undo[mark..].iter())
+            .filter_map(|x| match x {
+                Undo::Account(id, _) => Some(*id),
+                _ => None,
+            })
+            .collect::<BTreeSet<_>>();
+        let changed = changed.into_iter().map(|id| self.accounts[&id].clone());
+        self.history.push((timestamp, changed.collect()));
+        self.log.push((accounts, entry));
+        self.undo.push(Undo::Logged);
+        Ok(())
+    }
+
+    fn create_account(&mut self, account: Account) -> Result<()> {
+        account.check_institution()?;
+        let id = account.id;
+        // Tombstones included, as their IDs are still taken
+        ensure!(
+            !self.accounts.contains_key(&id),
+            "Cannot overwrite account with duplicate id {id}"
+        );
+        self.set_account(account);
+        Ok(())
+    }
+
+    fn modify_account(&mut self, id: Id<Account>, changes: Vec<AccountModification>) -> Result<()> {
+        let account = self
+            .account(id)
+            .ok_or_else(|| eyre!("No such account {id}"))?
+            .clone();
+        if changes
+            .iter()
+            .any(|x| matches!(x, AccountModification::ChangeType(_)))
+        {
+            ensure!(
+                !self.involved(id),
+                "Cannot change the type of an account with transactions"
+            );
+        }
+        if changes
+            .iter()
+            .any(|x| matches!(x, AccountModification::Delete))
+        {
+            ensure!(
+                account.current.is_zero() && !self.involved(id),
+                "Only accounts with nothing in them and no transactions can be deleted"
+            );
+        }
+        let mut changed = account;
+        for change in changes {
+            change.apply(&mut changed);
+        }
+        changed.check_institution()?;
+        self.set_account(changed);
+        Ok(())
+    }
+
+    fn add_transaction(&mut self, transaction: Transaction) -> Result<()> {
+        transaction.check_account_types(|id| {
+            let account = self.account(id).ok_or_else(|| eyre!("No such account"))?;
+            Ok((account.name.clone(), account.typ))
+        })?;
+        let id = transaction.id;
+        ensure!(
+            !self.transactions.contains_key(&id),
+            "Cannot overwrite transaction with duplicate id {id}"
+        );
+        for (id, amount) in transaction.results()? {
+            self.change_balance(id, [amount])?;
+        }
+        self.set_transaction(transaction);
+        Ok(())
+    }
+
+    fn update_transaction(
+        &mut self,
+        id: Id<Transaction>,
+        changes: &[TransactionModification],
+    ) -> Result<()> {
+        let old = self.transaction(id)?;
+        let new = edit(&old, changes)?;
+        new.check_account_types(|id| {
+            let account = self.account(id).ok_or_else(|| eyre!("No such account"))?;
+            Ok((account.name.clone(), account.typ))
+        })?;
+        for (acc, delta) in edit_deltas(&old, &new)? {
+            self.change_balance(acc, delta.0.into_values())?;
+        }
+        self.set_transaction(new);
+        Ok(())
+    }
+
+    fn rename_payees(&mut self, from: &[String], to: &str) {
+        let renamed = (self.transactions.values())
+            .filter(|x| {
+                x.payee()
+                    .is_some_and(|payee| from.iter().any(|x| x == payee))
+            })
+            .cloned()
+            .collect::<Vec<_>>();
+        for mut transaction in renamed {
+            if let Some(payee) = transaction.payee_mut() {
+                *payee = to.to_owned();
+            }
+            self.set_transaction(transaction);
+        }
+    }
+
+    fn change_balance(
+        &mut self,
+        id: Id<Account>,
+        amounts: impl IntoIterator<Item = Amount>,
+    ) -> Result<()> {
+        let mut account = self
+            .account(id)
+            .ok_or_else(|| eyre!("No such account {id}"))?
+            .clone();
+        for amount in amounts {
+            account.current.add_amount(amount)?;
+        }
+        ensure!(
+            !account.current.is_negative(),
+            "Account balance must never be below 0 in any currency"
+        );
+        self.set_account(account);
+        Ok(())
+    }
+}
@@ -1,25 +1,147 @@
 use eyre::{bail, ensure, eyre, Result};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use std::{
+    collections::{BTreeMap, BTreeSet},
     env,
     ffi::{OsStr, OsString},
     fmt::{self, Debug},
-    io::{stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
+    fs,
+    io::{self, stdin, stdout, BufRead, BufReader, BufWriter, Read, Write},
     net::{TcpListener, TcpStream},
-    process,
+    path::PathBuf,
+    process, thread,
+    time::Duration,
 };
 
-use tracing::{debug, instrument};
+use tracing::{debug, error, info_span, instrument};
 
-use crate::command::Command;
+use crate::command::{AccountModification, Command, Deltas, LogEntry, Origin};
 use crate::types::*;
 
-use super::Repository;
+use super::{stored, Page, Point, Repository, Stored};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 enum Message {
-    Command { command: Command },
+    Command {
+        command: Box<Command>,
+        #[serde(default)]
+        origin: Origin,
+        /// Reply with only the accounts the command changed, rather than all of them
+        #[serde(default)]
+        changes_only: bool,
+        /// Take currencies in it that aren't used yet as meant, and allow them from then on
+        #[serde(default)]
+        new_currency: bool,
+    },
     Transactions { account: Id<Account> },
+    Account { id: Id<Account> },
+    Search { query: String },
+    Activity,
+    Stats,
+    Config,
+    /// Every entity of the [`Stored`] kind kept under `path`
+    Entities { path: String },
+    AccountsAt { at: Point },
+    AccountLog { id: Id<Account> },
+    BulkModify {
+        selector: AccountSelector,
+        changes: Vec<AccountModification>,
+        #[serde(default)]
+        origin: Origin,
+    },
+}
+
+/// Sent in place of the reply to a message the server couldn't act on
+#[derive(Serialize, Deserialize, Debug)]
+enum Failure {
+    Failed {
+        error: String,
+        /// What the server logged the message under
+        request_id: String,
+    },
+}
+
+/// A new ID to tell a request apart by in the server's logs, sortable by when it was made
+fn request_id() -> String {
+    ulid::Ulid::new().to_string()
+}
+
+/// The body of `POST /accounts/bulk-modify`
+#[derive(Serialize, Deserialize, Debug)]
+struct BulkModify {
+    selector: AccountSelector,
+    changes: Vec<AccountModification>,
+}
+
+/// The reply to a command: the accounts as they are after it, and how it changed their balances
+#[derive(Serialize, Deserialize, Debug)]
+struct Ack {
+    /// Every account, from servers that don't know to send only those changed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    accounts: Option<Vec<Account>>,
+    /// Those the command changed, to be merged into the accounts the client has by ID
+    #[serde(default)]
+    changed: Vec<Account>,
+    deltas: BTreeMap<Id<Account>, Amounts>,
+}
+
+impl Ack {
+    /// Bring `accounts` up to date with the server's
+    fn apply(self, accounts: &mut Vec<Account>) {
+        if let Some(all) = self.accounts {
+            *accounts = all;
+        }
+        for account in self.changed {
+            merge(accounts, account);
+        }
+    }
+}
+
+/// Replace the account in `accounts` with the same ID as `account`, or add it if there isn't one
+fn merge(accounts: &mut Vec<Account>, account: Account) {
+    match accounts.iter_mut().find(|x| x.id == account.id) {
+        Some(x) => *x = account,
+        None => accounts.push(account),
+    }
+}
+
+/// The reply to a command that changed the accounts `changed`, after it's been run on `repo`
+fn ack(
+    repo: &Repository,
+    changed: &[Id<Account>],
+    deltas: Deltas,
+    changes_only: bool,
+) -> Result<Ack> {
+    Ok(Ack {
+        accounts: if changes_only {
+            None
+        } else {
+            Some(repo.accounts()?)
+        },
+        changed: if changes_only {
+            // Funding rules may have moved money into accounts the command didn't name
+            changed
+                .iter()
+                .copied()
+                .chain(deltas.iter().map(|&(id, _)| id))
+                .collect::<BTreeSet<_>>()
+                .into_iter()
+                .map(|id| repo.account(id))
+                .collect::<Result<_>>()?
+        } else {
+            vec![]
+        },
+        deltas: deltas.into_iter().collect(),
+    })
+}
+
+/// An account as listed by `GET /?activity=true`
+#[derive(Serialize, Deserialize, Debug)]
+struct AccountWithActivity {
+    #[serde(flatten)]
+    account: Account,
+    #[serde(flatten)]
+    activity: Activity,
 }
 
 struct Connection {
@@ -50,8 +172,9 @@ impl Connection {
 
     #[instrument(ret)]
     fn receive<T: DeserializeOwned + Debug>(&mut self) -> Result<T> {
-        self.receive_or_eof()
-            .and_then(|x| x.ok_or_else(|| eyre!("Unexpected EOF")))
+        self.receive_or_eof().and_then(|x| {
+            x.ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "Unexpected EOF").into())
+        })
     }
 
     #[instrument(ret)]
@@ -63,25 +186,57 @@ impl Connection {
         self.reader.read_until(0, &mut buf)?;
         buf.pop(); // Should always have a NUL suffix, as send will always add one. read_until includes it if it's present before EOF
         debug!(str = ?std::str::from_utf8(&buf));
-        Ok(Some(serde_json::from_slice(&buf)?))
+        match serde_json::from_slice(&buf) {
+            Ok(x) => Ok(Some(x)),
+            Err(e) => match serde_json::from_slice(&buf) {
+                Ok(Failure::Failed { error, request_id }) => {
+                    bail!("The server couldn't do that: {error} (request {request_id})")
+                }
+                Err(_) => Err(e.into()),
+            },
+        }
     }
 }
 
 #[derive(Debug)]
 enum RemoteHandle {
-    Tcp(Connection),
+    Tcp {
+        /// Where to reconnect to
+        addr: String,
+        connection: Connection,
+    },
     Http {
         agent: ureq::Agent,
         base_url: String,
     },
 }
 
+/// How many times to reconnect before giving up on a request
+const RETRIES: u32 = 4;
+/// How long to wait before reconnecting the first time; this doubles each time after
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Whether `error` may be down to a dropped connection or an overloaded server, and so worth trying again
+fn is_transient(error: &eyre::Report) -> bool {
+    if error.downcast_ref::<io::Error>().is_some() {
+        return true;
+    }
+    if let Some(error) = error.downcast_ref::<serde_json::Error>() {
+        return error.is_io() || error.is_eof();
+    }
+    matches!(
+        error.downcast_ref::<ureq::Error>(),
+        Some(ureq::Error::Transport(_) | ureq::Error::Status(502..=504, _))
+    )
+}
+
 impl RemoteHandle {
     #[instrument]
-    fn connect_tcp(stream: TcpStream) -> Result<(Self, Vec<Account>)> {
+    fn connect_tcp(addr: String) -> Result<(Self, Vec<Account>)> {
+        let stream = TcpStream::connect(&addr)?;
         let mut connection = Connection::new(stream.try_clone()?, stream);
         let accounts = connection.receive()?;
-        Ok((Self::Tcp(connection), accounts))
+        Ok((Self::Tcp { addr, connection }, accounts))
     }
 
     #[instrument]
@@ -95,91 +250,782 @@ impl RemoteHandle {
     }
 
     #[instrument]
-    fn run_command(&mut self, command: Command) -> Result<Vec<Account>> {
+    fn connect(address: &Address) -> Result<(Self, Vec<Account>)> {
+        match address {
+            Address::Tcp(addr) => Self::connect_tcp(addr.clone()),
+            Address::Http(url) => Self::connect_http(url.clone()),
+        }
+    }
+
+    /// Connect again, giving the accounts the server has now
+    #[instrument]
+    fn reconnect(&mut self) -> Result<Vec<Account>> {
+        let (handle, accounts) = match self {
+            Self::Tcp { addr, .. } => Self::connect_tcp(addr.clone())?,
+            Self::Http { base_url, .. } => Self::connect_http(base_url.clone())?,
+        };
+        *self = handle;
+        Ok(accounts)
+    }
+
+    /// Reconnect after `error` if it's worth trying again, giving the accounts the server has now
+    fn recover(&mut self, mut error: eyre::Report, attempt: &mut u32) -> Result<Vec<Account>> {
+        loop {
+            if *attempt >= RETRIES || !is_transient(&error) {
+                return Err(error);
+            }
+            let delay = RETRY_DELAY * 2u32.pow(*attempt);
+            *attempt += 1;
+            eprintln!("Lost connection to the server ({error}); reconnecting in {delay:?}");
+            thread::sleep(delay);
+            match self.reconnect() {
+                Ok(accounts) => return Ok(accounts),
+                Err(e) => error = e,
+            }
+        }
+    }
+
+    /// Run `f`, reconnecting and running it again if the connection fails
+    ///
+    /// Only for requests that don't change anything, which are safe to repeat.
+    fn retrying<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f(self) {
+                Ok(x) => return Ok(x),
+                Err(e) => {
+                    self.recover(e, &mut attempt)?;
+                }
+            }
+        }
+    }
+
+    #[instrument]
+    fn run_command(&mut self, command: Command, origin: Origin, new_currency: bool) -> Result<Ack> {
+        let mut attempt = 0;
+        loop {
+            match self.send_command(&command, &origin, new_currency) {
+                Ok(ack) => return Ok(ack),
+                Err(e) => {
+                    let accounts = self.recover(e, &mut attempt)?;
+                    // The connection may have dropped after the command was run, in which case running it again
+                    // would fail, or for a transaction, count it twice
+                    if self.was_run(&command, &accounts)? {
+                        return Ok(Ack {
+                            accounts: Some(accounts),
+                            changed: vec![],
+                            deltas: command.deltas()?.into_iter().collect(),
+                        });
+                    }
+                }
+            }
+        }
+    }
+
+    /// Whether the server has already run `command`, going by the IDs it creates
+    ///
+    /// Account modifications, edits and renames set rather than change values, so are safe to run again; an edit
+    /// moving a transaction to another account, or a void, is refused the second time rather than made twice.
+    fn was_run(&mut self, command: &Command, accounts: &[Account]) -> Result<bool> {
+        Ok(match command {
+            Command::CreateAccount(account) => accounts.iter().any(|x| x.id == account.id),
+            Command::AddTransaction(transaction) | Command::AddScheduled { transaction, .. } => {
+                match transaction.accounts().first() {
+                    Some(&account) => self
+                        .transactions(account)?
+                        .iter()
+                        .any(|x| x.id == transaction.id),
+                    None => false,
+                }
+            }
+            Command::RemoveSchedule(id) => !self
+                .entities::<ScheduledTransaction>()?
+                .iter()
+                .any(|x| x.id == *id),
+            Command::RemoveTemplate(name) => {
+                !self.config()?.transaction_templates.contains_key(name)
+            }
+            Command::UpdateAccount(..)
+            | Command::UpdateTransaction(..)
+            | Command::VoidTransaction { .. }
+            | Command::RenamePayees { .. }
+            | Command::SetSchedule(_)
+            | Command::SetTemplate { .. } => false,
+        })
+    }
+
+    fn send_command(
+        &mut self,
+        command: &Command,
+        origin: &Origin,
+        new_currency: bool,
+    ) -> Result<Ack> {
         match self {
-            Self::Tcp(conn) => {
-                conn.send(Message::Command { command })?;
-                conn.receive()
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Command {
+                    command: Box::new(command.clone()),
+                    origin: origin.clone(),
+                    changes_only: true,
+                    new_currency,
+                })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => {
+                let mut request = agent
+                    .post(&format!("{base_url}/"))
+                    .query("deltas", "true")
+                    .query("changes_only", "true")
+                    .query("new_currency", &new_currency.to_string());
+                if let Some(user) = &origin.user {
+                    request = request.set("X-Monfari-User", user);
+                }
+                if let Some(client) = &origin.client {
+                    request = request.set("User-Agent", client);
+                }
+                Ok(request.send_json(command)?.into_json()?)
             }
-            Self::Http { agent, base_url } => Ok(agent
-                .post(&format!("{base_url}/"))
-                .send_json(command)?
-                .into_json()?),
         }
     }
 
     #[instrument]
     fn transactions(&mut self, account: Id<Account>) -> Result<Vec<Transaction>> {
-        match self {
-            Self::Tcp(conn) => {
-                conn.send(Message::Transactions { account })?;
-                conn.receive()
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Transactions { account })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/transactions/{}", account.proquint()))
+                .call()?
+                .into_json()?),
+        })
+    }
+
+    /// The account as the server has it now, or `None` if it has no such account
+    #[instrument]
+    fn account(&mut self, id: Id<Account>) -> Result<Option<Account>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Account { id })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => {
+                let id = id.proquint();
+                match agent.get(&format!("{base_url}/accounts/{id}")).call() {
+                    Ok(response) => Ok(Some(response.into_json()?)),
+                    Err(ureq::Error::Status(404, _)) => Ok(None),
+                    Err(e) => Err(e.into()),
+                }
+            }
+        })
+    }
+
+    #[instrument]
+    fn config(&mut self) -> Result<Config> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Config)?;
+                connection.receive()
             }
             Self::Http { agent, base_url } => Ok(agent
-                .get(&format!("{base_url}/transactions/{account}"))
+                .get(&format!("{base_url}/config"))
                 .call()?
                 .into_json()?),
+        })
+    }
+
+    #[instrument]
+    fn entities<T: Stored>(&mut self) -> Result<Vec<T>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Entities {
+                    path: T::PATH.to_owned(),
+                })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/{}", T::PATH))
+                .call()?
+                .into_json()?),
+        })
+    }
+
+    /// Safe to retry, as accounts already changed are either changed the same way again or no longer selected
+    #[instrument]
+    fn bulk_modify(
+        &mut self,
+        selector: &AccountSelector,
+        changes: &[AccountModification],
+        origin: &Origin,
+    ) -> Result<Vec<Id<Account>>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::BulkModify {
+                    selector: selector.clone(),
+                    changes: changes.to_vec(),
+                    origin: origin.clone(),
+                })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => {
+                let mut request = agent.post(&format!("{base_url}/accounts/bulk-modify"));
+                if let Some(user) = &origin.user {
+                    request = request.set("X-Monfari-User", user);
+                }
+                if let Some(client) = &origin.client {
+                    request = request.set("User-Agent", client);
+                }
+                Ok(request
+                    .send_json(BulkModify {
+                        selector: selector.clone(),
+                        changes: changes.to_vec(),
+                    })?
+                    .into_json()?)
+            }
+        })
+    }
+
+    #[instrument]
+    fn account_log(&mut self, id: Id<Account>) -> Result<Vec<LogEntry>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::AccountLog { id })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/accounts/{}/log", id.proquint()))
+                .call()?
+                .into_json()?),
+        })
+    }
+
+    #[instrument]
+    fn accounts_at(&mut self, at: Point) -> Result<Vec<Account>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::AccountsAt { at: at.clone() })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/"))
+                .query("at", &at.to_string())
+                .call()?
+                .into_json()?),
+        })
+    }
+
+    #[instrument]
+    fn stats(&mut self) -> Result<Stats> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Stats)?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/stats"))
+                .call()?
+                .into_json()?),
+        })
+    }
+
+    #[instrument]
+    fn activity(&mut self) -> Result<BTreeMap<Id<Account>, Activity>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Activity)?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/"))
+                .query("activity", "true")
+                .call()?
+                .into_json::<Vec<AccountWithActivity>>()?
+                .into_iter()
+                .map(|x| (x.account.id, x.activity))
+                .collect()),
+        })
+    }
+
+    #[instrument]
+    fn search(&mut self, query: String) -> Result<Vec<SearchResult>> {
+        self.retrying(|this| match this {
+            Self::Tcp { connection, .. } => {
+                connection.send(Message::Search {
+                    query: query.clone(),
+                })?;
+                connection.receive()
+            }
+            Self::Http { agent, base_url } => Ok(agent
+                .get(&format!("{base_url}/search"))
+                .query("q", &query)
+                .call()?
+                .into_json()?),
+        })
+    }
+}
+
+/// Where a remote repository is served from
+#[derive(Debug, Clone)]
+pub(super) enum Address {
+    Tcp(String),
+    Http(String),
+}
+
+impl fmt::Display for Address {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Tcp(addr) => write!(f, "tcp:{addr}"),
+            Self::Http(url) => write!(f, "{url}"),
         }
     }
 }
 
+/// What's kept on disk about a remote repository, so commands can still be entered while it can't be reached
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Offline {
+    /// The accounts as the server last sent them
+    accounts: Vec<Account>,
+    /// Commands waiting to be sent, oldest first
+    queue: Vec<Queued>,
+    /// Queued commands the server would no longer accept, kept so they can be entered again by hand
+    #[serde(default)]
+    dropped: Vec<Dropped>,
+    /// The repository's configuration as the server last sent it
+    #[serde(default)]
+    config: Config,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Queued {
+    command: Command,
+    origin: Origin,
+    #[serde(default)]
+    new_currency: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct Dropped {
+    command: Command,
+    origin: Origin,
+    reason: String,
+}
+
+fn find(accounts: &[Account], id: Id<Account>) -> Result<Account> {
+    accounts
+        .iter()
+        .find(|x| x.id == id)
+        .cloned()
+        .ok_or_else(|| eyre!("No such account"))
+}
+
 #[derive(Debug)]
 pub(super) struct RemoteRepository {
-    handle: RemoteHandle,
-    accounts: Vec<Account>,
+    address: Address,
+    /// `None` while the server can't be reached
+    handle: Option<RemoteHandle>,
+    state: Offline,
+    /// Where `state` is kept
+    path: PathBuf,
+    /// Whether `state.config` has been fetched since connecting; it rarely changes, so isn't fetched again
+    config_fetched: bool,
 }
 
 impl RemoteRepository {
+    /// Connect to the repository at `address`, or if it can't be reached but has been before, work offline
     #[instrument]
-    pub(super) fn open_tcp(stream: TcpStream) -> Result<Self> {
-        let (handle, accounts) = RemoteHandle::connect_tcp(stream)?;
-        Ok(Self { handle, accounts })
+    pub(super) fn open(address: Address) -> Result<Self> {
+        let name = address
+            .to_string()
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+            .collect::<String>();
+        let path = crate::cache_dir()?
+            .join("remote")
+            .join(format!("{name}.json"));
+        let saved = match fs::read_to_string(&path) {
+            Ok(saved) => Some(serde_json::from_str(&saved)?),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+        let seen = saved.is_some();
+        let mut this = Self {
+            handle: None,
+            state: saved.unwrap_or_default(),
+            address,
+            path,
+            config_fetched: false,
+        };
+        match RemoteHandle::connect(&this.address) {
+            Ok((handle, accounts)) => {
+                this.handle = Some(handle);
+                this.state.accounts = accounts;
+            }
+            Err(e) if seen && is_transient(&e) => {
+                eprintln!("The server can't be reached ({e}), so working offline");
+            }
+            Err(e) => return Err(e),
+        }
+        this.flush()?;
+        this.save()?;
+        Ok(this)
     }
 
+    fn save(&self) -> Result<()> {
+        fs::create_dir_all(self.path.parent().expect("Always in a directory"))?;
+        fs::write(&self.path, serde_json::to_string(&self.state)?)?;
+        Ok(())
+    }
+
+    /// Connect if offline, sending anything queued if that works
+    fn connect(&mut self) -> Result<()> {
+        if self.handle.is_none() {
+            match RemoteHandle::connect(&self.address) {
+                Ok((handle, accounts)) => {
+                    self.handle = Some(handle);
+                    self.state.accounts = accounts;
+                }
+                Err(e) if is_transient(&e) => return Ok(()),
+                Err(e) => return Err(e),
+            }
+        }
+        self.flush()?;
+        self.save()
+    }
+
+    /// Send queued commands in order, until the queue is empty or the server can't be reached again
+    ///
+    /// Each is checked against the server's accounts first, as they may have changed since it was queued; those that
+    /// no longer apply are dropped from the queue.
     #[instrument]
-    pub(super) fn open_http(url: String) -> Result<Self> {
-        let (handle, accounts) = RemoteHandle::connect_http(url)?;
-        Ok(Self { handle, accounts })
+    fn flush(&mut self) -> Result<()> {
+        if self.handle.is_some() && !self.state.queue.is_empty() {
+            let n = self.state.queue.len();
+            let s = if n == 1 { "" } else { "s" };
+            eprintln!("Sending {n} command{s} queued while offline");
+        }
+        while let Some(Queued {
+            command,
+            origin,
+            new_currency,
+        }) = self.state.queue.first().cloned()
+        {
+            let Some(handle) = &mut self.handle else { return Ok(()) };
+            let accounts = &self.state.accounts;
+            // It may have been sent just before the connection dropped
+            if handle.was_run(&command, accounts)? {
+                self.state.queue.remove(0);
+                continue;
+            }
+            let checked = super::check(
+                &command,
+                |id| find(accounts, id),
+                |id| handle.transactions(id),
+                |_| bail!("Transactions can't be edited or voided offline"),
+            );
+            match checked {
+                Ok(_) => match handle.run_command(command, origin, new_currency) {
+                    Ok(ack) => {
+                        ack.apply(&mut self.state.accounts);
+                        self.state.queue.remove(0);
+                    }
+                    Err(e) if is_transient(&e) => self.handle = None,
+                    Err(e) => return Err(e),
+                },
+                Err(e) if is_transient(&e) => self.handle = None,
+                Err(e) => {
+                    eprintln!(
+                        "Couldn't send `{command}`, queued while offline: {e}\nIt's been kept in {}",
+                        self.path.display()
+                    );
+                    self.state.queue.remove(0);
+                    self.state.dropped.push(Dropped {
+                        command,
+                        origin,
+                        reason: e.to_string(),
+                    });
+                }
+            }
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// The handle to the server, if it can be reached
+    fn online(&mut self) -> Result<&mut RemoteHandle> {
+        self.connect()?;
+        self.handle.as_mut().ok_or_else(|| {
+            eyre!("The server can't be reached; only accounts can be listed and commands run while offline")
+        })
     }
 }
 
 impl RemoteRepository {
     #[instrument]
-    pub(super) fn run_command(&mut self, command: Command) -> Result<()> {
-        self.accounts = self.handle.run_command(command)?;
-        Ok(())
+    pub(super) fn run_command(
+        &mut self,
+        command: Command,
+        origin: Origin,
+        new_currency: bool,
+    ) -> Result<Deltas> {
+        self.connect()?;
+        if let Some(handle) = &mut self.handle {
+            match handle.run_command(command.clone(), origin.clone(), new_currency) {
+                Ok(ack) => {
+                    let deltas = ack.deltas.clone().into_iter().collect();
+                    ack.apply(&mut self.state.accounts);
+                    if let Command::SetTemplate { .. } | Command::RemoveTemplate(_) = command {
+                        // Templates are kept in the configuration, so it's fetched again
+                        self.config_fetched = false;
+                    }
+                    self.save()?;
+                    return Ok(deltas);
+                }
+                Err(e) if is_transient(&e) => self.handle = None,
+                Err(e) => return Err(e),
+            }
+        }
+        // Check what can be checked without the server, so what's queued is likely to be accepted
+        let accounts = self.accounts()?;
+        let deltas = super::check(
+            &command,
+            |id| find(&accounts, id),
+            |_| bail!("Accounts can't have their types changed or be deleted offline"),
+            |_| bail!("Transactions can't be edited or voided offline"),
+        )?;
+        // So a template saved offline can be applied before it's sent
+        command.apply_template(&mut self.state.config.transaction_templates)?;
+        eprintln!("The server can't be reached, so this will be sent once it can");
+        self.state.queue.push(Queued {
+            command,
+            origin,
+            new_currency,
+        });
+        self.save()?;
+        Ok(deltas)
     }
 
+    /// The accounts as the server last sent them, as changed by anything queued since
     #[instrument]
-    pub(super) fn accounts(&mut self) -> Vec<Account> {
-        self.accounts.clone()
+    pub(super) fn accounts(&mut self) -> Result<Vec<Account>> {
+        let mut accounts = self.state.accounts.clone();
+        for Queued { command, .. } in &self.state.queue {
+            match command.clone() {
+                Command::CreateAccount(account) => accounts.push(account),
+                Command::UpdateAccount(id, changes) => {
+                    if let Some(account) = accounts.iter_mut().find(|x| x.id == id) {
+                        for change in changes {
+                            change.apply(account);
+                        }
+                    }
+                }
+                Command::AddTransaction(transaction)
+                | Command::AddScheduled { transaction, .. } => {
+                    for (id, amount) in transaction.results()? {
+                        if let Some(account) = accounts.iter_mut().find(|x| x.id == id) {
+                            account.current.add_amount(amount)?;
+                        }
+                    }
+                }
+                Command::UpdateTransaction(..)
+                | Command::VoidTransaction { .. }
+                | Command::RenamePayees { .. }
+                | Command::SetSchedule(_)
+                | Command::RemoveSchedule(_)
+                | Command::SetTemplate { .. }
+                | Command::RemoveTemplate(_) => {}
+            }
+        }
+        accounts.retain(|x| !x.deleted);
+        Ok(accounts)
     }
 
+    /// The account as the server has it, or as last sent by it and changed by anything queued since while offline
     #[instrument]
-    pub(super) fn account(&mut self, id: Id<Account>) -> Option<Account> {
-        self.accounts.iter().find(|x| x.id == id).cloned()
+    pub(super) fn account(&mut self, id: Id<Account>) -> Result<Option<Account>> {
+        self.connect()?;
+        if let Some(handle) = &mut self.handle {
+            match handle.account(id) {
+                Ok(account) => {
+                    match &account {
+                        Some(account) => merge(&mut self.state.accounts, account.clone()),
+                        None => self.state.accounts.retain(|x| x.id != id),
+                    }
+                    self.save()?;
+                    return Ok(account);
+                }
+                Err(e) if is_transient(&e) => self.handle = None,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(self.accounts()?.into_iter().find(|x| x.id == id))
     }
 
     #[instrument]
     pub(super) fn transactions(&mut self, account: Id<Account>) -> Result<Vec<Transaction>> {
-        self.handle.transactions(account)
+        self.online()?.transactions(account)
+    }
+
+    /// Activity is only known to the server, so is left out while offline, rather than stop accounts being listed
+    #[instrument]
+    pub(super) fn activity(&mut self) -> Result<BTreeMap<Id<Account>, Activity>> {
+        self.connect()?;
+        match &mut self.handle {
+            Some(handle) => handle.activity(),
+            None => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// The configuration as the server has it, or as it last sent it while offline
+    #[instrument]
+    pub(super) fn config(&mut self) -> Result<Config> {
+        if !self.config_fetched {
+            self.connect()?;
+            if let Some(handle) = &mut self.handle {
+                match handle.config() {
+                    Ok(config) => {
+                        self.state.config = config;
+                        self.config_fetched = true;
+                        self.save()?;
+                    }
+                    Err(e) if is_transient(&e) => self.handle = None,
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(self.state.config.clone())
+    }
+
+    /// Only while online, as the server decides which accounts are selected
+    #[instrument]
+    pub(super) fn bulk_modify(
+        &mut self,
+        selector: &AccountSelector,
+        changes: Vec<AccountModification>,
+        origin: Origin,
+    ) -> Result<Vec<Id<Account>>> {
+        let ids = self.online()?.bulk_modify(selector, &changes, &origin)?;
+        // The server only says which changed, so each is fetched again to bring what's kept offline up to date
+        for &id in &ids {
+            self.account(id)?;
+        }
+        Ok(ids)
+    }
+
+    #[instrument]
+    pub(super) fn account_log(&mut self, id: Id<Account>) -> Result<Vec<LogEntry>> {
+        self.online()?.account_log(id)
+    }
+
+    /// The past is only known to the server
+    #[instrument]
+    pub(super) fn accounts_at(&mut self, at: &Point) -> Result<Vec<Account>> {
+        self.online()?.accounts_at(at.clone())
+    }
+
+    #[instrument]
+    pub(super) fn stats(&mut self) -> Result<Stats> {
+        self.online()?.stats()
+    }
+
+    #[instrument]
+    pub(super) fn entities<T: Stored>(&mut self) -> Result<Vec<T>> {
+        self.online()?.entities()
+    }
+
+    #[instrument]
+    pub(super) fn search(&mut self, query: &str) -> Result<Vec<SearchResult>> {
+        self.online()?.search(query.to_owned())
     }
 }
 
+/// Append where a connection came from to the client's description of itself
+fn via(client: Option<String>, peer: &str) -> Option<String> {
+    Some(match client {
+        Some(client) => format!("{client} via {peer}"),
+        None => peer.to_owned(),
+    })
+}
+
 #[instrument]
-fn run_session(mut connection: Connection, repo: &OsStr) -> Result<()> {
+fn run_session(mut connection: Connection, repo: &OsStr, peer: &str) -> Result<()> {
     let mut repo = Repository::open(repo)?;
     connection.send(repo.accounts()?)?;
     while let Some(msg) = connection.receive_or_eof::<Message>()? {
+        let request_id = request_id();
+        let _span = info_span!("message", %request_id).entered();
         debug!(?msg);
-        match msg {
-            Message::Command { command } => {
-                repo.run_command(command)?;
-                connection.send(repo.accounts()?)?;
-            }
-            Message::Transactions { account } => {
-                connection.send(repo.transactions(account)?)?;
-            }
+        // A message that can't be acted on needn't end the session
+        if let Err(e) = reply(&mut connection, &mut repo, msg, peer) {
+            error!(error = ?e, "Failed");
+            connection.send(Failure::Failed {
+                error: e.to_string(),
+                request_id,
+            })?;
+        }
+    }
+    Ok(())
+}
+
+/// Act on `msg`, sending the reply down `connection`
+fn reply(
+    connection: &mut Connection,
+    repo: &mut Repository,
+    msg: Message,
+    peer: &str,
+) -> Result<()> {
+    match msg {
+        Message::Command {
+            command,
+            origin,
+            changes_only,
+            new_currency,
+        } => {
+            let origin = Origin {
+                client: via(origin.client, peer),
+                ..origin
+            };
+            let changed = command.accounts();
+            let deltas = repo.run_command_with(*command, origin, new_currency)?;
+            connection.send(ack(repo, &changed, deltas, changes_only)?)?;
+        }
+        Message::Transactions { account } => {
+            connection.send(repo.transactions(account)?)?;
+        }
+        Message::Account { id } => {
+            connection.send(repo.account(id).ok())?;
+        }
+        Message::Search { query } => {
+            connection.send(repo.search(&query)?)?;
+        }
+        Message::Activity => {
+            connection.send(repo.activity()?)?;
+        }
+        Message::Stats => {
+            connection.send(repo.stats()?)?;
+        }
+        Message::Config => {
+            connection.send(repo.config()?)?;
+        }
+        Message::Entities { path } => {
+            let list = stored(&path).ok_or_else(|| eyre!("No kind of entity is kept at {path}"))?;
+            connection.send(list(repo)?)?;
+        }
+        Message::AccountsAt { at } => {
+            connection.send(repo.accounts_at(&at)?)?;
+        }
+        Message::AccountLog { id } => {
+            connection.send(repo.account_log(id)?)?;
+        }
+        Message::BulkModify {
+            selector,
+            changes,
+            origin,
+        } => {
+            let origin = Origin {
+                client: via(origin.client, peer),
+                ..origin
+            };
+            connection.send(repo.bulk_modify(&selector, changes, origin)?)?;
         }
     }
     Ok(())
@@ -188,9 +1034,9 @@ fn run_session(mut connection: Connection, repo: &OsStr) -> Result<()> {
 #[instrument]
 fn serve_listener(listener: TcpListener, repo: OsString) -> Result<()> {
     loop {
-        let (stream, _) = listener.accept()?;
+        let (stream, peer) = listener.accept()?;
         let connection = Connection::new(BufReader::new(stream.try_clone()?), stream);
-        run_session(connection, &repo)?;
+        run_session(connection, &repo, &peer.to_string())?;
     }
 }
 #[cfg(unix)]
@@ -232,64 +1078,806 @@ mod systemd {
 }
 
 mod http {
-    use tiny_http::{Header, Method, Request, Response};
+    use std::{
+        borrow::Cow,
+        collections::HashMap,
+        fs,
+        net::{IpAddr, SocketAddr},
+        num::NonZeroUsize,
+        path::{Path, PathBuf},
+        sync::Mutex,
+        thread,
+    };
+
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use eyre::Context;
+    use tiny_http::{Header, Method, Request, Response, ResponseBox, StatusCode};
     use tracing::info_span;
 
     use super::*;
+    use crate::export::{Export, OnConflict, Selection};
+    use crate::parser::{self, Parser, Span};
 
-    fn json(r: Request, s: impl Serialize) -> Result<()> {
-        let body = serde_json::to_string(&s)?;
-        r.respond(
-            Response::from_string(body)
-                .with_status_code(200)
-                .with_header(
-                    Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
-                ),
-        )?;
-        Ok(())
+    /// A response, built to be sent once it's known nothing went wrong
+    type Reply = ResponseBox;
+
+    fn json(r: &Request, s: impl Serialize) -> Result<Reply> {
+        json_with(r, s, [])
     }
-    fn err(request: Request, code: u32, reason: &'static str) -> Result<()> {
-        request.respond(Response::from_string(reason).with_status_code(code))?;
-        Ok(())
+    fn json_with(
+        r: &Request,
+        s: impl Serialize,
+        headers: impl IntoIterator<Item = Header>,
+    ) -> Result<Reply> {
+        json_status(r, 200, s, headers)
+    }
+    // The request is only asked how it wants amounts given
+    #[cfg_attr(not(feature = "structured-amounts"), allow(unused_variables))]
+    fn json_status(
+        r: &Request,
+        code: u32,
+        s: impl Serialize,
+        headers: impl IntoIterator<Item = Header>,
+    ) -> Result<Reply> {
+        // Clients are given IDs as people using the repository see them
+        let serialize = || crate::types::styled_ids(|| serde_json::to_string(&s));
+        #[cfg(feature = "structured-amounts")]
+        let query = r.url().split_once('?').unwrap_or_default().1;
+        #[cfg(feature = "structured-amounts")]
+        let body = if form_urlencoded::parse(query.as_bytes())
+            .any(|(key, value)| key == "amounts" && value == "structured")
+        {
+            crate::types::structured_amounts(serialize)?
+        } else {
+            serialize()?
+        };
+        #[cfg(not(feature = "structured-amounts"))]
+        let body = serialize()?;
+        let mut response = Response::from_string(body)
+            .with_status_code(code)
+            .with_header(
+                Header::from_bytes(&b"Content-Type"[..], &b"application/json"[..]).unwrap(),
+            );
+        for header in headers {
+            response.add_header(header);
+        }
+        Ok(response.boxed())
+    }
+    fn header(request: &Request, name: &'static str) -> Option<String> {
+        request
+            .headers()
+            .iter()
+            .rev()
+            .find(|x| x.field.equiv(name))
+            .map(|x| x.value.to_string())
+    }
+    /// Whether `request`'s Accept header lists `media_type`, other than as not acceptable with `q=0`
+    fn accepts(request: &Request, media_type: &str) -> bool {
+        header(request, "Accept").is_some_and(|accept| {
+            accept.split(',').any(|x| {
+                let mut params = x.split(';');
+                let typ = params.next().unwrap_or_default();
+                let q = params
+                    .filter_map(|x| x.split_once('='))
+                    .find(|(name, _)| name.trim().eq_ignore_ascii_case("q"))
+                    .map_or(Some(1.0), |(_, q)| q.trim().parse::<f32>().ok());
+                typ.trim().eq_ignore_ascii_case(media_type) && q.is_some_and(|q| q > 0.0)
+            })
+        })
+    }
+    fn err(code: u32, reason: &'static str) -> Reply {
+        Response::from_string(reason).with_status_code(code).boxed()
+    }
+    fn unauthorized() -> Reply {
+        err(401, "Unauthorized").with_header(
+            Header::from_bytes(
+                &b"WWW-Authenticate"[..],
+                &b"Basic realm=\"monfari\", charset=\"UTF-8\""[..],
+            )
+            .unwrap(),
+        )
+    }
+    /// Who sent `request`, and from where; users who logged in can't claim to be anyone else
+    fn origin(request: &Request, user: Option<&str>) -> Origin {
+        Origin {
+            user: user
+                .map(str::to_owned)
+                .or_else(|| header(request, "X-Monfari-User")),
+            client: via(
+                header(request, "User-Agent"),
+                &request
+                    .remote_addr()
+                    .map_or_else(|| "unknown".to_owned(), |x| x.to_string()),
+            ),
+        }
     }
 
-    #[instrument]
-    pub fn serve_http(addr: String, repo: OsString) -> Result<()> {
-        let mut repo = Repository::open(&repo)?;
+    /// What `POST /run` did
+    #[derive(Serialize)]
+    struct Ran {
+        /// As the repository describes it
+        description: String,
+        command: Command,
+        dry_run: bool,
+        deltas: Deltas,
+        /// The accounts it touched, with their balances after it
+        accounts: Vec<Account>,
+    }
 
-        let server = tiny_http::Server::http(addr).map_err(|e| eyre!(e))?;
-        for mut request in server.incoming_requests() {
-            let _span =
-                info_span!("request", url = request.url(), method = ?request.method()).entered();
-            match (
-                request.method(),
-                &request.url().split('/').skip(1).collect::<Vec<&str>>()[..],
-            ) {
-                (&Method::Get, &[""]) => json(request, &repo.accounts()?)?,
-                (&Method::Post, &[""]) => {
-                    let Some("application/json") = request.headers().iter().rev().find(|x| x.field.equiv("Content-Type")).map(|x| x.value.as_str()) else { err(request, 401, "JSON is required")?; continue };
-                    let Ok(command) = serde_json::from_reader(request.as_reader()) else { err(request, 401, "Invalid command")?; continue };
-                    repo.run_command(command)?;
-                    json(request, repo.accounts()?)?
+    /// Why `POST /run` didn't run a command
+    #[derive(Serialize)]
+    struct Refused {
+        error: String,
+        /// For a command that couldn't be parsed, the first token that couldn't be understood, or nothing if it ended
+        /// too soon
+        #[serde(skip_serializing_if = "Option::is_none")]
+        found: Option<String>,
+        /// The characters of the command that token is, or where it ended
+        #[serde(skip_serializing_if = "Option::is_none")]
+        span: Option<Span>,
+        /// What would have been accepted instead
+        #[serde(skip_serializing_if = "Vec::is_empty")]
+        expected: Vec<String>,
+    }
+
+    impl Refused {
+        fn new(error: impl ToString) -> Self {
+            Self {
+                error: error.to_string(),
+                found: None,
+                span: None,
+                expected: vec![],
+            }
+        }
+    }
+
+    impl From<parser::Error> for Refused {
+        fn from(e: parser::Error) -> Self {
+            let end = e.at + e.found.as_ref().map_or(0, |x| x.chars().count());
+            Self {
+                error: e.to_string(),
+                span: Some(Span::new(e.at, end)),
+                expected: e.expected.0.into_iter().map(|x| x.value).collect(),
+                found: e.found,
+            }
+        }
+    }
+
+    /// The accounts `command` would change
+    fn changed_by(repo: &Repository, command: &Command) -> Result<Vec<Id<Account>>> {
+        let mut changed = command.accounts();
+        // An edit or void changes the accounts the transaction was already in as well as any it names
+        if let Command::UpdateTransaction(..) | Command::VoidTransaction { .. } = command {
+            changed.extend(repo.simulate(command)?.into_iter().map(|(id, _)| id));
+        }
+        Ok(changed)
+    }
+
+    /// Parse `line` as the REPL would, and run it, for clients that would rather not build commands as JSON
+    fn run_line(
+        repo: &mut Repository,
+        line: &str,
+        origin: Origin,
+        scope: &Scope,
+        confirm_large: bool,
+    ) -> Result<Result<Ran, Refused>> {
+        let (_, parsed) = Parser::parse(line, repo.accounts()?, repo.config()?, None);
+        let (parsed, dry_run, new_currency) = match parsed {
+            Ok(parser::Command::DryRun(parsed)) => (*parsed, true, false),
+            Ok(parser::Command::NewCurrency(parsed)) => (*parsed, false, true),
+            Ok(parsed) => (parsed, false, false),
+            Err(e) => return Ok(Err(e.into())),
+        };
+        let command = match parsed {
+            parser::Command::AccountCreate { typ, name } => Command::CreateAccount(Account {
+                id: Id::generate(),
+                name,
+                notes: String::new(),
+                typ,
+                current: Default::default(),
+                enabled: true,
+                institution: None,
+                deleted: false,
+            }),
+            parser::Command::AccountModify(id, changes) => Command::UpdateAccount(id, changes),
+            parser::Command::TransactionAdd {
+                amount,
+                inner,
+                notes,
+                attachments,
+                debts,
+                date,
+            } if attachments.is_empty() => Command::AddTransaction(Transaction {
+                id: Id::generate(),
+                notes: notes.unwrap_or_default(),
+                amount,
+                inner,
+                attachments: vec![],
+                debts,
+                date,
+                voided: None,
+                tags: vec![],
+            }),
+            parser::Command::TransactionAdd { .. } => {
+                return Ok(Err(Refused::new("Attachments can't be uploaded with a command")))
+            }
+            _ => {
+                return Ok(Err(Refused::new(
+                    "Only commands that add transactions or create or change accounts can be run here",
+                )))
+            }
+        };
+        if !scope.allows(&changed_by(repo, &command)?) {
+            return Ok(Err(Refused::new("This login can't use that account")));
+        }
+        if let Command::AddTransaction(transaction) = &command {
+            if let Some(limit) = repo
+                .config()?
+                .large(transaction)
+                .filter(|_| !(dry_run || confirm_large))
+            {
+                return Ok(Err(Refused::new(format!(
+                    "{} is more than {limit}; send it with confirm_large=true to add it anyway",
+                    transaction.amount
+                ))));
+            }
+        }
+        let result = if dry_run {
+            repo.simulate(&command)
+        } else {
+            repo.run_command_with(command.clone(), origin, new_currency)
+        };
+        let deltas = match result {
+            Ok(deltas) => deltas,
+            Err(e) => return Ok(Err(Refused::new(e))),
+        };
+        // An account a dry run would have created isn't there to show
+        let mut accounts = command
+            .accounts()
+            .into_iter()
+            .chain(deltas.iter().map(|&(id, _)| id))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .filter(|&id| scope.sees(id))
+            .filter_map(|id| repo.account(id).ok())
+            .collect::<Vec<_>>();
+        if dry_run {
+            for account in &mut accounts {
+                for (id, delta) in &deltas {
+                    if *id == account.id {
+                        account.current = (account.current.clone() + delta.clone())?;
+                    }
                 }
-                (&Method::Get, &["transactions", account]) => {
-                    let Ok(account) = account.parse() else { err(request, 401, "Invalid account ID")?; continue };
-                    json(request, &repo.transactions(account)?)?
+            }
+        }
+        Ok(Ok(Ran {
+            description: command.to_string(),
+            command,
+            dry_run,
+            deltas: deltas
+                .into_iter()
+                .filter(|&(id, _)| scope.sees(id))
+                .collect(),
+            accounts,
+        }))
+    }
+
+    /// How `serve http` listens
+    #[derive(Debug, clap::Args)]
+    pub struct HttpOptions {
+        /// The address to listen on
+        #[arg(long, default_value = "127.0.0.1")]
+        bind: IpAddr,
+        #[arg(long, default_value_t = 8080)]
+        port: u16,
+        /// A PEM certificate chain to serve HTTPS with
+        #[arg(long, requires = "tls_key")]
+        tls_cert: Option<PathBuf>,
+        /// The PEM private key for `--tls-cert`
+        #[arg(long, requires = "tls_cert")]
+        tls_key: Option<PathBuf>,
+        /// Serve under this path, like `/monfari`, for running behind a reverse proxy
+        #[arg(long, value_parser = parse_base_path, default_value = "/")]
+        base_path: String,
+        /// How many requests to handle at once; commands still run one at a time
+        #[arg(long, default_value = "1")]
+        workers: NonZeroUsize,
+        /// A file of `NAME:PASSWORD` lines, for the users allowed in with HTTP basic auth
+        ///
+        /// Every request must then log in. Without it, anyone can use the server, but not `/export` or `/import`.
+        /// Each line may go on to limit what that user can do: `read-only`, and `account=ID` for each account they
+        /// can see and use, if not all of them.
+        #[arg(long, value_parser = parse_credentials)]
+        credentials: Option<Credentials>,
+    }
+
+    /// The users allowed in, their passwords, and what they can do
+    #[derive(Clone)]
+    pub struct Credentials(BTreeMap<String, (String, Scope)>);
+
+    /// What a user can do; by default, anything
+    #[derive(Debug, Clone, Default)]
+    struct Scope {
+        /// Only look, never change anything
+        read_only: bool,
+        /// The only accounts that can be seen or used, if not all of them
+        accounts: Option<BTreeSet<Id<Account>>>,
+    }
+
+    /// The scope of requests when there are no credentials to log in with
+    const EVERYTHING: Scope = Scope {
+        read_only: false,
+        accounts: None,
+    };
+
+    impl Scope {
+        fn sees(&self, id: Id<Account>) -> bool {
+            self.accounts.as_ref().is_none_or(|x| x.contains(&id))
+        }
+
+        /// Whether a command changing `changed` may be run
+        fn allows(&self, changed: &[Id<Account>]) -> bool {
+            // A command that names no accounts, such as renaming payees, could reach into any of them
+            self.accounts.is_none()
+                || (!changed.is_empty() && changed.iter().all(|&id| self.sees(id)))
+        }
+
+        /// `accounts`, less those that can't be seen
+        fn filter(&self, mut accounts: Vec<Account>) -> Vec<Account> {
+            accounts.retain(|x| self.sees(x.id));
+            accounts
+        }
+
+        /// `ack`, less the accounts that can't be seen, even if funding rules moved money into them
+        fn filter_ack(&self, ack: Ack) -> Ack {
+            Ack {
+                accounts: ack.accounts.map(|x| self.filter(x)),
+                changed: self.filter(ack.changed),
+                deltas: ack
+                    .deltas
+                    .into_iter()
+                    .filter(|&(id, _)| self.sees(id))
+                    .collect(),
+            }
+        }
+    }
+
+    // Passwords are kept out of logs
+    impl std::fmt::Debug for Credentials {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            f.debug_set().entries(self.0.keys()).finish()
+        }
+    }
+
+    fn parse_credentials(path: &str) -> Result<Credentials> {
+        let text = fs::read_to_string(path).wrap_err_with(|| format!("Couldn't read {path}"))?;
+        let users = text
+            .lines()
+            .map(str::trim)
+            .filter(|x| !x.is_empty() && !x.starts_with('#'))
+            .map(|line| {
+                let mut words = line.split_whitespace();
+                let (name, password) = words
+                    .next()
+                    .and_then(|x| x.split_once(':'))
+                    .filter(|(name, password)| !name.is_empty() && !password.is_empty())
+                    .ok_or_else(|| eyre!("Each line of {path} must start NAME:PASSWORD"))?;
+                let mut scope = Scope::default();
+                for word in words {
+                    match word.split_once('=') {
+                        None if word == "read-only" => scope.read_only = true,
+                        Some(("account", id)) => {
+                            let id = id.parse().map_err(|_| eyre!("Invalid account ID {id}"))?;
+                            scope.accounts.get_or_insert_with(BTreeSet::new).insert(id);
+                        }
+                        _ => bail!("{word} isn't read-only or account=ID"),
+                    }
                 }
-                (&Method::Post, &["__stop__"]) => break,
-                _ => err(request, 404, "Not Found")?,
+                Ok((name.to_owned(), (password.to_owned(), scope)))
+            })
+            .collect::<Result<BTreeMap<_, _>>>()?;
+        ensure!(!users.is_empty(), "{path} doesn't allow anyone in");
+        Ok(Credentials(users))
+    }
+
+    impl Credentials {
+        /// Who `request` logs in as with HTTP basic auth, and what they can do, if their password is right
+        fn user(&self, request: &Request) -> Option<(&str, &Scope)> {
+            let value = header(request, "Authorization")?;
+            let (scheme, encoded) = value.trim().split_once(' ')?;
+            if !scheme.eq_ignore_ascii_case("Basic") {
+                return None;
+            }
+            let decoded = String::from_utf8(BASE64.decode(encoded.trim()).ok()?).ok()?;
+            let (name, password) = decoded.split_once(':')?;
+            let (name, (expected, scope)) = self.0.get_key_value(name)?;
+            // Digests are compared, so how long that takes says nothing about the password
+            let digest = |x: &str| ring::digest::digest(&ring::digest::SHA256, x.as_bytes());
+            (digest(expected).as_ref() == digest(password).as_ref()).then_some((name, scope))
+        }
+    }
+
+    /// A base path without its trailing slash, so `/` is empty
+    fn parse_base_path(s: &str) -> Result<String> {
+        ensure!(s.starts_with('/'), "The base path must start with /");
+        ensure!(
+            !s.contains(['?', '#']),
+            "The base path can't have a query or fragment"
+        );
+        Ok(s.trim_end_matches('/').to_owned())
+    }
+
+    impl HttpOptions {
+        /// Where clients can reach the server
+        pub(super) fn url(&self) -> String {
+            let scheme = if self.tls_cert.is_some() {
+                "https"
+            } else {
+                "http"
             };
+            format!("{scheme}://{}:{}{}/", self.bind, self.port, self.base_path)
+        }
+
+        fn server(&self) -> Result<tiny_http::Server> {
+            let addr = SocketAddr::new(self.bind, self.port);
+            let ssl = match (&self.tls_cert, &self.tls_key) {
+                (Some(cert), Some(key)) => Some(tiny_http::SslConfig {
+                    certificate: pem(cert, "CERTIFICATE")?,
+                    private_key: pem(key, "PRIVATE KEY")?,
+                }),
+                _ => None,
+            };
+            tiny_http::Server::new(tiny_http::ServerConfig {
+                addr: tiny_http::ConfigListenAddr::IP(vec![addr]),
+                ssl,
+            })
+            .map_err(|e| eyre!("Couldn't listen on {addr}: {e}"))
+        }
+    }
+
+    /// The contents of a PEM file, checked to hold a `kind` so a mix-up doesn't surface as a TLS error later
+    fn pem(path: &Path, kind: &str) -> Result<Vec<u8>> {
+        let data = fs::read(path).wrap_err_with(|| format!("Couldn't read {}", path.display()))?;
+        ensure!(
+            String::from_utf8_lossy(&data)
+                .lines()
+                .any(|line| line.starts_with("-----BEGIN") && line.contains(kind)),
+            "{} isn't a PEM file containing a {}",
+            path.display(),
+            kind.to_lowercase()
+        );
+        Ok(data)
+    }
+
+    #[instrument]
+    pub fn serve_http(options: HttpOptions, repo: OsString) -> Result<()> {
+        let repo = Mutex::new(Repository::open(&repo)?);
+        let server = options.server()?;
+        let workers = options.workers.get();
+        thread::scope(|scope| {
+            let handles = (0..workers)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let result = work(&server, &repo, &options);
+                        // Whichever worker stops first, for whatever reason, stops the rest too
+                        for _ in 0..workers {
+                            server.unblock();
+                        }
+                        result
+                    })
+                })
+                .collect::<Vec<_>>();
+            handles
+                .into_iter()
+                .try_for_each(|handle| handle.join().expect("HTTP worker panicked"))
+        })
+    }
+
+    fn work(
+        server: &tiny_http::Server,
+        repo: &Mutex<Repository>,
+        options: &HttpOptions,
+    ) -> Result<()> {
+        for mut request in server.incoming_requests() {
+            // Clients may give their own ID, to find what they sent in the logs by
+            let request_id = header(&request, "X-Request-Id")
+                .filter(|x| {
+                    x.len() <= 64
+                        && x.chars()
+                            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+                })
+                .unwrap_or_else(request_id);
+            let _span = info_span!(
+                "request",
+                %request_id,
+                url = request.url(),
+                method = ?request.method()
+            )
+            .entered();
+            let url = request.url().to_owned();
+            let (path, query) = url.split_once('?').unwrap_or((&url, ""));
+            let path = path
+                .strip_prefix(options.base_path.as_str())
+                .filter(|x| x.is_empty() || x.starts_with('/'))
+                .map(|path| if path.is_empty() { "/" } else { path })
+                .map(|path| path.split('/').skip(1).collect::<Vec<&str>>());
+            let stop = matches!(
+                (request.method(), path.as_deref()),
+                (Method::Post, Some(&["__stop__"]))
+            );
+            let query = form_urlencoded::parse(query.as_bytes()).collect::<HashMap<_, _>>();
+            let login = options.credentials.as_ref().map(|x| x.user(&request));
+            let user = login.flatten();
+            let reply = match path {
+                // Once there are credentials, every request must log in
+                Some(_) if matches!(login, Some(None)) => unauthorized(),
+                // Only a login that can do anything may stop the server
+                Some(_) if stop => match user {
+                    Some((_, scope)) if scope.read_only || scope.accounts.is_some() => {
+                        err(403, "This login can't stop the server")
+                    }
+                    _ => break,
+                },
+                // A request that fails is answered, rather than stopping the server
+                Some(path) => route(&mut request, repo, &path, &query, user).unwrap_or_else(|e| {
+                    error!(error = ?e, "Failed");
+                    Response::from_string(format!(
+                        "Internal error; the server's logs have more under request {request_id}"
+                    ))
+                    .with_status_code(500)
+                    .boxed()
+                }),
+                None => err(404, "Not Found"),
+            };
+            let reply = reply.with_header(
+                Header::from_bytes(&b"X-Request-Id"[..], request_id.as_bytes()).unwrap(),
+            );
+            if let Err(e) = request.respond(reply) {
+                error!(error = ?e, "Couldn't reply");
+            }
         }
         Ok(())
     }
+
+    fn route(
+        request: &mut Request,
+        repo: &Mutex<Repository>,
+        path: &[&str],
+        query: &HashMap<Cow<str>, Cow<str>>,
+        login: Option<(&str, &Scope)>,
+    ) -> Result<Reply> {
+        let repo = || repo.lock().unwrap();
+        let (user, scope) = match login {
+            Some((user, scope)) => (Some(user), scope),
+            None => (None, &EVERYTHING),
+        };
+        if scope.read_only && *request.method() != Method::Get {
+            return Ok(err(403, "This login can only look"));
+        }
+        // What's about the whole repository can't be limited to some accounts
+        let whole_repository = matches!(
+            path,
+            ["accounts", "bulk-modify"]
+                | ["stats"]
+                | ["config"]
+                | ["schedule.ics"]
+                | ["search"]
+                | ["export"]
+                | ["import"]
+        ) || matches!(path, [kind] if stored(kind).is_some());
+        if scope.accounts.is_some() && whole_repository {
+            return Ok(err(403, "This login can only use some accounts"));
+        }
+        Ok(match (request.method(), path) {
+            (&Method::Get, &[""]) if query.get("activity").is_some_and(|x| x == "true") => {
+                let mut activity = repo().activity()?;
+                let accounts = scope
+                    .filter(repo().accounts()?)
+                    .into_iter()
+                    .map(|account| AccountWithActivity {
+                        activity: activity.remove(&account.id).unwrap_or_default(),
+                        account,
+                    })
+                    .collect::<Vec<_>>();
+                json(request, &accounts)?
+            }
+            (&Method::Get, &[""]) if query.contains_key("at") => {
+                let Ok(at) = query["at"].parse::<Point>();
+                json(request, scope.filter(repo().accounts_at(&at)?))?
+            }
+            (&Method::Get, &[""]) => {
+                let mut accounts = scope.filter(repo().accounts()?);
+                // Clients that keep a full copy of the account list never ask for this
+                if query.get("collapse").is_some_and(|x| x == "true") {
+                    let before = accounts.len();
+                    accounts.retain(|x| !x.is_collapsible());
+                    let collapsed = (before - accounts.len()).to_string();
+                    let header =
+                        Header::from_bytes(&b"X-Monfari-Collapsed"[..], collapsed.as_bytes())
+                            .unwrap();
+                    json_with(request, &accounts, [header])?
+                } else {
+                    json(request, &accounts)?
+                }
+            }
+            (&Method::Post, &[""]) => {
+                let Some("application/json") = request.headers().iter().rev().find(|x| x.field.equiv("Content-Type")).map(|x| x.value.as_str()) else { return Ok(err(401, "JSON is required")) };
+                let origin = origin(request, user);
+                let Ok(command) = serde_json::from_reader::<_, Command>(request.as_reader()) else { return Ok(err(401, "Invalid command")) };
+                let changed = changed_by(&repo(), &command)?;
+                if !scope.allows(&changed) {
+                    return Ok(err(403, "This login can't use that account"));
+                }
+                // So a mistyped amount isn't added unseen
+                if let Command::AddTransaction(transaction) = &command {
+                    let confirmed = query.get("confirm_large").is_some_and(|x| x == "true");
+                    if !confirmed && repo().config()?.large(transaction).is_some() {
+                        return Ok(err(
+                            409,
+                            "Unusually large; add confirm_large=true to add it",
+                        ));
+                    }
+                }
+                let new_currency = query.get("new_currency").is_some_and(|x| x == "true");
+                let deltas = repo().run_command_with(command, origin, new_currency)?;
+                // Older clients expect just the accounts
+                if query.get("deltas").is_some_and(|x| x == "true") {
+                    let changes_only = query.get("changes_only").is_some_and(|x| x == "true");
+                    let ack = ack(&repo(), &changed, deltas, changes_only)?;
+                    json(request, scope.filter_ack(ack))?
+                } else {
+                    json(request, scope.filter(repo().accounts()?))?
+                }
+            }
+            (&Method::Post, &["run"]) => {
+                let origin = origin(request, user);
+                let mut line = String::new();
+                let Ok(_) = request.as_reader().read_to_string(&mut line) else { return Ok(err(401, "The command must be text")) };
+                let confirm_large = query.get("confirm_large").is_some_and(|x| x == "true");
+                match run_line(&mut repo(), line.trim(), origin, scope, confirm_large)? {
+                    Ok(ran) => json(request, ran)?,
+                    Err(refused) => json_status(request, 400, refused, [])?,
+                }
+            }
+            (&Method::Get, &["accounts", id]) => {
+                let Ok(id) = id.parse() else { return Ok(err(401, "Invalid account ID")) };
+                if !scope.sees(id) {
+                    return Ok(err(404, "No such account"));
+                }
+                match repo().account(id) {
+                    Ok(account) => json(request, &account)?,
+                    Err(_) => err(404, "No such account"),
+                }
+            }
+            (&Method::Post, &["accounts", "bulk-modify"]) => {
+                let origin = origin(request, user);
+                let Ok(BulkModify { selector, changes }) = serde_json::from_reader(request.as_reader()) else { return Ok(err(401, "Invalid modification")) };
+                json(request, &repo().bulk_modify(&selector, changes, origin)?)?
+            }
+            (&Method::Get, &["accounts", id, "log"]) => {
+                let Ok(id) = id.parse() else { return Ok(err(401, "Invalid account ID")) };
+                if !scope.sees(id) {
+                    return Ok(err(404, "No such account"));
+                }
+                json(request, &repo().account_log(id)?)?
+            }
+            (&Method::Get, &["transactions", account]) => {
+                let Ok(account) = account.parse() else { return Ok(err(401, "Invalid account ID")) };
+                if !scope.sees(account) {
+                    return Ok(err(404, "No such account"));
+                }
+                // For clients that show transactions a screenful at a time
+                let after = query.get("after").map(|x| x.parse()).transpose();
+                let before = query.get("before").map(|x| x.parse()).transpose();
+                let limit = query.get("limit").map(|x| x.parse()).transpose();
+                let (Ok(after), Ok(before), Ok(limit)) = (after, before, limit) else { return Ok(err(401, "Invalid page")) };
+                let page = Page {
+                    after,
+                    before,
+                    limit,
+                };
+                let transactions = repo().transactions_page(account, page)?;
+                // Accept is a list that may be weighted, but anything asking for CSV, short of refusing it, gets it
+                let vary = Header::from_bytes(&b"Vary"[..], &b"Accept"[..]).unwrap();
+                if accepts(request, "text/csv") {
+                    let content_type =
+                        Header::from_bytes(&b"Content-Type"[..], &b"text/csv; charset=utf-8"[..])
+                            .unwrap();
+                    Response::from_string(crate::export::csv(account, &transactions)?)
+                        .with_header(content_type)
+                        .with_header(vary)
+                        .boxed()
+                } else {
+                    json_with(request, &transactions, [vary])?
+                }
+            }
+            (&Method::Get, &["export"]) => {
+                // Everything is given away, so only to someone who's logged in
+                let Some(user) = user else {
+                    return Ok(err(403, "Exports need the server to have --credentials"));
+                };
+                let with_snapshot = query.get("with_snapshot").is_some_and(|x| x == "true");
+                let export = Export::new(&repo(), &Selection::default(), with_snapshot)?;
+                debug!(user, "Exporting");
+                let content_type =
+                    Header::from_bytes(&b"Content-Type"[..], &b"application/x-ndjson"[..]).unwrap();
+                // Without a length, the export is sent in chunks as it's written
+                Response::new(
+                    StatusCode(200),
+                    vec![content_type],
+                    export.ndjson()?,
+                    None,
+                    None,
+                )
+                .boxed()
+            }
+            (&Method::Post, &["import"]) => {
+                let Some(user) = user else {
+                    return Ok(err(403, "Imports need the server to have --credentials"));
+                };
+                let on_conflict = match query.get("on_conflict").map(|x| x.as_ref()) {
+                    None | Some("abort") => OnConflict::Abort,
+                    Some("skip") => OnConflict::Skip,
+                    // There's no one to ask
+                    Some(_) => return Ok(err(401, "Conflicts can only abort or skip")),
+                };
+                let origin = origin(request, Some(user));
+                // A stream of commands, or a whole export, as either would be written
+                let Ok(export) = Export::read(request.as_reader()) else {
+                    return Ok(err(401, "Invalid export"));
+                };
+                match export.import(&mut repo(), on_conflict, None, false, &origin) {
+                    Ok(summary) => json(request, summary)?,
+                    // The batches before the failure stay imported, and are skipped if the export is sent again
+                    Err(e) => Response::from_string(format!("{e:#}"))
+                        .with_status_code(409)
+                        .boxed(),
+                }
+            }
+            (&Method::Get, &["stats"]) => json(request, &repo().stats()?)?,
+            (&Method::Get, &["config"]) => json(request, &repo().config()?)?,
+            (&Method::Get, &[kind]) if stored(kind).is_some() => {
+                let list = stored(kind).expect("Checked to be stored");
+                json(request, &list(&repo())?)?
+            }
+            (&Method::Get, &["schedule.ics"]) => {
+                let content_type =
+                    Header::from_bytes(&b"Content-Type"[..], &b"text/calendar; charset=utf-8"[..])
+                        .unwrap();
+                let calendar = crate::schedule::ics(&repo().schedules()?, crate::schedule::today());
+                Response::from_string(calendar)
+                    .with_header(content_type)
+                    .boxed()
+            }
+            (&Method::Get, &["search"]) => {
+                let Some(q) = query.get("q") else { return Ok(err(401, "Query is required")) };
+                json(request, &repo().search(q)?)?
+            }
+            _ => err(404, "Not Found"),
+        })
+    }
 }
 
+pub use http::HttpOptions;
+
 #[instrument]
 pub fn serve(mode: crate::ServeMode, repo: OsString) -> Result<()> {
+    let address = match &mode {
+        crate::ServeMode::Stdio => None,
+        crate::ServeMode::Bind { addr } => Some(format!("tcp:{addr}")),
+        crate::ServeMode::Http(options) => Some(options.url()),
+        #[cfg(unix)]
+        crate::ServeMode::Systemd => Some("a systemd socket".to_owned()),
+    };
+    // Each stdio session is its own short-lived process, so isn't worth telling others about
+    let _serving = match address {
+        Some(address) => super::serving(&repo, &address)?,
+        None => None,
+    };
     match mode {
-        crate::ServeMode::Stdio => run_session(Connection::new(stdin(), stdout()), &repo),
+        crate::ServeMode::Stdio => run_session(Connection::new(stdin(), stdout()), &repo, "stdio"),
         crate::ServeMode::Bind { addr } => serve_listener(TcpListener::bind(addr)?, repo),
-        crate::ServeMode::Http { addr } => http::serve_http(addr, repo),
+        crate::ServeMode::Http(options) => http::serve_http(options, repo),
         #[cfg(unix)]
         crate::ServeMode::Systemd => systemd::serve_systemd_listener(repo),
     }
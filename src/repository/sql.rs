@@ -1,18 +1,35 @@
-use std::{fmt::Display, str::FromStr};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env,
+    fmt::Display,
+    fs,
+    ops::RangeInclusive,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
 
+use super::{Filter, Page, Point, Stored};
 use crate::{
-    command::{AccountModification, Command},
-    types::{Account, AccountType, Amount, Id, Transaction, TransactionInner},
+    command::{edit, edit_deltas, void, AccountModification, Command, LogEntry, Origin},
+    types::{
+        Account, AccountType, Activity, Amount, Amounts, Config, Currency, Id, SearchResult,
+        Transaction, TransactionInner,
+    },
 };
+use chrono::NaiveDate;
 use exemplar::Model;
-use eyre::{Result, bail};
+use itertools::Itertools;
+use eyre::{Context, Result, bail, ensure, eyre};
 use rusqlite::{
     params, params_from_iter,
     types::{FromSql, FromSqlError},
-    Connection, ToSql,
+    Connection, ErrorCode, OptionalExtension, ToSql,
 };
-use rusqlite_migration::{Migrations, M};
-use tracing::instrument;
+use rusqlite_migration::{HookError, Migrations, M};
+use tracing::{instrument, warn};
 
 #[derive(Debug)]
 pub(super) struct SqlRepository {
@@ -37,12 +54,27 @@ macro_rules! to_from_sql {
 }
 
 to_from_sql! {
-    Id<T>;
     Amount;
+    Currency;
     AccountType;
     TransactionType;
 }
 
+// IDs are stored the same way however they're shown
+impl<T> FromSql for Id<T> {
+    fn column_result(value: rusqlite::types::ValueRef<'_>) -> rusqlite::types::FromSqlResult<Self> {
+        value
+            .as_str()?
+            .parse::<Self>()
+            .map_err(|err| FromSqlError::Other(err.into()))
+    }
+}
+impl<T> ToSql for Id<T> {
+    fn to_sql(&self) -> rusqlite::Result<rusqlite::types::ToSqlOutput<'_>> {
+        Ok(self.proquint().into())
+    }
+}
+
 #[derive(Copy, Clone, Eq, PartialEq, Hash, Debug)]
 enum TransactionType {
     Received,
@@ -50,6 +82,9 @@ enum TransactionType {
     MovePhys,
     MoveVirt,
     Convert,
+    Adjustment,
+    General,
+    Remap,
 }
 
 impl Display for TransactionType {
@@ -60,6 +95,9 @@ impl Display for TransactionType {
             TransactionType::MovePhys => "MovePhys",
             TransactionType::MoveVirt => "MoveVirt",
             TransactionType::Convert => "Convert",
+            TransactionType::Adjustment => "Adjustment",
+            TransactionType::General => "General",
+            TransactionType::Remap => "Remap",
         }.fmt(f)
     }
 }
@@ -74,6 +112,9 @@ impl FromStr for TransactionType {
             "MovePhys" => Self::MovePhys,
             "MoveVirt" => Self::MoveVirt,
             "Convert" => Self::Convert,
+            "Adjustment" => Self::Adjustment,
+            "General" => Self::General,
+            "Remap" => Self::Remap,
             s => bail!("Invalid transaction_type {s}")
         })
     }
@@ -87,10 +128,26 @@ struct TransactionDb {
     #[column("type")]
     typ: TransactionType,
     new_amount: Option<Amount>,
+    fee: Option<Amount>,
     external_party: Option<String>,
     acc_1: Id<Account>,
     acc_2: Id<Account>,
     notes: String,
+    /// JSON
+    attachments: String,
+    /// JSON
+    debts: String,
+    /// JSON, for general transactions; the first two accounts in them are kept in `acc_1` and `acc_2` too
+    legs: String,
+    voided: Option<Id<Transaction>>,
+    /// JSON; each is in `transaction_tags` too, to find them by
+    tags: String,
+    /// The account on the other side of a move whose money it was, or the virtual account a remap is of
+    held: Option<Id<Account>>,
+    /// YYYY-MM-DD, if it was given one
+    date: Option<String>,
+    /// YYYY-MM-DD, local; what it's dated, given or not, to find it by
+    day: String,
 }
 
 impl TransactionDb {
@@ -101,15 +158,29 @@ impl TransactionDb {
             amount,
             typ,
             new_amount,
+            fee,
             external_party,
             acc_1,
             acc_2,
             notes,
+            attachments,
+            debts,
+            legs,
+            voided,
+            tags,
+            held,
+            date,
+            day: _,
         } = self;
         Ok(Transaction {
             id,
             notes,
             amount,
+            attachments: serde_json::from_str(&attachments)?,
+            debts: serde_json::from_str(&debts)?,
+            date: date.map(|x| x.parse()).transpose()?,
+            voided,
+            tags: serde_json::from_str(&tags)?,
             inner: match typ {
                 TransactionType::Received => TransactionInner::Received {
                     src: external_party.ok_or_else(|| {
@@ -128,10 +199,12 @@ impl TransactionDb {
                 TransactionType::MovePhys => TransactionInner::MovePhys {
                     src: acc_1.unerase(),
                     dst: acc_2.unerase(),
+                    virt: held.map(|x| x.unerase()),
                 },
                 TransactionType::MoveVirt => TransactionInner::MoveVirt {
                     src: acc_1.unerase(),
                     dst: acc_2.unerase(),
+                    phys: held.map(|x| x.unerase()),
                 },
                 TransactionType::Convert => TransactionInner::Convert {
                     acc: acc_1.unerase(),
@@ -139,6 +212,24 @@ impl TransactionDb {
                     new_amount: new_amount.ok_or_else(|| {
                         eyre::eyre!("`new_amount` is required for `convert` transactions")
                     })?,
+                    fee,
+                },
+                TransactionType::Adjustment => TransactionInner::Adjustment {
+                    acc: acc_1.unerase(),
+                    acc_virt: acc_2.unerase(),
+                    reason: external_party.ok_or_else(|| {
+                        eyre::eyre!("`external_party` is required for `adjustment` transactions")
+                    })?,
+                },
+                TransactionType::General => TransactionInner::General {
+                    legs: serde_json::from_str(&legs)?,
+                },
+                TransactionType::Remap => TransactionInner::Remap {
+                    virt: held
+                        .ok_or_else(|| eyre::eyre!("`held` is required for `remap` transactions"))?
+                        .unerase(),
+                    src: acc_1.unerase(),
+                    dst: acc_2.unerase(),
                 },
             },
         })
@@ -154,6 +245,8 @@ struct AccountDb {
     name: String,
     notes: String,
     enabled: bool,
+    institution: Option<String>,
+    deleted: bool,
 }
 
 impl AccountDb {
@@ -168,16 +261,19 @@ impl AccountDb {
             name,
             notes,
             enabled,
+            institution,
+            deleted,
         } = self;
-        let current = transactions
-            .into_iter()
-            .flat_map(|t| {
-                t.results()
-                    .into_iter()
-                    .filter(|(acc, _)| acc == &id)
-                    .map(|(_, amount)| amount)
-            })
-            .sum();
+        let mut current = Amounts::default();
+        for transaction in transactions {
+            for (_, amount) in transaction
+                .results()?
+                .into_iter()
+                .filter(|(acc, _)| acc == &id)
+            {
+                current.add_amount(amount)?;
+            }
+        }
         Ok(Account {
             id,
             name,
@@ -185,57 +281,737 @@ impl AccountDb {
             typ,
             current,
             enabled,
+            institution,
+            deleted,
         })
     }
 }
 
-const MIGRATIONS: &[M] = &[M::up(
-    r#"
-        CREATE TABLE accounts (
-        	id TEXT NOT NULL PRIMARY KEY,
-        	type TEXT NOT NULL,
-        	name TEXT NOT NULL,
-        	notes TEXT NOT NULL DEFAULT '',
-        	enabled INT NOT NULL DEFAULT TRUE
-        ) STRICT;
-
-        CREATE TABLE transactions (
-        	id TEXT NOT NULL PRIMARY KEY,
-        	amount TEXT NOT NULL,
-        	type TEXT NOT NULL, -- Received, Paid, MovePhys, MoveVirt, Convert
-        	new_amount TEXT, -- Convert only
-        	external_party TEXT, -- src ordst for received and paid respectively
-        	acc_1 TEXT NOT NULL REFERENCES accounts (id), -- phys acc for {,_virt} types, src for moves
-        	acc_2 TEXT NOT NULL REFERENCES accounts (id), -- virt acc for {,_virt} types, dst for moves
-        	notes TEXT NOT NULL DEFAULT ''
-        ) STRICT;
-
-        CREATE TABLE commands (
-        	id TEXT NOT NULL PRIMARY KEY,
-        	command TEXT NOT NULL
-        ) STRICT;
-    "#,
-)];
+fn migrations() -> Migrations<'static> {
+    Migrations::new(vec![
+        M::up(
+            r#"
+            CREATE TABLE accounts (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	type TEXT NOT NULL,
+            	name TEXT NOT NULL,
+            	notes TEXT NOT NULL DEFAULT '',
+            	enabled INT NOT NULL DEFAULT TRUE
+            ) STRICT;
+
+            CREATE TABLE transactions (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	amount TEXT NOT NULL,
+            	type TEXT NOT NULL, -- Received, Paid, MovePhys, MoveVirt, Convert
+            	new_amount TEXT, -- Convert only
+            	external_party TEXT, -- src ordst for received and paid respectively
+            	acc_1 TEXT NOT NULL REFERENCES accounts (id), -- phys acc for {,_virt} types, src for moves
+            	acc_2 TEXT NOT NULL REFERENCES accounts (id), -- virt acc for {,_virt} types, dst for moves
+            	notes TEXT NOT NULL DEFAULT ''
+            ) STRICT;
+
+            CREATE TABLE commands (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	command TEXT NOT NULL
+            ) STRICT;
+        "#,
+        ),
+        M::up(
+            r#"
+            CREATE VIRTUAL TABLE notes_search USING fts5(
+            	kind UNINDEXED, -- account or transaction
+            	id UNINDEXED,
+            	notes
+            );
+
+            INSERT INTO notes_search SELECT 'account', id, notes FROM accounts;
+            INSERT INTO notes_search SELECT 'transaction', id, notes FROM transactions;
+        "#,
+        ),
+        M::up_with_hook(
+            r#"
+            ALTER TABLE commands ADD COLUMN timestamp TEXT; -- RFC 3339
+            ALTER TABLE commands ADD COLUMN user TEXT;
+            ALTER TABLE commands ADD COLUMN client TEXT;
+        "#,
+            |transaction: &rusqlite::Transaction| {
+                // Command IDs are ULIDs, so existing commands can be given the time they were created
+                let ids = transaction
+                    .prepare("SELECT id FROM commands WHERE timestamp IS NULL")?
+                    .query_map(params![], |row| row.get::<_, Id<Command>>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                for id in ids {
+                    transaction.execute(
+                        "UPDATE commands SET timestamp = ? WHERE id = ?",
+                        params![timestamp(id), id],
+                    )?;
+                }
+                Ok::<_, HookError>(())
+            },
+        ),
+        M::up(
+            r#"
+            ALTER TABLE transactions ADD COLUMN fee TEXT; -- Convert only
+        "#,
+        ),
+        M::up(
+            r#"
+            CREATE TABLE config (
+            	id INT NOT NULL PRIMARY KEY CHECK (id = 0), -- there is only one
+            	config TEXT NOT NULL -- JSON
+            ) STRICT;
+        "#,
+        ),
+        M::up(
+            r#"
+            ALTER TABLE transactions ADD COLUMN attachments TEXT NOT NULL DEFAULT '[]'; -- JSON
+        "#,
+        ),
+        M::up_with_hook(
+            r#"
+            CREATE TABLE balance_history (
+            	account TEXT NOT NULL REFERENCES accounts (id),
+            	day TEXT NOT NULL, -- YYYY-MM-DD, UTC
+            	currency TEXT NOT NULL,
+            	balance INT NOT NULL, -- at the end of the day
+            	PRIMARY KEY (account, day, currency)
+            ) STRICT;
+        "#,
+            |transaction: &rusqlite::Transaction| {
+                let transactions = transaction
+                    .prepare(
+                        r#"
+                        SELECT
+                            id,
+                            amount,
+                            type,
+                            new_amount,
+                            fee,
+                            external_party,
+                            acc_1,
+                            acc_2,
+                            notes,
+                            attachments,
+                            '[]' AS debts,
+                            '[]' AS legs,
+                            NULL AS voided,
+                            '[]' AS tags,
+                            NULL AS held,
+                            NULL AS date,
+                            '' AS day
+                        FROM transactions
+                        ORDER BY id
+                    "#,
+                    )?
+                    .query_and_then(params![], TransactionDb::from_row)?
+                    .map(|x| x?.to_transaction())
+                    .collect::<Result<Vec<_>>>()
+                    .map_err(|e| HookError::Hook(e.to_string()))?;
+                for t in &transactions {
+                    record_balances(transaction, t).map_err(|e| HookError::Hook(e.to_string()))?;
+                }
+                Ok::<_, HookError>(())
+            },
+        ),
+        // SQLite can only add constraints to a table by building it again
+        M::up_with_hook(
+            r#"
+            CREATE TABLE accounts_new (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	type TEXT NOT NULL CHECK (type IN ('physical', 'virtual')),
+            	name TEXT NOT NULL,
+            	notes TEXT NOT NULL DEFAULT '',
+            	enabled INT NOT NULL DEFAULT TRUE CHECK (enabled IN (0, 1))
+            ) STRICT;
+            INSERT INTO accounts_new (id, type, name, notes, enabled)
+            	SELECT id, type, name, notes, enabled FROM accounts;
+            DROP TABLE accounts;
+            ALTER TABLE accounts_new RENAME TO accounts;
+
+            CREATE TABLE transactions_new (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	amount TEXT NOT NULL CHECK (amount GLOB '*[0-9] [A-Z][A-Z][A-Z]'),
+            	type TEXT NOT NULL CHECK (type IN ('Received', 'Paid', 'MovePhys', 'MoveVirt', 'Convert', 'Adjustment')),
+            	new_amount TEXT CHECK (CASE type
+            		WHEN 'Convert' THEN coalesce(new_amount GLOB '*[0-9] [A-Z][A-Z][A-Z]', FALSE)
+            		ELSE new_amount IS NULL
+            	END),
+            	fee TEXT CHECK (fee IS NULL OR (type = 'Convert' AND fee GLOB '*[0-9] [A-Z][A-Z][A-Z]')),
+            	external_party TEXT CHECK ((type IN ('Received', 'Paid', 'Adjustment')) = (external_party IS NOT NULL)),
+            	acc_1 TEXT NOT NULL REFERENCES accounts (id),
+            	acc_2 TEXT NOT NULL REFERENCES accounts (id),
+            	notes TEXT NOT NULL DEFAULT '',
+            	attachments TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(attachments))
+            ) STRICT;
+            INSERT INTO transactions_new (id, amount, type, new_amount, fee, external_party, acc_1, acc_2, notes, attachments)
+            	SELECT id, amount, type, new_amount, fee, external_party, acc_1, acc_2, notes, attachments FROM transactions;
+            DROP TABLE transactions;
+            ALTER TABLE transactions_new RENAME TO transactions;
+        "#,
+            |transaction: &rusqlite::Transaction| {
+                // Foreign keys weren't enforced before, so there may be references to accounts that don't exist
+                let broken = transaction
+                    .prepare("PRAGMA foreign_key_check")?
+                    .query_map(params![], |row| row.get::<_, String>(0))?
+                    .collect::<rusqlite::Result<Vec<_>>>()?;
+                if !broken.is_empty() {
+                    return Err(HookError::Hook(format!(
+                        "{} rows refer to accounts that don't exist, in {}",
+                        broken.len(),
+                        broken.into_iter().unique().join(", ")
+                    )));
+                }
+                Ok(())
+            },
+        ),
+        M::up(
+            r#"
+            -- Deleted accounts are kept as tombstones, so the commands that made them can still be replayed
+            ALTER TABLE accounts ADD COLUMN deleted INT NOT NULL DEFAULT FALSE CHECK (deleted IN (0, 1));
+        "#,
+        ),
+        M::up(
+            r#"
+            ALTER TABLE transactions ADD COLUMN debts TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(debts));
+        "#,
+        ),
+        M::up(
+            r#"
+            ALTER TABLE accounts ADD COLUMN institution TEXT CHECK (institution IS NULL OR type = 'physical');
+        "#,
+        ),
+        // Only building the table again lets general transactions past the check on types
+        M::up(
+            r#"
+            CREATE TABLE transactions_new (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	amount TEXT NOT NULL CHECK (amount GLOB '*[0-9] [A-Z][A-Z][A-Z]'),
+            	type TEXT NOT NULL CHECK (type IN ('Received', 'Paid', 'MovePhys', 'MoveVirt', 'Convert', 'Adjustment', 'General')),
+            	new_amount TEXT CHECK (CASE type
+            		WHEN 'Convert' THEN coalesce(new_amount GLOB '*[0-9] [A-Z][A-Z][A-Z]', FALSE)
+            		ELSE new_amount IS NULL
+            	END),
+            	fee TEXT CHECK (fee IS NULL OR (type = 'Convert' AND fee GLOB '*[0-9] [A-Z][A-Z][A-Z]')),
+            	external_party TEXT CHECK ((type IN ('Received', 'Paid', 'Adjustment')) = (external_party IS NOT NULL)),
+            	acc_1 TEXT NOT NULL REFERENCES accounts (id),
+            	acc_2 TEXT NOT NULL REFERENCES accounts (id),
+            	notes TEXT NOT NULL DEFAULT '',
+            	attachments TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(attachments)),
+            	debts TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(debts)),
+            	legs TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(legs) AND (type = 'General') = (legs != '[]'))
+            ) STRICT;
+            INSERT INTO transactions_new (id, amount, type, new_amount, fee, external_party, acc_1, acc_2, notes, attachments, debts)
+            	SELECT id, amount, type, new_amount, fee, external_party, acc_1, acc_2, notes, attachments, debts FROM transactions;
+            DROP TABLE transactions;
+            ALTER TABLE transactions_new RENAME TO transactions;
+        "#,
+        ),
+        M::up(
+            r#"
+            ALTER TABLE transactions ADD COLUMN voided TEXT REFERENCES transactions (id);
+        "#,
+        ),
+        M::up(
+            r#"
+            ALTER TABLE transactions ADD COLUMN tags TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(tags));
+            CREATE TABLE transaction_tags (
+            	id TEXT NOT NULL REFERENCES transactions (id),
+            	tag TEXT NOT NULL,
+            	PRIMARY KEY (id, tag)
+            ) STRICT;
+            CREATE INDEX transaction_tags_by_tag ON transaction_tags (tag);
+        "#,
+        ),
+        // Only building the table again lets remaps past the check on types
+        M::up(
+            r#"
+            CREATE TABLE transactions_new (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	amount TEXT NOT NULL CHECK (amount GLOB '*[0-9] [A-Z][A-Z][A-Z]'),
+            	type TEXT NOT NULL CHECK (type IN ('Received', 'Paid', 'MovePhys', 'MoveVirt', 'Convert', 'Adjustment', 'General', 'Remap')),
+            	new_amount TEXT CHECK (CASE type
+            		WHEN 'Convert' THEN coalesce(new_amount GLOB '*[0-9] [A-Z][A-Z][A-Z]', FALSE)
+            		ELSE new_amount IS NULL
+            	END),
+            	fee TEXT CHECK (fee IS NULL OR (type = 'Convert' AND fee GLOB '*[0-9] [A-Z][A-Z][A-Z]')),
+            	external_party TEXT CHECK ((type IN ('Received', 'Paid', 'Adjustment')) = (external_party IS NOT NULL)),
+            	acc_1 TEXT NOT NULL REFERENCES accounts (id),
+            	acc_2 TEXT NOT NULL REFERENCES accounts (id),
+            	notes TEXT NOT NULL DEFAULT '',
+            	attachments TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(attachments)),
+            	debts TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(debts)),
+            	legs TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(legs) AND (type = 'General') = (legs != '[]')),
+            	voided TEXT REFERENCES transactions (id),
+            	tags TEXT NOT NULL DEFAULT '[]' CHECK (json_valid(tags)),
+            	held TEXT REFERENCES accounts (id) CHECK (CASE type
+            		WHEN 'Remap' THEN held IS NOT NULL
+            		ELSE held IS NULL OR type IN ('MovePhys', 'MoveVirt')
+            	END)
+            ) STRICT;
+            INSERT INTO transactions_new (id, amount, type, new_amount, fee, external_party, acc_1, acc_2, notes, attachments, debts, legs, voided, tags)
+            	SELECT id, amount, type, new_amount, fee, external_party, acc_1, acc_2, notes, attachments, debts, legs, voided, tags FROM transactions;
+            DROP TABLE transactions;
+            ALTER TABLE transactions_new RENAME TO transactions;
+        "#,
+        ),
+        M::up_with_hook(
+            r#"
+            ALTER TABLE transactions ADD COLUMN date TEXT; -- YYYY-MM-DD, if given
+            ALTER TABLE transactions ADD COLUMN day TEXT NOT NULL DEFAULT ''; -- YYYY-MM-DD, local; the date, or when it was added
+            CREATE INDEX transactions_by_day ON transactions (day);
+            -- Balances were kept by UTC day, and are kept by each transaction's date now
+            DELETE FROM balance_history;
+        "#,
+            |transaction: &rusqlite::Transaction| {
+                let transactions = transaction
+                    .prepare(
+                        r#"
+                        SELECT
+                            id,
+                            amount,
+                            type,
+                            new_amount,
+                            fee,
+                            external_party,
+                            acc_1,
+                            acc_2,
+                            notes,
+                            attachments,
+                            debts,
+                            legs,
+                            voided,
+                            tags,
+                            held,
+                            date,
+                            day
+                        FROM transactions
+                        ORDER BY id
+                    "#,
+                    )?
+                    .query_and_then(params![], TransactionDb::from_row)?
+                    .map(|x| x?.to_transaction())
+                    .collect::<Result<Vec<_>>>()
+                    .map_err(|e| HookError::Hook(e.to_string()))?;
+                for t in &transactions {
+                    transaction.execute(
+                        "UPDATE transactions SET day = ? WHERE id = ?",
+                        params![t.date().to_string(), t.id],
+                    )?;
+                    record_balances(transaction, t).map_err(|e| HookError::Hook(e.to_string()))?;
+                }
+                Ok::<_, HookError>(())
+            },
+        ),
+        M::up(
+            r#"
+            CREATE TABLE schedules (
+            	id TEXT NOT NULL PRIMARY KEY,
+            	schedule TEXT NOT NULL CHECK (json_valid(schedule))
+            ) STRICT;
+        "#,
+        ),
+        // Every kind of entity kept whole, schedules among them, in one table
+        M::up(
+            r#"
+            CREATE TABLE entities (
+            	kind TEXT NOT NULL,
+            	id TEXT NOT NULL,
+            	entity TEXT NOT NULL CHECK (json_valid(entity)),
+            	PRIMARY KEY (kind, id)
+            ) STRICT;
+            INSERT INTO entities (kind, id, entity) SELECT 'schedules', id, schedule FROM schedules;
+            DROP TABLE schedules;
+        "#,
+        ),
+    ])
+}
+
+/// Add `entity`, or replace the one of its kind with its ID
+fn put<T: Stored>(db: &Connection, entity: &T) -> Result<()> {
+    db.prepare_cached("INSERT OR REPLACE INTO entities (kind, id, entity) VALUES (?, ?, ?)")?
+        .execute(params![
+            T::PATH,
+            entity.id(),
+            serde_json::to_string(entity)?
+        ])?;
+    Ok(())
+}
+
+fn entity<T: Stored>(db: &Connection, id: Id<T>) -> Result<T> {
+    let entity = db
+        .prepare_cached("SELECT entity FROM entities WHERE kind = ? AND id = ?")?
+        .query_row(params![T::PATH, id], |row| row.get::<_, String>(0))
+        .optional()?
+        .ok_or_else(|| eyre!("No {} {id}", T::NAME))?;
+    Ok(serde_json::from_str(&entity)?)
+}
+
+fn remove<T: Stored>(db: &Connection, id: Id<T>) -> Result<()> {
+    let removed = db
+        .prepare_cached("DELETE FROM entities WHERE kind = ? AND id = ?")?
+        .execute(params![T::PATH, id])?;
+    ensure!(removed == 1, "No {} {id}", T::NAME);
+    Ok(())
+}
+
+/// Add the changes `transaction` makes to the daily balances of the accounts it affects
+fn record_balances(db: &Connection, transaction: &Transaction) -> Result<()> {
+    let day = transaction.date().to_string();
+    for (account, Amount(amount, currency)) in transaction.results()? {
+        // A day's balance starts from where the last day before it ended
+        db.prepare_cached(
+            r#"
+            INSERT OR IGNORE INTO balance_history (account, day, currency, balance)
+            VALUES (?1, ?2, ?3, coalesce((
+                SELECT balance FROM balance_history
+                WHERE account = ?1 AND currency = ?3 AND day < ?2
+                ORDER BY day DESC
+                LIMIT 1
+            ), 0))
+        "#,
+        )?
+        .execute(params![account, day, currency])?;
+        db.prepare_cached(
+            "UPDATE balance_history SET balance = balance + ? WHERE account = ? AND currency = ? AND day >= ?",
+        )?
+        .execute(params![amount, account, currency, day])?;
+    }
+    Ok(())
+}
+
+/// How many transactions involve account `?1`
+const USED: &str = r#"
+    SELECT count(*) FROM transactions
+    WHERE acc_1 = ?1 OR acc_2 = ?1 OR held = ?1 OR (type = 'General' AND ?1 IN (
+        SELECT value ->> '$.party.account' FROM json_each(legs)
+    ))
+"#;
+
+/// Store `t` as it is, without touching any balances
+fn insert_transaction(db: &Connection, t: Transaction) -> Result<()> {
+    let accounts = t.accounts();
+    let searched = t.all_notes();
+    let legs = match &t.inner {
+        TransactionInner::General { legs } => serde_json::to_string(legs)?,
+        _ => "[]".to_owned(),
+    };
+    let day = t.date().to_string();
+    let held = match t.inner {
+        TransactionInner::MovePhys { virt, .. } => virt.map(|x| x.erase()),
+        TransactionInner::MoveVirt { phys, .. } => phys.map(|x| x.erase()),
+        TransactionInner::Remap { virt, .. } => Some(virt.erase()),
+        _ => None,
+    };
+    let Transaction {
+        id,
+        notes,
+        amount,
+        inner,
+        attachments,
+        debts,
+        date,
+        voided,
+        tags,
+    } = t;
+    let (typ, acc_1, acc_2, external_party, new_amount, fee) = match inner {
+        TransactionInner::Received { src, dst, dst_virt } => (
+            TransactionType::Received,
+            dst.erase(),
+            dst_virt.erase(),
+            Some(src),
+            None,
+            None,
+        ),
+        TransactionInner::Paid { src, src_virt, dst } => (
+            TransactionType::Paid,
+            src.erase(),
+            src_virt.erase(),
+            Some(dst),
+            None,
+            None,
+        ),
+        TransactionInner::MovePhys { src, dst, .. } => (
+            TransactionType::MovePhys,
+            src.erase(),
+            dst.erase(),
+            None,
+            None,
+            None,
+        ),
+        TransactionInner::MoveVirt { src, dst, .. } => (
+            TransactionType::MoveVirt,
+            src.erase(),
+            dst.erase(),
+            None,
+            None,
+            None,
+        ),
+        TransactionInner::Convert {
+            acc,
+            acc_virt,
+            new_amount,
+            fee,
+        } => (
+            TransactionType::Convert,
+            acc.erase(),
+            acc_virt.erase(),
+            None,
+            Some(new_amount),
+            fee,
+        ),
+        TransactionInner::Adjustment {
+            acc,
+            acc_virt,
+            reason,
+        } => (
+            TransactionType::Adjustment,
+            acc.erase(),
+            acc_virt.erase(),
+            Some(reason),
+            None,
+            None,
+        ),
+        TransactionInner::General { .. } => (
+            TransactionType::General,
+            accounts[0],
+            accounts[1],
+            None,
+            None,
+            None,
+        ),
+        TransactionInner::Remap { virt: _, src, dst } => (
+            TransactionType::Remap,
+            src.erase(),
+            dst.erase(),
+            None,
+            None,
+            None,
+        ),
+    };
+    db.prepare_cached("INSERT INTO notes_search VALUES ('transaction', ?, ?)")?
+        .execute(params![id, searched])?;
+    TransactionDb {
+        id,
+        amount,
+        typ,
+        new_amount,
+        fee,
+        external_party,
+        acc_1,
+        acc_2,
+        notes,
+        attachments: serde_json::to_string(&attachments)?,
+        debts: serde_json::to_string(&debts)?,
+        legs,
+        voided,
+        tags: serde_json::to_string(&tags)?,
+        held,
+        date: date.map(|x| x.to_string()),
+        day,
+    }
+    .insert(db)?;
+    for tag in &tags {
+        db.prepare_cached("INSERT INTO transaction_tags (id, tag) VALUES (?, ?)")?
+            .execute(params![id, tag])?;
+    }
+    Ok(())
+}
+
+/// Whether `transaction`, once recorded, leaves any account it touches below zero
+fn overdrawn(db: &Connection, transaction: &Transaction) -> Result<bool> {
+    for (account, Amount(_, currency)) in transaction.results()? {
+        let balance: i32 = db
+            .prepare_cached(
+                "SELECT balance FROM balance_history WHERE account = ? AND currency = ? ORDER BY day DESC LIMIT 1",
+            )?
+            .query_row(params![account, currency], |row| row.get(0))?;
+        if balance < 0 {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Drop the balances `old` left on its day for accounts it changed in a currency `new` doesn't, unless another
+/// transaction that day still does; no day would be kept for them otherwise
+fn forget_balances(db: &Connection, old: &Transaction, new: &Transaction) -> Result<()> {
+    let day = old.date().to_string();
+    let kept = new.results()?;
+    for (account, Amount(_, currency)) in old.results()? {
+        let changes =
+            |(acc, amount): &(Id<Account>, Amount)| *acc == account && amount.1 == currency;
+        if kept.iter().any(changes) {
+            continue;
+        }
+        let others = db
+            .prepare_cached(
+                r#"
+                SELECT
+                    id,
+                    amount,
+                    type,
+                    new_amount,
+                    fee,
+                    external_party,
+                    acc_1,
+                    acc_2,
+                    notes,
+                    attachments,
+                    debts,
+                    legs,
+                    voided,
+                    tags,
+                    held,
+                    date,
+                    day
+                FROM transactions
+                WHERE day = ?1 AND id != ?2 AND (acc_1 = ?3 OR acc_2 = ?3 OR held = ?3 OR (type = 'General' AND ?3 IN (
+                    SELECT value ->> '$.party.account' FROM json_each(legs)
+                )))
+            "#,
+            )?
+            .query_map(params![day, old.id, account], TransactionDb::from_row)?
+            .map(|x| x?.to_transaction())
+            .collect::<Result<Vec<_>>>()?;
+        let mut still_changed = false;
+        for t in &others {
+            still_changed |= t.results()?.iter().any(changes);
+        }
+        if !still_changed {
+            db.prepare_cached(
+                "DELETE FROM balance_history WHERE account = ? AND day = ? AND currency = ?",
+            )?
+            .execute(params![account, day, currency])?;
+        }
+    }
+    Ok(())
+}
+
+/// How long to wait for another process to finish writing, unless `MONFARI_BUSY_TIMEOUT` gives a number of seconds
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
+/// How many more times to try a command the database stayed locked for, past the busy timeout
+const BUSY_RETRIES: u32 = 3;
+/// How long to wait before trying again the first time; this doubles each time after
+const BUSY_DELAY: Duration = Duration::from_millis(500);
+
+/// Whether `error` is down to another process holding the database's lock
+fn is_busy(error: &eyre::Report) -> bool {
+    matches!(
+        error.downcast_ref::<rusqlite::Error>(),
+        Some(rusqlite::Error::SqliteFailure(
+            rusqlite::ffi::Error {
+                code: ErrorCode::DatabaseBusy | ErrorCode::DatabaseLocked,
+                ..
+            },
+            _
+        ))
+    )
+}
+
+/// Where a server serving the database at `f` records itself, so other processes opening it can tell
+fn serving_path(f: &str) -> PathBuf {
+    PathBuf::from(format!("{f}-serving"))
+}
+
+/// Marks a database as being served by this process until dropped
+#[derive(Debug)]
+pub(super) struct Serving(PathBuf);
+
+impl Drop for Serving {
+    fn drop(&mut self) {
+        // Only if it's still ours
+        if server(&self.0).is_some_and(|(pid, _)| pid == process::id().to_string()) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+}
+
+/// The process ID and address of the server recorded at `path`, if it's still running
+fn server(path: &Path) -> Option<(String, String)> {
+    let serving = fs::read_to_string(path).ok()?;
+    let (pid, address) = serving.split_once(' ')?;
+    // Left behind by a server that didn't shut down cleanly
+    let proc = Path::new("/proc");
+    if proc.exists() && !proc.join(pid).exists() {
+        return None;
+    }
+    Some((pid.to_owned(), address.to_owned()))
+}
+
+fn timestamp(id: Id<Command>) -> String {
+    chrono::DateTime::<chrono::Utc>::from(id.0.datetime()).to_rfc3339()
+}
 
 impl SqlRepository {
     #[instrument]
     pub fn open(f: &str) -> Result<Self> {
         let mut db = Connection::open(f)?;
+        let timeout = match env::var("MONFARI_BUSY_TIMEOUT") {
+            Ok(secs) => secs
+                .parse()
+                .ok()
+                .and_then(|secs| Duration::try_from_secs_f64(secs).ok())
+                .ok_or_else(|| {
+                    eyre!("MONFARI_BUSY_TIMEOUT should be a number of seconds, not {secs:?}")
+                })?,
+            Err(_) => BUSY_TIMEOUT,
+        };
+        db.busy_timeout(timeout)?;
         db.pragma_update(None, "journal_mode", "WAL")?;
+        Self::warn_if_served(f);
 
-        MIGRATIONS
-            .iter()
-            .cloned()
-            .collect::<Migrations>()
-            .to_latest(&mut db)?;
+        migrations().to_latest(&mut db)?;
+        // Only once migrated, as tables are built again by dropping them while others still refer to them
+        db.pragma_update(None, "foreign_keys", true)?;
 
         Ok(Self { db })
     }
+
+    /// Record that this process is serving the database at `f`, unless another server already has
+    #[instrument]
+    pub fn serve(f: &str, address: &str) -> Result<Option<Serving>> {
+        let path = serving_path(f);
+        if server(&path).is_some() {
+            return Ok(None);
+        }
+        fs::write(&path, format!("{} {address}", process::id()))?;
+        Ok(Some(Serving(path)))
+    }
+
+    /// Warn if a server other than this process has the database at `f` open, as writing alongside it may have to wait
+    fn warn_if_served(f: &str) {
+        let Some((pid, address)) = server(&serving_path(f)) else {
+            return;
+        };
+        if pid == process::id().to_string() {
+            return;
+        }
+        warn!(pid, address, "Repository is being served");
+        eprintln!(
+            "This repository is being served at {address} (process {pid}); \
+             connecting through the server rather than opening the file avoids waiting on its writes"
+        );
+    }
+
+    /// Copy the database at `f` into `dest`, consistently even while it's in use
+    #[instrument]
+    pub fn snapshot(f: &str, dest: &Path) -> Result<()> {
+        let Some(dest) = dest.to_str() else {
+            bail!("Snapshots can only be written to UTF-8 paths")
+        };
+        Connection::open(f)?.execute("VACUUM INTO ?", params![dest])?;
+        Ok(())
+    }
 }
 
 impl SqlRepository {
     #[instrument]
-    pub fn transactions(&self, id: Id<Account>) -> Result<Vec<Transaction>> {
+    pub fn transactions(
+        &self,
+        id: Id<Account>,
+        page: Page<Transaction>,
+        filter: Filter,
+    ) -> Result<Vec<Transaction>> {
         self.db
             .prepare(
                 r#"
@@ -244,22 +1020,345 @@ impl SqlRepository {
                 amount,
                 type,
                 new_amount,
+                fee,
                 external_party,
                 acc_1,
                 acc_2,
-                notes
+                notes,
+                attachments,
+                debts,
+                legs,
+                voided,
+                tags,
+                held,
+                date,
+                day
             FROM transactions
-            WHERE acc_1 = ?1 OR acc_2 = ?1
+            WHERE (acc_1 = ?1 OR acc_2 = ?1 OR held = ?1 OR (type = 'General' AND ?1 IN (
+                SELECT value ->> '$.party.account' FROM json_each(legs)
+            ))) AND (?2 IS NULL OR id > ?2) AND (?4 IS NULL OR id < ?4)
+                AND (?5 IS NULL OR id IN (SELECT id FROM transaction_tags WHERE tag = ?5))
+                AND (?6 IS NULL OR day >= ?6) AND (?7 IS NULL OR day <= ?7)
+            ORDER BY id
+            LIMIT ?3
         "#,
             )?
-            .query_and_then(params![id], TransactionDb::from_row)?
+            .query_and_then(
+                // A negative limit is none at all
+                params![
+                    id,
+                    page.after,
+                    page.limit.map_or(-1, |x| x as i64),
+                    page.before,
+                    filter.tag,
+                    filter.from.map(|x| x.to_string()),
+                    filter.to.map(|x| x.to_string())
+                ],
+                TransactionDb::from_row,
+            )?
             .map(|x| x?.to_transaction())
             .collect()
     }
 
+    #[instrument]
+    pub fn activity(&self) -> Result<BTreeMap<Id<Account>, Activity>> {
+        let mut activity = self
+            .db
+            .prepare(
+                r#"
+                SELECT account, COUNT(*), MAX(id)
+                FROM (
+                    SELECT acc_1 AS account, id FROM transactions
+                    UNION
+                    SELECT acc_2 AS account, id FROM transactions
+                    UNION
+                    SELECT held AS account, id FROM transactions WHERE held IS NOT NULL
+                    UNION
+                    SELECT value ->> '$.party.account' AS account, transactions.id
+                    FROM transactions, json_each(legs)
+                    WHERE transactions.type = 'General' AND account IS NOT NULL
+                )
+                GROUP BY account
+            "#,
+            )?
+            .query_map(params![], |row| {
+                Ok((
+                    row.get::<_, Id<Account>>(0)?,
+                    Activity {
+                        transactions: row.get(1)?,
+                        last_activity: Some(row.get::<_, Id<Transaction>>(2)?.0.datetime().into()),
+                    },
+                ))
+            })?
+            .collect::<rusqlite::Result<BTreeMap<_, _>>>()?;
+        // Accounts with no transactions at all don't appear in `transactions`
+        for id in self
+            .db
+            .prepare("SELECT id FROM accounts WHERE NOT deleted")?
+            .query_map(params![], |row| row.get(0))?
+        {
+            activity.entry(id?).or_default();
+        }
+        Ok(activity)
+    }
+
+    /// How many transactions there are, and the IDs of the first and last if there are any
+    #[instrument]
+    pub fn account_log(&self, id: Id<Account>) -> Result<Vec<LogEntry>> {
+        self.account(id)?;
+        let mut entries = vec![];
+        // Only those mentioning the ID could have touched it
+        let mut statement = self.db.prepare(
+            "SELECT command, timestamp, user, client FROM commands WHERE instr(command, ?) > 0 ORDER BY id",
+        )?;
+        let mut rows = statement.query(params![id.proquint()])?;
+        while let Some(row) = rows.next()? {
+            let command = serde_json::from_str::<Command>(&row.get::<_, String>(0)?)?;
+            if !command.accounts().contains(&id) {
+                continue;
+            }
+            entries.push(LogEntry {
+                commit: None,
+                timestamp: chrono::DateTime::parse_from_rfc3339(&row.get::<_, String>(1)?)?.into(),
+                origin: Origin {
+                    user: row.get(2)?,
+                    client: row.get(3)?,
+                },
+                description: command.to_string().trim_end().to_owned(),
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Every account as it was at `at`, found by running the commands recorded up to then again
+    pub fn accounts_at(&self, at: &Point) -> Result<Vec<Account>> {
+        let Some(end) = at.end() else {
+            bail!("SQLite repositories have no commits, so can only be looked at as of a date")
+        };
+        let mut accounts = BTreeMap::new();
+        // Kept so edits to them can be made again
+        let mut transactions = BTreeMap::new();
+        let commands = self
+            .db
+            .prepare("SELECT command FROM commands WHERE timestamp < ? ORDER BY id")?
+            .query_map(params![end.to_rfc3339()], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        for command in commands {
+            match serde_json::from_str(&command)? {
+                Command::CreateAccount(account) => {
+                    accounts.insert(account.id, account);
+                }
+                Command::UpdateAccount(id, changes) => {
+                    let account = accounts
+                        .get_mut(&id)
+                        .ok_or_else(|| eyre!("No such account {id}"))?;
+                    for change in changes {
+                        change.apply(account);
+                    }
+                }
+                Command::RenamePayees { .. }
+                | Command::SetSchedule(_)
+                | Command::RemoveSchedule(_)
+                | Command::SetTemplate { .. }
+                | Command::RemoveTemplate(_) => {}
+                Command::AddTransaction(transaction)
+                | Command::AddScheduled { transaction, .. } => {
+                    for (id, amount) in transaction.results()? {
+                        let account = accounts
+                            .get_mut(&id)
+                            .ok_or_else(|| eyre!("No such account {id}"))?;
+                        account.current.add_amount(amount)?;
+                    }
+                    transactions.insert(transaction.id, transaction);
+                }
+                Command::UpdateTransaction(id, changes) => {
+                    let old = transactions
+                        .get_mut(&id)
+                        .ok_or_else(|| eyre!("No such transaction {id}"))?;
+                    let new = edit(old, &changes)?;
+                    for (id, delta) in edit_deltas(old, &new)? {
+                        let account = accounts
+                            .get_mut(&id)
+                            .ok_or_else(|| eyre!("No such account {id}"))?;
+                        account.current = (account.current.clone() + delta)?;
+                    }
+                    *old = new;
+                }
+                Command::VoidTransaction { id, reversal } => {
+                    let old = transactions
+                        .get_mut(&id)
+                        .ok_or_else(|| eyre!("No such transaction {id}"))?;
+                    let (voided, reversal) = void(old, reversal)?;
+                    for (id, amount) in reversal.results()? {
+                        let account = accounts
+                            .get_mut(&id)
+                            .ok_or_else(|| eyre!("No such account {id}"))?;
+                        account.current.add_amount(amount)?;
+                    }
+                    *old = voided;
+                    transactions.insert(reversal.id, reversal);
+                }
+            }
+        }
+        Ok(accounts.into_values().collect())
+    }
+
+    pub fn transaction_range(&self) -> Result<(usize, Option<RangeInclusive<Id<Transaction>>>)> {
+        Ok(self.db.query_row(
+            "SELECT COUNT(*), MIN(id), MAX(id) FROM transactions",
+            params![],
+            |row| {
+                let range = row.get::<_, Option<_>>(1)?.zip(row.get(2)?);
+                Ok((row.get(0)?, range.map(|(first, last)| first..=last)))
+            },
+        )?)
+    }
+
+    /// Every currency an account has held, read off the daily balances rather than adding up transactions
+    #[instrument]
+    pub fn currencies(&self) -> Result<BTreeSet<Currency>> {
+        self.db
+            .prepare_cached(
+                "SELECT DISTINCT currency FROM balance_history WHERE account IN (SELECT id FROM accounts WHERE NOT deleted)",
+            )?
+            .query_and_then(params![], |row| Ok(row.get::<_, Currency>(0)?))?
+            .collect()
+    }
+
+    /// The balance of `id` at the end of each day it changed, oldest first
+    #[instrument]
+    pub fn balance_history(&self, id: Id<Account>) -> Result<Vec<(NaiveDate, Amounts)>> {
+        let mut balance = Amounts::default();
+        let mut history = Vec::<(NaiveDate, Amounts)>::new();
+        for row in self
+            .db
+            .prepare(
+                "SELECT day, currency, balance FROM balance_history WHERE account = ? ORDER BY day",
+            )?
+            .query_map(params![id], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, Currency>(1)?,
+                    row.get::<_, i32>(2)?,
+                ))
+            })?
+        {
+            let (day, currency, amount) = row?;
+            let day = day.parse::<NaiveDate>()?;
+            balance.0.insert(currency, Amount(amount, currency));
+            match history.last_mut() {
+                Some((last, balances)) if *last == day => *balances = balance.clone(),
+                _ => history.push((day, balance.clone())),
+            }
+        }
+        Ok(history)
+    }
+
+    #[instrument]
+    fn transaction(&self, id: Id<Transaction>) -> Result<Transaction> {
+        self.db
+            .query_row(
+                r#"
+                SELECT
+                    id,
+                    amount,
+                    type,
+                    new_amount,
+                    fee,
+                    external_party,
+                    acc_1,
+                    acc_2,
+                    notes,
+                    attachments,
+                    debts,
+                    legs,
+                    voided,
+                    tags,
+                    held,
+                    date,
+                    day
+                FROM transactions
+                WHERE id = ?
+            "#,
+                params![id],
+                TransactionDb::from_row,
+            )?
+            .to_transaction()
+    }
+
+    #[instrument]
+    pub fn config(&self) -> Result<Config> {
+        let config = self
+            .db
+            .query_row("SELECT config FROM config", params![], |row| {
+                row.get::<_, String>(0)
+            })
+            .optional()?;
+        Ok(match config {
+            Some(config) => serde_json::from_str(&config)?,
+            None => Config::default(),
+        })
+    }
+
+    #[instrument]
+    pub fn set_config(&mut self, config: Config) -> Result<()> {
+        let config = serde_json::to_string(&config)?;
+        self.retrying_busy(|this| {
+            this.db.execute(
+                "INSERT OR REPLACE INTO config (id, config) VALUES (0, ?)",
+                params![config],
+            )?;
+            Ok(())
+        })
+    }
+
+    #[instrument]
+    pub(super) fn entities<T: Stored>(&self) -> Result<Vec<T>> {
+        self.db
+            .prepare_cached("SELECT entity FROM entities WHERE kind = ? ORDER BY id")?
+            .query_and_then(params![T::PATH], |row| {
+                Ok(serde_json::from_str(&row.get::<_, String>(0)?)?)
+            })?
+            .collect()
+    }
+
+    #[instrument]
+    pub fn search(&self, query: &str) -> Result<Vec<SearchResult>> {
+        let terms = super::search_terms(query);
+        if terms.is_empty() {
+            return Ok(vec![]);
+        }
+        // Each term is quoted, so it's searched for as written rather than read as FTS5's query syntax
+        let matching = terms
+            .iter()
+            .map(|x| format!("\"{}\"", x.replace('"', "\"\"")))
+            .join(" ");
+        let mut accounts = vec![];
+        let mut transactions = vec![];
+        let mut statement = self.db.prepare(
+            r#"
+            SELECT kind, id
+            FROM notes_search
+            WHERE notes_search MATCH ?
+            ORDER BY id
+        "#,
+        )?;
+        let mut rows = statement.query(params![matching])?;
+        while let Some(row) = rows.next()? {
+            let id = row.get::<_, String>(1)?;
+            match row.get_ref(0)?.as_str()? {
+                "account" => accounts.push(self.account(id.parse()?)?),
+                "transaction" => transactions.push(self.transaction(id.parse()?)?),
+                kind => bail!("Invalid search result kind {kind}"),
+            }
+        }
+        // Those FTS5 finds are ranked as every backend does, after leaving out any it only finds by ignoring accents
+        Ok(super::search_notes(query, accounts, transactions))
+    }
+
     #[instrument]
     pub fn account(&self, id: Id<Account>) -> Result<Account> {
-        let transactions = self.transactions(id)?;
+        let transactions = self.transactions(id, Page::default(), Filter::default())?;
         self.db
             .query_row(
                 r#"
@@ -268,9 +1367,11 @@ impl SqlRepository {
                     type,
                     name,
                     notes,
-                    enabled
+                    enabled,
+                    institution,
+                    deleted
                 FROM accounts
-                WHERE id = ?
+                WHERE id = ? AND NOT deleted
             "#,
                 params![id],
                 AccountDb::from_row,
@@ -278,6 +1379,12 @@ impl SqlRepository {
             .to_account(&transactions)
     }
 
+    #[instrument]
+    pub fn maintenance(&mut self) -> Result<()> {
+        self.db.execute_batch("VACUUM; PRAGMA optimize;")?;
+        Ok(())
+    }
+
     #[instrument]
     pub fn accounts(&self) -> Result<Vec<Account>> {
         self.db
@@ -288,45 +1395,247 @@ impl SqlRepository {
                     type,
                     name,
                     notes,
-                    enabled
+                    enabled,
+                    institution,
+                    deleted
                 FROM accounts
+                WHERE NOT deleted
             "#,
             )?
             .query_and_then(params![], AccountDb::from_row)?
             .map(|acc| {
                 let acc = acc?;
-                let transactions = self.transactions(acc.id)?;
+                let transactions = self.transactions(acc.id, Page::default(), Filter::default())?;
                 acc.to_account(&transactions)
             })
             .collect()
     }
-    pub fn run_command(&mut self, cmd: Command) -> Result<()> {
-        let transaction = self.db.transaction()?;
+    /// Whether a batch has been started and not yet committed or rolled back
+    pub fn in_batch(&self) -> bool {
+        !self.db.is_autocommit()
+    }
+
+    /// Start a batch of commands, which are only kept if it's committed
+    #[instrument]
+    pub fn begin(&mut self) -> Result<()> {
+        // Taking the write lock up front, as a batch that has to upgrade to it part way through can't wait for it
+        self.retrying_busy(|this| Ok(this.db.execute_batch("BEGIN IMMEDIATE")?))
+    }
+
+    #[instrument]
+    pub fn commit(&mut self) -> Result<()> {
+        self.db.execute_batch("COMMIT")?;
+        Ok(())
+    }
+
+    #[instrument]
+    pub fn rollback(&mut self) -> Result<()> {
+        self.db.execute_batch("ROLLBACK")?;
+        Ok(())
+    }
+
+    /// Run `f`, trying again if another process kept the database locked past the busy timeout
+    ///
+    /// Only outside a batch, as the commands run in the batch so far would be lost.
+    fn retrying_busy<T>(&mut self, mut f: impl FnMut(&mut Self) -> Result<T>) -> Result<T> {
+        let mut attempt = 0;
+        loop {
+            match f(self) {
+                Err(e) if is_busy(&e) && attempt < BUSY_RETRIES && self.db.is_autocommit() => {
+                    warn!("Database is locked; trying again: {e}");
+                    thread::sleep(BUSY_DELAY * 2u32.pow(attempt));
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    pub fn run_command(&mut self, cmd: Command, origin: Origin) -> Result<()> {
+        self.retrying_busy(|this| this.run_command_once(cmd.clone(), origin.clone()))
+    }
+
+    /// Add all of `transactions` in one database transaction, or none of them
+    #[instrument(skip(transactions))]
+    pub fn add_transactions(
+        &mut self,
+        transactions: Vec<Transaction>,
+        origin: Origin,
+    ) -> Result<()> {
+        // In a batch, this is part of it, so rolled back along with the rest if it fails
+        let own = self.db.is_autocommit();
+        if own {
+            self.begin()?;
+        }
+        let result = transactions
+            .into_iter()
+            .try_for_each(|t| self.run_command_once(Command::AddTransaction(t), origin.clone()));
+        match result {
+            Ok(()) if own => self.commit(),
+            Err(e) if own => {
+                self.rollback()?;
+                Err(e)
+            }
+            result => result,
+        }
+    }
+
+    fn run_command_once(&mut self, cmd: Command, origin: Origin) -> Result<()> {
+        let check_account_types = |t: &Transaction| {
+            t.check_account_types(|id| {
+                self.db
+                    .prepare_cached("SELECT name, type FROM accounts WHERE id = ? AND NOT deleted")?
+                    .query_row(params![id], |row| Ok((row.get(0)?, row.get(1)?)))
+                    .optional()?
+                    .ok_or_else(|| eyre!("No such account"))
+            })
+        };
+        // The transaction an edit changes, as it was and as it will be
+        let mut edited = None;
+        // The reversal a void adds
+        let mut reversal = None;
+        match &cmd {
+            Command::AddTransaction(t) | Command::AddScheduled { transaction: t, .. } => {
+                check_account_types(t)?
+            }
+            Command::UpdateTransaction(id, changes) => {
+                let old = self
+                    .transaction(*id)
+                    .wrap_err_with(|| format!("No transaction {id}"))?;
+                let new = edit(&old, changes)?;
+                check_account_types(&new)?;
+                edited = Some((old, new));
+            }
+            Command::VoidTransaction { id, reversal: by } => {
+                let old = self
+                    .transaction(*id)
+                    .wrap_err_with(|| format!("No transaction {id}"))?;
+                reversal = Some(void(&old, *by)?.1);
+            }
+            _ => {}
+        }
+        // A savepoint rather than a transaction, as this may be part of a batch
+        let transaction = self.db.savepoint()?;
 
         {
             let id = Id::<Command>::generate();
             let cmd = serde_json::to_string(&cmd)?;
-            transaction.execute("INSERT INTO commands VALUES (?, ?)", params![id, cmd])?;
+            transaction
+                .prepare_cached(
+                    "INSERT INTO commands (id, command, timestamp, user, client) VALUES (?, ?, ?, ?, ?)",
+                )?
+                .execute(params![id, cmd, timestamp(id), origin.user, origin.client])?;
         };
+        let added = cmd.transaction().or(reversal.as_ref());
+        if let Some(t) = added {
+            record_balances(&transaction, t)?;
+            ensure!(
+                !overdrawn(&transaction, t)?,
+                "Account balance must never be below 0 in any currency"
+            );
+        }
+        if let Some((old, new)) = &edited {
+            // Undone as of the same day, since edits keep what it's dated
+            let undone = Transaction {
+                id: old.id,
+                date: old.date,
+                ..old.reversal()?
+            };
+            record_balances(&transaction, &undone)?;
+            record_balances(&transaction, new)?;
+            forget_balances(&transaction, old, new)?;
+            ensure!(
+                !overdrawn(&transaction, old)? && !overdrawn(&transaction, new)?,
+                "Account balance must never be below 0 in any currency"
+            );
+        }
         match cmd {
-            Command::CreateAccount(Account {
-                id,
-                name,
-                notes,
-                typ,
-                enabled,
-                current: _,
-            }) => {
+            Command::CreateAccount(account) => {
+                account.check_institution()?;
+                let Account {
+                    id,
+                    name,
+                    notes,
+                    typ,
+                    enabled,
+                    current: _,
+                    institution,
+                    deleted,
+                } = account;
+                transaction.execute(
+                    "INSERT INTO notes_search VALUES ('account', ?, ?)",
+                    params![id, notes],
+                )?;
                 AccountDb {
                     id,
                     name,
                     notes,
                     typ,
                     enabled,
+                    institution,
+                    deleted,
                 }
                 .insert(&transaction)?;
             }
             Command::UpdateAccount(acc, changes) => {
+                let exists = transaction
+                    .query_row(
+                        "SELECT 1 FROM accounts WHERE id = ? AND NOT deleted",
+                        params![acc],
+                        |_| Ok(()),
+                    )
+                    .optional()?
+                    .is_some();
+                ensure!(exists, "No such account {acc}");
+                for change in &changes {
+                    match change {
+                        AccountModification::UpdateNotes(notes) => {
+                            transaction.execute(
+                                "UPDATE notes_search SET notes = ? WHERE kind = 'account' AND id = ?",
+                                params![notes, acc],
+                            )?;
+                        }
+                        AccountModification::ChangeType(_) => {
+                            let used = transaction
+                                .query_row(USED, params![acc], |row| row.get::<_, i64>(0))?;
+                            ensure!(
+                                used == 0,
+                                "Cannot change the type of an account with transactions"
+                            );
+                        }
+                        // With no transactions, there's nothing in it either
+                        AccountModification::Delete => {
+                            let used = transaction
+                                .query_row(USED, params![acc], |row| row.get::<_, i64>(0))?;
+                            ensure!(
+                                used == 0,
+                                "Only accounts with nothing in them and no transactions can be deleted"
+                            );
+                            transaction.execute(
+                                "DELETE FROM notes_search WHERE kind = 'account' AND id = ?",
+                                params![acc],
+                            )?;
+                        }
+                        _ => {}
+                    }
+                }
+                let (mut typ, mut institution): (AccountType, Option<String>) = transaction
+                    .query_row(
+                        "SELECT type, institution FROM accounts WHERE id = ?",
+                        params![acc],
+                        |row| Ok((row.get(0)?, row.get(1)?)),
+                    )?;
+                for change in &changes {
+                    match change {
+                        AccountModification::ChangeType(x) => typ = *x,
+                        AccountModification::UpdateInstitution(x) => institution = x.clone(),
+                        _ => {}
+                    }
+                }
+                ensure!(
+                    institution.is_none() || typ == AccountType::Physical,
+                    "Only physical accounts can be held at an institution"
+                );
                 let (columns, mut values) = changes
                     .into_iter()
                     .map(|x| match x {
@@ -335,6 +1644,11 @@ impl SqlRepository {
                         }
                         AccountModification::UpdateName(name) => ("name", Box::new(name) as _),
                         AccountModification::UpdateNotes(notes) => ("notes", Box::new(notes) as _),
+                        AccountModification::UpdateInstitution(institution) => {
+                            ("institution", Box::new(institution) as _)
+                        }
+                        AccountModification::ChangeType(typ) => ("type", Box::new(typ) as _),
+                        AccountModification::Delete => ("deleted", Box::new(true) as _),
                     })
                     .unzip::<_, _, Vec<_>, Vec<_>>();
                 values.push(Box::new(acc) as _);
@@ -350,64 +1664,65 @@ impl SqlRepository {
                     params_from_iter(values),
                 )?;
             }
-            Command::AddTransaction(Transaction {
-                id,
-                notes,
-                amount,
-                inner,
-            }) => {
-                let (typ, acc_1, acc_2, external_party, new_amount) = match inner {
-                    TransactionInner::Received { src, dst, dst_virt } => (
-                        TransactionType::Received,
-                        dst.erase(),
-                        dst_virt.erase(),
-                        Some(src),
-                        None,
-                    ),
-                    TransactionInner::Paid { src, src_virt, dst } => (
-                        TransactionType::Paid,
-                        src.erase(),
-                        src_virt.erase(),
-                        Some(dst),
-                        None,
-                    ),
-                    TransactionInner::MovePhys { src, dst } => (
-                        TransactionType::MovePhys,
-                        src.erase(),
-                        dst.erase(),
-                        None,
-                        None,
-                    ),
-                    TransactionInner::MoveVirt { src, dst } => (
-                        TransactionType::MoveVirt,
-                        src.erase(),
-                        dst.erase(),
-                        None,
-                        None,
-                    ),
-                    TransactionInner::Convert {
-                        acc,
-                        acc_virt,
-                        new_amount,
-                    } => (
-                        TransactionType::Convert,
-                        acc.erase(),
-                        acc_virt.erase(),
-                        None,
-                        Some(new_amount),
-                    ),
-                };
-                TransactionDb {
-                    id,
-                    amount,
-                    typ,
-                    new_amount,
-                    external_party,
-                    acc_1,
-                    acc_2,
-                    notes,
+            Command::AddTransaction(t) => insert_transaction(&transaction, t)?,
+            Command::UpdateTransaction(id, _) => {
+                let (_, new) = edited.expect("Edits are made before anything is written");
+                transaction.execute(
+                    "DELETE FROM notes_search WHERE kind = 'transaction' AND id = ?",
+                    params![id],
+                )?;
+                // A voided transaction refers to its reversal, which may be what's taken out and put back here
+                transaction.pragma_update(None, "defer_foreign_keys", true)?;
+                transaction
+                    .prepare_cached("DELETE FROM transaction_tags WHERE id = ?")?
+                    .execute(params![id])?;
+                transaction
+                    .prepare_cached("DELETE FROM transactions WHERE id = ?")?
+                    .execute(params![id])?;
+                insert_transaction(&transaction, new)?;
+            }
+            Command::VoidTransaction { id, reversal: by } => {
+                let reversal = reversal.expect("Voids are checked before anything is written");
+                insert_transaction(&transaction, reversal)?;
+                transaction
+                    .prepare_cached("UPDATE transactions SET voided = ? WHERE id = ?")?
+                    .execute(params![by, id])?;
+            }
+            Command::RenamePayees { from, to } => {
+                for from in from {
+                    transaction
+                        .prepare_cached(
+                            "UPDATE transactions SET external_party = ? WHERE type IN ('Received', 'Paid') AND external_party = ?",
+                        )?
+                        .execute(params![to, from])?;
                 }
-                .insert(&transaction)?;
+            }
+            Command::SetSchedule(schedule) => put(&transaction, &schedule)?,
+            Command::RemoveSchedule(id) => remove(&transaction, id)?,
+            Command::AddScheduled {
+                schedule: id,
+                transaction: t,
+            } => {
+                let mut schedule = entity(&transaction, id)?;
+                schedule.advance(t.date)?;
+                put(&transaction, &schedule)?;
+                insert_transaction(&transaction, t)?;
+            }
+            cmd @ (Command::SetTemplate { .. } | Command::RemoveTemplate(_)) => {
+                let mut config = match transaction
+                    .query_row("SELECT config FROM config", params![], |row| {
+                        row.get::<_, String>(0)
+                    })
+                    .optional()?
+                {
+                    Some(config) => serde_json::from_str::<Config>(&config)?,
+                    None => Config::default(),
+                };
+                cmd.apply_template(&mut config.transaction_templates)?;
+                transaction.execute(
+                    "INSERT OR REPLACE INTO config (id, config) VALUES (0, ?)",
+                    params![serde_json::to_string(&config)?],
+                )?;
             }
         }
 
@@ -0,0 +1,221 @@
+//! Just enough of the S3 API to keep objects in a bucket, for any S3-compatible service
+
+use std::{env, fmt::Write, io::Read};
+
+use chrono::Utc;
+use eyre::{eyre, Result};
+use ring::{digest, hmac};
+use tracing::instrument;
+
+/// A bucket, and a prefix within it, addressed like `s3://bucket/prefix`
+///
+/// Credentials and the service to use come from the usual `AWS_ACCESS_KEY_ID`, `AWS_SECRET_ACCESS_KEY`,
+/// `AWS_REGION` and `AWS_ENDPOINT_URL` environment variables.
+pub struct Bucket {
+    agent: ureq::Agent,
+    endpoint: String,
+    bucket: String,
+    prefix: String,
+    region: String,
+    access_key: String,
+    secret_key: String,
+}
+
+impl std::fmt::Debug for Bucket {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "s3://{}/{} at {}",
+            self.bucket, self.prefix, self.endpoint
+        )
+    }
+}
+
+impl Bucket {
+    pub fn from_url(url: &str) -> Result<Self> {
+        let path = url
+            .strip_prefix("s3://")
+            .ok_or_else(|| eyre!("S3 locations are given as s3://bucket/prefix"))?;
+        let (bucket, prefix) = path.split_once('/').unwrap_or((path, ""));
+        let var = |name| env::var(name).map_err(|_| eyre!("{name} must be set to use S3"));
+        let region = env::var("AWS_REGION").unwrap_or_else(|_| "us-east-1".to_owned());
+        let endpoint = env::var("AWS_ENDPOINT_URL")
+            .unwrap_or_else(|_| format!("https://s3.{region}.amazonaws.com"));
+        Ok(Self {
+            agent: ureq::Agent::new(),
+            endpoint: endpoint.trim_end_matches('/').to_owned(),
+            bucket: bucket.to_owned(),
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            region,
+            access_key: var("AWS_ACCESS_KEY_ID")?,
+            secret_key: var("AWS_SECRET_ACCESS_KEY")?,
+        })
+    }
+
+    fn key(&self, name: &str) -> String {
+        if self.prefix.is_empty() {
+            name.to_owned()
+        } else {
+            format!("{}/{name}", self.prefix)
+        }
+    }
+
+    #[instrument(skip(data))]
+    pub fn put(&self, name: &str, data: &[u8]) -> Result<()> {
+        self.request("PUT", &self.key(name), &[], data)?;
+        Ok(())
+    }
+
+    /// The object called `name`, or `None` if there isn't one
+    #[instrument]
+    pub fn get(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        match self.request("GET", &self.key(name), &[], &[]) {
+            Ok(response) => {
+                let mut data = vec![];
+                response.into_reader().read_to_end(&mut data)?;
+                Ok(Some(data))
+            }
+            Err(e) => match e.downcast_ref::<ureq::Error>() {
+                Some(ureq::Error::Status(404, _)) => Ok(None),
+                _ => Err(e),
+            },
+        }
+    }
+
+    #[instrument]
+    pub fn delete(&self, name: &str) -> Result<()> {
+        self.request("DELETE", &self.key(name), &[], &[])?;
+        Ok(())
+    }
+
+    /// The names of objects under the prefix, without it
+    #[instrument]
+    pub fn list(&self) -> Result<Vec<String>> {
+        let prefix = self.key("");
+        let mut names = vec![];
+        let mut continuation = None::<String>;
+        loop {
+            let mut query = vec![("list-type", "2".to_owned()), ("prefix", prefix.clone())];
+            if let Some(token) = continuation.take() {
+                query.push(("continuation-token", token));
+            }
+            let body = self.request("GET", "", &query, &[])?.into_string()?;
+            names.extend(
+                elements(&body, "Key")
+                    .into_iter()
+                    .map(|key| key[prefix.len()..].to_owned()),
+            );
+            continuation = elements(&body, "NextContinuationToken").pop();
+            if continuation.is_none() {
+                return Ok(names);
+            }
+        }
+    }
+
+    /// Make a request signed with AWS Signature Version 4
+    fn request(
+        &self,
+        method: &str,
+        key: &str,
+        query: &[(&str, String)],
+        body: &[u8],
+    ) -> Result<ureq::Response> {
+        let now = Utc::now();
+        let date = now.format("%Y%m%d").to_string();
+        let timestamp = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let payload_hash = hex(digest::digest(&digest::SHA256, body).as_ref());
+
+        let mut path = format!("/{}", encode(&self.bucket, false));
+        if !key.is_empty() {
+            path += &format!("/{}", encode(key, true));
+        }
+        let mut query = query
+            .iter()
+            .map(|(name, value)| format!("{}={}", encode(name, false), encode(value, false)))
+            .collect::<Vec<_>>();
+        query.sort();
+        let query = query.join("&");
+        let host = self
+            .endpoint
+            .split_once("://")
+            .map_or(&*self.endpoint, |(_, host)| host);
+
+        let canonical_request = format!(
+            "{method}\n{path}\n{query}\nhost:{host}\nx-amz-content-sha256:{payload_hash}\nx-amz-date:{timestamp}\n\nhost;x-amz-content-sha256;x-amz-date\n{payload_hash}"
+        );
+        let scope = format!("{date}/{}/s3/aws4_request", self.region);
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{timestamp}\n{scope}\n{}",
+            hex(digest::digest(&digest::SHA256, canonical_request.as_bytes()).as_ref())
+        );
+        let signing_key = [&*self.region, "s3", "aws4_request"].into_iter().fold(
+            sign(format!("AWS4{}", self.secret_key).as_bytes(), &date),
+            |key, part| sign(&key, part),
+        );
+        let signature = hex(&sign(&signing_key, &string_to_sign));
+
+        let mut url = format!("{}{path}", self.endpoint);
+        if !query.is_empty() {
+            url += &format!("?{query}");
+        }
+        let request = self
+            .agent
+            .request(method, &url)
+            .set("x-amz-content-sha256", &payload_hash)
+            .set("x-amz-date", &timestamp)
+            .set(
+                "Authorization",
+                &format!(
+                    "AWS4-HMAC-SHA256 Credential={}/{scope}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={signature}",
+                    self.access_key
+                ),
+            );
+        Ok(request.send_bytes(body)?)
+    }
+}
+
+fn sign(key: &[u8], data: &str) -> Vec<u8> {
+    hmac::sign(&hmac::Key::new(hmac::HMAC_SHA256, key), data.as_bytes())
+        .as_ref()
+        .to_vec()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut out, byte| {
+        let _ = write!(out, "{byte:02x}");
+        out
+    })
+}
+
+/// Percent-encode everything but unreserved characters, and slashes if `keep_slashes`, as S3 signatures expect
+fn encode(s: &str, keep_slashes: bool) -> String {
+    s.bytes().fold(String::new(), |mut out, byte| {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                out.push(byte as char)
+            }
+            b'/' if keep_slashes => out.push('/'),
+            _ => {
+                let _ = write!(out, "%{byte:02X}");
+            }
+        }
+        out
+    })
+}
+
+/// The text of every `<tag>` element in an XML response
+fn elements(xml: &str, tag: &str) -> Vec<String> {
+    let close = format!("</{tag}>");
+    xml.split(&format!("<{tag}>"))
+        .skip(1)
+        .filter_map(|x| Some(unescape(x.split_once(&close)?.0)))
+        .collect()
+}
+
+fn unescape(s: &str) -> String {
+    s.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
@@ -0,0 +1,192 @@
+use std::{ffi::OsString, thread, time::Duration};
+
+use chrono::{Local, NaiveDate};
+use eyre::{bail, ensure, Result};
+use tracing::{error, info, instrument};
+
+use crate::{
+    backup,
+    command::Command,
+    parser::{self, Parser},
+    repository::Repository,
+    types::{Id, Recurrence, ScheduledTransaction, Transaction},
+};
+
+/// How often `serve` adds the scheduled transactions that have come due
+#[derive(Debug, clap::Args)]
+pub struct Ticker {
+    /// Add scheduled transactions as they come due, checking this often, like `1h`
+    #[arg(long = "tick-every", value_parser = backup::parse_interval)]
+    tick_every: Option<Duration>,
+}
+
+impl Ticker {
+    /// Add scheduled transactions now and then in the background, if asked to
+    pub fn start(self, repo: OsString) -> Result<()> {
+        let Some(every) = self.tick_every else {
+            return Ok(());
+        };
+        thread::spawn(move || loop {
+            // A schedule that can't be kept shouldn't take the server down with it; the next tick tries again
+            match Repository::open(&repo).and_then(|mut repo| tick(&mut repo, today())) {
+                Ok(results) => {
+                    for (name, date, result) in results {
+                        match result {
+                            Ok(transaction) => {
+                                info!(name, %date, id = %transaction.id, "Added a scheduled transaction")
+                            }
+                            Err(e) => {
+                                error!(name, %date, "Couldn't add a scheduled transaction: {e:?}")
+                            }
+                        }
+                    }
+                }
+                Err(e) => error!("Adding scheduled transactions failed: {e:?}"),
+            }
+            thread::sleep(every);
+        });
+        Ok(())
+    }
+}
+
+pub fn today() -> NaiveDate {
+    Local::now().date_naive()
+}
+
+/// A schedule named `name` for the transaction `text` gives, written as it would be after `transaction`, like
+/// `900 EUR paid ...`
+pub fn parse(
+    repo: &Repository,
+    name: String,
+    every: Recurrence,
+    text: &str,
+    start: NaiveDate,
+) -> Result<ScheduledTransaction> {
+    const PREFIX: &str = "transaction ";
+    let cmd = Parser::parse(
+        &format!("{PREFIX}{text}"),
+        repo.accounts()?,
+        repo.config()?,
+        None,
+    )
+    .1
+    // Where the error is within what was given
+    .map_err(|e| parser::Error {
+        at: e.at.saturating_sub(PREFIX.len()),
+        ..e
+    })?;
+    let parser::Command::TransactionAdd {
+        amount,
+        inner,
+        notes,
+        attachments,
+        debts,
+        date,
+    } = cmd
+    else {
+        bail!("Only transactions can be scheduled, like `900 EUR paid ...`");
+    };
+    ensure!(
+        attachments.is_empty() && date.is_none(),
+        "Scheduled transactions can't have attachments or a day of their own"
+    );
+    Ok(ScheduledTransaction {
+        id: Id::generate(),
+        name,
+        every,
+        start,
+        last: None,
+        notes: notes.unwrap_or_default(),
+        amount,
+        inner,
+        debts,
+    })
+}
+
+/// Try to add every scheduled transaction due by `today` that hasn't been added yet, giving the schedule's name, the
+/// day, and whether it was added
+///
+/// Each schedule records the last day it was added for, so ticking again, or from elsewhere, doesn't add a day twice.
+/// A day that can't be added stops its schedule there, to be tried again next time, but not the rest.
+#[instrument(skip(repo))]
+pub fn tick(
+    repo: &mut Repository,
+    today: NaiveDate,
+) -> Result<Vec<(String, NaiveDate, Result<Transaction>)>> {
+    let mut results = vec![];
+    for schedule in repo.schedules()? {
+        for date in schedule.due(today) {
+            let transaction = schedule.transaction(date);
+            let result = repo
+                .run_command(Command::AddScheduled {
+                    schedule: schedule.id,
+                    transaction: transaction.clone(),
+                })
+                .map(|_| transaction);
+            let failed = result.is_err();
+            results.push((schedule.name.clone(), date, result));
+            if failed {
+                break;
+            }
+        }
+    }
+    Ok(results)
+}
+
+/// The days `schedules` are due on that they haven't been added for, up to a year from `today`, as an iCalendar of
+/// all-day events
+pub fn ics(schedules: &[ScheduledTransaction], today: NaiveDate) -> String {
+    let end = today
+        .checked_add_months(chrono::Months::new(12))
+        .unwrap_or(NaiveDate::MAX);
+    let stamp = chrono::Utc::now().format("%Y%m%dT%H%M%SZ");
+    let mut lines = vec![
+        "BEGIN:VCALENDAR".to_owned(),
+        "VERSION:2.0".to_owned(),
+        "PRODID:-//monfari//schedules//EN".to_owned(),
+        "CALSCALE:GREGORIAN".to_owned(),
+    ];
+    for schedule in schedules {
+        for date in schedule.due(end) {
+            let day = date.format("%Y%m%d");
+            lines.push("BEGIN:VEVENT".to_owned());
+            lines.push(format!("UID:{day}-{}@monfari", schedule.id.proquint()));
+            lines.push(format!("DTSTAMP:{stamp}"));
+            lines.push(format!("DTSTART;VALUE=DATE:{day}"));
+            lines.push(format!(
+                "SUMMARY:{}",
+                escape(&format!("{}: {}", schedule.name, schedule.amount))
+            ));
+            if !schedule.notes.is_empty() {
+                lines.push(format!("DESCRIPTION:{}", escape(&schedule.notes)));
+            }
+            lines.push("END:VEVENT".to_owned());
+        }
+    }
+    lines.push("END:VCALENDAR".to_owned());
+    lines.iter().map(|x| fold(x) + "\r\n").collect()
+}
+
+/// `text` as an iCalendar text value, with what would end or split it escaped
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace("\r\n", "\\n")
+        .replace('\n', "\\n")
+}
+
+/// `line` folded as iCalendar requires, into lines of at most 75 bytes, each after the first starting with a space
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut len = 0;
+    for c in line.chars() {
+        if len + c.len_utf8() > 75 {
+            folded += "\r\n ";
+            len = 1;
+        }
+        folded.push(c);
+        len += c.len_utf8();
+    }
+    folded
+}
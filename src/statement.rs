@@ -0,0 +1,406 @@
+//! Importing bank statements exported as CSV, one transaction per row
+//!
+//! Statements are parsed strictly by default, taking only plain `-1234.50` amounts and `2024-03-01` dates, and
+//! refusing the whole file if any row is wrong. `--permissive` reads the formats banks actually use, and skips
+//! what can't be read — garbage rows, footers, running totals — reporting each rather than stopping.
+//!
+//! Each row's currency is that in its currency column if there is one, or otherwise `--currency`, whatever the amount
+//! is written with, as symbols like `$` are shared. Without either, it's that written with the amount, like
+//! `12.50 GBP` or `£12.50`, or failing that the statement's, found from the amount column's heading, like
+//! `Amount (EUR)`, the one currency the account holds, or the default, in turn.
+
+use std::{fs, io, path::PathBuf};
+
+use chrono::NaiveDate;
+use eyre::{bail, ensure, eyre, Context, Result};
+use itertools::Itertools;
+
+use crate::{
+    command::{Command, Origin},
+    repository::Repository,
+    types::{
+        Account, Amount, Currency, Id, Physical, Rounding, Transaction, TransactionInner, Virtual,
+    },
+};
+
+/// How forgiving to be of what's in a statement
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseMode {
+    /// Only the one unambiguous format for each value
+    Strict,
+    /// Whatever a bank might have written
+    Permissive,
+}
+
+/// Where a statement is, and what to make of it
+#[derive(Debug, clap::Args)]
+pub struct Statement {
+    /// The statement, as CSV with a header row; standard input if not given
+    path: Option<PathBuf>,
+    /// The physical account the statement is of
+    #[arg(long)]
+    account: Id<Account<Physical>>,
+    /// The virtual account payments are taken from and receipts put in
+    #[arg(long = "virtual")]
+    virt: Id<Account<Virtual>>,
+    /// The currency of rows whose currency column doesn't give their own, whatever their amounts are written with
+    #[arg(long)]
+    currency: Option<Currency>,
+    /// The heading of the column giving each row's currency; a column headed `Currency` is used if there is one
+    #[arg(long)]
+    currency_column: Option<String>,
+    /// The heading of the column of dates
+    #[arg(long, default_value = "Date")]
+    date_column: String,
+    /// The heading of the column of amounts, negative for money going out
+    #[arg(long, default_value = "Amount")]
+    amount_column: String,
+    /// The heading of the column naming who was paid or paid in
+    #[arg(long, default_value = "Description")]
+    payee_column: String,
+    /// Read odd formats, like `1.234,50`, `(12.00)` and `01/03/2024`, and skip rows that can't be read
+    #[arg(long)]
+    permissive: bool,
+    /// Show the transactions that would be added, without adding them
+    #[arg(long)]
+    dry_run: bool,
+    /// Allow the statement's currencies, if no account holds any of them yet
+    #[arg(long)]
+    new_currency: bool,
+}
+
+impl Statement {
+    pub fn import(self, repo: &mut Repository) -> Result<()> {
+        let text = match &self.path {
+            Some(path) => fs::read_to_string(path)
+                .wrap_err_with(|| format!("Couldn't read {}", path.display()))?,
+            None => io::read_to_string(io::stdin())?,
+        };
+        let mode = if self.permissive {
+            ParseMode::Permissive
+        } else {
+            ParseMode::Strict
+        };
+
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .filter(|(_, x)| !x.trim().is_empty());
+        let (_, header) = lines
+            .next()
+            .ok_or_else(|| eyre!("The statement is empty"))?;
+        let delimiter = delimiter(header);
+        let header = fields(header, delimiter);
+        // Headings can name a currency, as in `Amount (EUR)`
+        let find = |name: &str| {
+            header
+                .iter()
+                .position(|x| x.trim().eq_ignore_ascii_case(name))
+                .or_else(|| {
+                    header
+                        .iter()
+                        .position(|x| without_currency(x).eq_ignore_ascii_case(name))
+                })
+        };
+        let column = |name: &str| {
+            find(name).ok_or_else(|| {
+                eyre!(
+                    "There's no {name} column; the columns are {}",
+                    header.iter().join(", ")
+                )
+            })
+        };
+        let columns = Columns {
+            date: column(&self.date_column)?,
+            amount: column(&self.amount_column)?,
+            payee: column(&self.payee_column)?,
+            currency: match &self.currency_column {
+                Some(name) => Some(column(name)?),
+                None => find("Currency"),
+            },
+        };
+        let currency = match currency_in(&header[columns.amount]) {
+            Some(currency) => Some(currency),
+            None => self.held_currency(repo)?,
+        };
+
+        let mut transactions = vec![];
+        let mut errors = vec![];
+        for (i, line) in lines {
+            match self.transaction(&fields(line, delimiter), &columns, currency, mode) {
+                Ok(transaction) => transactions.push(transaction),
+                // Lines are counted from 1, as editors do
+                Err(e) => errors.push(format!("Line {}: {e}", i + 1)),
+            }
+        }
+        if mode == ParseMode::Strict && !errors.is_empty() {
+            bail!(
+                "Nothing was imported, as {} rows couldn't be read; fix them, or skip them with --permissive:\n{}",
+                errors.len(),
+                errors.join("\n")
+            );
+        }
+        for error in &errors {
+            eprintln!("Skipped {error}");
+        }
+
+        let count = transactions.len();
+        if self.dry_run {
+            for transaction in transactions {
+                println!("{}", Command::AddTransaction(transaction));
+            }
+        } else {
+            repo.add_transactions_bulk(transactions, Origin::local(), self.new_currency)?;
+        }
+        let verb = if self.dry_run {
+            "Would import"
+        } else {
+            "Imported"
+        };
+        eprintln!("{verb} {count} transactions; skipped {} rows", errors.len());
+        Ok(())
+    }
+
+    /// The one currency the account holds, or otherwise the configured default
+    fn held_currency(&self, repo: &Repository) -> Result<Option<Currency>> {
+        let held = repo.account(self.account.erase())?.current.0;
+        Ok(match held.keys().exactly_one() {
+            Ok(&currency) => Some(currency),
+            Err(_) => repo.config()?.default_currency,
+        })
+    }
+
+    /// The transaction in `row`, in its own currency, that given, or otherwise the statement's
+    fn transaction(
+        &self,
+        row: &[String],
+        columns: &Columns,
+        currency: Option<Currency>,
+        mode: ParseMode,
+    ) -> Result<Transaction> {
+        let field = |i: usize, name: &str| {
+            row.get(i)
+                .map(|x| x.trim())
+                .ok_or_else(|| eyre!("There's no {name} in this row"))
+        };
+        let date = parse_date(field(columns.date, "date")?, mode)?;
+        let amount = field(columns.amount, "amount")?;
+        let written = currency_in(amount);
+        let currency = match columns.currency.map(|i| field(i, "currency")).transpose()? {
+            Some(given) if !given.is_empty() => currency_in(&given.to_uppercase())
+                .ok_or_else(|| eyre!("{given:?} isn't a currency"))?,
+            // Without --currency, the statement's is only known if something points to it
+            _ => self.currency.or(written).or(currency).ok_or_else(|| {
+                eyre!(
+                    "This row gives no currency, and the statement's must be given with --currency"
+                )
+            })?,
+        };
+        // Written with another's symbol or code, it's still in the currency given, so that's not part of the amount
+        let amount = match written.filter(|&x| x != currency) {
+            Some(other) => &without(amount, other),
+            None => amount,
+        };
+        let amount = parse_amount(amount, currency, mode)?;
+        let payee = field(columns.payee, "payee")?.to_owned();
+        ensure!(amount.0 != 0, "Transactions must be of something");
+        let inner = if amount.0 > 0 {
+            TransactionInner::Received {
+                src: payee,
+                dst: self.account,
+                dst_virt: self.virt,
+            }
+        } else {
+            TransactionInner::Paid {
+                src: self.account,
+                src_virt: self.virt,
+                dst: payee,
+            }
+        };
+        Ok(Transaction {
+            id: Id::generate(),
+            notes: String::new(),
+            amount: Amount(amount.0.abs(), currency),
+            inner,
+            attachments: vec![],
+            debts: vec![],
+            date: Some(date),
+            voided: None,
+            tags: vec![],
+        })
+    }
+}
+
+/// Which field of each row holds what
+struct Columns {
+    date: usize,
+    amount: usize,
+    payee: usize,
+    currency: Option<usize>,
+}
+
+/// The currency `s` names by its code, like `EUR`, or its symbol, like `€`
+fn currency_in(s: &str) -> Option<Currency> {
+    s.split(|c: char| !c.is_alphabetic())
+        .find(|x| is_code(x))
+        .and_then(|x| x.parse().ok())
+        .or_else(|| s.chars().find_map(Currency::from_symbol))
+}
+
+/// `amount` with `currency`'s code and symbol taken out
+fn without(amount: &str, currency: Currency) -> String {
+    let amount = amount.replace(&currency.to_string(), "");
+    match currency.symbol() {
+        Some(symbol) => amount.replace(symbol, ""),
+        None => amount,
+    }
+}
+
+/// Whether `s` looks like a currency code, which is three capitals
+fn is_code(s: &str) -> bool {
+    s.len() == 3 && s.chars().all(|c| c.is_ascii_uppercase())
+}
+
+/// A column's heading without the currency it names, so `Amount (EUR)` is found as `Amount`
+fn without_currency(heading: &str) -> String {
+    heading
+        .split(|c: char| !c.is_alphanumeric())
+        .filter(|x| !x.is_empty() && !is_code(x))
+        .join(" ")
+}
+
+/// The separator between fields, going by which appears most in the header: `,`, or `;` or tabs as used where
+/// commas are decimal points
+fn delimiter(header: &str) -> char {
+    // The last of equals is taken, so commas win ties
+    ['\t', ';', ',']
+        .into_iter()
+        .max_by_key(|&x| header.matches(x).count())
+        .expect("There are delimiters to choose from")
+}
+
+/// The fields of a CSV row, with quotes taken off
+fn fields(line: &str, delimiter: char) -> Vec<String> {
+    let mut fields = vec![String::new()];
+    let mut quoted = false;
+    let mut chars = line.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' if quoted && chars.peek() == Some(&'"') => {
+                chars.next();
+                fields.last_mut().expect("There's always a field").push('"');
+            }
+            '"' => quoted = !quoted,
+            c if c == delimiter && !quoted => fields.push(String::new()),
+            c => fields.last_mut().expect("There's always a field").push(c),
+        }
+    }
+    fields
+}
+
+/// An amount of `currency`, negative for money going out
+///
+/// Strictly, only `-1234.50`; permissively, also with thousands separators (`1,234.50`, `1.234,50`, `1 234,50`),
+/// currency codes or symbols, negatives in parentheses or with a trailing minus, and `CR`/`DR` suffixes.
+pub fn parse_amount(s: &str, currency: Currency, mode: ParseMode) -> Result<Amount> {
+    let invalid = || eyre!("{s:?} isn't an amount");
+    if mode == ParseMode::Strict {
+        let rounded = |rounding| Amount::from_decimal(s, currency, rounding).map_err(|_| invalid());
+        let amount = rounded(Rounding::Down)?;
+        ensure!(
+            amount == rounded(Rounding::Up)?,
+            "{s:?} has more decimal places than {currency} is counted to"
+        );
+        return Ok(amount);
+    }
+
+    let mut s = s.trim().to_owned();
+    let mut negative = false;
+    if let Some(inner) = s.strip_prefix('(').and_then(|x| x.strip_suffix(')')) {
+        (s, negative) = (inner.to_owned(), true);
+    }
+    let upper = s.to_uppercase();
+    if let Some(rest) = upper.strip_suffix("DR") {
+        (s, negative) = (rest.to_owned(), !negative);
+    } else if let Some(rest) = upper.strip_suffix("CR") {
+        s = rest.to_owned();
+    }
+    s = s.replace(&currency.to_string(), "");
+    s.retain(|c| {
+        !(c.is_whitespace() || c == '\'' || c == '\u{2019}' || Some(c) == currency.symbol())
+    });
+    if let Some(rest) = s.strip_suffix('-') {
+        (s, negative) = (rest.to_owned(), !negative);
+    }
+    if let Some(rest) = s.strip_prefix('-') {
+        (s, negative) = (rest.to_owned(), !negative);
+    }
+    ensure!(
+        !s.is_empty()
+            && s.chars()
+                .all(|c| c.is_ascii_digit() || c == '.' || c == ','),
+        invalid()
+    );
+    // The decimal point is whichever of `.` and `,` comes last; with only one of them, it's a thousands separator if
+    // it's used more than once or has three digits after it, as no bank gives amounts to a thousandth
+    let decimal = match (s.rfind('.'), s.rfind(',')) {
+        (Some(dot), Some(comma)) => Some(dot.max(comma)),
+        (Some(i), None) | (None, Some(i)) => (s.matches(&s[i..=i]).count() == 1
+            && (s.len() - i - 1 != 3 || s[..i].trim_start_matches('0').is_empty()))
+        .then_some(i),
+        (None, None) => None,
+    };
+    let (whole, fraction) = match decimal {
+        Some(i) => (&s[..i], &s[i + 1..]),
+        None => (s.as_str(), ""),
+    };
+    let whole = whole.replace(['.', ','], "");
+    ensure!(!fraction.contains(['.', ',']), invalid());
+    let sign = if negative { "-" } else { "" };
+    Amount::from_decimal(
+        &format!("{sign}{whole}.{fraction}"),
+        currency,
+        Rounding::HalfEven,
+    )
+    .map_err(|_| invalid())
+}
+
+/// A day
+///
+/// Strictly, only `2024-03-01`; permissively, also days before months with `/`, `.`, or `-` and two- or four-digit
+/// years, month names, and times after the day, which are ignored.
+pub fn parse_date(s: &str, mode: ParseMode) -> Result<NaiveDate> {
+    let invalid = || eyre!("{s:?} isn't a date");
+    if mode == ParseMode::Strict {
+        return NaiveDate::parse_from_str(s, "%Y-%m-%d").map_err(|_| invalid());
+    }
+    // Two-digit years first, as `%Y` would take `24` to be the year 24
+    const FORMATS: &[&str] = &[
+        "%d/%m/%y",
+        "%d.%m.%y",
+        "%d-%m-%y",
+        "%d-%b-%y",
+        "%Y-%m-%d",
+        "%Y/%m/%d",
+        "%d/%m/%Y",
+        "%d.%m.%Y",
+        "%d-%m-%Y",
+        "%d %b %Y",
+        "%d %B %Y",
+        "%d-%b-%Y",
+        "%b %d, %Y",
+        "%B %d, %Y",
+    ];
+    let s = s.trim();
+    // Any time given is after the day, separated by `T` or a space
+    let day = s
+        .split_once('T')
+        .filter(|(day, _)| day.len() == 10)
+        .map_or(s, |(day, _)| day);
+    let candidates = [day, day.split_whitespace().next().unwrap_or(day)];
+    candidates
+        .into_iter()
+        .cartesian_product(FORMATS)
+        .find_map(|(s, format)| NaiveDate::parse_from_str(s, format).ok())
+        .ok_or_else(invalid)
+}
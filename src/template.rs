@@ -0,0 +1,102 @@
+//! Starter sets of accounts for new repositories, built in or from a TOML file
+
+use std::fs;
+
+use eyre::{ensure, Context, Result};
+use itertools::Itertools;
+use serde::Deserialize;
+
+use crate::types::{Account, AccountType, Config, Currency, Id};
+
+/// The templates `monfari init --template` knows by name
+const BUILTIN: &[(&str, &str)] = &[
+    ("default", include_str!("../templates/default.toml")),
+    ("personal", include_str!("../templates/personal.toml")),
+    ("household", include_str!("../templates/household.toml")),
+    ("business", include_str!("../templates/business.toml")),
+];
+
+/// What a new repository starts with
+#[derive(Debug, Deserialize)]
+pub struct Template {
+    /// The currency of amounts given without one
+    #[serde(default)]
+    default_currency: Option<Currency>,
+    accounts: Vec<TemplateAccount>,
+}
+
+#[derive(Debug, Deserialize)]
+struct TemplateAccount {
+    name: String,
+    typ: AccountType,
+    #[serde(default)]
+    notes: String,
+    /// The bank or other institution a physical account is held at
+    #[serde(default)]
+    institution: Option<String>,
+    /// Whether quick entries use this account, of those of its type
+    #[serde(default)]
+    default: bool,
+}
+
+impl Template {
+    /// The built-in template `name`, or otherwise the one in the file at `name`
+    pub fn load(name: &str) -> Result<Self> {
+        let text = match BUILTIN.iter().find(|(x, _)| *x == name) {
+            Some((_, text)) => (*text).to_owned(),
+            None => fs::read_to_string(name).wrap_err_with(|| {
+                format!(
+                    "{name} is neither a built-in template ({}) nor a file",
+                    BUILTIN.iter().map(|(x, _)| x).join(", ")
+                )
+            })?,
+        };
+        toml::from_str(&text).wrap_err_with(|| format!("Invalid template {name}"))
+    }
+
+    /// The accounts to create, and the configuration pointing at them
+    pub fn build(self) -> Result<(Vec<Account>, Config)> {
+        ensure!(
+            self.accounts.iter().any(|x| x.typ == AccountType::Virtual),
+            "A template must have a virtual account, as a virtual account is required to do much"
+        );
+        for typ in [AccountType::Physical, AccountType::Virtual] {
+            ensure!(
+                self.accounts
+                    .iter()
+                    .filter(|x| x.typ == typ && x.default)
+                    .count()
+                    <= 1,
+                "Only one {typ} account can be the default"
+            );
+        }
+        let mut config = Config {
+            default_currency: self.default_currency,
+            ..Config::default()
+        };
+        let accounts = self
+            .accounts
+            .into_iter()
+            .map(|x| {
+                let id = Id::generate();
+                if x.default {
+                    match x.typ {
+                        AccountType::Physical => config.default_account = Some(id.unerase()),
+                        AccountType::Virtual => config.default_virtual_account = Some(id.unerase()),
+                    }
+                }
+                Account {
+                    id,
+                    name: x.name,
+                    notes: x.notes,
+                    typ: x.typ,
+                    current: Default::default(),
+                    enabled: true,
+                    institution: x.institution,
+                    deleted: false,
+                }
+            })
+            .collect();
+        Ok((accounts, config))
+    }
+}
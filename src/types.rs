@@ -1,13 +1,15 @@
 use std::{
-    collections::BTreeMap,
+    collections::{BTreeMap, BTreeSet},
     fmt::{Debug, Display},
     marker::PhantomData,
-    ops::{Add, AddAssign, Neg},
+    ops::{Add, Neg, Sub},
     str::FromStr,
+    sync::atomic::{AtomicU8, Ordering},
 };
 
+use chrono::Datelike;
 use clap::ValueEnum;
-use eyre::Result;
+use eyre::{ensure, eyre, Result};
 use ulid::Ulid;
 
 use serde::{de::Error, Deserialize, Serialize};
@@ -48,6 +50,74 @@ impl<T> Id<T> {
     pub fn new(id: Ulid) -> Self {
         Self(id, PhantomData)
     }
+
+    /// The ID as it's stored, however IDs are shown
+    pub fn proquint(&self) -> String {
+        use proqnt::IntoProquints;
+        self.0 .0.proquint_encode().to_string()
+    }
+
+    /// The ID as it's shown, unless that's short, for wherever it needs to be read back
+    pub fn full(&self) -> String {
+        match id_style() {
+            IdStyle::Ulid => self.0.to_string(),
+            IdStyle::Proquint | IdStyle::Short => self.proquint(),
+        }
+    }
+
+    /// The first 8 hex digits of a hash of the ID, as git shortens commits; the start of the ID itself would be much
+    /// the same for everything made around the same time
+    pub fn short(&self) -> String {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &self.0 .0.to_be_bytes());
+        digest.as_ref()[..4]
+            .iter()
+            .map(|x| format!("{x:02x}"))
+            .collect()
+    }
+}
+
+/// How IDs are shown
+///
+/// Whichever it is, IDs are stored as proquints, and can be typed as proquints or ULIDs. In commands, accounts can be
+/// named by their short IDs too, and transactions by their short IDs or enough of the start of them to pick one out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum IdStyle {
+    /// Like `bakod-hivat-juloz-zotil-modik-logag-nasov-fudaz`
+    #[default]
+    Proquint,
+    /// Like `01HQ3V8K2M7X9J4T6B5N0P1R2S`
+    Ulid,
+    /// Like `3fa92c1e`
+    Short,
+}
+
+static ID_STYLE: AtomicU8 = AtomicU8::new(IdStyle::Proquint as u8);
+
+/// Show IDs in `style` from now on
+pub fn show_ids(style: IdStyle) {
+    ID_STYLE.store(style as u8, Ordering::Relaxed);
+}
+
+fn id_style() -> IdStyle {
+    match ID_STYLE.load(Ordering::Relaxed) {
+        x if x == IdStyle::Ulid as u8 => IdStyle::Ulid,
+        x if x == IdStyle::Short as u8 => IdStyle::Short,
+        _ => IdStyle::Proquint,
+    }
+}
+
+thread_local! {
+    static STYLED_IDS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Serialize IDs as they're shown, rather than as they're stored, for the duration of `f`
+///
+/// Short IDs can't be read back, so are serialized as proquints.
+pub fn styled_ids<T>(f: impl FnOnce() -> T) -> T {
+    let previous = STYLED_IDS.with(|x| x.replace(true));
+    let res = f();
+    STYLED_IDS.with(|x| x.set(previous));
+    res
 }
 
 impl<T> Id<Account<T>> {
@@ -63,20 +133,22 @@ impl Id<Account> {
 
 impl<T> Debug for Id<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use proqnt::IntoProquints;
         write!(
             f,
             "Id::<{}>::(\"{}\")",
             std::any::type_name::<T>(),
-            self.0 .0.proquint_encode()
+            self.proquint()
         )
     }
 }
 
 impl<T> Display for Id<T> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        use proqnt::IntoProquints;
-        write!(f, "{}", self.0 .0.proquint_encode())
+        match id_style() {
+            IdStyle::Proquint => write!(f, "{}", self.proquint()),
+            IdStyle::Ulid => write!(f, "{}", self.0),
+            IdStyle::Short => write!(f, "{}", self.short()),
+        }
     }
 }
 
@@ -85,7 +157,10 @@ impl<T> FromStr for Id<T> {
 
     fn from_str(s: &str) -> Result<Self> {
         use proqnt::FromProquints;
-        Ok(Self::new(u128::parse_proquints(s)?.into()))
+        match u128::parse_proquints(s) {
+            Ok(id) => Ok(Self::new(id.into())),
+            Err(e) => Ok(Self::new(Ulid::from_string(s).map_err(|_| e)?)),
+        }
     }
 }
 
@@ -94,7 +169,10 @@ impl<T> Serialize for Id<T> {
     where
         S: serde::Serializer,
     {
-        self.to_string().serialize(serializer)
+        if STYLED_IDS.with(|x| x.get()) {
+            return self.full().serialize(serializer);
+        }
+        self.proquint().serialize(serializer)
     }
 }
 
@@ -115,6 +193,85 @@ impl Currency {
     pub const EUR: Self = Self(['E', 'U', 'R']);
     pub const GBP: Self = Self(['G', 'B', 'P']);
     pub const USD: Self = Self(['U', 'S', 'D']);
+    pub const JPY: Self = Self(['J', 'P', 'Y']);
+    pub const INR: Self = Self(['I', 'N', 'R']);
+
+    const SYMBOLS: &'static [(Self, char)] = &[
+        (Self::EUR, '€'),
+        (Self::GBP, '£'),
+        (Self::USD, '$'),
+        (Self::JPY, '¥'),
+        (Self::INR, '₹'),
+    ];
+
+    pub fn symbol(&self) -> Option<char> {
+        Self::SYMBOLS
+            .iter()
+            .find(|(c, _)| c == self)
+            .map(|&(_, symbol)| symbol)
+    }
+
+    pub fn from_symbol(symbol: char) -> Option<Self> {
+        Self::SYMBOLS
+            .iter()
+            .find(|&&(_, s)| s == symbol)
+            .map(|&(c, _)| c)
+    }
+
+    /// Currencies with no smaller unit in use
+    const WHOLE: &'static [Self] = &[
+        Self::JPY,
+        Self(['K', 'R', 'W']),
+        Self(['I', 'S', 'K']),
+        Self(['C', 'L', 'P']),
+        Self(['V', 'N', 'D']),
+        Self(['P', 'Y', 'G']),
+        Self(['U', 'G', 'X']),
+        Self(['X', 'A', 'F']),
+        Self(['X', 'O', 'F']),
+    ];
+
+    /// How many decimal places the currency is counted to
+    ///
+    /// Amounts are kept in hundredths whatever their currency, but there are no hundredths of a yen to pay with.
+    pub fn decimals(&self) -> u32 {
+        if Self::WHOLE.contains(self) {
+            0
+        } else {
+            2
+        }
+    }
+}
+
+/// Which way to round an amount that falls between two a currency can hold
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+pub enum Rounding {
+    /// To the nearest, halves away from zero
+    #[default]
+    HalfUp,
+    /// To the nearest, halves to the even one, as banks often do
+    HalfEven,
+    /// Towards zero
+    Down,
+    /// Away from zero
+    Up,
+}
+
+impl Rounding {
+    /// `numerator / denominator` rounded to a whole number this way, for a positive `denominator`
+    fn divide(self, numerator: i128, denominator: i128) -> i128 {
+        let (quotient, remainder) = (numerator / denominator, numerator % denominator);
+        let away = quotient + numerator.signum();
+        let half = (2 * remainder.abs()).cmp(&denominator);
+        match self {
+            _ if remainder == 0 => quotient,
+            Self::Down => quotient,
+            Self::Up => away,
+            Self::HalfUp if half.is_ge() => away,
+            Self::HalfEven if half.is_gt() || (half.is_eq() && quotient % 2 != 0) => away,
+            Self::HalfUp | Self::HalfEven => quotient,
+        }
+    }
 }
 impl Display for Currency {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
@@ -161,29 +318,92 @@ impl<'de> Deserialize<'de> for Currency {
 #[derive(Debug, Copy, Clone, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Amount(pub i32, pub Currency);
 impl Amount {
+    /// Minor units in a major one, whatever the currency
+    pub const SCALE: i32 = 100;
+
+    /// `major` units of `currency`, like 12.5 for 12.50 EUR, rounded to what the currency is counted to
+    pub fn from_major(major: f64, currency: Currency, rounding: Rounding) -> Result<Self> {
+        ensure!(major.is_finite(), "{major} isn't an amount");
+        // Formatting gives the shortest decimal that is exactly `major`, so 2.675 isn't taken for 2.67499...
+        Self::from_decimal(&major.to_string(), currency, rounding)
+    }
+
+    /// The decimal `s`, like `1234.5` or `-0.125`, as units of `currency`, rounded to what the currency is counted to
+    pub fn from_decimal(s: &str, currency: Currency, rounding: Rounding) -> Result<Self> {
+        let e = || eyre::eyre!("{s} isn't a decimal number");
+        let (negative, unsigned) = match s.strip_prefix('-') {
+            Some(s) => (true, s),
+            None => (false, s.strip_prefix('+').unwrap_or(s)),
+        };
+        let (whole, fraction) = unsigned.split_once('.').unwrap_or((unsigned, ""));
+        ensure!(
+            !(whole.is_empty() && fraction.is_empty())
+                && (whole.chars().chain(fraction.chars())).all(|c| c.is_ascii_digit()),
+            e()
+        );
+        let digits = format!("{whole}{fraction}");
+        let digits = digits.trim_start_matches('0');
+        // Past this, the arithmetic below could overflow
+        ensure!(
+            digits.len() <= 30 && fraction.len() <= 30,
+            "{s} has too many digits to be an amount"
+        );
+        let mantissa = digits.parse::<i128>().unwrap_or(0) * if negative { -1 } else { 1 };
+        // In hundredths, the smallest unit of the currency
+        let unit = 10i128.pow(2 - currency.decimals());
+        let units = rounding.divide(
+            mantissa * i128::from(Self::SCALE),
+            10i128.pow(fraction.len() as u32) * unit,
+        );
+        Ok(Self(
+            (units * unit)
+                .try_into()
+                .map_err(|_| eyre::eyre!("{s} is too large an amount"))?,
+            currency,
+        ))
+    }
+
+    /// The amount in major units, like 12.5 for 12.50 EUR
+    pub fn to_major(self) -> f64 {
+        f64::from(self.0) / f64::from(Self::SCALE)
+    }
+
     pub fn parse_num(s: &str) -> Option<i32> {
-        s.parse::<i32>().ok().map(|x| x * 100).or_else(|| {
-            let (whole, cents) = s.split_once('.')?;
-            if cents.len() != 2 || cents.chars().any(|c| !c.is_ascii_digit()) {
-                return None;
-            };
-            Some(whole.parse::<i32>().ok()? * 100 + cents.parse::<i32>().ok()?)
-        })
+        if let Some(s) = s.strip_prefix('-').filter(|s| !s.starts_with(['-', '+'])) {
+            return Self::parse_num(s).map(|x| -x);
+        }
+        // Too large an amount is no amount, rather than overflowing
+        s.parse::<i32>()
+            .ok()
+            .and_then(|x| x.checked_mul(100))
+            .or_else(|| {
+                let (whole, cents) = s.split_once('.')?;
+                if cents.len() != 2 || cents.chars().any(|c| !c.is_ascii_digit()) {
+                    return None;
+                };
+                whole
+                    .parse::<i32>()
+                    .ok()?
+                    .checked_mul(100)?
+                    .checked_add(cents.parse::<i32>().ok()?)
+            })
     }
 }
+// The alternate form (`{:#}`) uses the currency's symbol, where it has one: `€12.50` rather than `12.50 EUR`
 impl Display for Amount {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(
-            f,
-            "{}{} {}",
-            self.0 / 100,
-            if self.0 % 100 != 0 {
-                format!(".{:02}", self.0 % 100)
-            } else {
-                "".to_owned()
-            },
-            self.1
-        )
+        let sign = if self.0 < 0 { "-" } else { "" };
+        let whole = self.0.unsigned_abs() / 100;
+        let cents = self.0.unsigned_abs() % 100;
+        let cents = if cents != 0 {
+            format!(".{cents:02}")
+        } else {
+            "".to_owned()
+        };
+        match self.1.symbol() {
+            Some(symbol) if f.alternate() => write!(f, "{sign}{symbol}{whole}{cents}"),
+            _ => write!(f, "{sign}{whole}{cents} {}", self.1),
+        }
     }
 }
 impl FromStr for Amount {
@@ -191,6 +411,14 @@ impl FromStr for Amount {
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let e = || eyre::eyre!("Amounts of currency are formatted as XXXX.XX CCC");
+        let (sign, unsigned) = match s.strip_prefix('-') {
+            Some(s) => (-1, s),
+            None => (1, s),
+        };
+        if let Some(currency) = unsigned.chars().next().and_then(Currency::from_symbol) {
+            let amount = &unsigned[currency.symbol().unwrap().len_utf8()..];
+            return Ok(Self(sign * Self::parse_num(amount).ok_or_else(e)?, currency));
+        }
         let (amount, currency) = s.split_once(' ').ok_or_else(e)?;
         Ok(Self(
             Self::parse_num(amount).ok_or_else(e)?,
@@ -199,11 +427,49 @@ impl FromStr for Amount {
     }
 }
 
+#[cfg(feature = "structured-amounts")]
+thread_local! {
+    static STRUCTURED_AMOUNTS: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+/// Serialize amounts as `{minor_units, currency, scale}` rather than as strings for the duration of `f`
+#[cfg(feature = "structured-amounts")]
+pub fn structured_amounts<T>(f: impl FnOnce() -> T) -> T {
+    let previous = STRUCTURED_AMOUNTS.with(|x| x.replace(true));
+    let res = f();
+    STRUCTURED_AMOUNTS.with(|x| x.set(previous));
+    res
+}
+
+#[derive(Serialize, Deserialize)]
+struct StructuredAmount {
+    minor_units: i32,
+    currency: Currency,
+    // Number of decimal places in `minor_units`
+    scale: u32,
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AmountRepr {
+    String(String),
+    Structured(StructuredAmount),
+}
+
 impl Serialize for Amount {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
     where
         S: serde::Serializer,
     {
+        #[cfg(feature = "structured-amounts")]
+        if STRUCTURED_AMOUNTS.with(|x| x.get()) {
+            return StructuredAmount {
+                minor_units: self.0,
+                currency: self.1,
+                scale: 2,
+            }
+            .serialize(serializer);
+        }
         self.to_string().serialize(serializer)
     }
 }
@@ -213,54 +479,175 @@ impl<'de> Deserialize<'de> for Amount {
     where
         D: serde::Deserializer<'de>,
     {
-        String::deserialize(deserializer)?
-            .parse()
-            .map_err(D::Error::custom)
+        match AmountRepr::deserialize(deserializer)? {
+            AmountRepr::String(s) => s.parse().map_err(D::Error::custom),
+            AmountRepr::Structured(StructuredAmount {
+                minor_units,
+                currency,
+                scale,
+            }) => {
+                let minor_units = match scale {
+                    0..=2 => 10i32
+                        .checked_pow(2 - scale)
+                        .and_then(|x| x.checked_mul(minor_units)),
+                    // Given by clients, so as many places as they like mustn't overflow
+                    _ => match 10i32.checked_pow(scale - 2) {
+                        Some(unit) => Some(minor_units / unit).filter(|_| minor_units % unit == 0),
+                        // Nothing but zero is a whole number of cents with more places than fit
+                        None => Some(0).filter(|_| minor_units == 0),
+                    },
+                }
+                .ok_or_else(|| D::Error::custom("Amounts must be representable in cents"))?;
+                Ok(Self(minor_units, currency))
+            }
+        }
     }
 }
 
 impl Neg for Amount {
-    type Output = Self;
+    type Output = Result<Self, AmountError>;
 
     fn neg(self) -> Self::Output {
-        Self(-self.0, self.1)
+        Ok(Self(
+            self.0.checked_neg().ok_or(AmountError::Overflow(self.1))?,
+            self.1,
+        ))
     }
 }
 
 impl Add<i32> for Amount {
-    type Output = Self;
+    type Output = Result<Self, AmountError>;
     fn add(self, rhs: i32) -> Self::Output {
-        Self(self.0 + rhs, self.1)
+        Ok(Self(
+            self.0
+                .checked_add(rhs)
+                .ok_or(AmountError::Overflow(self.1))?,
+            self.1,
+        ))
     }
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, Default)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum AmountError {
+    CurrencyMismatch(Currency, Currency),
+    /// The result is more than can be held in the currency
+    Overflow(Currency),
+}
+impl Display for AmountError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CurrencyMismatch(x, y) => write!(f, "Cannot combine amounts of {x} and {y}"),
+            Self::Overflow(currency) => {
+                write!(f, "Amounts come to more than can be held in {currency}")
+            }
+        }
+    }
+}
+impl std::error::Error for AmountError {}
+
+impl Add for Amount {
+    type Output = Result<Self, AmountError>;
+    fn add(self, rhs: Self) -> Self::Output {
+        if self.1 != rhs.1 {
+            return Err(AmountError::CurrencyMismatch(self.1, rhs.1));
+        }
+        self + rhs.0
+    }
+}
+
+impl Sub for Amount {
+    type Output = Result<Self, AmountError>;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self + (-rhs)?
+    }
+}
+
+impl Amount {
+    pub fn is_zero(&self) -> bool {
+        self.0 == 0
+    }
+    pub fn is_negative(&self) -> bool {
+        self.0 < 0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize, Default)]
 pub struct Amounts(pub BTreeMap<Currency, Amount>);
 
-impl AddAssign<Amount> for Amounts {
-    fn add_assign(&mut self, amount: Amount) {
+impl Amounts {
+    /// Zero in every currency, including those with no entry at all
+    pub fn is_zero(&self) -> bool {
+        self.0.values().all(Amount::is_zero)
+    }
+    /// Negative in any currency
+    pub fn is_negative(&self) -> bool {
+        self.0.values().any(Amount::is_negative)
+    }
+    /// Add `amount` to what's held in its currency
+    pub fn add_amount(&mut self, amount: Amount) -> Result<(), AmountError> {
         let present = self.0.entry(amount.1).or_insert(Amount(0, amount.1));
-        assert!(present.1 == amount.1);
-        present.0 += amount.0;
+        *present = (*present + amount)?;
+        Ok(())
+    }
+    /// Take `amount` from what's held in its currency
+    pub fn sub_amount(&mut self, amount: Amount) -> Result<(), AmountError> {
+        self.add_amount((-amount)?)
+    }
+}
+
+impl Add for Amounts {
+    type Output = Result<Self, AmountError>;
+    fn add(mut self, rhs: Self) -> Self::Output {
+        for amount in rhs.0.into_values() {
+            self.add_amount(amount)?;
+        }
+        Ok(self)
+    }
+}
+
+impl Sub for Amounts {
+    type Output = Result<Self, AmountError>;
+    fn sub(self, rhs: Self) -> Self::Output {
+        self + (-rhs)?
+    }
+}
+
+impl Neg for Amounts {
+    type Output = Result<Self, AmountError>;
+    fn neg(self) -> Self::Output {
+        Ok(Self(
+            self.0
+                .into_iter()
+                .map(|(c, x)| Ok((c, (-x)?)))
+                .collect::<Result<_, AmountError>>()?,
+        ))
     }
 }
 
-impl std::iter::Sum<Amount> for Amounts {
-    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Self {
-        iter.fold(Self::default(), |mut acc, am| {
-            acc += am;
-            acc
+impl std::iter::Sum<Amount> for Result<Amounts, AmountError> {
+    fn sum<I: Iterator<Item = Amount>>(mut iter: I) -> Self {
+        iter.try_fold(Amounts::default(), |mut acc, am| {
+            acc.add_amount(am)?;
+            Ok(acc)
         })
     }
 }
 
 impl Display for Amounts {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let alternate = f.alternate();
         write!(
             f,
             "{}",
-            itertools::intersperse(self.0.values().map(|x| x.to_string()), ", ".to_owned())
-                .collect::<String>()
+            itertools::intersperse(
+                self.0.values().map(|x| if alternate {
+                    format!("{x:#}")
+                } else {
+                    x.to_string()
+                }),
+                ", ".to_owned()
+            )
+            .collect::<String>()
         )
     }
 }
@@ -307,6 +694,136 @@ pub struct Account<Type = AccountType> {
     pub typ: Type,
     pub current: Amounts,
     pub enabled: bool,
+    /// The bank or other institution a physical account is held at
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub institution: Option<String>,
+    /// A tombstone for an account deleted before it was used, kept so its history can still be replayed, but left out
+    /// of everything else
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub deleted: bool,
+}
+
+impl<T> Account<T> {
+    /// Disabled and empty, so there's no reason to show it by default
+    pub fn is_collapsible(&self) -> bool {
+        !self.enabled && self.current.is_zero()
+    }
+}
+
+impl Account {
+    /// Only physical accounts hold money at an institution
+    pub fn check_institution(&self) -> Result<()> {
+        ensure!(
+            self.institution.is_none() || self.typ == AccountType::Physical,
+            "Only physical accounts can be held at an institution"
+        );
+        Ok(())
+    }
+}
+
+/// Which accounts a change to many at once applies to; every condition given must hold, so none selects them all
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccountSelector {
+    #[serde(default)]
+    pub typ: Option<AccountType>,
+    /// Part of the name, in any case
+    #[serde(default)]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub enabled: Option<bool>,
+    /// Whether there's nothing in the account
+    #[serde(default)]
+    pub empty: Option<bool>,
+}
+
+/// A condition on accounts, as given to `--filter`: `type=virtual`, `name=holiday`, `enabled=false`, or `empty=true`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccountCondition {
+    Type(AccountType),
+    Name(String),
+    Enabled(bool),
+    Empty(bool),
+}
+
+impl FromStr for AccountCondition {
+    type Err = eyre::Report;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((key, value)) = s.split_once('=') else {
+            eyre::bail!("Conditions are given as `key=value`")
+        };
+        Ok(match key {
+            "type" => Self::Type(value.parse().map_err(|e| eyre::eyre!("{e}"))?),
+            "name" => Self::Name(value.to_owned()),
+            "enabled" => Self::Enabled(value.parse()?),
+            "empty" => Self::Empty(value.parse()?),
+            _ => eyre::bail!("Accounts can only be selected by type, name, enabled, or empty"),
+        })
+    }
+}
+
+impl AccountSelector {
+    pub fn add(&mut self, condition: AccountCondition) {
+        match condition {
+            AccountCondition::Type(typ) => self.typ = Some(typ),
+            AccountCondition::Name(name) => self.name = Some(name),
+            AccountCondition::Enabled(enabled) => self.enabled = Some(enabled),
+            AccountCondition::Empty(empty) => self.empty = Some(empty),
+        }
+    }
+
+    pub fn matches(&self, account: &Account) -> bool {
+        self.typ.is_none_or(|typ| account.typ == typ)
+            && self
+                .name
+                .as_ref()
+                .is_none_or(|name| account.name.to_lowercase().contains(&name.to_lowercase()))
+            && self
+                .enabled
+                .is_none_or(|enabled| account.enabled == enabled)
+            && self
+                .empty
+                .is_none_or(|empty| account.current.is_zero() == empty)
+    }
+}
+
+/// How much an account has been used
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Activity {
+    /// The number of transactions involving the account
+    pub transactions: usize,
+    /// When the latest of them was added
+    pub last_activity: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Activity {
+    pub fn of(transactions: &[Transaction]) -> Self {
+        Self {
+            transactions: transactions.len(),
+            last_activity: transactions
+                .iter()
+                .map(|x| x.id)
+                .max()
+                .map(|id| id.0.datetime().into()),
+        }
+    }
+}
+
+/// An overview of a repository, for dashboards
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    pub physical_accounts: usize,
+    pub virtual_accounts: usize,
+    /// Of both kinds
+    pub disabled_accounts: usize,
+    pub transactions: usize,
+    pub first_transaction: Option<chrono::DateTime<chrono::Utc>>,
+    pub last_transaction: Option<chrono::DateTime<chrono::Utc>>,
+    /// Held across all physical accounts
+    pub totals: Amounts,
+    /// How the repository is stored: `local` or `sqlite`
+    pub backend: String,
+    /// Of monfari, where the repository is stored
+    pub version: String,
 }
 
 impl From<Id<Account<Physical>>> for Id<Account> {
@@ -327,6 +844,36 @@ pub struct Transaction {
     pub amount: Amount,
     #[serde(flatten)]
     pub inner: TransactionInner,
+    /// Files such as receipts, kept outside the repository
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub attachments: Vec<Attachment>,
+    /// Parts of it owed between me and others, such as their share of a bill I paid
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub debts: Vec<Debt>,
+    /// The day it happened, if not the day it was added
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub date: Option<chrono::NaiveDate>,
+    /// The transaction reversing it, if it was voided
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub voided: Option<Id<Transaction>>,
+    /// Free-form labels to find it by, such as `holiday` or `tax-deductible`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+}
+
+/// A file attached to a transaction, stored by its contents
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Attachment {
+    pub name: String,
+    pub sha256: String,
+}
+
+/// Part of a transaction owed between me and someone else
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Debt {
+    pub person: String,
+    /// Positive if they owe it to me, negative if I owe it to them
+    pub amount: Amount,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
@@ -345,26 +892,90 @@ pub enum TransactionInner {
     MovePhys {
         src: Id<Account<Physical>>,
         dst: Id<Account<Physical>>,
+        /// The virtual account whose money was moved, to track where it's held; taken from those held in `src` in
+        /// proportion if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        virt: Option<Id<Account<Virtual>>>,
     },
     MoveVirt {
         src: Id<Account<Virtual>>,
         dst: Id<Account<Virtual>>,
+        /// The physical account the money moved is held in, to track it; taken from those holding `src` in proportion
+        /// if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        phys: Option<Id<Account<Physical>>>,
     },
     // Goes to the same account it came from
     Convert {
         acc: Id<Account<Physical>>,
         acc_virt: Id<Account<Virtual>>,
         new_amount: Amount,
+        /// The part of `amount` taken in fees rather than converted, if known
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fee: Option<Amount>,
     },
+    /// A correction to match reality, such as after reconciling; `amount` is added, and may be negative
+    Adjustment {
+        acc: Id<Account<Physical>>,
+        acc_virt: Id<Account<Virtual>>,
+        reason: String,
+    },
+    /// Any number of legs, for what the rest can't record at once, like a salary with tax and a pension taken out
+    ///
+    /// Physical and virtual accounts each change by as much as came in from outside them, and `amount` is the largest
+    /// leg; the rest are the common shapes of this.
+    General { legs: Vec<Leg> },
+    /// A correction to where `virt`'s money is held: `amount` of it moves from being held in `src` to `dst`, and as
+    /// much of what else was held in `dst` back to `src`; no balance changes
+    Remap {
+        virt: Id<Account<Virtual>>,
+        src: Id<Account<Physical>>,
+        dst: Id<Account<Physical>>,
+    },
+}
+
+/// One account's part in a general transaction, or that of someone outside the repository
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Leg {
+    pub party: Party,
+    /// Into the account or to the party if positive, out of it or from them if negative
+    pub amount: Amount,
+    /// What this part of it was for, shown in the account's register; `#tags` in it count as in the transaction's
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Party {
+    Account(Id<Account>),
+    /// Someone outside the repository, like an employer or a shop
+    External(String),
+}
+
+impl TransactionInner {
+    /// The currencies of any amounts besides the transaction's own
+    pub fn currencies(&self) -> Vec<Currency> {
+        match *self {
+            TransactionInner::Convert {
+                new_amount, fee, ..
+            } => [Some(new_amount.1), fee.map(|x| x.1)]
+                .into_iter()
+                .flatten()
+                .collect(),
+            _ => vec![],
+        }
+    }
 }
 
 impl Transaction {
-    pub fn results(&self) -> Vec<(Id<Account>, Amount)> {
+    pub fn results(&self) -> Result<Vec<(Id<Account>, Amount)>, AmountError> {
         use TransactionInner::*;
         let &Transaction {
             amount, ref inner, ..
         } = self;
-        match *inner {
+        let minus = || -amount;
+        Ok(match *inner {
             Received {
                 src: _,
                 dst,
@@ -376,43 +987,1301 @@ impl Transaction {
                 src,
                 src_virt,
                 dst: _,
-            } => vec![(src.into(), -amount), (src_virt.into(), -amount)],
-            MovePhys { src, dst } => {
-                vec![(src.into(), -amount), (dst.into(), amount)]
+            } => vec![(src.into(), minus()?), (src_virt.into(), minus()?)],
+            MovePhys { src, dst, .. } => {
+                vec![(src.into(), minus()?), (dst.into(), amount)]
             }
-            MoveVirt { src, dst } => vec![(src.into(), -amount), (dst.into(), amount)],
+            MoveVirt { src, dst, .. } => vec![(src.into(), minus()?), (dst.into(), amount)],
             Convert {
                 acc,
                 acc_virt,
                 new_amount,
+                fee: _,
             } => vec![
-                (acc.into(), -amount),
+                (acc.into(), minus()?),
                 (acc.into(), new_amount),
-                (acc_virt.into(), -amount),
+                (acc_virt.into(), minus()?),
                 (acc_virt.into(), new_amount),
             ],
+            Adjustment {
+                acc,
+                acc_virt,
+                reason: _,
+            } => vec![(acc.into(), amount), (acc_virt.into(), amount)],
+            General { ref legs } => legs
+                .iter()
+                .filter_map(|leg| match leg.party {
+                    Party::Account(id) => Some((id, leg.amount)),
+                    Party::External(_) => None,
+                })
+                .collect(),
+            Remap { .. } => vec![],
+        })
+    }
+
+    /// Check every amount in the transaction is positive, or for adjustments that it isn't zero
+    ///
+    /// Mistakes are undone with a [`reversal`](Self::reversal), not with negative or zero amounts.
+    pub fn validate(&self) -> Result<()> {
+        for (i, tag) in self.tags.iter().enumerate() {
+            ensure!(
+                !tag.is_empty() && !tag.starts_with('#') && !tag.contains(char::is_whitespace),
+                "Tags are single words, without a #, not \"{tag}\""
+            );
+            ensure!(!self.tags[..i].contains(tag), "Tag {tag} is given twice");
         }
+        for Debt { person, amount } in &self.debts {
+            ensure!(
+                !person.trim().is_empty(),
+                "A debt must be owed by or to someone"
+            );
+            ensure!(
+                amount.1 == self.amount.1
+                    && amount.0 != 0
+                    && amount.0.unsigned_abs() <= self.amount.0.unsigned_abs(),
+                "A debt must be of some of the transaction's {}, not {amount}",
+                self.amount
+            );
+        }
+        if let TransactionInner::Adjustment { .. } = self.inner {
+            ensure!(self.amount.0 != 0, "An adjustment must change a balance");
+            return Ok(());
+        }
+        if let TransactionInner::General { legs } = &self.inner {
+            for (i, leg) in legs.iter().enumerate() {
+                ensure!(
+                    leg.amount.1 == self.amount.1 && leg.amount.0 != 0,
+                    "Every leg of a transaction in {} must be of some amount in it, not {}",
+                    self.amount.1,
+                    leg.amount
+                );
+                ensure!(
+                    legs[..i].iter().all(|x| x.party != leg.party),
+                    "Each account and outside party can only have one leg of a transaction"
+                );
+            }
+            ensure!(
+                legs.iter()
+                    .filter(|x| matches!(x.party, Party::Account(_)))
+                    .count()
+                    >= 2,
+                "A transaction must involve at least two accounts"
+            );
+            ensure!(
+                legs.iter().map(|x| x.amount.0.unsigned_abs()).max()
+                    == Some(self.amount.0.unsigned_abs()),
+                "The amount of a transaction with legs is its largest leg, not {}",
+                self.amount
+            );
+        }
+        let mut amounts = vec![("amount", self.amount)];
+        if let TransactionInner::Convert {
+            new_amount, fee, ..
+        } = self.inner
+        {
+            amounts.push(("converted amount", new_amount));
+            amounts.extend(fee.map(|fee| ("fee", fee)));
+        }
+        for (name, amount) in amounts {
+            ensure!(
+                amount.0 > 0,
+                "The {name} of a transaction must be positive, not {amount}; reverse a transaction to undo it"
+            );
+        }
+        Ok(())
     }
 
-    pub fn accounts(&self) -> [Id<Account>; 2] {
+    /// The currencies of the amounts in this transaction
+    pub fn currencies(&self) -> Vec<Currency> {
+        let mut currencies = vec![self.amount.1];
+        currencies.extend(self.inner.currencies());
+        currencies
+    }
+
+    /// A new transaction undoing this one
+    pub fn reversal(&self) -> Result<Self, AmountError> {
+        use TransactionInner::*;
+        let (amount, inner) = match self.inner.clone() {
+            Received { src, dst, dst_virt } => (
+                self.amount,
+                Paid {
+                    src: dst,
+                    src_virt: dst_virt,
+                    dst: src,
+                },
+            ),
+            Paid { src, src_virt, dst } => (
+                self.amount,
+                Received {
+                    src: dst,
+                    dst: src,
+                    dst_virt: src_virt,
+                },
+            ),
+            MovePhys { src, dst, virt } => (
+                self.amount,
+                MovePhys {
+                    src: dst,
+                    dst: src,
+                    virt,
+                },
+            ),
+            MoveVirt { src, dst, phys } => (
+                self.amount,
+                MoveVirt {
+                    src: dst,
+                    dst: src,
+                    phys,
+                },
+            ),
+            Convert {
+                acc,
+                acc_virt,
+                new_amount,
+                fee: _,
+            } => (
+                new_amount,
+                Convert {
+                    acc,
+                    acc_virt,
+                    new_amount: self.amount,
+                    fee: None,
+                },
+            ),
+            Adjustment {
+                acc,
+                acc_virt,
+                reason,
+            } => (
+                (-self.amount)?,
+                Adjustment {
+                    acc,
+                    acc_virt,
+                    reason,
+                },
+            ),
+            General { legs } => (
+                self.amount,
+                General {
+                    legs: legs
+                        .into_iter()
+                        .map(|leg| {
+                            Ok(Leg {
+                                amount: (-leg.amount)?,
+                                ..leg
+                            })
+                        })
+                        .collect::<Result<_, AmountError>>()?,
+                },
+            ),
+            Remap { virt, src, dst } => (
+                self.amount,
+                Remap {
+                    virt,
+                    src: dst,
+                    dst: src,
+                },
+            ),
+        };
+        Ok(Self {
+            id: Id::generate(),
+            notes: format!("Reverses {}", self.id.proquint()),
+            amount,
+            inner,
+            attachments: vec![],
+            debts: self
+                .debts
+                .iter()
+                .map(|x| {
+                    Ok(Debt {
+                        person: x.person.clone(),
+                        amount: (-x.amount)?,
+                    })
+                })
+                .collect::<Result<_, AmountError>>()?,
+            date: None,
+            voided: None,
+            tags: self.tags.clone(),
+        })
+    }
+
+    /// The virtual account a payment came out of, or a receipt went into
+    pub fn virtual_account(&self) -> Option<Id<Account<Virtual>>> {
+        match self.inner {
+            TransactionInner::Paid { src_virt, .. } => Some(src_virt),
+            TransactionInner::Received { dst_virt, .. } => Some(dst_virt),
+            _ => None,
+        }
+    }
+
+    /// The virtual account money was spent from and how much, for payments and the fees on conversions
+    pub fn spent_from(&self) -> Option<(Id<Account<Virtual>>, Amount)> {
+        match self.inner {
+            TransactionInner::Paid { src_virt, .. } => Some((src_virt, self.amount)),
+            TransactionInner::Convert {
+                acc_virt,
+                fee: Some(fee),
+                ..
+            } => Some((acc_virt, fee)),
+            _ => None,
+        }
+    }
+
+    /// Who a payment went to, or a receipt came from
+    pub fn payee(&self) -> Option<&str> {
+        match &self.inner {
+            TransactionInner::Paid { dst, .. } => Some(dst),
+            TransactionInner::Received { src, .. } => Some(src),
+            _ => None,
+        }
+    }
+
+    /// Who a payment went to, or a receipt came from, to change it
+    pub fn payee_mut(&mut self) -> Option<&mut String> {
+        match &mut self.inner {
+            TransactionInner::Paid { dst, .. } => Some(dst),
+            TransactionInner::Received { src, .. } => Some(src),
+            _ => None,
+        }
+    }
+
+    /// The notes on `account`'s leg, for general transactions with some there
+    pub fn leg_notes(&self, account: Id<Account>) -> Option<&str> {
+        match &self.inner {
+            TransactionInner::General { legs } => legs
+                .iter()
+                .find(|x| x.party == Party::Account(account))
+                .map(|x| x.notes.as_str())
+                .filter(|x| !x.is_empty()),
+            _ => None,
+        }
+    }
+
+    /// The notes on `account`'s leg, to change them, for general transactions involving it
+    pub fn leg_notes_mut(&mut self, account: Id<Account>) -> Option<&mut String> {
+        match &mut self.inner {
+            TransactionInner::General { legs } => legs
+                .iter_mut()
+                .find(|x| x.party == Party::Account(account))
+                .map(|x| &mut x.notes),
+            _ => None,
+        }
+    }
+
+    /// Its notes followed by those on each of its legs, for searching and finding tags in
+    pub fn all_notes(&self) -> String {
+        let mut notes = self.notes.clone();
+        if let TransactionInner::General { legs } = &self.inner {
+            for leg in legs.iter().filter(|x| !x.notes.is_empty()) {
+                notes.push('\n');
+                notes += &leg.notes;
+            }
+        }
+        notes
+    }
+
+    /// The virtual account a payment came out of, or a receipt went into, to change it
+    pub fn virtual_account_mut(&mut self) -> Option<&mut Id<Account<Virtual>>> {
+        match &mut self.inner {
+            TransactionInner::Paid { src_virt, .. } => Some(src_virt),
+            TransactionInner::Received { dst_virt, .. } => Some(dst_virt),
+            _ => None,
+        }
+    }
+
+    /// Use `to` wherever this uses `from`, giving whether it did anywhere
+    ///
+    /// Whether `to` is of the type it's used as is left to [`check_account_types`](Self::check_account_types).
+    pub fn replace_account(&mut self, from: Id<Account>, to: Id<Account>) -> bool {
+        use TransactionInner::*;
+        let ids = match &mut self.inner {
+            Received { dst, dst_virt, .. } => vec![&mut dst.0, &mut dst_virt.0],
+            Paid { src, src_virt, .. } => vec![&mut src.0, &mut src_virt.0],
+            MovePhys { src, dst, virt } => [&mut src.0, &mut dst.0]
+                .into_iter()
+                .chain(virt.as_mut().map(|x| &mut x.0))
+                .collect(),
+            MoveVirt { src, dst, phys } => [&mut src.0, &mut dst.0]
+                .into_iter()
+                .chain(phys.as_mut().map(|x| &mut x.0))
+                .collect(),
+            Convert { acc, acc_virt, .. } | Adjustment { acc, acc_virt, .. } => {
+                vec![&mut acc.0, &mut acc_virt.0]
+            }
+            General { legs } => legs
+                .iter_mut()
+                .filter_map(|leg| match &mut leg.party {
+                    Party::Account(id) => Some(&mut id.0),
+                    Party::External(_) => None,
+                })
+                .collect(),
+            Remap { virt, src, dst } => vec![&mut virt.0, &mut src.0, &mut dst.0],
+        };
+        let mut replaced = false;
+        for id in ids {
+            if *id == from.0 {
+                *id = to.0;
+                replaced = true;
+            }
+        }
+        replaced
+    }
+
+    /// A new transaction moving this one's amount between virtual accounts, as if it had come out of or gone into
+    /// `virt` instead, for payments and receipts
+    pub fn categorization(&self, virt: Id<Account<Virtual>>, tags: &[&str]) -> Option<Self> {
+        let inner = match self.inner {
+            TransactionInner::Paid { src, src_virt, .. } => TransactionInner::MoveVirt {
+                src: virt,
+                dst: src_virt,
+                phys: Some(src),
+            },
+            TransactionInner::Received { dst, dst_virt, .. } => TransactionInner::MoveVirt {
+                src: dst_virt,
+                dst: virt,
+                phys: Some(dst),
+            },
+            _ => return None,
+        };
+        let mut notes = format!("Categorizes {}", self.id.proquint());
+        for tag in tags {
+            notes += " ";
+            notes += tag;
+        }
+        Some(Self {
+            id: Id::generate(),
+            notes,
+            amount: self.amount,
+            inner,
+            attachments: vec![],
+            debts: vec![],
+            date: None,
+            voided: None,
+            tags: vec![],
+        })
+    }
+
+    /// How much of the new currency each unit of the old one bought, after fees, for conversions
+    pub fn effective_rate(&self) -> Option<f64> {
+        match self.inner {
+            TransactionInner::Convert { new_amount, .. } => {
+                Some(f64::from(new_amount.0) / f64::from(self.amount.0))
+            }
+            _ => None,
+        }
+    }
+
+    /// The day it's dated, in local time; that's when it was added, unless it was given a date
+    pub fn date(&self) -> chrono::NaiveDate {
+        self.date.unwrap_or_else(|| {
+            chrono::DateTime::<chrono::Local>::from(self.id.0.datetime()).date_naive()
+        })
+    }
+
+    /// The accounts involved, as [`accounts`](Self::accounts) gives them, with the type each is used as, for all but
+    /// general transactions, whose legs can be in either
+    pub fn account_types(&self) -> Vec<(Id<Account>, AccountType)> {
+        use AccountType::*;
+        let accounts = self.accounts();
+        let types: &[_] = match self.inner {
+            TransactionInner::MovePhys { .. } => &[Physical, Physical, Virtual],
+            TransactionInner::MoveVirt { .. } => &[Virtual, Virtual, Physical],
+            TransactionInner::Remap { .. } => &[Virtual, Physical, Physical],
+            TransactionInner::General { .. } => return vec![],
+            _ => &[Physical, Virtual],
+        };
+        accounts.into_iter().zip(types.iter().copied()).collect()
+    }
+
+    /// Check each account involved is of the type it's used as, given the name and type of each by `account`
+    ///
+    /// IDs are typed by the kind of account they're for, but only as far as whoever made the transaction typed them.
+    pub fn check_account_types(
+        &self,
+        mut account: impl FnMut(Id<Account>) -> Result<(String, AccountType)>,
+    ) -> Result<()> {
+        for (id, expected) in self.account_types() {
+            let (name, typ) = account(id)?;
+            ensure!(
+                typ == expected,
+                "{name} is a {typ} account, so can't be used as a {expected} one"
+            );
+        }
+        if let TransactionInner::General { legs } = &self.inner {
+            let currency = self.amount.1;
+            // Legs are as they're sent, so may be large enough to overflow
+            let overflow =
+                || eyre!("The legs of a transaction add up to more than can be held in {currency}");
+            let (mut physical, mut virt, mut outside) = (0i32, 0i32, 0i32);
+            for leg in legs {
+                match leg.party {
+                    Party::Account(id) => {
+                        let total = match account(id)?.1 {
+                            AccountType::Physical => &mut physical,
+                            AccountType::Virtual => &mut virt,
+                        };
+                        *total = total.checked_add(leg.amount.0).ok_or_else(overflow)?;
+                    }
+                    Party::External(_) => {
+                        outside = outside.checked_sub(leg.amount.0).ok_or_else(overflow)?
+                    }
+                }
+            }
+            ensure!(
+                physical == outside && virt == outside,
+                "Physical and virtual accounts must each change by as much as comes in from outside them, not by {} and {} with {} coming in",
+                Amount(physical, currency),
+                Amount(virt, currency),
+                Amount(outside, currency)
+            );
+        }
+        Ok(())
+    }
+
+    pub fn accounts(&self) -> Vec<Id<Account>> {
         match &self.inner {
             TransactionInner::Received {
                 src: _,
                 dst,
                 dst_virt,
-            } => [dst.erase(), dst_virt.erase()],
+            } => vec![dst.erase(), dst_virt.erase()],
             TransactionInner::Paid {
                 src,
                 src_virt,
                 dst: _,
-            } => [src.erase(), src_virt.erase()],
-            TransactionInner::MovePhys { src, dst } => [src.erase(), dst.erase()],
-            TransactionInner::MoveVirt { src, dst } => [src.erase(), dst.erase()],
+            } => vec![src.erase(), src_virt.erase()],
+            TransactionInner::MovePhys { src, dst, virt } => [src.erase(), dst.erase()]
+                .into_iter()
+                .chain(virt.map(|x| x.erase()))
+                .collect(),
+            TransactionInner::MoveVirt { src, dst, phys } => [src.erase(), dst.erase()]
+                .into_iter()
+                .chain(phys.map(|x| x.erase()))
+                .collect(),
             TransactionInner::Convert {
                 acc,
                 acc_virt,
                 new_amount: _,
-            } => [acc.erase(), acc_virt.erase()],
+                fee: _,
+            } => vec![acc.erase(), acc_virt.erase()],
+            TransactionInner::Adjustment {
+                acc,
+                acc_virt,
+                reason: _,
+            } => vec![acc.erase(), acc_virt.erase()],
+            TransactionInner::General { legs } => legs
+                .iter()
+                .filter_map(|leg| match leg.party {
+                    Party::Account(id) => Some(id),
+                    Party::External(_) => None,
+                })
+                .collect(),
+            TransactionInner::Remap { virt, src, dst } => {
+                vec![virt.erase(), src.erase(), dst.erase()]
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum SearchResult {
+    Account(Account),
+    Transaction(Transaction),
+}
+
+/// Settings kept with a repository, so they apply to everyone using it
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// The currency of amounts given without one
+    pub default_currency: Option<Currency>,
+    /// The account quick entries are paid from
+    pub default_account: Option<Id<Account<Physical>>>,
+    /// The virtual account quick entries are paid from, if they don't name one
+    pub default_virtual_account: Option<Id<Account<Virtual>>>,
+    /// The notes and coins to ask about when counting cash, largest first, by currency
+    pub denominations: BTreeMap<Currency, Vec<Amount>>,
+    /// Where attachments are stored, as `s3://bucket/prefix`
+    pub attachments: Option<String>,
+    /// The virtual account imported payments and receipts go into when theirs isn't in the repository, to be triaged
+    pub uncategorized: Option<Id<Account<Virtual>>>,
+    /// Shares of money received to set aside in other virtual accounts as it comes in
+    pub funding: Vec<FundingRule>,
+    /// Ways of sharing an amount out between virtual accounts with `allocate`, by name
+    pub plans: BTreeMap<String, Vec<Allocation>>,
+    /// What a transaction's notes start as when they're asked for, with `{date}`, `{amount}` and `{payee}` filled in
+    pub notes_template: Option<String>,
+    /// The currencies transactions may be in besides the default and those already held, so a typo doesn't start a
+    /// new one
+    pub currencies: Vec<Currency>,
+    /// Loans being paid back in monthly instalments, by name
+    pub loans: BTreeMap<String, Loan>,
+    /// Schedules kept here before they were kept on their own, moved out when the repository is opened
+    #[serde(rename = "schedules", skip_serializing)]
+    pub legacy_schedules: BTreeMap<String, LegacySchedule>,
+    /// Transactions added with `transaction apply` by giving only their amount, such as a weekly shop, by name
+    pub transaction_templates: BTreeMap<String, TransactionTemplate>,
+    /// How IDs are shown
+    pub id_style: IdStyle,
+    /// The amounts, by currency, above which a transaction must be confirmed before it's added, to catch an amount
+    /// typed wrong
+    pub large_transactions: BTreeMap<Currency, Amount>,
+    /// How years are divided into the periods reports cover
+    pub periods: Periods,
+}
+
+impl Config {
+    /// The threshold `transaction` is over, if it's large enough to need confirming
+    pub fn large(&self, transaction: &Transaction) -> Option<Amount> {
+        let converted = match transaction.inner {
+            TransactionInner::Convert { new_amount, .. } => Some(new_amount),
+            _ => None,
+        };
+        std::iter::once(transaction.amount)
+            .chain(converted)
+            .find_map(|Amount(amount, currency)| {
+                self.large_transactions
+                    .get(&currency)
+                    .filter(|limit| amount > limit.0)
+                    .copied()
+            })
+    }
+}
+
+/// How years are divided into periods: months by default, or the fiscal year's own periods
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Periods {
+    /// The day each year starts on; months start on its day too, as they do from one payday to the next
+    pub year_start: MonthDay,
+    /// The lengths in weeks of the periods a year is divided into, repeated through it, like 4, 4 and 5, rather than
+    /// months; the last runs on to the end of the year
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub weeks: Vec<u32>,
+}
+
+impl Default for Periods {
+    fn default() -> Self {
+        Self {
+            year_start: MonthDay { month: 1, day: 1 },
+            weeks: vec![],
+        }
+    }
+}
+
+impl Periods {
+    /// The year `date` is in, which is the calendar year unless the year starts on another day
+    pub fn year_of(&self, date: chrono::NaiveDate) -> Period {
+        let MonthDay { month, day } = self.year_start;
+        let start_in = |year| {
+            chrono::NaiveDate::from_ymd_opt(year, month, day).expect("Every year has the 28th")
+        };
+        let start = match start_in(date.year()) {
+            start if start <= date => start,
+            _ => start_in(date.year() - 1),
+        };
+        Period {
+            start,
+            end: start + chrono::Months::new(12),
+        }
+    }
+
+    /// The period `date` is in
+    pub fn of(&self, date: chrono::NaiveDate) -> Period {
+        let year = self.year_of(date);
+        let mut start = year.start;
+        if self.weeks.is_empty() {
+            while start + chrono::Months::new(1) <= date {
+                start = start + chrono::Months::new(1);
+            }
+            return Period {
+                start,
+                end: start + chrono::Months::new(1),
+            };
+        }
+        let mut lengths = self.weeks.iter().cycle().peekable();
+        loop {
+            let weeks = lengths.next().expect("Periods have lengths");
+            let mut end = start + chrono::Days::new(7 * u64::from(*weeks));
+            let next = **lengths.peek().expect("Lengths repeat");
+            // The odd day or so a whole number of weeks leaves over goes in the year's last period
+            if end + chrono::Days::new(7 * u64::from(next)) > year.end {
+                end = year.end;
+            }
+            if date < end {
+                return Period { start, end };
+            }
+            start = end;
         }
     }
+
+    /// The period just before `period`
+    pub fn before(&self, period: Period) -> Period {
+        self.of(period.start - chrono::Days::new(1))
+    }
+}
+
+/// A run of days, from `start` up to but not including `end`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Period {
+    pub start: chrono::NaiveDate,
+    pub end: chrono::NaiveDate,
+}
+
+impl Period {
+    pub fn contains(&self, date: chrono::NaiveDate) -> bool {
+        self.start <= date && date < self.end
+    }
+
+    fn is_calendar(&self, months: u32) -> bool {
+        self.start.day() == 1 && self.end == self.start + chrono::Months::new(months)
+    }
+
+    /// A name for it that sorts with others of the same length: the month or year if it's a calendar one, otherwise
+    /// the day it starts
+    pub fn key(&self) -> String {
+        if self.is_calendar(1) {
+            self.start.format("%Y-%m").to_string()
+        } else if self.is_calendar(12) {
+            self.start.format("%Y").to_string()
+        } else {
+            self.start.to_string()
+        }
+    }
+}
+
+impl Display for Period {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_calendar(1) {
+            return write!(f, "{}", self.start.format("%B %Y"));
+        }
+        let last = self.end.pred_opt().expect("Periods end after they start");
+        write!(
+            f,
+            "{} to {}",
+            self.start.format("%-d %B %Y"),
+            last.format("%-d %B %Y")
+        )
+    }
+}
+
+/// A day of the year, like `04-06`; it's no later than the 28th, so every month has it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MonthDay {
+    pub month: u32,
+    pub day: u32,
+}
+
+impl FromStr for MonthDay {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let e = || eyre::eyre!("Days are given as MM-DD, like 04-06, no later than the 28th");
+        let (month, day) = s.split_once('-').ok_or_else(e)?;
+        let month = month.parse().map_err(|_| e())?;
+        let day = day.parse().map_err(|_| e())?;
+        ensure!((1..=12).contains(&month) && (1..=28).contains(&day), e());
+        Ok(Self { month, day })
+    }
+}
+
+impl Display for MonthDay {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:02}-{:02}", self.month, self.day)
+    }
+}
+
+impl Serialize for MonthDay {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for MonthDay {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Parse the lengths in weeks of a year's periods, like `4-4-5`, or `months` for none
+pub fn parse_period_weeks(s: &str) -> Result<Vec<u32>> {
+    if s == "months" {
+        return Ok(vec![]);
+    }
+    let weeks = s
+        .split('-')
+        .map(|x| x.trim().parse::<u32>())
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| eyre::eyre!("Periods are lengths in weeks, like 4-4-5, or `months`"))?;
+    ensure!(
+        weeks.iter().all(|&x| x > 0) && weeks.iter().sum::<u32>() <= 52,
+        "Periods are at least a week long, and fit in a year"
+    );
+    Ok(weeks)
+}
+
+/// A virtual account's share of an amount allocated by a plan
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Allocation {
+    pub to: Id<Account<Virtual>>,
+    pub share: Share,
+}
+
+/// On every transaction received into `on`, move a share of it from the virtual account it went into to `to`
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FundingRule {
+    /// The physical or virtual account the transaction was received into
+    pub on: Id<Account>,
+    pub to: Id<Account<Virtual>>,
+    pub share: Share,
+}
+
+impl FundingRule {
+    /// The move this rule makes after `transaction`, if it applies to it, of no more than the `left` of it that
+    /// earlier rules haven't moved
+    ///
+    /// The move's ID follows the transaction's, `offset` along, so it sorts straight after it, and it's dated the same.
+    pub fn fund(
+        &self,
+        transaction: &Transaction,
+        left: Amount,
+        offset: u128,
+    ) -> Option<Transaction> {
+        let TransactionInner::Received { dst, dst_virt, .. } = transaction.inner else {
+            return None;
+        };
+        if !(self.on == dst.erase() || self.on == dst_virt.erase()) || self.to == dst_virt {
+            return None;
+        }
+        let amount = self.share.of(transaction.amount)?.min(left);
+        if amount.0 <= 0 {
+            return None;
+        }
+        Some(Transaction {
+            id: Id::new(Ulid(transaction.id.0 .0.wrapping_add(offset))),
+            notes: format!("Funding {}", self.share),
+            amount,
+            inner: TransactionInner::MoveVirt {
+                src: dst_virt,
+                dst: self.to,
+                phys: Some(dst),
+            },
+            attachments: vec![],
+            debts: vec![],
+            date: transaction.date,
+            voided: None,
+            tags: vec![],
+        })
+    }
+}
+
+/// How much of a transaction a funding rule moves: a percentage, like `10%`, or at most an amount, like `50 EUR`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Share {
+    /// In hundredths of a percent
+    Percent(u32),
+    Fixed(Amount),
+}
+
+impl Share {
+    /// This share of `amount`, rounded down so as never to move more than is there, or nothing if it comes to nothing
+    pub fn of(self, amount: Amount) -> Option<Amount> {
+        let share = match self {
+            Self::Percent(hundredths) => Amount(
+                Rounding::Down.divide(i128::from(amount.0) * i128::from(hundredths), 10000) as i32,
+                amount.1,
+            ),
+            Self::Fixed(fixed) if fixed.1 == amount.1 => fixed.min(amount),
+            Self::Fixed(_) => return None,
+        };
+        (share.0 > 0).then_some(share)
+    }
+}
+
+impl Display for Share {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Percent(hundredths) => {
+                write!(f, "{}", hundredths / 100)?;
+                match hundredths % 100 {
+                    0 => {}
+                    x if x % 10 == 0 => write!(f, ".{}", x / 10)?,
+                    x => write!(f, ".{x:02}")?,
+                }
+                write!(f, "%")
+            }
+            Self::Fixed(amount) => write!(f, "{amount}"),
+        }
+    }
+}
+
+impl FromStr for Share {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some(percent) = s.strip_suffix('%') else {
+            return Ok(Self::Fixed(s.parse()?));
+        };
+        let e = || eyre::eyre!("Shares are formatted as a percentage, like 12.5%, or an amount");
+        let percent = percent.trim();
+        let (whole, fraction) = percent.split_once('.').unwrap_or((percent, ""));
+        ensure!(
+            !whole.is_empty()
+                && fraction.len() <= 2
+                && (whole.chars().chain(fraction.chars())).all(|c| c.is_ascii_digit()),
+            e()
+        );
+        let fraction = format!("{fraction:0<2}").parse::<u32>().map_err(|_| e())?;
+        let hundredths = whole
+            .parse::<u32>()
+            .ok()
+            .and_then(|x| x.checked_mul(100)?.checked_add(fraction))
+            .ok_or_else(e)?;
+        ensure!(
+            (1..=10000).contains(&hundredths),
+            "Shares are between 0.01% and 100%"
+        );
+        Ok(Self::Percent(hundredths))
+    }
+}
+
+impl Serialize for Share {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.to_string().serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Share {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+impl Config {
+    /// The denominations of `currency` to count, as configured or else those in circulation for common currencies
+    pub fn denominations(&self, currency: Currency) -> Vec<Amount> {
+        if let Some(denominations) = self.denominations.get(&currency) {
+            return denominations.clone();
+        }
+        let units: &[i32] = match currency {
+            Currency::EUR => &[
+                50000, 20000, 10000, 5000, 2000, 1000, 500, 200, 100, 50, 20, 10, 5, 2, 1,
+            ],
+            Currency::GBP => &[5000, 2000, 1000, 500, 200, 100, 50, 20, 10, 5, 2, 1],
+            Currency::USD => &[10000, 5000, 2000, 1000, 500, 100, 25, 10, 5, 1],
+            _ => &[],
+        };
+        units.iter().map(|&x| Amount(x, currency)).collect()
+    }
+}
+
+/// Parse a funding rule like `ACCOUNT:10%:SAVINGS`, moving 10% of what's received into ACCOUNT to SAVINGS
+pub fn parse_funding_rule(s: &str) -> Result<FundingRule> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(on), Some(share), Some(to)) = (parts.next(), parts.next(), parts.next()) else {
+        eyre::bail!(
+            "Funding rules are formatted as ACCOUNT:SHARE:VIRTUAL, like ACCOUNT:10%:VIRTUAL"
+        );
+    };
+    Ok(FundingRule {
+        on: on.parse()?,
+        share: share.parse()?,
+        to: to.parse()?,
+    })
+}
+
+/// Parse a plan like `salary=RENT:40%,SAVINGS:100 EUR`
+pub fn parse_plan(s: &str) -> Result<(String, Vec<Allocation>)> {
+    let e = || eyre::eyre!("Plans are formatted as NAME=VIRTUAL:SHARE,VIRTUAL:SHARE,...");
+    let (name, allocations) = s.split_once('=').ok_or_else(e)?;
+    ensure!(
+        !name.is_empty() && !name.contains(char::is_whitespace),
+        "Plan names are single words"
+    );
+    let allocations = allocations
+        .split(',')
+        .map(|x| {
+            let (to, share) = x.trim().split_once(':').ok_or_else(e)?;
+            Ok(Allocation {
+                to: to.parse()?,
+                share: share.parse()?,
+            })
+        })
+        .collect::<Result<_>>()?;
+    Ok((name.to_owned(), allocations))
+}
+
+/// A loan paid back in monthly instalments, each split into the month's interest and a part of the principal
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Loan {
+    /// What was borrowed
+    pub principal: Amount,
+    /// The interest a year, as a percentage; none for a loan without interest
+    #[serde(default)]
+    pub rate: Option<Share>,
+    pub instalment: Amount,
+    /// The month of the first instalment
+    pub start: chrono::NaiveDate,
+    /// The virtual account the interest is paid from
+    pub interest_account: Id<Account<Virtual>>,
+    /// The virtual account the rest of each instalment is paid from
+    pub principal_account: Id<Account<Virtual>>,
+}
+
+impl Loan {
+    /// A month's interest on `outstanding`
+    pub fn interest(&self, outstanding: Amount) -> Amount {
+        let hundredths = match self.rate {
+            Some(Share::Percent(hundredths)) => hundredths,
+            _ => 0,
+        };
+        Amount(
+            Rounding::HalfEven.divide(
+                i128::from(outstanding.0) * i128::from(hundredths),
+                10000 * 12,
+            ) as i32,
+            outstanding.1,
+        )
+    }
+
+    /// How many more instalments it takes to pay off `outstanding`, if they ever do
+    pub fn instalments_left(&self, mut outstanding: Amount) -> Option<u32> {
+        let mut count = 0;
+        while outstanding.0 > 0 {
+            let principal = self.instalment.0 - self.interest(outstanding).0;
+            // A century of instalments is as good as never
+            if principal <= 0 || count == 1200 {
+                return None;
+            }
+            outstanding.0 -= principal;
+            count += 1;
+        }
+        Some(count)
+    }
+}
+
+/// Parse a loan like `car=10000 EUR:4.5%:300 EUR:2024-01:INTEREST:PRINCIPAL`
+pub fn parse_loan(s: &str) -> Result<(String, Loan)> {
+    let e = || {
+        eyre::eyre!(
+            "Loans are formatted as NAME=PRINCIPAL:RATE:INSTALMENT:FIRST-MONTH:INTEREST-VIRTUAL:PRINCIPAL-VIRTUAL"
+        )
+    };
+    let (name, loan) = s.split_once('=').ok_or_else(e)?;
+    ensure!(!name.trim().is_empty(), e());
+    let mut fields = loan.split(':').map(str::trim);
+    let mut field = || fields.next().ok_or_else(e);
+    let principal = field()?.parse::<Amount>()?;
+    let rate = match field()? {
+        "0%" => None,
+        rate => Some(rate.parse::<Share>()?),
+    };
+    ensure!(
+        rate.is_none_or(|x| matches!(x, Share::Percent(_))),
+        "A loan's rate is a percentage, like 4.5%"
+    );
+    let instalment = field()?.parse::<Amount>()?;
+    let start = field()?;
+    let start = chrono::NaiveDate::parse_from_str(&format!("{start}-01"), "%Y-%m-%d")
+        .map_err(|_| eyre::eyre!("The first month is formatted like 2024-01, not {start}"))?;
+    let loan = Loan {
+        principal,
+        rate,
+        instalment,
+        start,
+        interest_account: field()?.parse()?,
+        principal_account: field()?.parse()?,
+    };
+    ensure!(
+        principal.0 > 0 && instalment.0 > 0 && principal.1 == instalment.1,
+        "A loan's principal and instalment must be positive, and in the same currency"
+    );
+    ensure!(
+        loan.interest_account != loan.principal_account,
+        "Interest and principal must be paid from different virtual accounts, to be told apart"
+    );
+    Ok((name.trim().to_owned(), loan))
+}
+
+/// A transaction added again on each day its recurrence comes round, such as rent or a subscription
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ScheduledTransaction {
+    pub id: Id<Self>,
+    pub name: String,
+    pub every: Recurrence,
+    /// The first day it may be added on; days before it was scheduled aren't made up for
+    pub start: chrono::NaiveDate,
+    /// The last day it's been added for, so each day is added once, however often and from wherever it's ticked
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last: Option<chrono::NaiveDate>,
+    /// The notes of each transaction added; its name if empty
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+    pub amount: Amount,
+    #[serde(flatten)]
+    pub inner: TransactionInner,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub debts: Vec<Debt>,
+}
+
+impl ScheduledTransaction {
+    /// The days it's due on that it hasn't been added for, up to `today`, oldest first
+    pub fn due(&self, today: chrono::NaiveDate) -> impl Iterator<Item = chrono::NaiveDate> + '_ {
+        let from = match self.last.and_then(|x| x.succ_opt()) {
+            Some(next) => next.max(self.start),
+            None => self.start,
+        };
+        from.iter_days()
+            .take_while(move |x| *x <= today)
+            .filter(|x| self.every.matches(*x))
+    }
+
+    /// The transaction to add for `date`
+    pub fn transaction(&self, date: chrono::NaiveDate) -> Transaction {
+        Transaction {
+            id: Id::generate(),
+            notes: if self.notes.is_empty() {
+                self.name.clone()
+            } else {
+                self.notes.clone()
+            },
+            amount: self.amount,
+            inner: self.inner.clone(),
+            attachments: vec![],
+            debts: self.debts.clone(),
+            date: Some(date),
+            voided: None,
+            tags: vec![],
+        }
+    }
+
+    /// Record that it's been added for `date`, which must be a day it's due on and after the last it was added for
+    pub fn advance(&mut self, date: Option<chrono::NaiveDate>) -> Result<()> {
+        let Some(date) = date else {
+            eyre::bail!("A scheduled transaction must be dated the day it's added for")
+        };
+        ensure!(
+            date >= self.start && self.every.matches(date),
+            "{} isn't due on {date}",
+            self.name
+        );
+        ensure!(
+            self.last.is_none_or(|last| date > last),
+            "{} has already been added for {date}",
+            self.name
+        );
+        self.last = Some(date);
+        Ok(())
+    }
+}
+
+/// A schedule as configurations kept them, by name, before schedules were kept on their own
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct LegacySchedule {
+    pub every: Recurrence,
+    pub start: chrono::NaiveDate,
+    #[serde(default)]
+    pub notes: String,
+    pub amount: Amount,
+    #[serde(flatten)]
+    pub inner: TransactionInner,
+    #[serde(default)]
+    pub debts: Vec<Debt>,
+}
+
+/// A transaction to add again and again with a different amount, such as a weekly shop
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TransactionTemplate {
+    /// The notes of each transaction added; asked for if empty
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub notes: String,
+    #[serde(flatten)]
+    pub inner: TransactionInner,
+}
+
+impl TransactionTemplate {
+    /// The transaction it makes of `amount`, left undated
+    pub fn transaction(&self, amount: Amount) -> Transaction {
+        Transaction {
+            id: Id::generate(),
+            notes: self.notes.clone(),
+            amount,
+            inner: self.inner.clone(),
+            attachments: vec![],
+            debts: vec![],
+            date: None,
+            voided: None,
+            tags: vec![],
+        }
+    }
+}
+
+/// The days something recurs on, as the last three fields of a cron schedule: days of the month, months, and days of
+/// the week, like `1 * *` or `* * mon-fri`
+///
+/// Each field is `*`, or a list of numbers, ranges like `1-5` and steps like `*/3` or `1-12/2`. As with cron, a day
+/// matching either of the day fields recurs when both are given, and months without a day given are skipped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Recurrence {
+    text: String,
+    days: Option<BTreeSet<u32>>,
+    months: Option<BTreeSet<u32>>,
+    weekdays: Option<BTreeSet<u32>>,
+}
+
+impl Recurrence {
+    pub fn matches(&self, date: chrono::NaiveDate) -> bool {
+        let has = |field: &Option<BTreeSet<u32>>, x| field.as_ref().is_none_or(|f| f.contains(&x));
+        let day = has(&self.days, date.day());
+        let weekday = has(&self.weekdays, date.weekday().num_days_from_sunday());
+        let day = match (&self.days, &self.weekdays) {
+            (Some(_), Some(_)) => day || weekday,
+            _ => day && weekday,
+        };
+        day && has(&self.months, date.month())
+    }
+}
+
+const WEEKDAYS: [&str; 7] = ["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+const MONTHS: [&str; 12] = [
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+
+/// One field of a recurrence, within `min..=max`, with `names` for the values from `min` on; `None` for `*`
+fn recurrence_field(s: &str, min: u32, max: u32, names: &[&str]) -> Result<Option<BTreeSet<u32>>> {
+    if s == "*" {
+        return Ok(None);
+    }
+    let value = |x: &str| {
+        let n = match names.iter().position(|name| x.eq_ignore_ascii_case(name)) {
+            Some(i) => min + i as u32,
+            None => x
+                .parse()
+                .map_err(|_| eyre::eyre!("{x} isn't a number or a name in a recurrence"))?,
+        };
+        ensure!(
+            (min..=max).contains(&n),
+            "{n} is outside {min}-{max} in a recurrence"
+        );
+        Ok(n)
+    };
+    let mut values = BTreeSet::new();
+    for part in s.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => (range, step.parse::<usize>()?),
+            None => (part, 1),
+        };
+        ensure!(step > 0, "A recurrence's steps must be at least 1");
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            // `N/STEP` runs from N to the end
+            None if step > 1 => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        ensure!(start <= end, "{range} runs backwards in a recurrence");
+        values.extend((start..=end).step_by(step));
+    }
+    Ok(Some(values))
+}
+
+impl FromStr for Recurrence {
+    type Err = eyre::Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let fields = s.split_whitespace().collect::<Vec<_>>();
+        let [days, months, weekdays] = fields[..] else {
+            eyre::bail!(
+                "A recurrence has three fields, days of the month, months, and days of the week, like `1 * *`"
+            );
+        };
+        let mut weekdays = recurrence_field(weekdays, 0, 7, &WEEKDAYS)?;
+        // Sunday is 0 or 7, as in cron
+        if let Some(weekdays) = &mut weekdays {
+            if weekdays.remove(&7) {
+                weekdays.insert(0);
+            }
+        }
+        Ok(Self {
+            text: fields.join(" "),
+            days: recurrence_field(days, 1, 31, &[])?,
+            months: recurrence_field(months, 1, 12, &MONTHS)?,
+            weekdays,
+        })
+    }
+}
+
+impl Display for Recurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.text)
+    }
+}
+
+impl Serialize for Recurrence {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        self.text.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Recurrence {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        String::deserialize(deserializer)?
+            .parse()
+            .map_err(D::Error::custom)
+    }
+}
+
+/// Parse a schedule like `rent:1 * *:900 EUR paid ...`, leaving the transaction to be parsed with the repository's
+/// accounts
+pub fn parse_schedule(s: &str) -> Result<(String, Recurrence, String)> {
+    let e = || {
+        eyre::eyre!(
+            "Schedules are formatted as NAME:RECURRENCE:TRANSACTION, like `rent:1 * *:900 EUR ...`"
+        )
+    };
+    let mut fields = s.splitn(3, ':').map(str::trim);
+    let mut field = || fields.next().ok_or_else(e);
+    let name = field()?;
+    ensure!(!name.is_empty(), e());
+    let every = field()?.parse()?;
+    Ok((name.to_owned(), every, field()?.to_owned()))
+}
+
+/// Parse denominations like `EUR=50,20,10,0.50`
+pub fn parse_denominations(s: &str) -> Result<(Currency, Vec<Amount>)> {
+    let (currency, denominations) = s
+        .split_once('=')
+        .ok_or_else(|| eyre::eyre!("Denominations are formatted as CCC=AMOUNT,AMOUNT,..."))?;
+    let currency = currency.parse()?;
+    let denominations = denominations
+        .split(',')
+        .map(|x| {
+            Amount::parse_num(x.trim())
+                .map(|x| Amount(x, currency))
+                .ok_or_else(|| eyre::eyre!("Invalid denomination {x}"))
+        })
+        .collect::<Result<_>>()?;
+    Ok((currency, denominations))
 }